@@ -1,4 +1,8 @@
-use std::{borrow::Cow, collections::HashMap, path::PathBuf};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
 
 use clap::{Args, Parser, ValueEnum};
 use figment::{
@@ -60,6 +64,18 @@ fn default_cache_type() -> RouteCacheType {
     RouteCacheType::MemCache
 }
 
+fn default_max_buffered_body_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_max_redirect_hops() -> usize {
+    5
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
 fn default_cache_path() -> PathBuf {
     PathBuf::from("/tmp")
 }
@@ -167,7 +183,7 @@ impl Default for Path {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub struct RouteHeaderAdd {
     /// The name of the header
     pub name: Cow<'static, str>,
@@ -182,6 +198,311 @@ pub struct RouteHeaderRemove {
     pub name: Cow<'static, str>,
 }
 
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RouteRequireHeader {
+    /// The name of the header that must be present in the request (ex.: "X-Api-Version")
+    pub name: Cow<'static, str>,
+
+    /// Optional: the value the header must match (exact match).
+    /// If not set, the header only needs to be present.
+    pub value: Option<Cow<'static, str>>,
+}
+
+/// Routes the requests to named groups of upstreams (the `group` of the
+/// upstreams) depending on their headers
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteUpstreamGroups {
+    /// The group of the requests matching none of the rules (default: `default`,
+    /// the group of the upstreams without one)
+    #[serde(default = "default_upstream_group")]
+    pub default: Cow<'static, str>,
+
+    /// The group of a request is the one of the first rule it matches
+    #[serde(default)]
+    pub rules: Vec<RouteUpstreamGroupRule>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteUpstreamGroupRule {
+    /// The name of the request header (ex.: "X-Canary")
+    pub name: Cow<'static, str>,
+
+    /// Optional: the value the header must match (exact match).
+    /// If not set, the header only needs to be present.
+    pub value: Option<Cow<'static, str>>,
+
+    /// The group handling the requests matching the rule
+    pub group: Cow<'static, str>,
+}
+
+/// The group of the upstreams without one
+pub const DEFAULT_UPSTREAM_GROUP: &str = "default";
+
+fn default_upstream_group() -> Cow<'static, str> {
+    Cow::Borrowed(DEFAULT_UPSTREAM_GROUP)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteRedirect {
+    /// The URL that requests are redirected to (ex: "https://www.example.com")
+    pub to: Cow<'static, str>,
+
+    /// The status code of the redirect (301, 302, 303, 307 or 308)
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct RouteTrailers {
+    /// Response trailers removed before the response reaches the client
+    /// (ex: "grpc-status-details-bin")
+    #[serde(default)]
+    pub strip: Vec<Cow<'static, str>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteRetry {
+    /// How many times a failed request is sent again to another upstream
+    /// (0 disables retries)
+    #[serde(default)]
+    pub attempts: usize,
+
+    /// Buffers the request body before proxying so that requests with a body
+    /// (ex: POST, PUT) can be retried after being sent to an upstream.
+    /// Without it, only requests without a body are retried.
+    #[serde(default)]
+    pub buffer_request_body: bool,
+
+    /// The largest request body (in bytes) that is buffered, bigger bodies
+    /// are streamed to the upstream and the request is not retried.
+    /// (max: 65536)
+    #[serde(default = "default_max_buffered_body_bytes")]
+    pub max_buffered_body_bytes: usize,
+}
+
+/// Redirects of the upstreams followed by proksi, the client only receives
+/// the final response
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteFollowRedirects {
+    /// How many redirects are followed for a request, the last redirect is
+    /// sent to the client as is (default: 5, max: 10)
+    #[serde(default = "default_max_redirect_hops")]
+    pub max_hops: usize,
+}
+
+impl Default for RouteFollowRedirects {
+    fn default() -> Self {
+        Self {
+            max_hops: default_max_redirect_hops(),
+        }
+    }
+}
+
+impl Default for RouteRetry {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            buffer_request_body: false,
+            max_buffered_body_bytes: default_max_buffered_body_bytes(),
+        }
+    }
+}
+
+/// The labels of a route (name, value)
+pub type RouteLabels = BTreeMap<Cow<'static, str>, Cow<'static, str>>;
+
+/// An HTTP health check sent to every upstream of a route
+/// (without it, upstreams are checked with a TCP connection)
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteHealthCheck {
+    /// The method of the health check requests (ex: `HEAD`, default: `GET`)
+    #[serde(default = "default_health_check_method")]
+    pub method: Cow<'static, str>,
+
+    /// The path requested on the upstreams (default: `/`)
+    #[serde(default = "default_health_check_path")]
+    pub path: Cow<'static, str>,
+
+    /// Headers sent with the health check (ex: `Authorization`).
+    /// The `Host` header is the route host unless it is set here.
+    #[serde(default)]
+    pub headers: Vec<RouteHeaderAdd>,
+
+    /// A text the response body must contain, on top of a `200` status
+    pub expected_body: Option<Cow<'static, str>>,
+}
+
+impl Default for RouteHealthCheck {
+    fn default() -> Self {
+        Self {
+            method: default_health_check_method(),
+            path: default_health_check_path(),
+            headers: vec![],
+            expected_body: None,
+        }
+    }
+}
+
+fn default_health_check_method() -> Cow<'static, str> {
+    Cow::Borrowed("GET")
+}
+
+fn default_health_check_path() -> Cow<'static, str> {
+    Cow::Borrowed("/")
+}
+
+/// Weights of the upstreams derived from a load metric they expose
+/// (ex: their CPU usage), busy upstreams get less traffic
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RouteLoadWeights {
+    /// The path of the metrics of the upstreams, in the Prometheus text
+    /// format (default: `/metrics`)
+    #[serde(default = "default_load_weights_path")]
+    pub path: Cow<'static, str>,
+
+    /// The name of the load metric (ex: `process_cpu_usage`)
+    pub metric: Cow<'static, str>,
+
+    /// The value of the metric of a fully loaded upstream (default: `1.0`)
+    #[serde(default = "default_load_weights_max_load")]
+    pub max_load: f64,
+
+    /// The weight of an idle upstream, a fully loaded one gets `1` (default: `10`)
+    #[serde(default = "default_load_weights_max_weight")]
+    pub max_weight: usize,
+
+    /// How often the metrics are scraped (default: `10`)
+    #[serde(default = "default_load_weights_interval_secs")]
+    pub interval_secs: u64,
+}
+
+fn default_load_weights_path() -> Cow<'static, str> {
+    Cow::Borrowed("/metrics")
+}
+
+fn default_load_weights_max_load() -> f64 {
+    1.0
+}
+
+fn default_load_weights_max_weight() -> usize {
+    10
+}
+
+fn default_load_weights_interval_secs() -> u64 {
+    10
+}
+
+/// How the upstream of each request of a route is selected
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RouteSelectionAlgorithm {
+    /// Weighted round robin, the turns of a backend are consecutive
+    /// (weights `3, 1` give `a a a b`)
+    #[default]
+    RoundRobin,
+    /// Smooth weighted round robin, the turns of the backends are interleaved
+    /// (weights `3, 1` give `a a b a`)
+    SmoothWeightedRoundRobin,
+    /// The requests with the same key (see `hash_key`) are sent to the same
+    /// backend while it is available, weights are not used
+    ConsistentHash,
+}
+
+impl RouteSelectionAlgorithm {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::RoundRobin => "round_robin",
+            Self::SmoothWeightedRoundRobin => "smooth_weighted_round_robin",
+            Self::ConsistentHash => "consistent_hash",
+        }
+    }
+}
+
+/// What happens to an update of a route (config reload, discovery) that only
+/// removes upstreams and leaves it without any of its healthy backends
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RouteLastHealthyBackend {
+    /// The update is applied, requests fail until a backend is healthy again
+    #[default]
+    Allow,
+    /// The update is refused and the current upstreams of the route are kept
+    Refuse,
+}
+
+/// What happens to the requests of a route that don't match its path patterns
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RouteNoMatchAction {
+    /// Answers with a 404
+    #[default]
+    Respond,
+    /// Proxies the request to the route upstreams anyway
+    Proxy,
+}
+
+/// How requests that don't match the path patterns of a route are handled
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteNoMatch {
+    /// `respond` (default) with a 404 or `proxy` to the route upstreams
+    #[serde(default, deserialize_with = "no_match_action_deser")]
+    pub action: RouteNoMatchAction,
+
+    /// The body of the 404 (default: none)
+    pub body: Option<Cow<'static, str>>,
+
+    /// The content type of the 404 body (default: `text/plain`)
+    #[serde(default = "default_no_match_content_type")]
+    pub content_type: Cow<'static, str>,
+}
+
+fn default_no_match_content_type() -> Cow<'static, str> {
+    Cow::Borrowed("text/plain")
+}
+
+/// Where the access logs of a route are written
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteAccessLog {
+    /// The file receiving the access logs of the route instead of the global
+    /// logger target (ex: a restricted file for routes with sensitive data)
+    pub destination: PathBuf,
+}
+
+/// How the addresses of the upstreams of a route are resolved
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RouteDns {
+    /// How long (in seconds) a failed resolution is remembered before the
+    /// upstream is resolved again, the upstream is unavailable meanwhile.
+    /// (0 disables it, default: 5)
+    #[serde(default = "default_dns_negative_ttl_secs")]
+    pub negative_ttl_secs: u64,
+}
+
+impl Default for RouteDns {
+    fn default() -> Self {
+        Self {
+            negative_ttl_secs: default_dns_negative_ttl_secs(),
+        }
+    }
+}
+
+fn default_dns_negative_ttl_secs() -> u64 {
+    5
+}
+
+/// Limits how many requests of a route are proxied at the same time
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct RouteConcurrency {
+    /// In-flight requests allowed for the whole route, other requests are
+    /// rejected with a 503 (unlimited when not set)
+    pub max_in_flight: Option<usize>,
+
+    /// In-flight requests allowed for a single client, other requests of
+    /// that client are rejected with a 429 (unlimited when not set)
+    pub max_in_flight_per_client: Option<usize>,
+
+    /// The request header identifying a client (ex: an API key header),
+    /// when not set (or missing from the request) the client IP is used
+    pub client_key_header: Option<Cow<'static, str>>,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RouteHeader {
     /// The name of the header
@@ -189,6 +510,24 @@ pub struct RouteHeader {
 
     /// The value of the header
     pub remove: Option<Vec<RouteHeaderRemove>>,
+
+    /// Overrides the global `hop_by_hop_headers` policy for the route
+    /// (ex: `preserve` for routes serving WebSockets)
+    #[serde(default, deserialize_with = "hop_by_hop_headers_opt_deser")]
+    pub hop_by_hop: Option<HopByHopHeaders>,
+}
+
+/// What to do with the hop-by-hop headers (RFC 7230) of a request
+/// before it is forwarded to the upstream
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HopByHopHeaders {
+    /// `Connection`, `Keep-Alive`, `Upgrade`, `TE`, `Trailer`, `Proxy-*` and every
+    /// header listed in `Connection` are removed (`TE: trailers` is kept)
+    #[default]
+    Strip,
+    /// Headers are forwarded as they are received (required for WebSockets),
+    /// except `Trailer` as the request trailers are never forwarded
+    Preserve,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -209,6 +548,41 @@ pub struct RouteUpstream {
     pub sni: Option<String>,
 
     pub headers: Option<RouteHeader>,
+
+    /// Optional: Whether the upstream only accepts reads (`read_only`) or
+    /// both reads and writes (`read_write`). Write methods (POST, PUT etc.)
+    /// are never sent to `read_only` upstreams. (default: `read_write`)
+    #[serde(default, deserialize_with = "upstream_access_deser")]
+    pub access: RouteUpstreamAccess,
+
+    /// Optional: The zone of the upstream (ex: `eu-west-1a`). Upstreams in the
+    /// same zone as proksi (`server.zone`) are preferred while they are healthy.
+    pub zone: Option<Cow<'static, str>>,
+
+    /// Optional: The priority tier of the upstream (default: 1). The upstreams
+    /// of the lowest priority with healthy backends receive all the traffic,
+    /// the next tier only takes over when none of them is available.
+    pub priority: Option<u16>,
+
+    /// Optional: The group of the upstream (default: `default`), requests are
+    /// routed to a group by the `upstream_groups` of the route
+    pub group: Option<Cow<'static, str>>,
+
+    /// Optional: The upstream speaks HTTP/2 without TLS (h2c, ex: gRPC servers).
+    /// TLS upstreams negotiate the protocol with TLS instead.
+    #[serde(default)]
+    pub h2c: bool,
+
+    /// Optional: Whether proksi connects to the upstream (and runs its health
+    /// checks) with TLS (default: only the upstreams on port 443)
+    pub tls: Option<bool>,
+}
+
+impl RouteUpstream {
+    /// Whether the upstream is connected to with TLS
+    pub fn is_tls(&self) -> bool {
+        self.tls.unwrap_or(self.port == 443)
+    }
 }
 
 impl Default for RouteUpstream {
@@ -220,11 +594,26 @@ impl Default for RouteUpstream {
             weight: None,
             sni: None,
             headers: None,
+            access: RouteUpstreamAccess::default(),
+            zone: None,
+            priority: None,
+            group: None,
+            h2c: false,
+            tls: None,
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RouteUpstreamAccess {
+    /// Serves every request method
+    #[default]
+    ReadWrite,
+    /// Only serves read methods (GET, HEAD)
+    ReadOnly,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RouteSslCertificate {
     /// Whether to use a self-signed certificate if the certificate can't be
     /// retrieved from the path or object storage (or generated from letsencrypt)
@@ -256,7 +645,7 @@ pub struct RoutePlugin {
     pub config: Option<HashMap<Cow<'static, str>, serde_json::Value>>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RouteSslPath {
     /// Path to the certificate .key file (e.g. `/etc/proksi/certs/my-host.key`)
     pub key: PathBuf,
@@ -265,7 +654,7 @@ pub struct RouteSslPath {
     pub pem: PathBuf,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub enum ProtoVersion {
     V1_1,
     V1_2,
@@ -283,7 +672,7 @@ impl From<pingora::tls::ssl::SslVersion> for ProtoVersion {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct RouteSsl {
     /// If provided, will be used instead of generating certificates from
     /// Let's Encrypt or self-signed certificates.
@@ -343,7 +732,7 @@ pub struct RouteCache {
     pub path: PathBuf,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Default, Clone)]
 pub struct Route {
     /// The hostname that the proxy will accept
     /// requests for the upstreams in the route.
@@ -353,6 +742,12 @@ pub struct Route {
     /// also be used to create the certificate for the domain when `letsencrypt` is enabled.
     pub host: Cow<'static, str>,
 
+    /// (Optional) The route only matches the requests received on this port of
+    /// proksi (ex: an internal listener from `server.additional_https_addresses`).
+    /// For a given host, the route of the listener port takes precedence over
+    /// the route without one, which matches every listener.
+    pub listener_port: Option<u16>,
+
     pub cache: Option<RouteCache>,
 
     /// Plugins that will be applied to the route/host
@@ -370,11 +765,125 @@ pub struct Route {
     pub headers: Option<RouteHeader>,
 
     /// The upstreams to which the request will be proxied,
+    #[serde(default)]
     pub upstreams: Vec<RouteUpstream>,
 
+    /// Redirects every request to the given URL instead of proxying it
+    /// (cannot be used with `upstreams`)
+    pub redirect: Option<RouteRedirect>,
+
+    /// How the trailers of the responses are forwarded
+    pub trailers: Option<RouteTrailers>,
+
     /// The matcher for the route
     /// (ex: path, query, etc.)
     pub match_with: Option<RouteMatcher>,
+
+    /// What happens to requests that don't match `match_with`
+    /// (default: a 404 without body)
+    pub no_match: Option<RouteNoMatch>,
+
+    /// Headers that every request must have, requests without them
+    /// are rejected with a 400 before reaching the upstreams
+    pub require_headers: Option<Vec<RouteRequireHeader>>,
+
+    /// Only the request headers of this list are forwarded to the upstreams,
+    /// the others are dropped (`Host` and the headers framing the body are
+    /// always kept). Every header is forwarded by default.
+    pub forward_headers_allowlist: Option<Vec<Cow<'static, str>>>,
+
+    /// Routes the requests to groups of upstreams depending on their headers
+    /// (ex: the canary upstreams for requests with `X-Canary: true`)
+    pub upstream_groups: Option<RouteUpstreamGroups>,
+
+    /// Overrides the destination of the access logs of the route
+    pub access_log: Option<RouteAccessLog>,
+
+    /// Overrides `logging.slow_request_threshold_ms` for the route
+    /// (0 disables slow request logs for the route)
+    pub slow_request_threshold_ms: Option<u64>,
+
+    /// Retries for requests that failed to reach an upstream
+    pub retry: Option<RouteRetry>,
+
+    /// Follows the redirects (3xx with a `Location`) of the upstreams to the
+    /// host of the request instead of sending them to the client. Only `GET`
+    /// and `HEAD` requests are followed. Disabled by default.
+    pub follow_redirects: Option<RouteFollowRedirects>,
+
+    /// In-flight request limits for the route and for each of its clients
+    pub concurrency: Option<RouteConcurrency>,
+
+    /// How the upstreams of the route are health checked
+    pub health_check: Option<RouteHealthCheck>,
+
+    /// Derives the weights of the upstreams from a load metric they expose,
+    /// instead of their `weight` (the route then uses the smooth weighted round robin)
+    pub load_weights: Option<RouteLoadWeights>,
+
+    /// How the upstream addresses of the route are resolved
+    #[serde(default)]
+    pub dns: RouteDns,
+
+    /// Free-form labels (ex: `team = "payments"`) added to the series of the
+    /// route metrics and listed by the admin API. Their names must be valid
+    /// Prometheus label names.
+    #[serde(default)]
+    pub labels: RouteLabels,
+
+    /// Adds how the upstream was selected (backend, weight, algorithm and
+    /// whether it was a fallback or a retry) to the access logs of the route.
+    /// Disabled by default as it increases the log volume.
+    #[serde(default)]
+    pub log_upstream_selection: bool,
+
+    /// Logs the retries, fallbacks and priority tier failovers of the requests
+    /// to the route as distinct events (with the attempt, the previous and the
+    /// next backend and the reason). Disabled by default.
+    #[serde(default)]
+    pub log_upstream_events: bool,
+
+    /// Debug: the name of a response header set to the backend that served
+    /// the request (ex: `X-Upstream: 10.0.0.3:8080`). Unset by default so that
+    /// the backend addresses aren't disclosed in production.
+    pub debug_upstream_header: Option<Cow<'static, str>>,
+
+    /// How the upstream of each request is selected: `round_robin` (default),
+    /// `smooth_weighted_round_robin` or `consistent_hash`
+    #[serde(default, deserialize_with = "selection_algorithm_deser")]
+    pub selection_algorithm: RouteSelectionAlgorithm,
+
+    /// The template of the key of the `consistent_hash` selection, made of
+    /// text and `{header.<name>}`, `{query.<name>}`, `{path.<index>}`, `{path}`,
+    /// `{method}` or `{client_ip}` (ex: `{header.x-tenant}/{path.1}`).
+    /// Defaults to `{client_ip}`.
+    pub hash_key: Option<Cow<'static, str>>,
+
+    /// The seed of the upstream selection, it decides which backend the
+    /// round robin starts with. Random by default, a fixed seed makes the
+    /// selection sequence reproducible (ex: in tests or benchmarks).
+    pub selection_seed: Option<u64>,
+
+    /// Whether removing the last healthy backends of the route is `allow`ed
+    /// (default) or `refuse`d, to protect against a total outage
+    #[serde(default, deserialize_with = "last_healthy_backend_deser")]
+    pub last_healthy_backend: RouteLastHealthyBackend,
+
+    /// When a reload removes the route, the requests in flight have this many
+    /// seconds to complete instead of being cut, the new requests are answered
+    /// with a 503 meanwhile. Removed at once by default.
+    pub drain_on_remove_secs: Option<u64>,
+
+    /// When the route is added, it only starts serving once one of its
+    /// backends passed a health check, instead of assuming they are all ready.
+    /// Meanwhile its requests are answered with a 503.
+    #[serde(default)]
+    pub initial_check: bool,
+
+    /// When the route is added, it only starts serving after this many seconds
+    /// (counted after the `initial_check`), to let its backends warm up.
+    /// Meanwhile its requests are answered with a 503.
+    pub warmup_secs: Option<u64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Clone, ValueEnum)]
@@ -413,6 +922,10 @@ pub enum LogRotation {
 pub enum LogFormat {
     Json,
     Pretty,
+    /// Apache Common Log Format (access logs only, other logs are plain text)
+    Common,
+    /// Apache Combined Log Format (access logs only, other logs are plain text)
+    Combined,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Args)]
@@ -473,6 +986,172 @@ pub struct Logging {
     #[clap(skip)]
     #[serde(deserialize_with = "log_rotation_deser", default)]
     pub rotation: LogRotation,
+
+    /// Requests taking longer than this (in milliseconds) are logged
+    /// as slow requests (disabled by default)
+    #[clap(skip)]
+    #[serde(default)]
+    pub slow_request_threshold_ms: Option<u64>,
+
+    /// Sends the access logs to a syslog server (disabled by default)
+    #[clap(skip)]
+    #[serde(default)]
+    pub syslog: Option<LogSyslog>,
+}
+
+/// A syslog server receiving the access logs (RFC 5424 messages)
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct LogSyslog {
+    /// The address of the syslog server (ex: "logs.example.com:514")
+    pub address: Cow<'static, str>,
+
+    /// How the messages are sent, "udp" or "tcp" (default: "udp")
+    #[serde(default, deserialize_with = "syslog_protocol_deser")]
+    pub protocol: SyslogProtocol,
+
+    /// The facility of the messages (default: "local0")
+    #[serde(default, deserialize_with = "syslog_facility_deser")]
+    pub facility: SyslogFacility,
+
+    /// The severity of the messages (default: "info")
+    #[serde(default, deserialize_with = "syslog_severity_deser")]
+    pub severity: SyslogSeverity,
+
+    /// Whether the access logs are still written to stdout (or `path`) as
+    /// well, instead of only being sent to the syslog server (default: false)
+    #[serde(default)]
+    pub keep_local: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyslogProtocol {
+    /// A datagram per message
+    #[default]
+    Udp,
+    /// Messages framed with their length (RFC 6587 octet counting)
+    Tcp,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyslogFacility {
+    User,
+    Daemon,
+    Auth,
+    #[default]
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl SyslogFacility {
+    /// The numerical code of the facility (RFC 5424)
+    pub fn code(self) -> u8 {
+        match self {
+            Self::User => 1,
+            Self::Daemon => 3,
+            Self::Auth => 4,
+            Self::Local0 => 16,
+            Self::Local1 => 17,
+            Self::Local2 => 18,
+            Self::Local3 => 19,
+            Self::Local4 => 20,
+            Self::Local5 => 21,
+            Self::Local6 => 22,
+            Self::Local7 => 23,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SyslogSeverity {
+    Emergency,
+    Alert,
+    Critical,
+    Error,
+    Warning,
+    Notice,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl SyslogSeverity {
+    /// The numerical code of the severity (RFC 5424)
+    pub fn code(self) -> u8 {
+        self as u8
+    }
+}
+
+/// Defines how the real client IP is extracted when proksi runs behind other proxies
+/// (load balancers, CDNs etc.) that append to `X-Forwarded-For`/`Forwarded`.
+/// Without any trusted proxy the connection address is always used.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct RealIp {
+    /// The number of trusted proxies in front of proksi.
+    /// (ex: 1 for a single load balancer)
+    #[serde(default)]
+    pub trusted_hops: usize,
+
+    /// The CIDR blocks of trusted proxies (ex: `10.0.0.0/8`).
+    /// Cannot be used together with `trusted_hops`.
+    #[serde(default)]
+    pub trusted_cidrs: Vec<Cow<'static, str>>,
+}
+
+/// Settings for the TLS connections to HTTPS upstreams
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct UpstreamTls {
+    /// Whether the certificates of the upstreams are verified
+    /// (disabled by default, as upstreams often use self-signed certificates)
+    #[serde(default)]
+    pub verify: bool,
+
+    /// Rejects upstream certificates with RSA/DSA keys smaller than the given
+    /// size in bits (ex: 2048)
+    pub min_key_bits: Option<u32>,
+
+    /// Logs a warning when an upstream certificate expires in less than the
+    /// given number of days (ex: 14)
+    pub warn_expiry_days: Option<u32>,
+}
+
+/// TLS passthrough: connections are routed based on the SNI of the TLS `ClientHello`
+/// and tunneled to the upstreams, TLS is only terminated by the upstreams.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TlsPassthrough {
+    /// The address to accept passthrough connections on (ex: `0.0.0.0:8443`).
+    /// TLS passthrough is disabled if not set.
+    pub address: Option<Cow<'static, str>>,
+
+    /// The SNI to upstreams routes
+    #[serde(default)]
+    pub routes: Vec<TlsPassthroughRoute>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TlsPassthroughRoute {
+    /// The server name (SNI) sent by the clients (ex: `db.example.com`)
+    pub sni: Cow<'static, str>,
+
+    /// The upstreams the raw TLS connections are tunneled to
+    pub upstreams: Vec<RouteUpstream>,
+}
+
+/// The admin API, used to operate a running proksi (ex: reloading the configuration)
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct Admin {
+    /// The address to accept admin API requests on (ex: `127.0.0.1:9091`).
+    /// The admin API is disabled if not set.
+    pub address: Option<Cow<'static, str>>,
+
+    /// The token every request must send as `Authorization: Bearer <token>`
+    /// (required when `address` is set)
+    pub token: Option<Cow<'static, str>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, Args)]
@@ -516,6 +1195,12 @@ pub struct ServerCfg {
     )]
     pub https_address: Option<Cow<'static, str>>,
 
+    /// Other addresses the HTTPS server is bound to (ex: `10.0.0.5:8443` for
+    /// internal clients), routes can be restricted to one of them with `listener_port`
+    #[serde(default)]
+    #[clap(skip)]
+    pub additional_https_addresses: Vec<Cow<'static, str>>,
+
     /// The address used to solve challenges (only HTTP)
     #[arg(
         long = "server.http_address",
@@ -524,6 +1209,51 @@ pub struct ServerCfg {
         default_value = "0.0.0.0:80"
     )]
     pub http_address: Option<Cow<'static, str>>,
+
+    /// The address to expose the prometheus metrics on (ex: 0.0.0.0:9090)
+    /// (disabled by default)
+    #[arg(long = "server.metrics_address", required = false, value_parser)]
+    pub metrics_address: Option<Cow<'static, str>>,
+
+    /// The zone proksi runs in (ex: `eu-west-1a`), upstreams of the same zone
+    /// are preferred and the other zones are only used when none is healthy
+    #[arg(long = "server.zone", required = false, value_parser)]
+    pub zone: Option<Cow<'static, str>>,
+
+    /// Whether the connections of HTTP/1.0 clients sending `Connection: keep-alive`
+    /// are kept open (`honor`, default) or closed after each response (`close`).
+    /// Requests without a `Content-Length` are always closed, their body lasts
+    /// until the connection is closed.
+    #[serde(default, deserialize_with = "http10_keep_alive_deser")]
+    #[arg(
+        long = "server.http10_keep_alive",
+        required = false,
+        value_enum,
+        default_value = "honor"
+    )]
+    pub http10_keep_alive: Http10KeepAlive,
+
+    /// The maximum number of connections a single client IP can keep open on
+    /// the HTTPS and TLS passthrough listeners, the connections past it are
+    /// closed once accepted (no limit by default)
+    #[arg(long = "server.max_connections_per_ip", required = false, value_parser)]
+    pub max_connections_per_ip: Option<usize>,
+
+    /// The `Server` header of the responses generated by proksi (errors,
+    /// redirects of the routes, plugin responses), an empty value removes it. The responses of
+    /// the upstreams are left untouched. (default: `Pingora`)
+    #[arg(long = "server.server_header", required = false, value_parser)]
+    pub server_header: Option<Cow<'static, str>>,
+}
+
+/// How the keep-alive of HTTP/1.0 clients is handled
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Http10KeepAlive {
+    /// Keeps the connection open when the client asks for it
+    #[default]
+    Honor,
+    /// Closes the connection after every response
+    Close,
 }
 
 /// The main configuration struct.
@@ -598,6 +1328,14 @@ pub(crate) struct Config {
     #[clap(short, long, required = false, default_value = "2")]
     pub worker_threads: Option<usize>,
 
+    /// How many route/certificate messages (ex: docker discovery updates) can
+    /// be queued before the slowest service starts missing them.
+    ///
+    /// Every queued message is kept in memory until all services have read it.
+    #[clap(skip = default_broadcast_capacity())]
+    #[serde(default = "default_broadcast_capacity")]
+    pub broadcast_capacity: usize,
+
     /// The PATH to the configuration file to be used.
     ///
     /// The configuration file should be named either `proksi.hcl` or `proksi.yaml`
@@ -620,6 +1358,43 @@ pub(crate) struct Config {
     #[clap(skip)]
     pub lets_encrypt: LetsEncrypt,
 
+    /// How to extract the real client IP when behind trusted proxies
+    #[clap(skip)]
+    pub real_ip: RealIp,
+
+    /// Routes connections by SNI without terminating TLS
+    #[clap(skip)]
+    pub tls_passthrough: TlsPassthrough,
+
+    /// The admin API
+    #[clap(skip)]
+    pub admin: Admin,
+
+    /// How the TLS connections to HTTPS upstreams are verified
+    #[clap(skip)]
+    pub upstream_tls: UpstreamTls,
+
+    /// Whether hop-by-hop headers are stripped before forwarding requests
+    /// (can be overridden per route with `headers.hop_by_hop`)
+    #[clap(skip)]
+    #[serde(deserialize_with = "hop_by_hop_headers_deser")]
+    pub hop_by_hop_headers: HopByHopHeaders,
+
+    /// The maximum size (in bytes) of the response headers of an upstream, the
+    /// responses with larger headers are answered with a 502 (no limit by default).
+    /// The headers are checked once parsed: pingora still reads up to 1 MiB
+    /// (and 256 headers) of them, its own limit can't be configured.
+    #[clap(skip)]
+    #[serde(default)]
+    pub max_upstream_header_size: Option<usize>,
+
+    /// The maximum duration (in seconds) of a request, as a safety net against
+    /// requests hanging forever. Requests still running are answered with a
+    /// 504 (no limit by default).
+    #[clap(skip)]
+    #[serde(default)]
+    pub request_timeout_secs: Option<u64>,
+
     /// Configuration for paths (TLS, config file, etc.)
     #[clap(skip)]
     pub paths: Path,
@@ -639,13 +1414,27 @@ impl Default for Config {
             service_name: Cow::Borrowed("proksi"),
             server: ServerCfg {
                 https_address: Some(Cow::Borrowed("0.0.0.0:443")),
+                additional_https_addresses: vec![],
                 http_address: Some(Cow::Borrowed("0.0.0.0:80")),
+                metrics_address: None,
+                zone: None,
+                http10_keep_alive: Http10KeepAlive::Honor,
+                max_connections_per_ip: None,
+                server_header: None,
             },
             worker_threads: Some(2),
+            broadcast_capacity: default_broadcast_capacity(),
             upgrade: false,
             daemon: false,
             docker: Docker::default(),
             lets_encrypt: LetsEncrypt::default(),
+            real_ip: RealIp::default(),
+            tls_passthrough: TlsPassthrough::default(),
+            admin: Admin::default(),
+            upstream_tls: UpstreamTls::default(),
+            hop_by_hop_headers: HopByHopHeaders::default(),
+            max_upstream_header_size: None,
+            request_timeout_secs: None,
             routes: vec![],
             auto_reload: AutoReload::default(),
             store: StoreConfig::default(),
@@ -657,6 +1446,8 @@ impl Default for Config {
                 format: LogFormat::Json,
                 path: None,
                 rotation: LogRotation::Never,
+                slow_request_threshold_ms: None,
+                syslog: None,
             },
             paths: Path::default(),
         }
@@ -722,6 +1513,10 @@ pub fn load(fallback: &str) -> Result<Config, figment::Error> {
     Ok(config)
 }
 
+fn default_broadcast_capacity() -> usize {
+    1024
+}
+
 /// Deserialize function to convert a string to a `LogLevel` Enum
 fn log_level_deser<'de, D>(deserializer: D) -> Result<LogLevel, D::Error>
 where
@@ -764,7 +1559,11 @@ where
     match s.to_lowercase().as_str() {
         "json" => Ok(LogFormat::Json),
         "pretty" => Ok(LogFormat::Pretty),
-        _ => Err(serde::de::Error::custom("expected one of: json, pretty")),
+        "common" | "clf" => Ok(LogFormat::Common),
+        "combined" => Ok(LogFormat::Combined),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: json, pretty, common, combined",
+        )),
     }
 }
 
@@ -812,22 +1611,163 @@ where
     }
 }
 
-fn store_type_deser<'de, D>(deserializer: D) -> Result<StoreType, D::Error>
+fn upstream_access_deser<'de, D>(deserializer: D) -> Result<RouteUpstreamAccess, D::Error>
 where
     D: Deserializer<'de>,
 {
     let s = String::deserialize(deserializer)?;
     match s.to_lowercase().as_str() {
-        "memory" => Ok(StoreType::Memory),
-        "redis" => Ok(StoreType::Redis),
-        _ => Err(serde::de::Error::custom("expected one of: memory, redis")),
+        "read_write" => Ok(RouteUpstreamAccess::ReadWrite),
+        "read_only" => Ok(RouteUpstreamAccess::ReadOnly),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: read_write, read_only",
+        )),
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use serde_json::json;
-
+fn no_match_action_deser<'de, D>(deserializer: D) -> Result<RouteNoMatchAction, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "respond" => Ok(RouteNoMatchAction::Respond),
+        "proxy" => Ok(RouteNoMatchAction::Proxy),
+        _ => Err(serde::de::Error::custom("expected one of: respond, proxy")),
+    }
+}
+
+fn selection_algorithm_deser<'de, D>(deserializer: D) -> Result<RouteSelectionAlgorithm, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "round_robin" => Ok(RouteSelectionAlgorithm::RoundRobin),
+        "smooth_weighted_round_robin" => Ok(RouteSelectionAlgorithm::SmoothWeightedRoundRobin),
+        "consistent_hash" => Ok(RouteSelectionAlgorithm::ConsistentHash),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: round_robin, smooth_weighted_round_robin, consistent_hash",
+        )),
+    }
+}
+
+fn last_healthy_backend_deser<'de, D>(deserializer: D) -> Result<RouteLastHealthyBackend, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "allow" => Ok(RouteLastHealthyBackend::Allow),
+        "refuse" => Ok(RouteLastHealthyBackend::Refuse),
+        _ => Err(serde::de::Error::custom("expected one of: allow, refuse")),
+    }
+}
+
+fn http10_keep_alive_deser<'de, D>(deserializer: D) -> Result<Http10KeepAlive, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "honor" => Ok(Http10KeepAlive::Honor),
+        "close" => Ok(Http10KeepAlive::Close),
+        _ => Err(serde::de::Error::custom("expected one of: honor, close")),
+    }
+}
+
+fn syslog_protocol_deser<'de, D>(deserializer: D) -> Result<SyslogProtocol, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "udp" => Ok(SyslogProtocol::Udp),
+        "tcp" => Ok(SyslogProtocol::Tcp),
+        _ => Err(serde::de::Error::custom("expected one of: udp, tcp")),
+    }
+}
+
+fn syslog_facility_deser<'de, D>(deserializer: D) -> Result<SyslogFacility, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "user" => Ok(SyslogFacility::User),
+        "daemon" => Ok(SyslogFacility::Daemon),
+        "auth" => Ok(SyslogFacility::Auth),
+        "local0" => Ok(SyslogFacility::Local0),
+        "local1" => Ok(SyslogFacility::Local1),
+        "local2" => Ok(SyslogFacility::Local2),
+        "local3" => Ok(SyslogFacility::Local3),
+        "local4" => Ok(SyslogFacility::Local4),
+        "local5" => Ok(SyslogFacility::Local5),
+        "local6" => Ok(SyslogFacility::Local6),
+        "local7" => Ok(SyslogFacility::Local7),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: user, daemon, auth, local0 to local7",
+        )),
+    }
+}
+
+fn syslog_severity_deser<'de, D>(deserializer: D) -> Result<SyslogSeverity, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "emergency" | "emerg" => Ok(SyslogSeverity::Emergency),
+        "alert" => Ok(SyslogSeverity::Alert),
+        "critical" | "crit" => Ok(SyslogSeverity::Critical),
+        "error" | "err" => Ok(SyslogSeverity::Error),
+        "warning" | "warn" => Ok(SyslogSeverity::Warning),
+        "notice" => Ok(SyslogSeverity::Notice),
+        "info" => Ok(SyslogSeverity::Info),
+        "debug" => Ok(SyslogSeverity::Debug),
+        _ => Err(serde::de::Error::custom(
+            "expected one of: emergency, alert, critical, error, warning, notice, info, debug",
+        )),
+    }
+}
+
+fn hop_by_hop_headers_deser<'de, D>(deserializer: D) -> Result<HopByHopHeaders, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "strip" => Ok(HopByHopHeaders::Strip),
+        "preserve" => Ok(HopByHopHeaders::Preserve),
+        _ => Err(serde::de::Error::custom("expected one of: strip, preserve")),
+    }
+}
+
+fn hop_by_hop_headers_opt_deser<'de, D>(
+    deserializer: D,
+) -> Result<Option<HopByHopHeaders>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    hop_by_hop_headers_deser(deserializer).map(Some)
+}
+
+fn store_type_deser<'de, D>(deserializer: D) -> Result<StoreType, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    match s.to_lowercase().as_str() {
+        "memory" => Ok(StoreType::Memory),
+        "redis" => Ok(StoreType::Redis),
+        _ => Err(serde::de::Error::custom("expected one of: memory, redis")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
     use super::*;
 
     #[test]
@@ -910,6 +1850,13 @@ mod tests {
               - ip: "10.0.1.3/25"
                 port: 3000
                 network: "public"
+              - ip: "10.0.1.4"
+                port: 3000
+                access: "read_only"
+            require_headers:
+              - name: "X-Api-Version"
+              - name: "X-Tenant"
+                value: "acme"
       "#
     }
 
@@ -924,6 +1871,15 @@ mod tests {
             let proxy_config = config.unwrap();
             assert_eq!(proxy_config.service_name, "proksi");
 
+            let upstreams = &proxy_config.routes[0].upstreams;
+            assert_eq!(upstreams[0].access, RouteUpstreamAccess::ReadWrite);
+            assert_eq!(upstreams[1].access, RouteUpstreamAccess::ReadOnly);
+
+            let require_headers = proxy_config.routes[0].require_headers.as_ref().unwrap();
+            assert_eq!(require_headers[0].name, "X-Api-Version");
+            assert_eq!(require_headers[0].value, None);
+            assert_eq!(require_headers[1].value, Some(Cow::Borrowed("acme")));
+
             Ok(())
         });
     }
@@ -1138,4 +2094,638 @@ mod tests {
             Ok(())
         });
     }
+
+    #[test]
+    fn test_load_config_with_real_ip() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                real_ip {
+                    trusted_cidrs = ["10.0.0.0/8", "2001:db8::/32"]
+                }
+                "#,
+            )?;
+
+            let proxy_config = load(&tmp_dir).unwrap();
+            assert_eq!(proxy_config.real_ip.trusted_hops, 0);
+            assert_eq!(
+                proxy_config.real_ip.trusted_cidrs,
+                vec![Cow::Borrowed("10.0.0.0/8"), Cow::Borrowed("2001:db8::/32")]
+            );
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                real_ip {
+                    trusted_cidrs = ["10.0.0.0/40"]
+                }
+                "#,
+            )?;
+
+            let err = load(&tmp_dir).unwrap_err();
+            assert!(err.to_string().contains("real_ip.trusted_cidrs0"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_redirect() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        redirect = {
+                            to = "https://www.example.com"
+                        }
+                    }
+                ]
+                "#,
+            )?;
+
+            let proxy_config = load(&tmp_dir).unwrap();
+            let redirect = proxy_config.routes[0].redirect.as_ref().unwrap();
+            assert_eq!(redirect.to, "https://www.example.com");
+            assert_eq!(redirect.status, 301);
+            assert!(proxy_config.routes[0].upstreams.is_empty());
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_upstream_tls_flag() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        upstreams = [
+                            { ip = "10.0.0.1", port = 443 },
+                            { ip = "10.0.0.2", port = 8443, tls = true },
+                            { ip = "10.0.0.3", port = 443, tls = false },
+                            { ip = "10.0.0.4", port = 80 },
+                        ]
+                    }
+                ]
+                "#,
+            )?;
+
+            let proxy_config = load(&tmp_dir).unwrap();
+            let tls = proxy_config.routes[0]
+                .upstreams
+                .iter()
+                .map(RouteUpstream::is_tls)
+                .collect::<Vec<_>>();
+            assert_eq!(tls, [true, true, false, false]);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_rejects_redirect_with_upstreams() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        redirect = {
+                            to = "https://www.example.com"
+                        }
+                        upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                    }
+                ]
+                "#,
+            )?;
+
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("routes0"), "{err}");
+            assert!(err.contains("`upstreams`"), "{err}");
+            assert!(err.contains("`redirect`"), "{err}");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_rejects_invalid_label_names() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                        labels = {
+                            team = "payments"
+                            "cost-center" = "42"
+                        }
+                    }
+                ]
+                "#,
+            )?;
+
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("routes0.labels.cost-center"), "{err}");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_rejects_routes_without_upstreams_or_redirect() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                    }
+                ]
+                "#,
+            )?;
+
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("routes0 must set one of `upstreams` or `redirect`"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_follow_redirects() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        follow_redirects = {}
+                        upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                    },
+                    {
+                        host = "capped.example.com"
+                        follow_redirects = { max_hops = 2 }
+                        upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                    }
+                ]
+                "#,
+            )?;
+
+            let config = load(&tmp_dir).unwrap();
+            let max_hops = config
+                .routes
+                .iter()
+                .map(|route| route.follow_redirects.as_ref().unwrap().max_hops)
+                .collect::<Vec<_>>();
+            assert_eq!(max_hops, [5, 2]);
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        follow_redirects = { max_hops = 20 }
+                        upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                    }
+                ]
+                "#,
+            )?;
+
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("routes0.follow_redirects.max_hops"), "{err}");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_syslog() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config_with_syslog = |syslog: &str| {
+                format!(
+                    r#"
+                    lets_encrypt {{
+                        email = "domain@valid.com"
+                    }}
+                    logging {{
+                        level = "info"
+                        syslog = {syslog}
+                    }}
+                    "#
+                )
+            };
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                &config_with_syslog(r#"{ address = "10.0.0.5:514" }"#),
+            )?;
+            let syslog = load(&tmp_dir).unwrap().logging.syslog.unwrap();
+            assert_eq!(syslog.protocol, SyslogProtocol::Udp);
+            assert_eq!(syslog.facility, SyslogFacility::Local0);
+            assert_eq!(syslog.severity, SyslogSeverity::Info);
+            assert!(!syslog.keep_local);
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                &config_with_syslog(
+                    r#"{ address = "logs.example.com:6514", protocol = "tcp", facility = "local3", severity = "warning", keep_local = true }"#,
+                ),
+            )?;
+            let syslog = load(&tmp_dir).unwrap().logging.syslog.unwrap();
+            assert_eq!(syslog.protocol, SyslogProtocol::Tcp);
+            assert_eq!(syslog.facility.code(), 19);
+            assert_eq!(syslog.severity.code(), 4);
+            assert!(syslog.keep_local);
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                &config_with_syslog(r#"{ address = "logs.example.com" }"#),
+            )?;
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("logging.syslog.address"), "{err}");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_invalid_plugin_config() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+            let config_with_plugins = |plugins: &str| {
+                format!(
+                    r#"
+                    lets_encrypt {{
+                        email = "domain@valid.com"
+                    }}
+                    routes = [
+                        {{
+                            host = "example.com"
+                            plugins = [{plugins}]
+                            upstreams = [{{ ip = "10.0.0.1", port = 3000 }}]
+                        }}
+                    ]
+                    "#
+                )
+            };
+
+            // Plugins that aren't built in are not checked
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                &config_with_plugins(
+                    r#"{ name = "cors", config = { allowed_origins = ["*"] } },
+                    { name = "rate_limit", config = { requests = 10, window_secs = 60 } }"#,
+                ),
+            )?;
+            assert!(load(&tmp_dir).is_ok());
+
+            for (plugins, expected) in [
+                (
+                    r#"{ name = "request_id" }, { name = "rate_limit", config = { requests = 0 } }"#,
+                    "routes0.plugins1 (rate_limit) is invalid: requests must be a positive integer",
+                ),
+                (
+                    r#"{ name = "basic_auth", config = { user = "admin" } }"#,
+                    "routes0.plugins0 (basic_auth) is invalid: pass must be a string",
+                ),
+                (
+                    r#"{ name = "compression", config = { level = "best" } }"#,
+                    "routes0.plugins0 (compression) is invalid: level must be a positive integer",
+                ),
+                (
+                    r#"{ name = "oauth2", config = { provider = "github", client_id = "id" } }"#,
+                    "routes0.plugins0 (oauth2) is invalid: Missing or invalid client_secret",
+                ),
+            ] {
+                jail.create_file(
+                    format!("{}/proksi.hcl", tmp_dir),
+                    &config_with_plugins(plugins),
+                )?;
+                let err = load(&tmp_dir).unwrap_err().to_string();
+                assert!(err.contains(expected), "{err}");
+            }
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_listener_port() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                server {
+                    additional_https_addresses = ["127.0.0.1:8443"]
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        listener_port = 8443
+                        upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                    },
+                    {
+                        host = "example.com"
+                        upstreams = [{ ip = "10.0.0.2", port = 3000 }]
+                    }
+                ]
+                "#,
+            )?;
+
+            let config = load(&tmp_dir).unwrap();
+            assert_eq!(
+                config.server.additional_https_addresses,
+                vec![Cow::Borrowed("127.0.0.1:8443")]
+            );
+            assert_eq!(config.routes[0].listener_port, Some(8443));
+            assert_eq!(config.routes[1].listener_port, None);
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        listener_port = 8443
+                        upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                    }
+                ]
+                "#,
+            )?;
+
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(err.contains("routes0.listener_port"), "{err}");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_upstream_groups() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        upstreams = [
+                            { ip = "10.0.0.1", port = 3000 },
+                            { ip = "10.0.0.2", port = 3000, group = "canary" }
+                        ]
+                        upstream_groups = {
+                            rules = [{ name = "x-canary", value = "true", group = "canary" }]
+                        }
+                    }
+                ]
+                "#,
+            )?;
+
+            let config = load(&tmp_dir).unwrap();
+            let upstream_groups = config.routes[0].upstream_groups.as_ref().unwrap();
+            assert_eq!(upstream_groups.default, DEFAULT_UPSTREAM_GROUP);
+            assert_eq!(
+                upstream_groups.rules,
+                vec![RouteUpstreamGroupRule {
+                    name: Cow::Borrowed("x-canary"),
+                    value: Some(Cow::Borrowed("true")),
+                    group: Cow::Borrowed("canary"),
+                }]
+            );
+            assert_eq!(
+                config.routes[0].upstreams[1].group.as_deref(),
+                Some("canary")
+            );
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                        upstream_groups = {
+                            rules = [{ name = "x-canary", group = "canary" }]
+                        }
+                    }
+                ]
+                "#,
+            )?;
+
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains(
+                    "routes0.upstream_groups.rules0.group is not the group of any upstream"
+                ),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_hash_key() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                        selection_algorithm = "consistent_hash"
+                        hash_key = "{header.x-tenant}/{path.1}"
+                    }
+                ]
+                "#,
+            )?;
+
+            let config = load(&tmp_dir).unwrap();
+            assert_eq!(
+                config.routes[0].selection_algorithm,
+                RouteSelectionAlgorithm::ConsistentHash
+            );
+            assert_eq!(
+                config.routes[0].hash_key.as_deref(),
+                Some("{header.x-tenant}/{path.1}")
+            );
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                        hash_key = "{header.x-tenant}"
+                    }
+                ]
+                "#,
+            )?;
+
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("routes0.hash_key requires the consistent_hash selection_algorithm"),
+                "{err}"
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_load_config_with_concurrency() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                        concurrency = {
+                            max_in_flight = 100
+                            max_in_flight_per_client = 10
+                            client_key_header = "x-api-key"
+                        }
+                    },
+                    {
+                        host = "invalid.example.com"
+                        upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                        concurrency = {
+                            max_in_flight_per_client = 0
+                        }
+                    }
+                ]
+                "#,
+            )?;
+
+            let err = load(&tmp_dir).unwrap_err().to_string();
+            assert!(
+                err.contains("routes1.concurrency limits must be greater than 0"),
+                "{err}"
+            );
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "example.com"
+                        upstreams = [{ ip = "10.0.0.1", port = 3000 }]
+                        concurrency = {
+                            max_in_flight = 100
+                            max_in_flight_per_client = 10
+                            client_key_header = "x-api-key"
+                        }
+                    }
+                ]
+                "#,
+            )?;
+
+            let config = load(&tmp_dir).unwrap();
+            assert_eq!(
+                config.routes[0].concurrency,
+                Some(RouteConcurrency {
+                    max_in_flight: Some(100),
+                    max_in_flight_per_client: Some(10),
+                    client_key_header: Some(Cow::Borrowed("x-api-key")),
+                })
+            );
+
+            Ok(())
+        });
+    }
 }
@@ -1,6 +1,15 @@
+use std::str::FromStr;
+
 use anyhow::anyhow;
+use http::{HeaderName, HeaderValue, Method};
+
+use crate::plugins::validate_plugin_config;
+use crate::proxy_server::{
+    client_ip::Cidr, redirects::MAX_REDIRECT_HOPS, retry::MAX_BUFFERED_BODY_BYTES,
+};
+use crate::stores::hash_key::HashKeyTemplate;
 
-use super::Config;
+use super::{Config, RouteSelectionAlgorithm, DEFAULT_UPSTREAM_GROUP};
 
 /// given a Config struct, validate the values to ensure
 /// That we program won't panic when we try to use them
@@ -27,8 +36,151 @@ pub fn check_config(config: &Config) -> Result<(), anyhow::Error> {
         return Err(anyhow!("paths.lets_encrypt cannot be empty"));
     }
 
+    // Validate that the real_ip trusted proxies are well defined
+    if config.real_ip.trusted_hops > 0 && !config.real_ip.trusted_cidrs.is_empty() {
+        return Err(anyhow!(
+            "real_ip.trusted_hops and real_ip.trusted_cidrs cannot be used together"
+        ));
+    }
+
+    for (index, cidr) in config.real_ip.trusted_cidrs.iter().enumerate() {
+        if let Err(err) = Cidr::from_str(cidr) {
+            return Err(anyhow!(
+                "real_ip.trusted_cidrs{} is not a valid CIDR ({}): {err}",
+                index,
+                cidr
+            ));
+        }
+    }
+
+    // Validate the TLS passthrough routes
+    if config.tls_passthrough.address.is_some() && config.tls_passthrough.routes.is_empty() {
+        return Err(anyhow!(
+            "tls_passthrough.routes cannot be empty when tls_passthrough.address is set"
+        ));
+    }
+
+    for (route_index, route) in config.tls_passthrough.routes.iter().enumerate() {
+        if route.sni.is_empty() {
+            return Err(anyhow!(
+                "tls_passthrough.routes{}.sni cannot be empty",
+                route_index
+            ));
+        }
+
+        if route.upstreams.is_empty() {
+            return Err(anyhow!(
+                "tls_passthrough.routes{}.upstreams cannot be empty",
+                route_index
+            ));
+        }
+
+        if let Some(upstream_index) = route.upstreams.iter().position(|u| u.port == 0) {
+            return Err(anyhow!(
+                "tls_passthrough.routes{}.upstreams{}.port must be greater than 0",
+                route_index,
+                upstream_index
+            ));
+        }
+    }
+
+    // Validate that the admin API can't be used without authentication
+    if config.admin.address.is_some() && config.admin.token.as_ref().is_none_or(|v| v.is_empty()) {
+        return Err(anyhow!("admin.token is required when admin.address is set"));
+    }
+
+    // Validate that the upstream certificate key size is sensible
+    if config.upstream_tls.min_key_bits.is_some_and(|v| v == 0) {
+        return Err(anyhow!("upstream_tls.min_key_bits must be greater than 0"));
+    }
+
+    if config.server.max_connections_per_ip == Some(0) {
+        return Err(anyhow!(
+            "server.max_connections_per_ip must be greater than 0"
+        ));
+    }
+
+    if config
+        .server
+        .server_header
+        .as_ref()
+        .is_some_and(|value| HeaderValue::from_str(value).is_err())
+    {
+        return Err(anyhow!("server.server_header is not a valid header value"));
+    }
+
+    if let Some(syslog) = config.logging.syslog.as_ref() {
+        if address_port(&syslog.address).is_none() {
+            return Err(anyhow!(
+                "logging.syslog.address must be an address with a port"
+            ));
+        }
+    }
+
+    if config.max_upstream_header_size == Some(0) {
+        return Err(anyhow!("max_upstream_header_size must be greater than 0"));
+    }
+
+    if config.request_timeout_secs == Some(0) {
+        return Err(anyhow!("request_timeout_secs must be greater than 0"));
+    }
+
+    if config.broadcast_capacity == 0 {
+        return Err(anyhow!("broadcast_capacity must be greater than 0"));
+    }
+
+    // The ports of the HTTPS listeners, routes can be restricted to one of them
+    let mut listener_ports = Vec::new();
+    for (index, address) in config.server.additional_https_addresses.iter().enumerate() {
+        let Some(port) = address_port(address) else {
+            return Err(anyhow!(
+                "server.additional_https_addresses{} must be an address with a port",
+                index
+            ));
+        };
+        listener_ports.push(port);
+    }
+    listener_ports.extend(
+        config
+            .server
+            .https_address
+            .as_deref()
+            .and_then(address_port),
+    );
+
     // Validate the routes
     for (route_index, route) in config.routes.iter().enumerate() {
+        // A route answers requests in a single way (proxy, redirect etc.)
+        check_one_of_fields(
+            &format!("routes{route_index}"),
+            &[
+                ("upstreams", !route.upstreams.is_empty()),
+                ("redirect", route.redirect.is_some()),
+            ],
+        )?;
+
+        if let Some(listener_port) = route.listener_port {
+            if !listener_ports.contains(&listener_port) {
+                return Err(anyhow!(
+                    "routes{}.listener_port must be the port of server.https_address or of one of server.additional_https_addresses",
+                    route_index
+                ));
+            }
+        }
+
+        if let Some(redirect) = route.redirect.as_ref() {
+            if redirect.to.is_empty() {
+                return Err(anyhow!("routes{}.redirect.to cannot be empty", route_index));
+            }
+
+            if ![301, 302, 303, 307, 308].contains(&redirect.status) {
+                return Err(anyhow!(
+                    "routes{}.redirect.status must be one of 301, 302, 303, 307 or 308",
+                    route_index
+                ));
+            }
+        }
+
         // Validate the route's upstreams
         for (upstream_index, upstream) in route.upstreams.iter().enumerate() {
             // Validate the upstream's address
@@ -47,7 +199,343 @@ pub fn check_config(config: &Config) -> Result<(), anyhow::Error> {
                     upstream_index
                 ));
             }
+
+            if upstream.weight.is_some_and(|weight| weight <= 0) {
+                return Err(anyhow!(
+                    "routes{}.upstreams{}.weight must be greater than 0",
+                    route_index,
+                    upstream_index
+                ));
+            }
+
+            if upstream.priority == Some(0) {
+                return Err(anyhow!(
+                    "routes{}.upstreams{}.priority must be greater than 0",
+                    route_index,
+                    upstream_index
+                ));
+            }
+        }
+
+        if let Some(no_match) = route.no_match.as_ref() {
+            if HeaderValue::from_str(&no_match.content_type).is_err() {
+                return Err(anyhow!(
+                    "routes{}.no_match.content_type is not a valid header value",
+                    route_index
+                ));
+            }
+        }
+
+        if let Some(access_log) = route.access_log.as_ref() {
+            if access_log.destination.as_os_str().is_empty() || access_log.destination.is_dir() {
+                return Err(anyhow!(
+                    "routes{}.access_log.destination must be a file path",
+                    route_index
+                ));
+            }
+        }
+
+        // Validate the route's retries
+        if let Some(retry) = route.retry.as_ref() {
+            if retry.max_buffered_body_bytes > MAX_BUFFERED_BODY_BYTES {
+                return Err(anyhow!(
+                    "routes{}.retry.max_buffered_body_bytes cannot be greater than {}",
+                    route_index,
+                    MAX_BUFFERED_BODY_BYTES
+                ));
+            }
+        }
+
+        // Validate the route's followed redirects
+        if let Some(follow_redirects) = route.follow_redirects.as_ref() {
+            if follow_redirects.max_hops == 0 || follow_redirects.max_hops > MAX_REDIRECT_HOPS {
+                return Err(anyhow!(
+                    "routes{}.follow_redirects.max_hops must be between 1 and {}",
+                    route_index,
+                    MAX_REDIRECT_HOPS
+                ));
+            }
+        }
+
+        // Validate the route's concurrency limits
+        if let Some(concurrency) = route.concurrency.as_ref() {
+            if concurrency.max_in_flight == Some(0)
+                || concurrency.max_in_flight_per_client == Some(0)
+            {
+                return Err(anyhow!(
+                    "routes{}.concurrency limits must be greater than 0",
+                    route_index
+                ));
+            }
+
+            if concurrency
+                .client_key_header
+                .as_ref()
+                .is_some_and(|name| HeaderName::from_str(name).is_err())
+            {
+                return Err(anyhow!(
+                    "routes{}.concurrency.client_key_header is not a valid header name",
+                    route_index
+                ));
+            }
         }
+
+        // Validate the route's health check
+        if let Some(health_check) = route.health_check.as_ref() {
+            let Ok(method) = Method::from_str(&health_check.method) else {
+                return Err(anyhow!(
+                    "routes{}.health_check.method is not a valid HTTP method",
+                    route_index
+                ));
+            };
+
+            if method == Method::HEAD && health_check.expected_body.is_some() {
+                return Err(anyhow!(
+                    "routes{}.health_check.expected_body cannot be used with `HEAD` requests",
+                    route_index
+                ));
+            }
+
+            if !health_check.path.starts_with('/') {
+                return Err(anyhow!(
+                    "routes{}.health_check.path must start with `/`",
+                    route_index
+                ));
+            }
+
+            for (header_index, header) in health_check.headers.iter().enumerate() {
+                if HeaderName::from_str(&header.name).is_err()
+                    || HeaderValue::from_str(&header.value).is_err()
+                {
+                    return Err(anyhow!(
+                        "routes{}.health_check.headers{} is not a valid header",
+                        route_index,
+                        header_index
+                    ));
+                }
+            }
+        }
+
+        if route.labels.keys().any(|label| label.trim().is_empty()) {
+            return Err(anyhow!(
+                "routes{}.labels cannot have an empty name",
+                route_index
+            ));
+        }
+
+        // The labels are added to the route metrics
+        if let Some(label) = route
+            .labels
+            .keys()
+            .find(|label| !is_metric_label_name(label))
+        {
+            return Err(anyhow!(
+                "routes{}.labels.{} must only contain letters, digits and underscores, and not start with a digit",
+                route_index,
+                label
+            ));
+        }
+
+        if let Some(load_weights) = route.load_weights.as_ref() {
+            if load_weights.metric.trim().is_empty() {
+                return Err(anyhow!(
+                    "routes{}.load_weights.metric is required",
+                    route_index
+                ));
+            }
+
+            if load_weights.max_load.is_nan()
+                || load_weights.max_load <= 0.0
+                || load_weights.max_weight == 0
+            {
+                return Err(anyhow!(
+                    "routes{}.load_weights.max_load and max_weight must be greater than 0",
+                    route_index
+                ));
+            }
+
+            if load_weights.interval_secs == 0 {
+                return Err(anyhow!(
+                    "routes{}.load_weights.interval_secs must be greater than 0",
+                    route_index
+                ));
+            }
+
+            if route.selection_algorithm == RouteSelectionAlgorithm::ConsistentHash {
+                return Err(anyhow!(
+                    "routes{}.load_weights cannot be used with the consistent_hash selection_algorithm",
+                    route_index
+                ));
+            }
+        }
+
+        if let Some(hash_key) = route.hash_key.as_ref() {
+            if route.selection_algorithm != RouteSelectionAlgorithm::ConsistentHash {
+                return Err(anyhow!(
+                    "routes{}.hash_key requires the consistent_hash selection_algorithm",
+                    route_index
+                ));
+            }
+
+            if let Err(err) = HashKeyTemplate::parse(hash_key) {
+                return Err(anyhow!("routes{}.hash_key is invalid: {err}", route_index));
+            }
+        }
+
+        if route
+            .debug_upstream_header
+            .as_ref()
+            .is_some_and(|name| HeaderName::from_str(name).is_err())
+        {
+            return Err(anyhow!(
+                "routes{}.debug_upstream_header is not a valid header name",
+                route_index
+            ));
+        }
+
+        let trailers = route.trailers.iter().flat_map(|trailers| &trailers.strip);
+        for (trailer_index, trailer) in trailers.enumerate() {
+            if HeaderName::from_str(trailer).is_err() {
+                return Err(anyhow!(
+                    "routes{}.trailers.strip{} is not a valid trailer name",
+                    route_index,
+                    trailer_index
+                ));
+            }
+        }
+
+        // Validate the route's required headers
+        for (header_index, header) in route.require_headers.iter().flatten().enumerate() {
+            if HeaderName::from_str(&header.name).is_err() {
+                return Err(anyhow!(
+                    "routes{}.require_headers{}.name is not a valid header name",
+                    route_index,
+                    header_index
+                ));
+            }
+
+            if header
+                .value
+                .as_ref()
+                .is_some_and(|value| HeaderValue::from_str(value).is_err())
+            {
+                return Err(anyhow!(
+                    "routes{}.require_headers{}.value is not a valid header value",
+                    route_index,
+                    header_index
+                ));
+            }
+        }
+
+        // Validate the route's forwarded headers
+        for (header_index, name) in route.forward_headers_allowlist.iter().flatten().enumerate() {
+            if HeaderName::from_str(name).is_err() {
+                return Err(anyhow!(
+                    "routes{}.forward_headers_allowlist{} is not a valid header name",
+                    route_index,
+                    header_index
+                ));
+            }
+        }
+
+        // Each plugin validates its own configuration
+        for (plugin_index, plugin) in route.plugins.iter().flatten().enumerate() {
+            if let Err(err) = validate_plugin_config(plugin) {
+                return Err(anyhow!(
+                    "routes{}.plugins{} ({}) is invalid: {err}",
+                    route_index,
+                    plugin_index,
+                    plugin.name
+                ));
+            }
+        }
+
+        // Validate the route's upstream groups
+        if let Some(upstream_groups) = route.upstream_groups.as_ref() {
+            let has_group = |group: &str| {
+                route.upstreams.iter().any(|upstream| {
+                    upstream.group.as_deref().unwrap_or(DEFAULT_UPSTREAM_GROUP) == group
+                })
+            };
+
+            if !has_group(&upstream_groups.default) {
+                return Err(anyhow!(
+                    "routes{}.upstream_groups.default is not the group of any upstream",
+                    route_index
+                ));
+            }
+
+            for (rule_index, rule) in upstream_groups.rules.iter().enumerate() {
+                if HeaderName::from_str(&rule.name).is_err() {
+                    return Err(anyhow!(
+                        "routes{}.upstream_groups.rules{}.name is not a valid header name",
+                        route_index,
+                        rule_index
+                    ));
+                }
+
+                if rule
+                    .value
+                    .as_ref()
+                    .is_some_and(|value| HeaderValue::from_str(value).is_err())
+                {
+                    return Err(anyhow!(
+                        "routes{}.upstream_groups.rules{}.value is not a valid header value",
+                        route_index,
+                        rule_index
+                    ));
+                }
+
+                if !has_group(&rule.group) {
+                    return Err(anyhow!(
+                        "routes{}.upstream_groups.rules{}.group is not the group of any upstream",
+                        route_index,
+                        rule_index
+                    ));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The port of a listening address (ex: `0.0.0.0:443`)
+fn address_port(address: &str) -> Option<u16> {
+    address.rsplit_once(':')?.1.parse().ok()
+}
+
+/// Whether `name` is a valid Prometheus label name (ex: `team`, `cost_center`)
+fn is_metric_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Fails unless exactly one of the mutually exclusive `fields` (name, is set) is set
+fn check_one_of_fields(prefix: &str, fields: &[(&str, bool)]) -> Result<(), anyhow::Error> {
+    let set = fields
+        .iter()
+        .filter(|(_, is_set)| *is_set)
+        .map(|(name, _)| format!("`{name}`"))
+        .collect::<Vec<_>>();
+
+    if set.is_empty() {
+        let names = fields
+            .iter()
+            .map(|(name, _)| format!("`{name}`"))
+            .collect::<Vec<_>>();
+        return Err(anyhow!("{} must set one of {}", prefix, names.join(" or ")));
+    }
+
+    if set.len() > 1 {
+        return Err(anyhow!(
+            "{} cannot set {} at the same time, they are mutually exclusive",
+            prefix,
+            set.join(" and ")
+        ));
     }
 
     Ok(())
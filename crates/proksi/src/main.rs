@@ -1,26 +1,34 @@
-use ::pingora::server::Server;
+use ::pingora::server::{RunArgs, Server};
 
 use bytes::Bytes;
 use clap::crate_version;
-use config::{load, LogFormat, RouteHeaderAdd, RouteHeaderRemove, RoutePlugin};
+use config::{load, LogFormat, Logging, RouteHeaderAdd, RouteHeaderRemove, RoutePlugin};
 use stores::{MemoryStore, global::init_store};
 use tracing_subscriber::EnvFilter;
 
 use std::{borrow::Cow, sync::Arc};
 
-use pingora::{listeners::tls::TlsSettings, proxy::http_proxy_service, server::configuration::Opt};
+use pingora::{proxy::http_proxy_service, server::configuration::Opt};
 
-use proxy_server::cert_store::CertStore;
-use services::{logger::ProxyLoggerReceiver, BackgroundFunctionService};
+use proxy_server::accept::{AcceptApp, TlsHandshake};
+use proxy_server::connection_limit::ConnectionLimiter;
+use server::ProksiShutdownSignal;
+use services::{
+    logger::{ClfEventFormat, ClfStyle, ProxyLog, ProxyLoggerReceiver},
+    BackgroundFunctionService,
+};
 
 mod cache;
 mod channel;
 mod config;
+mod metrics;
 mod plugins;
 mod proxy_server;
 mod server;
 mod services;
 mod stores;
+#[cfg(test)]
+mod test_support;
 mod tools;
 mod wasm;
 
@@ -34,12 +42,17 @@ pub struct MsgRoute {
     plugins: Vec<RoutePlugin>,
 
     self_signed_certs: bool,
+    /// See `Route::initial_check` and `Route::warmup_secs`
+    initial_check: bool,
+    warmup_secs: Option<u64>,
 }
 
+/// A new certificate (and its private key) for `host`, both PEM encoded
 #[derive(Clone)]
 pub struct MsgCert {
-    _cert: Bytes,
-    _key: Bytes,
+    pub host: String,
+    pub cert: Bytes,
+    pub key: Bytes,
 }
 
 #[derive(Clone)]
@@ -49,6 +62,42 @@ pub enum MsgProxy {
     ConfigUpdate(()),
 }
 
+/// Creates the tracing/logging subscriber based on the logging configuration
+fn init_subscriber(logging: &Logging, appender: ProxyLog) {
+    match logging.format {
+        LogFormat::Json => {
+            tracing_subscriber::fmt()
+                .json()
+                .with_env_filter(EnvFilter::from_default_env())
+                .with_max_level(&logging.level)
+                .with_writer(appender)
+                .init();
+        }
+        LogFormat::Pretty => {
+            tracing_subscriber::fmt()
+                .with_env_filter(EnvFilter::from_default_env())
+                .with_max_level(&logging.level)
+                .with_ansi(logging.path.is_none())
+                .with_writer(appender)
+                .init();
+        }
+        LogFormat::Common | LogFormat::Combined => {
+            let style = if logging.format == LogFormat::Common {
+                ClfStyle::Common
+            } else {
+                ClfStyle::Combined
+            };
+
+            tracing_subscriber::fmt()
+                .event_format(ClfEventFormat::new(style))
+                .with_env_filter(EnvFilter::from_default_env())
+                .with_max_level(&logging.level)
+                .with_writer(appender)
+                .init();
+        }
+    }
+}
+
 #[deny(
     clippy::all,
     clippy::pedantic,
@@ -71,10 +120,12 @@ fn main() -> Result<(), anyhow::Error> {
     let le_address = proxy_config.server.http_address.clone().unwrap_or_default();
 
     // Logging channel
-    let (log_sender, log_receiver) = tokio::sync::mpsc::unbounded_channel::<Vec<u8>>();
+    let (log_sender, log_receiver) =
+        tokio::sync::mpsc::unbounded_channel::<services::logger::LogLine>();
 
     // Receiver channel for Routes/Certificates/etc
-    let (sender, mut _receiver) = tokio::sync::broadcast::channel::<MsgProxy>(10);
+    let (sender, mut _receiver) =
+        tokio::sync::broadcast::channel::<MsgProxy>(proxy_config.broadcast_capacity);
     let appender = services::logger::ProxyLog::new(
         log_sender,
         proxy_config.logging.enabled,
@@ -83,21 +134,7 @@ fn main() -> Result<(), anyhow::Error> {
     );
 
     // Creates a tracing/logging subscriber based on the configuration provided
-    if proxy_config.logging.format == LogFormat::Json {
-        tracing_subscriber::fmt()
-            .json()
-            .with_env_filter(EnvFilter::from_default_env())
-            .with_max_level(&proxy_config.logging.level)
-            .with_writer(appender)
-            .init();
-    } else {
-        tracing_subscriber::fmt()
-            .with_env_filter(EnvFilter::from_default_env())
-            .with_max_level(&proxy_config.logging.level)
-            .with_ansi(proxy_config.logging.path.is_none())
-            .with_writer(appender)
-            .init();
-    };
+    init_subscriber(&proxy_config.logging, appender);
 
     // Initialize global store based on configuration
     match proxy_config.store.store_type {
@@ -134,45 +171,57 @@ fn main() -> Result<(), anyhow::Error> {
     // we can use a simple mock LoadBalancer
     let mut http_public_service = http_proxy_service(
         &pingora_server.configuration,
-        proxy_server::http_proxy::HttpLB {},
+        proxy_server::http_proxy::HttpLB::new(&proxy_config),
     );
 
     // Service: HTTPS Load Balancer (main service)
     // The router will also handle health checks and failover in case of upstream failure
-    let router = proxy_server::https_proxy::Router {};
-    let mut https_secure_service = http_proxy_service(&pingora_server.configuration, router);
+    // The connections are capped per client IP before their TLS handshake
+    let connection_limiter = ConnectionLimiter::from_config(&proxy_config);
+    let router = proxy_server::https_proxy::Router::new(&proxy_config);
+    let mut https_secure_service = AcceptApp::service(
+        http_proxy_service(&pingora_server.configuration, router),
+        connection_limiter.clone(),
+        Some(TlsHandshake::https()?),
+    );
     http_public_service.add_tcp(&le_address);
 
     // Worker threads per configuration
     https_secure_service.threads = proxy_config.worker_threads;
 
-    // Setup tls settings and Enable HTTP/2
-    let cert_store = CertStore::new();
-    let mut tls_settings = TlsSettings::with_callbacks(Box::new(cert_store)).unwrap();
-    tls_settings.enable_h2();
-
-    // tls_settings.set_session_cache_mode(SslSessionCacheMode::SERVER);
-    tls_settings.set_servername_callback(move |ssl_ref, _| CertStore::sni_callback(ssl_ref));
-
-    // For now this is a hardcoded recommendation based on
-    // https://developers.cloudflare.com/ssl/reference/protocols/
-    // but will be made configurable in the future
-    tls_settings.set_min_proto_version(Some(pingora::tls::ssl::SslVersion::TLS1_2))?;
-    tls_settings.set_max_proto_version(Some(pingora::tls::ssl::SslVersion::TLS1_3))?;
-
-    // Add TLS settings to the HTTPS service
-    https_secure_service.add_tls_with_settings(&https_address, None, tls_settings);
+    // Add the HTTPS listeners, the TLS handshake is done by the service
+    for address in
+        std::iter::once(&https_address).chain(&proxy_config.server.additional_https_addresses)
+    {
+        https_secure_service.add_tcp(address);
+    }
 
     // Add Prometheus service
-    // let mut prometheus_service_http = Service::prometheus_http_service();
-    // prometheus_service_http.add_tcp("0.0.0.0:9090");
-    // pingora_server.add_service(prometheus_service_http);
+    if let Some(metrics_address) = proxy_config.server.metrics_address.as_ref() {
+        let mut prometheus_service_http =
+            pingora::services::listening::Service::prometheus_http_service();
+        prometheus_service_http.add_tcp(metrics_address);
+        pingora_server.add_service(prometheus_service_http);
+    }
+
+    // TLS passthrough (SNI routing without termination)
+    pingora_server.add_services(proxy_server::tls_passthrough::tls_passthrough_services(
+        &proxy_config,
+        connection_limiter,
+    ));
+
+    // Admin API (reload etc.)
+    if let Some(admin_service) = services::admin::admin_service(&proxy_config) {
+        pingora_server.add_service(admin_service);
+    }
 
     // Non-dedicated background services
     pingora_server.add_service(BackgroundFunctionService::new(proxy_config.clone(), sender));
 
     // Dedicated logger service
-    pingora_server.add_service(ProxyLoggerReceiver::new(log_receiver, &proxy_config));
+    let logger_service = ProxyLoggerReceiver::new(log_receiver, &proxy_config);
+    let log_flusher = logger_service.flusher();
+    pingora_server.add_service(logger_service);
 
     // Listen on HTTP and HTTPS ports
     pingora_server.add_service(http_public_service);
@@ -188,5 +237,10 @@ fn main() -> Result<(), anyhow::Error> {
         server_info,
     );
 
-    pingora_server.run_forever();
+    // Blocks until a shutdown signal is received and every service has stopped
+    pingora_server.run(RunArgs {
+        shutdown_signal: Box::new(ProksiShutdownSignal::new(log_flusher)),
+    });
+
+    Ok(())
 }
@@ -0,0 +1,238 @@
+//! Prometheus metrics, exposed on `server.metrics_address` when enabled
+use once_cell::sync::Lazy;
+use std::{ops::Deref, time::Duration};
+
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{LabelPair, MetricFamily},
+    register_int_counter_vec, CounterVec, IntCounterVec, Opts,
+};
+
+use crate::stores;
+
+/// A metric of the routes (with a `host` label), the series of a route also
+/// carry the labels configured for it. They are added when the metric is
+/// collected, so updating the labels of a route updates all of its series.
+pub struct RouteMetric<M> {
+    metric: M,
+}
+
+impl<M: Collector + Clone + 'static> RouteMetric<M> {
+    /// Registers `metric` in the default registry
+    fn register(metric: M) -> Self {
+        prometheus::register(Box::new(RouteMetric {
+            metric: metric.clone(),
+        }))
+        .expect("Unable to register a route metric; this is a bug");
+
+        Self { metric }
+    }
+}
+
+impl<M> Deref for RouteMetric<M> {
+    type Target = M;
+
+    fn deref(&self) -> &M {
+        &self.metric
+    }
+}
+
+impl<M: Collector> Collector for RouteMetric<M> {
+    fn desc(&self) -> Vec<&Desc> {
+        self.metric.desc()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let mut families = self.metric.collect();
+
+        for metric in families.iter_mut().flat_map(MetricFamily::mut_metric) {
+            let host = metric
+                .get_label()
+                .iter()
+                .find(|pair| pair.name() == "host")
+                .map(|pair| pair.value().to_string());
+            let Some(labels) = host.and_then(|host| stores::get_route_labels(&host)) else {
+                continue;
+            };
+
+            // The labels of the metric itself take precedence
+            let mut pairs = metric.take_label();
+            for (name, value) in labels {
+                if pairs.iter().all(|pair| pair.name() != name) {
+                    let mut pair = LabelPair::default();
+                    pair.set_name(name.into_owned());
+                    pair.set_value(value.into_owned());
+                    pairs.push(pair);
+                }
+            }
+            pairs.sort_by(|a, b| a.name().cmp(b.name()));
+            metric.set_label(pairs);
+        }
+
+        families
+    }
+}
+
+/// Requests that took longer than the configured slow request threshold
+pub static SLOW_REQUESTS: Lazy<RouteMetric<IntCounterVec>> = Lazy::new(|| {
+    RouteMetric::register(
+        IntCounterVec::new(
+            Opts::new(
+                "proksi_slow_requests_total",
+                "Requests that took longer than the slow request threshold",
+            ),
+            &["host"],
+        )
+        .expect("Unable to create the slow requests metric; this is a bug"),
+    )
+});
+
+/// Configuration reloads that failed to load or validate, the previous
+/// configuration is kept. `source` is what triggered the reload
+/// (`admin_api` or `file_watcher`).
+pub static CONFIG_RELOAD_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "proksi_config_reload_failures_total",
+        "Configuration reloads that failed, the previous configuration is kept",
+        &["source"]
+    )
+    .expect("Unable to register the config reload failures metric; this is a bug")
+});
+
+/// Response bytes before compression, by route and algorithm
+pub static COMPRESSION_INPUT_BYTES: Lazy<RouteMetric<IntCounterVec>> = Lazy::new(|| {
+    RouteMetric::register(
+        IntCounterVec::new(
+            Opts::new(
+                "proksi_compression_input_bytes_total",
+                "Response bytes before compression",
+            ),
+            &["host", "algorithm"],
+        )
+        .expect("Unable to create the compression input bytes metric; this is a bug"),
+    )
+});
+
+/// Response bytes after compression, by route and algorithm. The ratio with
+/// `proksi_compression_input_bytes_total` is the compression ratio.
+pub static COMPRESSION_OUTPUT_BYTES: Lazy<RouteMetric<IntCounterVec>> = Lazy::new(|| {
+    RouteMetric::register(
+        IntCounterVec::new(
+            Opts::new(
+                "proksi_compression_output_bytes_total",
+                "Response bytes after compression",
+            ),
+            &["host", "algorithm"],
+        )
+        .expect("Unable to create the compression output bytes metric; this is a bug"),
+    )
+});
+
+/// Time spent compressing the responses, by route and algorithm
+pub static COMPRESSION_SECONDS: Lazy<RouteMetric<CounterVec>> = Lazy::new(|| {
+    RouteMetric::register(
+        CounterVec::new(
+            Opts::new(
+                "proksi_compression_seconds_total",
+                "Time spent compressing responses",
+            ),
+            &["host", "algorithm"],
+        )
+        .expect("Unable to create the compression time metric; this is a bug"),
+    )
+});
+
+/// Records the compression of a response of `host`
+pub fn record_compression(
+    host: &str,
+    algorithm: &str,
+    input_bytes: usize,
+    output_bytes: usize,
+    took: Duration,
+) {
+    let labels = [host, algorithm];
+    COMPRESSION_INPUT_BYTES
+        .with_label_values(&labels)
+        .inc_by(input_bytes as u64);
+    COMPRESSION_OUTPUT_BYTES
+        .with_label_values(&labels)
+        .inc_by(output_bytes as u64);
+    COMPRESSION_SECONDS
+        .with_label_values(&labels)
+        .inc_by(took.as_secs_f64());
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{config::RouteLabels, test_support};
+
+    use super::*;
+
+    /// The labels (name=value) of the series of `metric` for `host`
+    fn helper_series_labels(metric: &impl Collector, host: &str) -> Vec<Vec<String>> {
+        metric
+            .collect()
+            .iter()
+            .flat_map(|family| family.get_metric().to_vec())
+            .filter(|metric| {
+                metric
+                    .get_label()
+                    .iter()
+                    .any(|pair| pair.name() == "host" && pair.value() == host)
+            })
+            .map(|metric| {
+                metric
+                    .get_label()
+                    .iter()
+                    .map(|pair| format!("{}={}", pair.name(), pair.value()))
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_route_metrics_carry_the_configured_labels() {
+        let host = "labels.metrics.test";
+        let mut route = test_support::route(host, []);
+        route.labels = RouteLabels::from([
+            ("team".into(), "payments".into()),
+            ("tier".into(), "critical".into()),
+            // The labels of the metric can't be overridden
+            ("host".into(), "other.metrics.test".into()),
+        ]);
+        test_support::add_route(route.clone()).await;
+
+        SLOW_REQUESTS.with_label_values(&[host]).inc();
+        record_compression(host, "gzip", 100, 10, Duration::from_millis(1));
+        assert_eq!(
+            helper_series_labels(&*SLOW_REQUESTS, host),
+            [["host=labels.metrics.test", "team=payments", "tier=critical"]]
+        );
+        assert_eq!(
+            helper_series_labels(&*COMPRESSION_INPUT_BYTES, host),
+            [[
+                "algorithm=gzip",
+                "host=labels.metrics.test",
+                "team=payments",
+                "tier=critical"
+            ]]
+        );
+
+        // The series follow the labels of the route
+        route.labels = RouteLabels::from([("team".into(), "billing".into())]);
+        test_support::add_route(route).await;
+        assert_eq!(
+            helper_series_labels(&*SLOW_REQUESTS, host),
+            [["host=labels.metrics.test", "team=billing"]]
+        );
+
+        // Routes without labels keep the labels of the metric only
+        SLOW_REQUESTS
+            .with_label_values(&["unlabeled.metrics.test"])
+            .inc();
+        assert_eq!(
+            helper_series_labels(&*SLOW_REQUESTS, "unlabeled.metrics.test"),
+            [["host=unlabeled.metrics.test"]]
+        );
+    }
+}
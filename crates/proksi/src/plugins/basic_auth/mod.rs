@@ -1,5 +1,6 @@
 use std::{borrow::Cow, collections::HashMap};
 
+use anyhow::anyhow;
 use async_trait::async_trait;
 use http::{header, StatusCode};
 use openssl::base64;
@@ -48,6 +49,21 @@ impl BasicAuth {
 
 #[async_trait]
 impl MiddlewarePlugin for BasicAuth {
+    /// Without `user` and `pass`, the requests are not authenticated at all
+    fn validate_config(&self, plugin: &RoutePlugin) -> anyhow::Result<()> {
+        let config = plugin
+            .config
+            .as_ref()
+            .ok_or_else(|| anyhow!("user and pass are required"))?;
+        for key in ["user", "pass"] {
+            if !config.get(key).is_some_and(serde_json::Value::is_string) {
+                return Err(anyhow!("{key} must be a string"));
+            }
+        }
+
+        Ok(())
+    }
+
     async fn request_filter(
         &self,
         session: &mut Session,
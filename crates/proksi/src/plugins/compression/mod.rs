@@ -0,0 +1,210 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
+
+use super::MiddlewarePlugin;
+
+/// The compression level used when the plugin doesn't configure one
+const DEFAULT_LEVEL: u32 = 6;
+
+/// Compresses the responses of the route with the best algorithm accepted by
+/// the client (gzip, brotli or zstd). Responses that are already compressed,
+/// too small or of a binary type (ex: images) are left untouched.
+/// The bytes before and after compression are recorded in the metrics.
+pub struct Compression {}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    /// The `level` of the plugin configuration (`6` by default)
+    fn level(plugin: &RoutePlugin) -> anyhow::Result<u32> {
+        let Some(level) = plugin.config.as_ref().and_then(|v| v.get("level")) else {
+            return Ok(DEFAULT_LEVEL);
+        };
+
+        level
+            .as_u64()
+            .and_then(|level| u32::try_from(level).ok())
+            .filter(|level| *level > 0)
+            .ok_or_else(|| anyhow!("level must be a positive integer"))
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for Compression {
+    fn validate_config(&self, plugin: &RoutePlugin) -> anyhow::Result<()> {
+        Self::level(plugin).map(|_| ())
+    }
+
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> anyhow::Result<bool> {
+        let Ok(level) = Self::level(plugin) else {
+            tracing::warn!(
+                host = ctx.host,
+                "invalid compression level, the responses are not compressed"
+            );
+            return Ok(false);
+        };
+
+        session.upstream_compression.adjust_level(level);
+        Ok(false)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use pingora::protocols::http::compression::ResponseCompressionCtx;
+
+    use super::*;
+    use crate::{
+        metrics::{self, COMPRESSION_INPUT_BYTES, COMPRESSION_OUTPUT_BYTES},
+        test_support::{add_route, route, TestBackend, TestProxy},
+    };
+
+    fn helper_plugin(config: serde_json::Value) -> RoutePlugin {
+        RoutePlugin {
+            name: "compression".into(),
+            config: serde_json::from_value(config).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_level() {
+        assert_eq!(
+            Compression::level(&helper_plugin(serde_json::json!({}))).ok(),
+            Some(6)
+        );
+        assert_eq!(
+            Compression::level(&helper_plugin(serde_json::json!({ "level": 9 }))).ok(),
+            Some(9)
+        );
+        assert_eq!(
+            Compression::level(&helper_plugin(serde_json::json!({ "level": 0 })))
+                .unwrap_err()
+                .to_string(),
+            "level must be a positive integer"
+        );
+    }
+
+    #[test]
+    fn test_compressed_body_updates_the_byte_counters() {
+        let host = "compressed.compression.test";
+        let body = Bytes::from("hello world, ".repeat(100));
+
+        // The compression of a response as done by the proxy
+        let mut compression = ResponseCompressionCtx::new(DEFAULT_LEVEL, false, false);
+        let mut request = RequestHeader::build("GET", b"/", None).unwrap();
+        request.insert_header("accept-encoding", "gzip").unwrap();
+        compression.request_filter(&request);
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response
+            .insert_header("content-type", "text/plain")
+            .unwrap();
+        response
+            .insert_header("content-length", body.len())
+            .unwrap();
+        compression.response_header_filter(&mut response, false);
+        let compressed = compression.response_body_filter(Some(&body), true).unwrap();
+
+        let (algorithm, input_bytes, output_bytes, took) = compression.get_info().unwrap();
+        metrics::record_compression(host, algorithm, input_bytes, output_bytes, took);
+
+        assert_eq!(algorithm, "gzip");
+        assert_eq!(
+            COMPRESSION_INPUT_BYTES
+                .with_label_values(&[host, "gzip"])
+                .get(),
+            1300
+        );
+        assert_eq!(
+            COMPRESSION_OUTPUT_BYTES
+                .with_label_values(&[host, "gzip"])
+                .get(),
+            compressed.len() as u64
+        );
+        assert!(compressed.len() < body.len());
+    }
+
+    #[tokio::test]
+    async fn test_proxied_response_is_compressed_and_recorded() {
+        const BODY: &str = "hello world, hello world, hello world, hello world, hello world, \
+                            hello world, hello world, hello world, hello world, hello world";
+        let backend = TestBackend::start_with_headers(
+            200,
+            BODY,
+            vec![("content-type".into(), "text/plain".into())],
+        )
+        .await;
+        let host = "proxied.compression.test";
+        let mut compressed = route(host, [backend.addr()]);
+        compressed.plugins = Some(vec![helper_plugin(serde_json::json!({}))]);
+        add_route(compressed).await;
+
+        let proxy = TestProxy::start().await;
+        let response = proxy
+            .request(reqwest::Method::GET, host, "/")
+            .header("accept-encoding", "gzip")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["content-encoding"], "gzip");
+        let body = response.bytes().await.unwrap();
+        assert!(body.len() < BODY.len());
+
+        // The metrics are recorded by the logging phase, once the response is sent
+        let input_bytes = COMPRESSION_INPUT_BYTES.with_label_values(&[host, "gzip"]);
+        for _ in 0..50 {
+            if input_bytes.get() > 0 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(input_bytes.get(), BODY.len() as u64);
+        assert_eq!(
+            COMPRESSION_OUTPUT_BYTES
+                .with_label_values(&[host, "gzip"])
+                .get(),
+            body.len() as u64
+        );
+    }
+}
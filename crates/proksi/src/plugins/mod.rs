@@ -3,29 +3,37 @@ use std::{borrow::Cow, collections::HashMap};
 use anyhow::{anyhow, Result};
 use async_trait::async_trait;
 use basic_auth::BasicAuth;
+use compression::Compression;
 use oauth2::Oauth2;
 use once_cell::sync::Lazy;
 use pingora::http::{RequestHeader, ResponseHeader};
 use pingora::proxy::Session;
+use rate_limit::RateLimit;
 use request_id::RequestId;
 
 use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext};
 
 pub mod basic_auth;
+pub mod compression;
 pub mod jwt;
 pub mod oauth2;
+pub mod rate_limit;
 pub mod request_id;
 
 pub(crate) struct ProxyPlugins {
     pub basic_auth: Lazy<BasicAuth>,
+    pub compression: Lazy<Compression>,
     pub oauth2: Lazy<Oauth2>,
+    pub rate_limit: Lazy<RateLimit>,
     pub request_id: Lazy<RequestId>,
 }
 
 /// Static plugin registry (plugins that don't generate a new instance for each request)
 pub static PLUGINS: Lazy<ProxyPlugins> = Lazy::new(|| ProxyPlugins {
     basic_auth: Lazy::new(BasicAuth::new),
+    compression: Lazy::new(Compression::new),
     oauth2: Lazy::new(Oauth2::new),
+    rate_limit: Lazy::new(RateLimit::new),
     request_id: Lazy::new(RequestId::new),
 });
 
@@ -41,8 +49,27 @@ fn get_required_config(
         .ok_or_else(|| anyhow!("Missing or invalid {}", key))
 }
 
+/// Validates the configuration of a route plugin with the registered plugin of
+/// the same name, the configurations of other plugins are not checked
+pub fn validate_plugin_config(plugin: &RoutePlugin) -> Result<()> {
+    match plugin.name.as_ref() {
+        "basic_auth" => PLUGINS.basic_auth.validate_config(plugin),
+        "compression" => PLUGINS.compression.validate_config(plugin),
+        "oauth2" => PLUGINS.oauth2.validate_config(plugin),
+        "rate_limit" => PLUGINS.rate_limit.validate_config(plugin),
+        "request_id" => PLUGINS.request_id.validate_config(plugin),
+        _ => Ok(()),
+    }
+}
+
 #[async_trait]
 pub trait MiddlewarePlugin {
+    /// Checks the configuration of the plugin when the configuration is loaded,
+    /// so that an invalid one fails at startup instead of at request time
+    fn validate_config(&self, _config: &RoutePlugin) -> Result<()> {
+        Ok(())
+    }
+
     /// Create a new state for the middleware
     /// Filter requests based on the middleware's logic
     /// Return false if the request should be allowed to pass through and was not handled
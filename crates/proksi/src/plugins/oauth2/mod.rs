@@ -174,6 +174,25 @@ impl Oauth2 {
 
 #[async_trait]
 impl MiddlewarePlugin for Oauth2 {
+    fn validate_config(&self, plugin: &RoutePlugin) -> Result<()> {
+        let plugin_config = plugin.config.as_ref().ok_or_else(|| {
+            anyhow!("provider, client_id, client_secret and jwt_secret are required")
+        })?;
+
+        Self::parse_provider(plugin_config)?;
+        for key in ["client_id", "client_secret", "jwt_secret"] {
+            get_required_config(plugin_config, key)?;
+        }
+
+        // Validations that are not a list would authorize every user
+        let validations = plugin_config.get("validations");
+        if validations.is_some_and(|validations| !validations.is_array()) {
+            bail!("validations must be a list");
+        }
+
+        Ok(())
+    }
+
     async fn upstream_request_filter(
         &self,
         _: &mut Session,
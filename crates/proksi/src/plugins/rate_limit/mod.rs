@@ -0,0 +1,451 @@
+use std::{collections::HashMap, net::IpAddr, num::NonZeroUsize, sync::Mutex, time::Instant};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use http::{header, StatusCode};
+use lru::LruCache;
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    proxy::Session,
+};
+
+use crate::{config::RoutePlugin, proxy_server::https_proxy::RouterContext, stores};
+
+use super::MiddlewarePlugin;
+
+/// The buckets kept for each route, past it the least recently used bucket
+/// is dropped (its client starts again with a full bucket)
+const MAX_BUCKETS: NonZeroUsize = NonZeroUsize::new(10_000).unwrap();
+
+/// The buckets of the clients of a route, by client IP. Clients without a
+/// known IP share the same bucket.
+type RouteBuckets = LruCache<Option<IpAddr>, TokenBucket>;
+
+/// Limits the requests of every client to `requests` per `window_secs`
+/// (token bucket). Throttled requests are answered with a 429 telling the
+/// client when to retry (`Retry-After` and the `RateLimit-*` headers).
+pub struct RateLimit {
+    /// The buckets of every route, the routes that were removed are dropped when
+    /// a new one is added (see `acquire`)
+    routes: Mutex<HashMap<String, RouteBuckets>>,
+}
+
+impl RateLimit {
+    pub fn new() -> Self {
+        Self {
+            routes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token from the bucket of `client_ip` for the route of `host`
+    fn acquire(
+        &self,
+        host: &str,
+        client_ip: Option<IpAddr>,
+        limit: &RateLimitConfig,
+        now: Instant,
+    ) -> Result<(), Throttled> {
+        let mut routes = self.routes.lock().unwrap();
+        if !routes.contains_key(host) {
+            // The hosts of removed routes (reloads, Docker discovery) would
+            // otherwise keep their buckets forever
+            let hosts = stores::get_route_hosts();
+            routes.retain(|host, _| hosts.contains(host));
+            routes.insert(host.to_string(), LruCache::new(MAX_BUCKETS));
+        }
+        let Some(buckets) = routes.get_mut(host) else {
+            return Ok(());
+        };
+
+        buckets
+            .get_or_insert_mut(client_ip, || TokenBucket::full(limit, now))
+            .try_acquire(limit, now)
+    }
+
+    /// The 429 response of a throttled request
+    fn respond_throttled(
+        throttled: &Throttled,
+        limit: &RateLimitConfig,
+    ) -> anyhow::Result<Box<ResponseHeader>> {
+        let mut response = ResponseHeader::build_no_case(StatusCode::TOO_MANY_REQUESTS, Some(5))?;
+        response.insert_header(header::RETRY_AFTER, throttled.retry_after_secs)?;
+        if limit.headers {
+            response.insert_header("RateLimit-Limit", throttled.limit)?;
+            response.insert_header("RateLimit-Remaining", throttled.remaining)?;
+            response.insert_header("RateLimit-Reset", throttled.reset_secs)?;
+        }
+        response.insert_header(header::CONTENT_LENGTH, 0)?;
+
+        Ok(Box::new(response))
+    }
+}
+
+/// The plugin configuration of a route
+#[derive(Debug, Clone, Copy)]
+struct RateLimitConfig {
+    /// The requests a client can send in a window (the size of the bucket)
+    requests: u64,
+    /// Buckets are refilled with `requests` tokens every `window_secs`
+    window_secs: u64,
+    /// Whether throttled responses have the `RateLimit-*` headers
+    headers: bool,
+}
+
+impl RateLimitConfig {
+    fn from_plugin(plugin: &RoutePlugin) -> anyhow::Result<Self> {
+        let config = plugin
+            .config
+            .as_ref()
+            .ok_or_else(|| anyhow!("requests is required"))?;
+        let requests = config
+            .get("requests")
+            .and_then(serde_json::Value::as_u64)
+            .filter(|v| *v > 0)
+            .ok_or_else(|| anyhow!("requests must be a positive integer"))?;
+        let window_secs = match config.get("window_secs") {
+            Some(value) => value
+                .as_u64()
+                .filter(|v| *v > 0)
+                .ok_or_else(|| anyhow!("window_secs must be a positive integer"))?,
+            None => 1,
+        };
+        let headers = match config.get("headers") {
+            Some(value) => value
+                .as_bool()
+                .ok_or_else(|| anyhow!("headers must be a boolean"))?,
+            None => true,
+        };
+
+        Ok(Self {
+            requests,
+            window_secs,
+            headers,
+        })
+    }
+
+    /// The time it takes to refill `tokens` tokens, in seconds
+    fn refill_secs(&self, tokens: f64) -> f64 {
+        tokens * self.window_secs as f64 / self.requests as f64
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+/// The state of a bucket that rejected a request
+#[derive(Debug, PartialEq, Eq)]
+struct Throttled {
+    limit: u64,
+    remaining: u64,
+    /// Seconds until the bucket is full again
+    reset_secs: u64,
+    /// Seconds until the next request is accepted
+    retry_after_secs: u64,
+}
+
+impl TokenBucket {
+    fn full(limit: &RateLimitConfig, now: Instant) -> Self {
+        Self {
+            tokens: limit.requests as f64,
+            updated_at: now,
+        }
+    }
+
+    fn refill(&mut self, limit: &RateLimitConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at);
+        let refilled = elapsed.as_secs_f64() * limit.requests as f64 / limit.window_secs as f64;
+
+        self.tokens = (self.tokens + refilled).min(limit.requests as f64);
+        self.updated_at = now;
+    }
+
+    fn try_acquire(&mut self, limit: &RateLimitConfig, now: Instant) -> Result<(), Throttled> {
+        self.refill(limit, now);
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        Err(Throttled {
+            limit: limit.requests,
+            remaining: 0,
+            reset_secs: limit
+                .refill_secs(limit.requests as f64 - self.tokens)
+                .ceil() as u64,
+            retry_after_secs: limit.refill_secs(1.0 - self.tokens).ceil() as u64,
+        })
+    }
+}
+
+#[async_trait]
+impl MiddlewarePlugin for RateLimit {
+    fn validate_config(&self, plugin: &RoutePlugin) -> anyhow::Result<()> {
+        RateLimitConfig::from_plugin(plugin).map(|_| ())
+    }
+
+    async fn request_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+        plugin: &RoutePlugin,
+    ) -> anyhow::Result<bool> {
+        let Ok(limit) = RateLimitConfig::from_plugin(plugin) else {
+            tracing::warn!(
+                host = ctx.host,
+                "invalid rate_limit configuration, the requests are not limited"
+            );
+            return Ok(false);
+        };
+
+        let Err(throttled) = self.acquire(&ctx.host, ctx.client_ip, &limit, Instant::now()) else {
+            return Ok(false);
+        };
+
+        tracing::debug!(
+            host = ctx.host,
+            client_ip = ?ctx.client_ip,
+            retry_after_secs = throttled.retry_after_secs,
+            "request throttled by the rate_limit plugin"
+        );
+        session
+            .write_response_header(Self::respond_throttled(&throttled, &limit)?, true)
+            .await?;
+        Ok(true)
+    }
+
+    async fn upstream_request_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RequestHeader,
+        _: &mut RouterContext,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut RouterContext,
+        _: &RoutePlugin,
+    ) -> anyhow::Result<bool> {
+        Ok(false)
+    }
+
+    fn upstream_response_filter(
+        &self,
+        _: &mut Session,
+        _: &mut ResponseHeader,
+        _: &mut RouterContext,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::stores::routes::RouteStoreContainer;
+    use crate::test_support::{add_route, route, TestBackend, TestProxy};
+
+    fn helper_limit(requests: u64, window_secs: u64) -> RateLimitConfig {
+        RateLimitConfig {
+            requests,
+            window_secs,
+            headers: true,
+        }
+    }
+
+    #[test]
+    fn test_throttled_bucket_tells_when_to_retry() {
+        let limit = helper_limit(2, 10);
+        let start = Instant::now();
+        let mut bucket = TokenBucket::full(&limit, start);
+
+        assert!(bucket.try_acquire(&limit, start).is_ok());
+        assert!(bucket.try_acquire(&limit, start).is_ok());
+        assert_eq!(
+            bucket.try_acquire(&limit, start),
+            Err(Throttled {
+                limit: 2,
+                remaining: 0,
+                reset_secs: 10,
+                retry_after_secs: 5,
+            })
+        );
+
+        // Half a token is back
+        let now = start + Duration::from_millis(2500);
+        assert_eq!(
+            bucket.try_acquire(&limit, now),
+            Err(Throttled {
+                limit: 2,
+                remaining: 0,
+                reset_secs: 8,
+                retry_after_secs: 3,
+            })
+        );
+
+        // A token is back, then the bucket is empty again
+        let now = start + Duration::from_secs(5);
+        assert!(bucket.try_acquire(&limit, now).is_ok());
+        assert_eq!(
+            bucket
+                .try_acquire(&limit, now)
+                .unwrap_err()
+                .retry_after_secs,
+            5
+        );
+    }
+
+    #[test]
+    fn test_buckets_are_refilled_up_to_the_limit() {
+        let limit = helper_limit(3, 3);
+        let start = Instant::now();
+        let mut bucket = TokenBucket::full(&limit, start);
+
+        let later = start + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(bucket.try_acquire(&limit, later).is_ok());
+        }
+        assert!(bucket.try_acquire(&limit, later).is_err());
+    }
+
+    #[test]
+    fn test_buckets_are_kept_per_route_up_to_the_limit() {
+        let rate_limit = RateLimit::new();
+        let limit = helper_limit(1, 60);
+        let now = Instant::now();
+        let client = |index: usize| Some(IpAddr::from((index as u128).to_be_bytes()));
+        for host in ["a.test", "b.test"] {
+            stores::insert_route(host.to_string(), RouteStoreContainer::default());
+        }
+
+        assert!(rate_limit.acquire("a.test", client(0), &limit, now).is_ok());
+        assert!(rate_limit
+            .acquire("a.test", client(0), &limit, now)
+            .is_err());
+        // The same client has its own bucket on every route
+        assert!(rate_limit.acquire("b.test", client(0), &limit, now).is_ok());
+
+        for index in 1..=MAX_BUCKETS.get() {
+            assert!(rate_limit
+                .acquire("a.test", client(index), &limit, now)
+                .is_ok());
+        }
+        let routes = rate_limit.routes.lock().unwrap();
+        assert_eq!(routes["a.test"].len(), MAX_BUCKETS.get());
+        // The least recently used bucket was dropped
+        assert!(!routes["a.test"].contains(&client(0)));
+        assert_eq!(routes["b.test"].len(), 1);
+    }
+
+    #[test]
+    fn test_buckets_of_removed_routes_are_dropped() {
+        let rate_limit = RateLimit::new();
+        let limit = helper_limit(1, 60);
+        let now = Instant::now();
+        stores::insert_route(
+            "kept.rate-limit.test:8443".to_string(),
+            RouteStoreContainer::default(),
+        );
+
+        for host in ["removed.rate-limit.test", "kept.rate-limit.test"] {
+            assert!(rate_limit.acquire(host, None, &limit, now).is_ok());
+        }
+        // The buckets of the routes still in the store are kept
+        assert!(rate_limit
+            .acquire("added.rate-limit.test", None, &limit, now)
+            .is_ok());
+
+        let routes = rate_limit.routes.lock().unwrap();
+        assert!(!routes.contains_key("removed.rate-limit.test"));
+        assert_eq!(routes["kept.rate-limit.test"].len(), 1);
+        assert!(routes.contains_key("added.rate-limit.test"));
+    }
+
+    #[test]
+    fn test_config_from_plugin() {
+        let plugin = |config: serde_json::Value| RoutePlugin {
+            name: "rate_limit".into(),
+            config: serde_json::from_value(config).unwrap(),
+        };
+
+        let limit =
+            RateLimitConfig::from_plugin(&plugin(serde_json::json!({ "requests": 10 }))).unwrap();
+        assert_eq!(
+            (limit.requests, limit.window_secs, limit.headers),
+            (10, 1, true)
+        );
+
+        let limit = RateLimitConfig::from_plugin(&plugin(
+            serde_json::json!({ "requests": 100, "window_secs": 60, "headers": false }),
+        ))
+        .unwrap();
+        assert_eq!(
+            (limit.requests, limit.window_secs, limit.headers),
+            (100, 60, false)
+        );
+
+        let error = |config| {
+            RateLimitConfig::from_plugin(&plugin(config))
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(serde_json::json!({ "requests": 0 })),
+            "requests must be a positive integer"
+        );
+        assert_eq!(
+            error(serde_json::json!({})),
+            "requests must be a positive integer"
+        );
+        assert_eq!(
+            error(serde_json::json!({ "requests": 10, "window_secs": "1m" })),
+            "window_secs must be a positive integer"
+        );
+        assert_eq!(
+            error(serde_json::json!({ "requests": 10, "headers": "no" })),
+            "headers must be a boolean"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_throttled_response_has_rate_limit_headers() {
+        let backend = TestBackend::start(200, "hello").await;
+        let host = "throttled.rate-limit.test";
+        let mut route = route(host, [backend.addr()]);
+        route.plugins = Some(vec![RoutePlugin {
+            name: "rate_limit".into(),
+            config: serde_json::from_value(serde_json::json!({
+                "requests": 2,
+                "window_secs": 60,
+            }))
+            .unwrap(),
+        }]);
+        add_route(route).await;
+
+        let proxy = TestProxy::start().await;
+        assert_eq!(proxy.get(host, "/").await.0, 200);
+        assert_eq!(proxy.get(host, "/").await.0, 200);
+
+        let response = proxy
+            .request(reqwest::Method::GET, host, "/")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 429);
+
+        let header = |name: &str| response.headers().get(name).unwrap().to_str().unwrap();
+        assert_eq!(header("retry-after"), "30");
+        assert_eq!(header("ratelimit-limit"), "2");
+        assert_eq!(header("ratelimit-remaining"), "0");
+        assert_eq!(header("ratelimit-reset"), "60");
+        assert_eq!(backend.requests(), 2);
+    }
+}
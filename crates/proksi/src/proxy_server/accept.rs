@@ -0,0 +1,316 @@
+use std::{sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use pingora::{
+    apps::ServerApp,
+    listeners::TlsAcceptCallbacks,
+    protocols::{l4, tls::server::handshake_with_callback, GetSocketDigest, Stream},
+    server::ShutdownWatch,
+    services::{listening::Service as ListeningService, Service},
+    tls::ssl::{select_next_proto, AlpnError, SslAcceptor, SslMethod, SslRef, SslVersion},
+};
+
+use super::cert_store::CertStore;
+use super::connection_limit::{ConnectionLimiter, ConnectionPermit};
+
+/// The handshakes that aren't done by then are dropped (the timeout of pingora)
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The ALPN protocols of the HTTPS listeners, HTTP/2 is preferred
+const HTTPS_ALPN: &[u8] = b"\x02h2\x08http/1.1";
+
+/// Accepts the connections of a listener before they reach its application.
+/// The connections of the client IPs at their cap are closed right away,
+/// without an answer. The TLS handshake (if any) is only done afterwards, so
+/// the connections that never complete it count as well.
+pub struct AcceptApp<A> {
+    app: Arc<A>,
+    limiter: Option<Arc<ConnectionLimiter>>,
+    tls: Option<TlsHandshake>,
+}
+
+impl<A> AcceptApp<A> {
+    pub fn new(app: A, limiter: Option<Arc<ConnectionLimiter>>, tls: Option<TlsHandshake>) -> Self {
+        Self {
+            app: Arc::new(app),
+            limiter,
+            tls,
+        }
+    }
+
+    /// Counts the connection for its client IP, `None` if the IP is at its cap.
+    /// The connections without an IP (ex: Unix sockets) aren't limited.
+    fn permit(&self, stream: &Stream) -> Result<Option<ConnectionPermit>, ()> {
+        let Some(limiter) = self.limiter.as_ref() else {
+            return Ok(None);
+        };
+        let Some(ip) = stream
+            .get_socket_digest()
+            .and_then(|digest| digest.peer_addr().and_then(|addr| addr.as_inet()).copied())
+            .map(|addr| addr.ip())
+        else {
+            return Ok(None);
+        };
+
+        match limiter.acquire(ip) {
+            Some(permit) => Ok(Some(permit)),
+            None => {
+                tracing::debug!(
+                    peer_ip = %ip,
+                    connections = limiter.connections(ip),
+                    "connection refused, too many connections from the client IP"
+                );
+                Err(())
+            }
+        }
+    }
+}
+
+impl<A> AcceptApp<A>
+where
+    A: ServerApp + Send + Sync + 'static,
+{
+    /// The service (ex: a `http_proxy_service`) with its connections accepted
+    /// by `AcceptApp` first. Its listeners have to be added afterwards (TCP ones
+    /// when `tls` is set).
+    pub fn service(
+        service: ListeningService<A>,
+        limiter: Option<Arc<ConnectionLimiter>>,
+        tls: Option<TlsHandshake>,
+    ) -> ListeningService<Self> {
+        let name = service.name().to_string();
+        ListeningService::new(name, Self::new(into_app(service), limiter, tls))
+    }
+}
+
+/// The application of a service that wasn't started. Pingora only creates an
+/// `HttpProxy` through `http_proxy_service`, it is taken back from the service.
+fn into_app<A>(mut service: ListeningService<A>) -> A {
+    let app = service
+        .app_logic_mut()
+        .expect("the service was already started");
+    // SAFETY: the service is forgotten right away, the application is neither
+    // dropped nor used through it
+    let app = unsafe { std::ptr::read(app) };
+    std::mem::forget(service);
+    app
+}
+
+#[async_trait]
+impl<A> ServerApp for AcceptApp<A>
+where
+    A: ServerApp + Send + Sync + 'static,
+{
+    async fn process_new(
+        self: &Arc<Self>,
+        stream: Stream,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        let Ok(_permit) = self.permit(&stream) else {
+            return None;
+        };
+
+        let stream = match self.tls.as_ref() {
+            Some(tls) => tls.handshake(stream).await?,
+            None => stream,
+        };
+
+        // The connection counts until it is closed, its reuses are served here
+        let mut reused = self.app.process_new(stream, shutdown).await;
+        while let Some(stream) = reused {
+            reused = self.app.process_new(stream, shutdown).await;
+        }
+
+        None
+    }
+
+    async fn cleanup(&self) {
+        self.app.cleanup().await;
+    }
+}
+
+/// The TLS handshake of the connections accepted by a TCP listener
+pub struct TlsHandshake {
+    acceptor: SslAcceptor,
+    callbacks: TlsAcceptCallbacks,
+}
+
+impl TlsHandshake {
+    pub fn new(acceptor: SslAcceptor, callbacks: TlsAcceptCallbacks) -> Self {
+        Self {
+            acceptor,
+            callbacks,
+        }
+    }
+
+    /// The handshake of the HTTPS listeners: the certificates come from the
+    /// certificate store and HTTP/2 is enabled
+    pub fn https() -> anyhow::Result<Self> {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())?;
+        acceptor.set_alpn_select_callback(prefer_h2);
+
+        // acceptor.set_session_cache_mode(SslSessionCacheMode::SERVER);
+        acceptor.set_servername_callback(move |ssl_ref, _| CertStore::sni_callback(ssl_ref));
+
+        // For now this is a hardcoded recommendation based on
+        // https://developers.cloudflare.com/ssl/reference/protocols/
+        // but will be made configurable in the future
+        acceptor.set_min_proto_version(Some(SslVersion::TLS1_2))?;
+        acceptor.set_max_proto_version(Some(SslVersion::TLS1_3))?;
+
+        Ok(Self::new(acceptor.build(), Box::new(CertStore::new())))
+    }
+
+    /// The TLS stream of the connection, `None` if the handshake failed
+    async fn handshake(&self, stream: Stream) -> Option<Stream> {
+        // The connections of TCP listeners are L4 streams
+        let stream = stream.into_any().downcast::<l4::stream::Stream>().ok()?;
+        let peer_addr = stream
+            .get_socket_digest()
+            .and_then(|digest| digest.peer_addr().cloned());
+
+        let handshake = handshake_with_callback(&self.acceptor, *stream, &self.callbacks);
+        match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, handshake).await {
+            Ok(Ok(stream)) => Some(Box::new(stream)),
+            Ok(Err(err)) => {
+                tracing::debug!(peer_addr = ?peer_addr, "downstream TLS handshake failed: {err}");
+                None
+            }
+            Err(_) => {
+                tracing::debug!(peer_addr = ?peer_addr, "downstream TLS handshake timed out");
+                None
+            }
+        }
+    }
+}
+
+/// Selects HTTP/2 when the client supports it, the clients without ALPN get
+/// HTTP/1.1
+fn prefer_h2<'a>(_: &mut SslRef, client_protocols: &'a [u8]) -> Result<&'a [u8], AlpnError> {
+    select_next_proto(HTTPS_ALPN, client_protocols).ok_or(AlpnError::NOACK)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::{IpAddr, SocketAddr},
+        os::fd::AsRawFd,
+    };
+
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+    use pingora::{listeners::TlsAccept, protocols::SocketDigest};
+    use tokio::{io::AsyncReadExt, io::AsyncWriteExt, net::TcpStream};
+
+    use super::*;
+    use crate::test_support::self_signed_certificate;
+
+    /// Answers every connection with `hello`
+    struct HelloApp;
+
+    #[async_trait]
+    impl ServerApp for HelloApp {
+        async fn process_new(
+            self: &Arc<Self>,
+            mut stream: Stream,
+            _shutdown: &ShutdownWatch,
+        ) -> Option<Stream> {
+            stream.write_all(b"hello").await.ok()?;
+            stream.flush().await.ok()?;
+            None
+        }
+    }
+
+    /// The certificate is set on the acceptor
+    struct NoCertificateCallback;
+
+    #[async_trait]
+    impl TlsAccept for NoCertificateCallback {}
+
+    /// Serves the TCP connections of a local listener with `app`, like a
+    /// pingora TCP listener
+    async fn helper_serve(app: AcceptApp<HelloApp>) -> SocketAddr {
+        let app = Arc::new(app);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (_shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+            while let Ok((tcp, _)) = listener.accept().await {
+                let socket_digest = SocketDigest::from_raw_fd(tcp.as_raw_fd());
+                let mut stream = l4::stream::Stream::from(tcp);
+                stream.set_socket_digest(socket_digest);
+
+                let (app, shutdown) = (app.clone(), shutdown.clone());
+                tokio::spawn(async move { app.process_new(Box::new(stream), &shutdown).await });
+            }
+        });
+
+        addr
+    }
+
+    async fn helper_wait_for_connections(limiter: &ConnectionLimiter, expected: usize) {
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        for _ in 0..100 {
+            if limiter.connections(ip) == expected {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("the client IP has {} connections", limiter.connections(ip));
+    }
+
+    #[tokio::test]
+    async fn test_connections_past_the_cap_are_closed_before_the_tls_handshake() {
+        let (cert, key) = self_signed_certificate("accept.test", 2048, 1);
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        let tls = TlsHandshake::new(acceptor.build(), Box::new(NoCertificateCallback));
+
+        let limiter = Arc::new(ConnectionLimiter::new(1));
+        let addr = helper_serve(AcceptApp::new(HelloApp, Some(limiter.clone()), Some(tls))).await;
+
+        // A connection that never starts its handshake counts
+        let idle = TcpStream::connect(addr).await.unwrap();
+        helper_wait_for_connections(&limiter, 1).await;
+
+        let mut refused = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), refused.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0))), "{read:?}");
+
+        // Once it is closed, a new connection is served over TLS
+        drop(idle);
+        helper_wait_for_connections(&limiter, 0).await;
+        let answer = tokio::task::spawn_blocking(move || {
+            let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            let stream = std::net::TcpStream::connect(addr).unwrap();
+            let mut tls = connector.build().connect("accept.test", stream).unwrap();
+
+            let mut answer = String::new();
+            tls.read_to_string(&mut answer).ok();
+            answer
+        })
+        .await
+        .unwrap();
+        assert_eq!(answer, "hello");
+        helper_wait_for_connections(&limiter, 0).await;
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_not_limited_without_a_cap() {
+        let addr = helper_serve(AcceptApp::new(HelloApp, None, None)).await;
+
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            streams.push(TcpStream::connect(addr).await.unwrap());
+        }
+        for stream in &mut streams {
+            let mut answer = String::new();
+            stream.read_to_string(&mut answer).await.unwrap();
+            assert_eq!(answer, "hello");
+        }
+    }
+}
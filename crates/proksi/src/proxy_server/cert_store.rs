@@ -50,8 +50,88 @@ impl TlsAccept for CertStore {
         ext::ssl_use_private_key(ssl, &cert.key).unwrap();
         ext::ssl_use_certificate(ssl, &cert.leaf).unwrap();
 
-        if let Some(chain) = &cert.chain {
+        for chain in &cert.chain {
             ext::ssl_add_chain_cert(ssl, chain).unwrap();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use openssl::x509::X509;
+    use pingora::{
+        listeners::TlsAcceptCallbacks,
+        protocols::tls::{server::handshake_with_callback, SslStream},
+        tls::ssl::{Ssl, SslAcceptor, SslContext, SslMethod, SslVerifyMode},
+    };
+
+    use super::*;
+    use crate::{
+        services::certificates::update_certificate,
+        stores::certificates::Certificate,
+        test_support::{init_memory_store, self_signed_certificate},
+        MsgCert,
+    };
+
+    /// Performs a TLS handshake for `host` against the certificate store,
+    /// returns the certificate the client was served
+    async fn helper_served_certificate(host: &'static str) -> X509 {
+        let (client, server) = tokio::io::duplex(16 * 1024);
+
+        let client = tokio::spawn(async move {
+            let context = SslContext::builder(SslMethod::tls()).unwrap().build();
+            let mut ssl = Ssl::new(&context).unwrap();
+            ssl.set_hostname(host).unwrap();
+            ssl.set_verify(SslVerifyMode::NONE);
+
+            let mut stream = SslStream::new(ssl, client).unwrap();
+            stream.connect().await.unwrap();
+            // Kept open until the server is done with the handshake
+            stream
+        });
+
+        let acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+            .unwrap()
+            .build();
+        let callbacks: TlsAcceptCallbacks = Box::new(CertStore::new());
+        let _server = handshake_with_callback(&acceptor, server, &callbacks)
+            .await
+            .unwrap();
+
+        let client = client.await.unwrap();
+        client.ssl().peer_certificate().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_new_handshakes_are_served_the_updated_certificate() {
+        init_memory_store();
+        let host = "updated.cert-store.test";
+
+        let (first, key) = self_signed_certificate(host, 2048, 1);
+        stores::global::get_store()
+            .set_certificate(
+                host,
+                Certificate {
+                    key,
+                    leaf: first.clone(),
+                    chain: Vec::new(),
+                },
+            )
+            .await
+            .unwrap();
+        let served = helper_served_certificate(host).await;
+        assert_eq!(served.to_der().unwrap(), first.to_der().unwrap());
+
+        let (second, key) = self_signed_certificate(host, 2048, 1);
+        update_certificate(&MsgCert {
+            host: host.to_string(),
+            cert: second.to_pem().unwrap().into(),
+            key: key.private_key_to_pem_pkcs8().unwrap().into(),
+        })
+        .await
+        .unwrap();
+
+        let served = helper_served_certificate(host).await;
+        assert_eq!(served.to_der().unwrap(), second.to_der().unwrap());
+    }
+}
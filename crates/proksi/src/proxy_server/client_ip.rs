@@ -0,0 +1,334 @@
+use std::{net::IpAddr, str::FromStr};
+
+use anyhow::anyhow;
+use http::HeaderMap;
+
+use crate::config::RealIp;
+
+/// A CIDR block (ex: `10.0.0.0/8`, `2001:db8::/32`) used to match trusted proxies
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// Whether the given IP is part of this block
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(*ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(*ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = value.split_once('/').unwrap_or((value, ""));
+        let network = IpAddr::from_str(ip.trim())?;
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+
+        let prefix = if prefix.is_empty() {
+            max_prefix
+        } else {
+            prefix.trim().parse::<u8>()?
+        };
+
+        if prefix > max_prefix {
+            return Err(anyhow!("prefix /{prefix} is too large for {network}"));
+        }
+
+        Ok(Cidr { network, prefix })
+    }
+}
+
+/// Extracts the real client IP from a request based on the `real_ip` configuration.
+///
+/// Every feature that needs the client IP (logging, filters, limits etc.) should
+/// rely on this resolver (or on `RouterContext::client_ip`) instead of reading
+/// `X-Forwarded-For`/`Forwarded` on its own.
+#[derive(Debug, Clone, Default)]
+pub struct ClientIpResolver {
+    trusted_hops: usize,
+    trusted_cidrs: Vec<Cidr>,
+}
+
+impl ClientIpResolver {
+    pub fn new(config: &RealIp) -> Self {
+        Self {
+            trusted_hops: config.trusted_hops,
+            // invalid values are rejected when the configuration is validated
+            trusted_cidrs: config
+                .trusted_cidrs
+                .iter()
+                .filter_map(|cidr| Cidr::from_str(cidr).ok())
+                .collect(),
+        }
+    }
+
+    /// Returns the client IP for a request received from `peer`.
+    ///
+    /// - Without trusted proxies the peer (connection) address is always used.
+    /// - With `trusted_cidrs`, the forwarded chain is walked from right to left,
+    ///   skipping trusted proxies, and the first untrusted address is the client.
+    /// - With `trusted_hops`, the address `trusted_hops` positions to the left of the
+    ///   peer in the forwarded chain is the client.
+    pub fn resolve(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+        if self.trusted_hops == 0 && self.trusted_cidrs.is_empty() {
+            return peer;
+        }
+
+        let peer = peer?;
+        let mut chain = forwarded_chain(headers);
+        chain.push(Some(peer));
+
+        if !self.trusted_cidrs.is_empty() {
+            return Some(self.resolve_with_cidrs(&chain, peer));
+        }
+
+        // The rightmost `trusted_hops` addresses are our own proxies
+        let index = chain.len().saturating_sub(self.trusted_hops + 1);
+        Some(chain[index].unwrap_or(peer))
+    }
+
+    fn resolve_with_cidrs(&self, chain: &[Option<IpAddr>], peer: IpAddr) -> IpAddr {
+        let mut client = peer;
+
+        for ip in chain.iter().rev() {
+            // A malformed entry can't be trusted, use the last address we know is valid
+            let Some(ip) = ip else {
+                return client;
+            };
+
+            client = *ip;
+            if !self.is_trusted(ip) {
+                return client;
+            }
+        }
+
+        client
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_cidrs.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// Returns the list of addresses (client first) from the `X-Forwarded-For` header,
+/// falling back to the `for=` parameters of the `Forwarded` header (RFC 7239).
+/// Entries that cannot be parsed are kept as `None`.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let xff = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|v| parse_forwarded_ip(v.trim()))
+        .collect::<Vec<_>>();
+
+    if !xff.is_empty() {
+        return xff;
+    }
+
+    headers
+        .get_all(http::header::FORWARDED)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| parse_forwarded_ip(value.trim().trim_matches('"')))
+            })
+        })
+        .collect()
+}
+
+/// Parses an address that may contain a port and/or brackets
+/// (ex: `192.0.2.60`, `192.0.2.60:4711`, `[2001:db8::1]:4711`)
+fn parse_forwarded_ip(value: &str) -> Option<IpAddr> {
+    if let Ok(ip) = IpAddr::from_str(value) {
+        return Some(ip);
+    }
+
+    if let Ok(addr) = std::net::SocketAddr::from_str(value) {
+        return Some(addr.ip());
+    }
+
+    IpAddr::from_str(value.trim_start_matches('[').trim_end_matches(']')).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn header_map(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    fn resolver_with_hops(trusted_hops: usize) -> ClientIpResolver {
+        ClientIpResolver::new(&RealIp {
+            trusted_hops,
+            trusted_cidrs: vec![],
+        })
+    }
+
+    fn resolver_with_cidrs(cidrs: &[&'static str]) -> ClientIpResolver {
+        ClientIpResolver::new(&RealIp {
+            trusted_hops: 0,
+            trusted_cidrs: cidrs.iter().map(|v| Cow::Borrowed(*v)).collect(),
+        })
+    }
+
+    #[test]
+    fn test_cidr_contains() {
+        let cidr = Cidr::from_str("10.0.0.0/8").unwrap();
+        assert!(cidr.contains(&ip("10.20.30.40")));
+        assert!(!cidr.contains(&ip("11.0.0.1")));
+        assert!(!cidr.contains(&ip("::1")));
+
+        let single = Cidr::from_str("192.168.1.1").unwrap();
+        assert!(single.contains(&ip("192.168.1.1")));
+        assert!(!single.contains(&ip("192.168.1.2")));
+
+        let v6 = Cidr::from_str("2001:db8::/32").unwrap();
+        assert!(v6.contains(&ip("2001:db8::1")));
+        assert!(!v6.contains(&ip("2001:db9::1")));
+
+        assert!(Cidr::from_str("0.0.0.0/0").unwrap().contains(&ip("8.8.8.8")));
+        assert!(Cidr::from_str("10.0.0.0/33").is_err());
+        assert!(Cidr::from_str("not-an-ip/8").is_err());
+    }
+
+    #[test]
+    fn test_ignores_forwarded_headers_without_trusted_proxies() {
+        let resolver = ClientIpResolver::default();
+        let headers = header_map("x-forwarded-for", "1.1.1.1, 2.2.2.2");
+
+        assert_eq!(
+            resolver.resolve(&headers, Some(ip("10.0.0.1"))),
+            Some(ip("10.0.0.1"))
+        );
+    }
+
+    #[test]
+    fn test_trusted_hops() {
+        let headers = header_map("x-forwarded-for", "1.1.1.1, 2.2.2.2, 3.3.3.3");
+        let peer = Some(ip("10.0.0.1"));
+
+        assert_eq!(
+            resolver_with_hops(1).resolve(&headers, peer),
+            Some(ip("3.3.3.3"))
+        );
+        assert_eq!(
+            resolver_with_hops(2).resolve(&headers, peer),
+            Some(ip("2.2.2.2"))
+        );
+        assert_eq!(
+            resolver_with_hops(3).resolve(&headers, peer),
+            Some(ip("1.1.1.1"))
+        );
+
+        // More hops than entries: the leftmost address is the best guess
+        assert_eq!(
+            resolver_with_hops(10).resolve(&headers, peer),
+            Some(ip("1.1.1.1"))
+        );
+
+        // No header at all: the peer is the client
+        assert_eq!(resolver_with_hops(1).resolve(&HeaderMap::new(), peer), peer);
+    }
+
+    #[test]
+    fn test_trusted_hops_with_multiple_headers_and_ports() {
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", HeaderValue::from_static("1.1.1.1:3000"));
+        headers.append(
+            "x-forwarded-for",
+            HeaderValue::from_static("[2001:db8::1]:443, 3.3.3.3"),
+        );
+
+        assert_eq!(
+            resolver_with_hops(2).resolve(&headers, Some(ip("10.0.0.1"))),
+            Some(ip("2001:db8::1"))
+        );
+        assert_eq!(
+            resolver_with_hops(3).resolve(&headers, Some(ip("10.0.0.1"))),
+            Some(ip("1.1.1.1"))
+        );
+    }
+
+    #[test]
+    fn test_trusted_cidrs() {
+        let resolver = resolver_with_cidrs(&["10.0.0.0/8", "172.16.0.0/12"]);
+        let headers = header_map("x-forwarded-for", "1.1.1.1, 2.2.2.2, 172.16.0.5, 10.1.1.1");
+
+        assert_eq!(
+            resolver.resolve(&headers, Some(ip("10.0.0.1"))),
+            Some(ip("2.2.2.2"))
+        );
+
+        // The peer is not a trusted proxy, any forwarded header is ignored
+        assert_eq!(
+            resolver.resolve(&headers, Some(ip("8.8.8.8"))),
+            Some(ip("8.8.8.8"))
+        );
+
+        // Everything is trusted, the leftmost address is used
+        let headers = header_map("x-forwarded-for", "10.9.9.9, 172.16.0.5");
+        assert_eq!(
+            resolver.resolve(&headers, Some(ip("10.0.0.1"))),
+            Some(ip("10.9.9.9"))
+        );
+    }
+
+    #[test]
+    fn test_trusted_cidrs_stop_at_malformed_entries() {
+        let resolver = resolver_with_cidrs(&["10.0.0.0/8"]);
+        let headers = header_map("x-forwarded-for", "1.1.1.1, garbage, 10.1.1.1");
+
+        assert_eq!(
+            resolver.resolve(&headers, Some(ip("10.0.0.1"))),
+            Some(ip("10.1.1.1"))
+        );
+    }
+
+    #[test]
+    fn test_forwarded_header() {
+        let headers = header_map(
+            "forwarded",
+            r#"for=192.0.2.60;proto=http;by=203.0.113.43, for="[2001:db8:cafe::17]:4711""#,
+        );
+
+        assert_eq!(
+            resolver_with_hops(1).resolve(&headers, Some(ip("10.0.0.1"))),
+            Some(ip("2001:db8:cafe::17"))
+        );
+        assert_eq!(
+            resolver_with_hops(2).resolve(&headers, Some(ip("10.0.0.1"))),
+            Some(ip("192.0.2.60"))
+        );
+    }
+}
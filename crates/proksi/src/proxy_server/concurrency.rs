@@ -0,0 +1,193 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use http::HeaderMap;
+
+use crate::config::RouteConcurrency;
+
+/// Why a request was not allowed to be proxied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConcurrencyRejection {
+    /// The route has no in-flight budget left (503)
+    Route,
+    /// The client has used its share of the route budget (429)
+    Client,
+}
+
+impl ConcurrencyRejection {
+    pub fn status_code(self) -> u16 {
+        match self {
+            Self::Route => 503,
+            Self::Client => 429,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct InFlight {
+    total: usize,
+    per_client: HashMap<String, usize>,
+}
+
+/// Counts the in-flight requests of a route, in total and for each client,
+/// so that a single client cannot use the whole in-flight budget of the route
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+    max_in_flight: Option<usize>,
+    max_in_flight_per_client: Option<usize>,
+    client_key_header: Option<String>,
+    in_flight: Mutex<InFlight>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(config: &RouteConcurrency) -> Self {
+        Self {
+            max_in_flight: config.max_in_flight,
+            max_in_flight_per_client: config.max_in_flight_per_client,
+            client_key_header: config.client_key_header.as_ref().map(|v| v.to_string()),
+            in_flight: Mutex::new(InFlight::default()),
+        }
+    }
+
+    /// The key identifying the client of a request: the value of
+    /// `client_key_header` if present, otherwise the client IP
+    pub fn client_key(&self, headers: &HeaderMap, client_ip: Option<&str>) -> String {
+        self.client_key_header
+            .as_ref()
+            .and_then(|name| headers.get(name.as_str()))
+            .and_then(|value| value.to_str().ok())
+            .map(|value| format!("header:{value}"))
+            .unwrap_or_else(|| format!("ip:{}", client_ip.unwrap_or_default()))
+    }
+
+    /// Reserves an in-flight slot for the client, the slot is released when
+    /// the returned permit is dropped (at the end of the request)
+    pub fn acquire(
+        self: &Arc<Self>,
+        client_key: String,
+    ) -> Result<InFlightPermit, ConcurrencyRejection> {
+        let mut in_flight = self.in_flight.lock().unwrap();
+
+        if self.max_in_flight.is_some_and(|max| in_flight.total >= max) {
+            return Err(ConcurrencyRejection::Route);
+        }
+
+        let client = in_flight.per_client.get(&client_key).copied().unwrap_or(0);
+        if self
+            .max_in_flight_per_client
+            .is_some_and(|max| client >= max)
+        {
+            return Err(ConcurrencyRejection::Client);
+        }
+
+        in_flight.total += 1;
+        in_flight.per_client.insert(client_key.clone(), client + 1);
+
+        Ok(InFlightPermit {
+            limiter: Arc::clone(self),
+            client_key,
+        })
+    }
+
+    /// The number of requests currently in-flight for the route
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.lock().unwrap().total
+    }
+
+    fn release(&self, client_key: &str) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        in_flight.total = in_flight.total.saturating_sub(1);
+
+        if let Some(count) = in_flight.per_client.get_mut(client_key) {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                in_flight.per_client.remove(client_key);
+            }
+        }
+    }
+}
+
+/// An in-flight slot of a client, released on drop
+#[derive(Debug)]
+pub struct InFlightPermit {
+    limiter: Arc<ConcurrencyLimiter>,
+    client_key: String,
+}
+
+impl Drop for InFlightPermit {
+    fn drop(&mut self) {
+        self.limiter.release(&self.client_key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    fn helper_limiter(
+        max_in_flight: Option<usize>,
+        max_in_flight_per_client: Option<usize>,
+    ) -> Arc<ConcurrencyLimiter> {
+        Arc::new(ConcurrencyLimiter::new(&RouteConcurrency {
+            max_in_flight,
+            max_in_flight_per_client,
+            client_key_header: Some("x-api-key".into()),
+        }))
+    }
+
+    #[test]
+    fn test_client_at_its_cap_leaves_capacity_for_others() {
+        let limiter = helper_limiter(Some(4), Some(2));
+
+        let _first = limiter.acquire("ip:10.0.0.1".into()).unwrap();
+        let _second = limiter.acquire("ip:10.0.0.1".into()).unwrap();
+        assert_eq!(
+            limiter.acquire("ip:10.0.0.1".into()).unwrap_err(),
+            ConcurrencyRejection::Client
+        );
+
+        // Other clients still get the rest of the route budget
+        let _third = limiter.acquire("ip:10.0.0.2".into()).unwrap();
+        let _fourth = limiter.acquire("ip:10.0.0.3".into()).unwrap();
+        assert_eq!(limiter.in_flight(), 4);
+
+        // Until the route budget itself is used
+        assert_eq!(
+            limiter.acquire("ip:10.0.0.4".into()).unwrap_err(),
+            ConcurrencyRejection::Route
+        );
+    }
+
+    #[test]
+    fn test_dropping_a_permit_releases_the_slot() {
+        let limiter = helper_limiter(None, Some(1));
+
+        let permit = limiter.acquire("ip:10.0.0.1".into()).unwrap();
+        assert!(limiter.acquire("ip:10.0.0.1".into()).is_err());
+
+        drop(permit);
+        assert_eq!(limiter.in_flight(), 0);
+        assert!(limiter.acquire("ip:10.0.0.1".into()).is_ok());
+    }
+
+    #[test]
+    fn test_client_key() {
+        let limiter = helper_limiter(None, Some(1));
+        let mut headers = HeaderMap::new();
+
+        assert_eq!(
+            limiter.client_key(&headers, Some("10.0.0.1")),
+            "ip:10.0.0.1"
+        );
+
+        headers.insert("x-api-key", HeaderValue::from_static("tenant-a"));
+        assert_eq!(
+            limiter.client_key(&headers, Some("10.0.0.1")),
+            "header:tenant-a"
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use crate::config::Config;
+
+/// Caps the connections a single client IP keeps open, whatever the routes
+/// they are used for. A connection counts for its IP as long as its permit is
+/// kept (see `AcceptApp`, until the connection is closed), only the IPs with
+/// open connections are tracked.
+#[derive(Debug)]
+pub struct ConnectionLimiter {
+    max_per_ip: usize,
+    connections: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_ip: usize) -> Self {
+        Self {
+            max_per_ip,
+            connections: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The limiter of `server.max_connections_per_ip`, if it is set
+    pub fn from_config(config: &Config) -> Option<Arc<Self>> {
+        config
+            .server
+            .max_connections_per_ip
+            .map(|max_per_ip| Arc::new(Self::new(max_per_ip)))
+    }
+
+    /// Counts a new connection of `ip`, unless the IP already has
+    /// `max_per_ip` open connections
+    pub fn acquire(self: &Arc<Self>, ip: IpAddr) -> Option<ConnectionPermit> {
+        let mut connections = self.connections.lock().unwrap();
+        if connections.get(&ip).copied().unwrap_or(0) >= self.max_per_ip {
+            return None;
+        }
+
+        *connections.entry(ip).or_default() += 1;
+        Some(ConnectionPermit {
+            limiter: self.clone(),
+            ip,
+        })
+    }
+
+    /// The number of open connections of `ip`
+    pub fn connections(&self, ip: IpAddr) -> usize {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(&ip)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+/// An open connection of a client IP, it no longer counts once dropped
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl Drop for ConnectionPermit {
+    fn drop(&mut self) {
+        let mut connections = self.limiter.connections.lock().unwrap();
+        if let Entry::Occupied(mut open) = connections.entry(self.ip) {
+            *open.get_mut() -= 1;
+            if *open.get() == 0 {
+                open.remove();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ip_at_its_cap_is_refused_other_ips_are_not() {
+        let limiter = Arc::new(ConnectionLimiter::new(2));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let _first = limiter.acquire(ip).unwrap();
+        let _second = limiter.acquire(ip).unwrap();
+        assert!(limiter.acquire(ip).is_none());
+        assert_eq!(limiter.connections(ip), 2);
+
+        let _third = limiter.acquire(other).unwrap();
+        assert_eq!(limiter.connections(other), 1);
+    }
+
+    #[test]
+    fn test_closed_connections_free_their_slot() {
+        let limiter = Arc::new(ConnectionLimiter::new(1));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+
+        let first = limiter.acquire(ip).unwrap();
+        assert!(limiter.acquire(ip).is_none());
+
+        drop(first);
+        assert_eq!(limiter.connections(ip), 0);
+        // The IPs without open connections are no longer tracked
+        assert!(limiter.connections.lock().unwrap().is_empty());
+        assert!(limiter.acquire(ip).is_some());
+    }
+}
@@ -0,0 +1,173 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use pingora::{Error, ErrorSource};
+
+use super::upstream_error::{resolve_upstream, DNS_FAILURE};
+
+/// Remembers the upstreams that failed to resolve for `negative_ttl`, so that
+/// the resolver isn't queried again on every request while it is failing.
+/// Meanwhile the upstream is considered unavailable.
+#[derive(Debug)]
+pub struct DnsNegativeCache {
+    negative_ttl: Duration,
+    failures: Mutex<HashMap<(String, u16), Instant>>,
+}
+
+impl DnsNegativeCache {
+    /// A `negative_ttl` of 0 disables the cache
+    pub fn new(negative_ttl: Duration) -> Self {
+        Self {
+            negative_ttl,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Resolves the address of an upstream, unless it failed to resolve less
+    /// than `negative_ttl` ago
+    pub fn resolve(&self, ip: &str, port: u16) -> pingora::Result<Vec<SocketAddr>> {
+        self.resolve_with(ip, port, Instant::now(), resolve_upstream)
+    }
+
+    fn resolve_with(
+        &self,
+        ip: &str,
+        port: u16,
+        now: Instant,
+        resolve: impl FnOnce(&str, u16) -> pingora::Result<Vec<SocketAddr>>,
+    ) -> pingora::Result<Vec<SocketAddr>> {
+        let key = (ip.to_string(), port);
+
+        if let Some(failed_at) = self.failures.lock().unwrap().get(&key) {
+            let elapsed = now.saturating_duration_since(*failed_at);
+            if elapsed < self.negative_ttl {
+                return Err(Error::create(
+                    DNS_FAILURE,
+                    ErrorSource::Upstream,
+                    Some(
+                        format!(
+                            "upstream {ip}:{port} failed to resolve {}ms ago",
+                            elapsed.as_millis()
+                        )
+                        .into(),
+                    ),
+                    None,
+                ));
+            }
+        }
+
+        let resolved = resolve(ip, port);
+
+        let mut failures = self.failures.lock().unwrap();
+        if resolved.is_err() && !self.negative_ttl.is_zero() {
+            failures.insert(key, now);
+        } else {
+            failures.remove(&key);
+        }
+
+        resolved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::proxy_server::upstream_error::UpstreamErrorCause;
+
+    fn helper_failing(
+        calls: &Cell<usize>,
+    ) -> impl FnOnce(&str, u16) -> pingora::Result<Vec<SocketAddr>> + '_ {
+        move |ip, port| {
+            calls.set(calls.get() + 1);
+            resolve_upstream(ip, port)
+        }
+    }
+
+    #[test]
+    fn test_failure_is_not_retried_until_the_negative_ttl_elapses() {
+        let cache = DnsNegativeCache::new(Duration::from_secs(5));
+        let calls = Cell::new(0);
+        let start = Instant::now();
+
+        assert!(cache
+            .resolve_with("upstream.invalid", 80, start, helper_failing(&calls))
+            .is_err());
+        assert_eq!(calls.get(), 1);
+
+        // Still in the negative TTL, the resolver isn't called again
+        let err = cache
+            .resolve_with(
+                "upstream.invalid",
+                80,
+                start + Duration::from_secs(4),
+                helper_failing(&calls),
+            )
+            .unwrap_err();
+        assert_eq!(calls.get(), 1);
+        assert_eq!(
+            UpstreamErrorCause::from_error(&err),
+            Some(UpstreamErrorCause::DnsFailure)
+        );
+
+        // Once it elapsed, the upstream is resolved again
+        assert!(cache
+            .resolve_with(
+                "upstream.invalid",
+                80,
+                start + Duration::from_secs(5),
+                helper_failing(&calls),
+            )
+            .is_err());
+        assert_eq!(calls.get(), 2);
+    }
+
+    #[test]
+    fn test_successful_resolution_clears_the_failure() {
+        let cache = DnsNegativeCache::new(Duration::from_secs(5));
+        let start = Instant::now();
+
+        assert!(cache
+            .resolve_with("upstream.invalid", 80, start, resolve_upstream)
+            .is_err());
+        assert!(cache
+            .resolve_with("127.0.0.1", 80, start, resolve_upstream)
+            .is_ok());
+
+        // Every upstream has its own entry
+        assert!(cache
+            .failures
+            .lock()
+            .unwrap()
+            .contains_key(&("upstream.invalid".to_string(), 80)));
+        assert_eq!(cache.failures.lock().unwrap().len(), 1);
+
+        let resolved = cache.resolve_with(
+            "upstream.invalid",
+            80,
+            start + Duration::from_secs(6),
+            |_, _| Ok(vec!["127.0.0.1:80".parse().unwrap()]),
+        );
+        assert!(resolved.is_ok());
+        assert!(cache.failures.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_zero_negative_ttl_disables_the_cache() {
+        let cache = DnsNegativeCache::new(Duration::ZERO);
+        let calls = Cell::new(0);
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert!(cache
+                .resolve_with("upstream.invalid", 80, now, helper_failing(&calls))
+                .is_err());
+        }
+        assert_eq!(calls.get(), 3);
+    }
+}
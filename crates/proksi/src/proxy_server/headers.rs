@@ -0,0 +1,209 @@
+use http::{header, HeaderName};
+use pingora::http::RequestHeader;
+
+use crate::config::HopByHopHeaders;
+
+/// Hop-by-hop headers (RFC 7230 section 6.1) that only make sense for a single
+/// connection and should not be forwarded by proxies
+const HOP_BY_HOP_HEADERS: [&str; 7] = [
+    "connection",
+    "keep-alive",
+    "upgrade",
+    "te",
+    "trailer",
+    "proxy-connection",
+    "proxy-authorization",
+];
+
+/// Applies the hop-by-hop headers policy to a request before it is forwarded.
+/// The `Upgrade` of upgrade requests (ex: WebSocket) is kept, with
+/// `Connection: upgrade`, as the upstream has to see it.
+pub fn filter_hop_by_hop_headers(policy: HopByHopHeaders, request: &mut RequestHeader) {
+    if policy == HopByHopHeaders::Preserve {
+        return;
+    }
+
+    // Headers listed in `Connection` are hop-by-hop as well
+    let listed = request
+        .headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+
+    let upgrade = request
+        .headers
+        .get(header::UPGRADE)
+        .filter(|_| listed.iter().any(|name| name == "upgrade"))
+        .cloned();
+
+    // `TE: trailers` is the only TE value allowed to reach the upstream (ex: gRPC)
+    let keeps_trailers = request
+        .headers
+        .get_all(header::TE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case("trailers"));
+
+    for name in HOP_BY_HOP_HEADERS
+        .iter()
+        .copied()
+        .chain(listed.iter().map(String::as_str))
+    {
+        request.remove_header(name);
+    }
+
+    if keeps_trailers {
+        request.insert_header(header::TE, "trailers").ok();
+    }
+
+    if let Some(upgrade) = upgrade {
+        request.insert_header(header::CONNECTION, "upgrade").ok();
+        request.insert_header(header::UPGRADE, upgrade).ok();
+    }
+}
+
+/// Headers kept whatever the `forward_headers_allowlist` of the route, the
+/// request can't be forwarded without them
+const ALWAYS_FORWARDED_HEADERS: [HeaderName; 3] = [
+    header::HOST,
+    header::CONTENT_LENGTH,
+    header::TRANSFER_ENCODING,
+];
+
+/// Removes the headers of a request missing from the `allowlist` of its route
+/// before it is forwarded, every header is kept without an allowlist
+pub fn filter_forwarded_headers(allowlist: Option<&[HeaderName]>, request: &mut RequestHeader) {
+    let Some(allowlist) = allowlist else {
+        return;
+    };
+
+    let dropped = request
+        .headers
+        .keys()
+        .filter(|name| !allowlist.contains(name) && !ALWAYS_FORWARDED_HEADERS.contains(name))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    for name in dropped {
+        request.remove_header(&name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn helper_request() -> RequestHeader {
+        let mut request = RequestHeader::build("GET", b"/chat", None).unwrap();
+        request.insert_header("Host", "example.com").unwrap();
+        request
+            .insert_header("Connection", "keep-alive, X-Custom-Hop")
+            .unwrap();
+        request.insert_header("Upgrade", "h2c").unwrap();
+        request.insert_header("Keep-Alive", "timeout=5").unwrap();
+        request.insert_header("Trailer", "Expires").unwrap();
+        request
+            .insert_header("Proxy-Authorization", "Basic abc")
+            .unwrap();
+        request.insert_header("Proxy-Foo", "bar").unwrap();
+        request.insert_header("X-Custom-Hop", "1").unwrap();
+        request.insert_header("X-Forwarded-For", "1.1.1.1").unwrap();
+        request
+    }
+
+    #[test]
+    fn test_strips_hop_by_hop_headers_by_default() {
+        let mut request = helper_request();
+        filter_hop_by_hop_headers(HopByHopHeaders::default(), &mut request);
+
+        for name in [
+            "connection",
+            "upgrade",
+            "keep-alive",
+            "trailer",
+            "proxy-authorization",
+            "x-custom-hop",
+        ] {
+            assert!(
+                request.headers.get(name).is_none(),
+                "{name} was not removed"
+            );
+        }
+
+        // Only the hop-by-hop headers are removed
+        assert_eq!(request.headers.get("host").unwrap(), "example.com");
+        assert_eq!(request.headers.get("x-forwarded-for").unwrap(), "1.1.1.1");
+        assert_eq!(request.headers.get("proxy-foo").unwrap(), "bar");
+    }
+
+    #[test]
+    fn test_keeps_the_upgrade_of_upgrade_requests() {
+        let mut request = helper_request();
+        request
+            .insert_header("Connection", "Upgrade, X-Custom-Hop")
+            .unwrap();
+        request.insert_header("Upgrade", "websocket").unwrap();
+        filter_hop_by_hop_headers(HopByHopHeaders::Strip, &mut request);
+
+        assert_eq!(request.headers.get("connection").unwrap(), "upgrade");
+        assert_eq!(request.headers.get("upgrade").unwrap(), "websocket");
+        assert!(request.headers.get("x-custom-hop").is_none());
+        assert!(request.headers.get("keep-alive").is_none());
+    }
+
+    #[test]
+    fn test_keeps_te_trailers() {
+        let mut request = helper_request();
+        request.insert_header("TE", "trailers, deflate").unwrap();
+        filter_hop_by_hop_headers(HopByHopHeaders::Strip, &mut request);
+        assert_eq!(request.headers.get("te").unwrap(), "trailers");
+
+        let mut request = helper_request();
+        request.insert_header("TE", "gzip").unwrap();
+        filter_hop_by_hop_headers(HopByHopHeaders::Strip, &mut request);
+        assert!(request.headers.get("te").is_none());
+    }
+
+    #[test]
+    fn test_preserves_hop_by_hop_headers_when_configured() {
+        let mut request = helper_request();
+        filter_hop_by_hop_headers(HopByHopHeaders::Preserve, &mut request);
+
+        assert_eq!(
+            request.headers.get("connection").unwrap(),
+            "keep-alive, X-Custom-Hop"
+        );
+        assert_eq!(request.headers.get("upgrade").unwrap(), "h2c");
+        assert_eq!(request.headers.get("proxy-foo").unwrap(), "bar");
+        assert_eq!(request.headers.get("x-custom-hop").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_only_allowlisted_headers_are_forwarded() {
+        let mut request = helper_request();
+        request.insert_header("Content-Length", "12").unwrap();
+        request.insert_header("Cookie", "session=abc").unwrap();
+        let allowlist = [HeaderName::from_static("x-forwarded-for")];
+        filter_forwarded_headers(Some(&allowlist), &mut request);
+
+        let mut names = request
+            .headers
+            .keys()
+            .map(HeaderName::as_str)
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(names, ["content-length", "host", "x-forwarded-for"]);
+    }
+
+    #[test]
+    fn test_every_header_is_forwarded_without_allowlist() {
+        let mut request = helper_request();
+        filter_forwarded_headers(None, &mut request);
+        assert_eq!(request.headers.len(), helper_request().headers.len());
+    }
+}
@@ -0,0 +1,313 @@
+use http::{header, Version};
+use pingora::{
+    http::{RequestHeader, ResponseHeader},
+    protocols::http::ServerSession,
+};
+
+use crate::config::Http10KeepAlive;
+
+fn is_http10(request: &RequestHeader) -> bool {
+    request.version == Version::HTTP_10
+}
+
+fn wants_keep_alive(request: &RequestHeader) -> bool {
+    request
+        .headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("keep-alive"))
+}
+
+/// Applies the keep-alive policy to HTTP/1.0 requests, before anything is
+/// written to the client. The connection of an HTTP/1.0 client is only kept
+/// open if it sent `Connection: keep-alive` (and the policy honors it), the
+/// request is forwarded as it was received.
+pub fn apply_keep_alive_policy(session: &mut ServerSession, policy: Http10KeepAlive) {
+    let request = session.req_header();
+    if !is_http10(request) {
+        return;
+    }
+
+    // Without a length, the body of an HTTP/1.0 request lasts until the
+    // connection is closed, so the next request can't be told apart from it
+    let has_length = request.headers.contains_key(header::CONTENT_LENGTH)
+        || request.headers.contains_key(header::TRANSFER_ENCODING);
+    if policy == Http10KeepAlive::Close || !wants_keep_alive(request) || !has_length {
+        session.set_keepalive(None);
+    }
+}
+
+/// HTTP/1.0 clients don't support chunked bodies, which are used for the
+/// upstream responses without a `Content-Length`. The body of such responses
+/// is delimited by the end of the connection instead, so it is closed after it.
+pub fn downgrade_response(session: &mut ServerSession, response: &mut ResponseHeader) {
+    if !is_http10(session.req_header()) {
+        return;
+    }
+
+    let chunked = response
+        .headers
+        .get(header::TRANSFER_ENCODING)
+        .is_some_and(|value| value.as_bytes().eq_ignore_ascii_case(b"chunked"));
+
+    if chunked {
+        response.remove_header(&header::TRANSFER_ENCODING);
+        session.set_keepalive(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::SocketAddr, time::Duration};
+
+    use bytes::Bytes;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::mpsc,
+    };
+
+    use super::*;
+    use crate::test_support;
+
+    /// Sends `request` and answers it with `response` (and `body`) after
+    /// applying the HTTP/1.0 handling. Returns whether the connection can be
+    /// reused and the raw response.
+    async fn helper_exchange(
+        request: &'static [u8],
+        policy: Http10KeepAlive,
+        response: ResponseHeader,
+        body: &'static [u8],
+    ) -> (bool, String) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client.write_all(request).await.unwrap();
+
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.ok();
+            String::from_utf8_lossy(&response).to_string()
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut session = ServerSession::new_http1(Box::new(
+            pingora::protocols::l4::stream::Stream::from(stream),
+        ));
+        assert!(session.read_request().await.unwrap());
+
+        apply_keep_alive_policy(&mut session, policy);
+        let mut response = response;
+        downgrade_response(&mut session, &mut response);
+
+        session
+            .write_response_header(Box::new(response))
+            .await
+            .unwrap();
+        if !body.is_empty() {
+            session
+                .write_response_body(Bytes::from_static(body), true)
+                .await
+                .unwrap();
+        }
+        let reusable = session.finish().await.unwrap().is_some();
+
+        (reusable, client.await.unwrap().to_lowercase())
+    }
+
+    fn helper_empty_response() -> ResponseHeader {
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header("content-length", 0).unwrap();
+        response
+    }
+
+    const KEEP_ALIVE_REQUEST: &[u8] =
+        b"GET / HTTP/1.0\r\nHost: example.com\r\nConnection: keep-alive\r\nContent-Length: 0\r\n\r\n";
+
+    #[tokio::test]
+    async fn test_keep_alive_is_honored() {
+        let (reusable, response) = helper_exchange(
+            KEEP_ALIVE_REQUEST,
+            Http10KeepAlive::Honor,
+            helper_empty_response(),
+            b"",
+        )
+        .await;
+
+        assert!(reusable);
+        assert!(
+            response.contains("connection: keep-alive\r\n"),
+            "{response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_request_without_length_is_closed() {
+        let (reusable, response) = helper_exchange(
+            b"GET / HTTP/1.0\r\nHost: example.com\r\nConnection: keep-alive\r\n\r\n",
+            Http10KeepAlive::Honor,
+            helper_empty_response(),
+            b"",
+        )
+        .await;
+
+        assert!(!reusable);
+        assert!(response.contains("connection: close\r\n"), "{response}");
+    }
+
+    /// Starts an upstream answering `hello` to every request, the raw
+    /// requests it received are sent to the returned channel
+    async fn helper_raw_upstream() -> (SocketAddr, mpsc::UnboundedReceiver<String>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (sender, receiver) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    let mut request = [0; 4096];
+                    while let Ok(read) = stream.read(&mut request).await {
+                        if read == 0 {
+                            break;
+                        }
+                        sender
+                            .send(String::from_utf8_lossy(&request[..read]).to_lowercase())
+                            .ok();
+                        let response = "HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+                        stream.write_all(response.as_bytes()).await.ok();
+                    }
+                });
+            }
+        });
+
+        (addr, receiver)
+    }
+
+    /// Reads a response with a `hello` body from `client`
+    async fn helper_read_response(client: &mut tokio::net::TcpStream) -> String {
+        let mut response = Vec::new();
+        let mut buf = [0; 4096];
+        while !response.ends_with(b"hello") {
+            let read = tokio::time::timeout(Duration::from_secs(2), client.read(&mut buf))
+                .await
+                .expect("the response wasn't received")
+                .unwrap();
+            assert!(read > 0, "the connection was closed");
+            response.extend_from_slice(&buf[..read]);
+        }
+
+        String::from_utf8_lossy(&response).to_lowercase()
+    }
+
+    #[tokio::test]
+    async fn test_proxied_keep_alive_requests() {
+        let (upstream, mut requests) = helper_raw_upstream().await;
+        let host = "keep-alive.http10.test";
+        test_support::add_route(test_support::route(host, [upstream])).await;
+        let proxy = test_support::TestProxy::start().await;
+
+        // A request with a length keeps its connection open
+        let mut client = tokio::net::TcpStream::connect(proxy.addr()).await.unwrap();
+        let request = format!(
+            "GET / HTTP/1.0\r\nHost: {host}\r\nConnection: keep-alive\r\nContent-Length: 0\r\n\r\n"
+        );
+        for _ in 0..2 {
+            client.write_all(request.as_bytes()).await.unwrap();
+            let response = helper_read_response(&mut client).await;
+            assert!(
+                response.contains("connection: keep-alive\r\n"),
+                "{response}"
+            );
+        }
+        requests.recv().await.unwrap();
+        requests.recv().await.unwrap();
+
+        // Without a length, the client is asked to close the connection after
+        // the response and the request is forwarded untouched
+        let mut client = tokio::net::TcpStream::connect(proxy.addr()).await.unwrap();
+        let request = format!("GET / HTTP/1.0\r\nHost: {host}\r\nConnection: keep-alive\r\n\r\n");
+        client.write_all(request.as_bytes()).await.unwrap();
+        let response = helper_read_response(&mut client).await;
+        assert!(response.contains("connection: close\r\n"), "{response}");
+
+        let upstream_request = requests.recv().await.unwrap();
+        assert!(
+            !upstream_request.contains("content-length"),
+            "{upstream_request}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_keep_alive_is_closed_by_policy() {
+        let (reusable, response) = helper_exchange(
+            KEEP_ALIVE_REQUEST,
+            Http10KeepAlive::Close,
+            helper_empty_response(),
+            b"",
+        )
+        .await;
+
+        assert!(!reusable);
+        assert!(response.contains("connection: close\r\n"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn test_plain_request_is_closed() {
+        let (reusable, response) = helper_exchange(
+            b"GET / HTTP/1.0\r\nHost: example.com\r\n\r\n",
+            Http10KeepAlive::Honor,
+            helper_empty_response(),
+            b"",
+        )
+        .await;
+
+        assert!(!reusable);
+        assert!(response.contains("connection: close\r\n"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn test_chunked_response_is_downgraded() {
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response
+            .insert_header("transfer-encoding", "chunked")
+            .unwrap();
+
+        let (reusable, response) = helper_exchange(
+            KEEP_ALIVE_REQUEST,
+            Http10KeepAlive::Honor,
+            response,
+            b"hello",
+        )
+        .await;
+
+        assert!(!reusable);
+        assert!(!response.contains("transfer-encoding"), "{response}");
+        assert!(response.contains("connection: close\r\n"), "{response}");
+        assert!(response.ends_with("\r\n\r\nhello"), "{response}");
+    }
+
+    #[tokio::test]
+    async fn test_http11_responses_are_left_untouched() {
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response
+            .insert_header("transfer-encoding", "chunked")
+            .unwrap();
+
+        let (reusable, response) = helper_exchange(
+            b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            Http10KeepAlive::Close,
+            response,
+            b"hello",
+        )
+        .await;
+
+        assert!(reusable);
+        assert!(
+            response.contains("transfer-encoding: chunked\r\n"),
+            "{response}"
+        );
+    }
+}
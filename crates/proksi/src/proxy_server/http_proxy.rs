@@ -5,14 +5,29 @@ use http::{
     StatusCode, Uri,
 };
 use pingora::http::ResponseHeader;
+use pingora::modules::http::{compression::ResponseCompressionBuilder, HttpModules};
 use pingora::upstreams::peer::HttpPeer;
+use pingora::{ErrorSource, ErrorType};
 
-use pingora::proxy::{ProxyHttp, Session};
+use pingora::proxy::{FailToProxy, ProxyHttp, Session};
 use tracing::info;
 
+use crate::config::Config;
 use crate::stores::global;
 
-pub struct HttpLB {}
+use super::server_header::{respond_error, ServerHeader};
+
+pub struct HttpLB {
+    server_header: ServerHeader,
+}
+
+impl HttpLB {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            server_header: ServerHeader::new(config.server.server_header.as_deref()),
+        }
+    }
+}
 
 #[async_trait]
 impl ProxyHttp for HttpLB {
@@ -20,6 +35,11 @@ impl ProxyHttp for HttpLB {
 
     fn new_ctx(&self) -> Self::CTX {}
 
+    fn init_downstream_modules(&self, modules: &mut HttpModules) {
+        modules.add_module(ResponseCompressionBuilder::enable(0));
+        modules.add_module(self.server_header.module());
+    }
+
     /// Filters based on path (used by LetsEncrypt/ZeroSSL challenges)
     async fn request_filter(
         &self,
@@ -119,6 +139,38 @@ impl ProxyHttp for HttpLB {
     ) -> pingora::Result<Box<HttpPeer>> {
         Err(pingora::Error::new(pingora::ErrorType::HTTPStatus(404)))
     }
+
+    /// The default behaviour, with the `Server` header of proksi
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &pingora::Error,
+        _ctx: &mut Self::CTX,
+    ) -> FailToProxy {
+        let code = match e.etype() {
+            ErrorType::HTTPStatus(code) => *code,
+            ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed
+                if e.esource() == &ErrorSource::Downstream =>
+            {
+                // the connection is already dead
+                0
+            }
+            _ if e.esource() == &ErrorSource::Downstream => 400,
+            _ if e.esource() == &ErrorSource::Upstream => 502,
+            _ => 500,
+        };
+
+        if code > 0 {
+            respond_error(session, code).await.unwrap_or_else(|err| {
+                tracing::error!("failed to send error response to downstream: {err}");
+            });
+        }
+
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
 }
 
 /// Retrieves the host from the request headers based on
@@ -134,3 +186,34 @@ fn get_host(session: &Session) -> &str {
 
     ""
 }
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+    use crate::test_support::{init_memory_store, TestProxy};
+
+    #[tokio::test]
+    async fn test_server_header_of_the_http_responses() {
+        init_memory_store();
+        let mut config = Config::default();
+        config.server.server_header = Some(Cow::Borrowed("edge"));
+        let proxy = TestProxy::start_with_app(HttpLB::new(&config)).await;
+
+        // The responses of the requests, the redirect and the unknown challenge
+        for (path, status) in [
+            ("/ping", 200),
+            ("/", 308),
+            ("/.well-known/acme-challenge/unknown", 404),
+        ] {
+            let response = proxy
+                .request(reqwest::Method::GET, "server-header.http.test", path)
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(response.status().as_u16(), status);
+            assert_eq!(response.headers()[http::header::SERVER], "edge");
+        }
+    }
+}
@@ -1,34 +1,64 @@
-use std::net::ToSocketAddrs;
+use std::net::IpAddr;
 use std::str::FromStr;
 use std::time::{Duration, SystemTime};
 use std::{borrow::Cow, collections::HashMap};
 
 use async_trait::async_trait;
+use bytes::Bytes;
 
 use http::uri::PathAndQuery;
 use http::{HeaderName, HeaderValue, Uri};
 use once_cell::sync::Lazy;
+use tracing::Instrument;
 
 use openssl::base64;
 use pingora::http::{RequestHeader, ResponseHeader};
-use pingora::protocols::Digest;
-use pingora::proxy::{ProxyHttp, Session};
+use pingora::lb::Backend;
+use pingora::modules::http::{compression::ResponseCompressionBuilder, HttpModules};
+use pingora::protocols::{Digest, ALPN};
+use pingora::proxy::{FailToProxy, ProxyHttp, Session};
 use pingora::upstreams::peer::Peer;
-use pingora::{upstreams::peer::HttpPeer, ErrorType::HTTPStatus};
+use pingora::{
+    upstreams::peer::HttpPeer,
+    ErrorSource,
+    ErrorType::{self, HTTPStatus},
+};
 
 use pingora_cache::lock::CacheLock;
 
 use pingora_cache::{CacheKey, CacheMeta, ForcedInvalidationKind, NoCacheReason, RespCacheable};
 
 use crate::cache::disk::storage::DiskCache;
-use crate::config::{RouteCacheType, RouteUpstream};
-use crate::stores::{self, routes::RouteStoreContainer};
+use crate::config::{Config, HopByHopHeaders, Http10KeepAlive, RouteCacheType, RouteUpstream};
+use crate::metrics;
+use crate::services::logger::with_access_log_destination;
+use crate::stores::{
+    self,
+    routes::{BackendCriteria, RouteStoreContainer, UpstreamSelection},
+};
 
+use super::client_ip::ClientIpResolver;
+use super::concurrency::InFlightPermit;
 use super::default_peer_opts;
+use super::headers::{filter_forwarded_headers, filter_hop_by_hop_headers};
+use super::http10::{apply_keep_alive_policy, downgrade_response};
 use super::middleware::{
     execute_request_plugins, execute_response_plugins, execute_upstream_request_plugins,
     execute_upstream_response_plugins,
 };
+use super::no_match::respond_no_match;
+use super::redirects::{
+    follow_redirect_error, is_followable_request, redirect_target, FOLLOW_REDIRECT,
+};
+use super::request_span::request_span;
+use super::request_timeout::{RequestDeadline, REQUEST_TIMEOUT};
+use super::retry::{prepare_retry, RequestRetry};
+use super::server_header::{mark_proxied, respond_error, ServerHeader};
+use super::slow_request::{report_slow_request, SlowRequest};
+use super::trailers::{strip_announced_trailers, strip_trailers};
+use super::upstream_error::{check_header_size, respond_upstream_error, UpstreamErrorCause};
+use super::upstream_events::UpstreamEvent;
+use super::upstream_tls::UpstreamCertChecker;
 
 static STORAGE_MEM_CACHE: Lazy<pingora_cache::MemCache> = Lazy::new(pingora_cache::MemCache::new);
 static STORAGE_CACHE: Lazy<DiskCache> = Lazy::new(DiskCache::new);
@@ -37,7 +67,177 @@ static CACHEABLE_METHODS: Lazy<Vec<http::Method>> =
 static CACHE_LOCK: Lazy<CacheLock> = Lazy::new(|| CacheLock::new(Duration::from_secs(1)));
 
 /// Load balancer proxy struct
-pub struct Router {}
+pub struct Router {
+    client_ip: ClientIpResolver,
+    hop_by_hop_headers: HopByHopHeaders,
+    slow_request_threshold_ms: Option<u64>,
+    verify_upstream_certs: bool,
+    upstream_certs: UpstreamCertChecker,
+    http10_keep_alive: Http10KeepAlive,
+    zone: Option<String>,
+    max_upstream_header_size: Option<usize>,
+    request_timeout: Option<Duration>,
+    server_header: ServerHeader,
+}
+
+impl Router {
+    pub fn new(config: &Config) -> Self {
+        Router {
+            client_ip: ClientIpResolver::new(&config.real_ip),
+            hop_by_hop_headers: config.hop_by_hop_headers,
+            slow_request_threshold_ms: config.logging.slow_request_threshold_ms,
+            verify_upstream_certs: config.upstream_tls.verify,
+            upstream_certs: UpstreamCertChecker::new(&config.upstream_tls),
+            http10_keep_alive: config.server.http10_keep_alive,
+            zone: config.server.zone.as_deref().map(ToString::to_string),
+            max_upstream_header_size: config.max_upstream_header_size,
+            request_timeout: config.request_timeout_secs.map(Duration::from_secs),
+            server_header: ServerHeader::new(config.server.server_header.as_deref()),
+        }
+    }
+
+    /// Finds the route of the request and handles everything that doesn't need
+    /// an upstream (redirects, rejections, plugins etc.), see `request_filter`
+    async fn route_request(
+        &self,
+        session: &mut Session,
+        ctx: &mut RouterContext,
+    ) -> pingora::Result<bool> {
+        apply_keep_alive_policy(session, self.http10_keep_alive);
+
+        let peer_ip = session
+            .client_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(std::net::SocketAddr::ip);
+        ctx.client_ip = self
+            .client_ip
+            .resolve(&session.req_header().headers, peer_ip);
+
+        // The same host can be routed differently on each listener
+        let listener_port = session
+            .server_addr()
+            .and_then(|addr| addr.as_inet())
+            .map(std::net::SocketAddr::port);
+
+        let req_host = get_host(session);
+        let host_without_port = req_host.split(':').collect::<Vec<_>>()[0];
+        host_without_port.clone_into(&mut ctx.host);
+
+        ctx.host = host_without_port.to_string();
+
+        // If there's no host matching, returns a 404
+        let Some(route_container) =
+            stores::get_route_for_listener(host_without_port, listener_port)
+        else {
+            respond_error(session, 404).await?;
+            return Ok(true);
+        };
+
+        // The backends of a newly added route aren't known to be ready yet
+        if route_container.is_warming_up() {
+            respond_error(session, 503).await?;
+            return Ok(true);
+        }
+
+        // The route is going away: only the requests already in flight are
+        // served, the new ones are refused and their connection closed
+        if route_container.draining {
+            tracing::debug!(host = ctx.host, "request refused, the route is draining");
+            session.set_keepalive(None);
+            respond_error(session, 503).await?;
+            return Ok(true);
+        }
+
+        // Match request pattern based on the URI
+        let uri = get_uri(session);
+
+        let path_matcher = &route_container.path_matcher;
+        match &path_matcher.pattern {
+            Some(pattern)
+                if pattern.find(uri.path()).is_none()
+                    && respond_no_match(session, path_matcher.no_match.as_ref()).await? =>
+            {
+                return Ok(true);
+            }
+            _ => {}
+        }
+
+        if let Some(redirect) = route_container.redirect.as_ref() {
+            let mut response = ResponseHeader::build(redirect.status, Some(2))?;
+            response.insert_header(http::header::LOCATION, redirect.to.as_ref())?;
+            response.insert_header(http::header::CONTENT_LENGTH, 0)?;
+            session
+                .write_response_header(Box::new(response), true)
+                .await?;
+            return Ok(true);
+        }
+
+        // Reject requests without the headers required by the route
+        if let Some(missing) =
+            route_container.missing_required_header(&session.req_header().headers)
+        {
+            tracing::debug!(
+                host = ctx.host,
+                header = missing.name.as_str(),
+                "request rejected, missing required header"
+            );
+            respond_error(session, 400).await?;
+            return Ok(true);
+        }
+
+        // Keep a single client from using the whole in-flight budget of the route
+        if let Some(limiter) = route_container.concurrency.as_ref() {
+            let client_ip = ctx.client_ip.map(|ip| ip.to_string());
+            let client_key =
+                limiter.client_key(&session.req_header().headers, client_ip.as_deref());
+
+            match limiter.acquire(client_key) {
+                Ok(permit) => ctx.in_flight = Some(permit),
+                Err(rejection) => {
+                    tracing::debug!(
+                        host = ctx.host,
+                        status_code = rejection.status_code(),
+                        in_flight = limiter.in_flight(),
+                        "request rejected, too many in-flight requests"
+                    );
+                    respond_error(session, rejection.status_code()).await?;
+                    return Ok(true);
+                }
+            }
+        }
+
+        // Middleware phase: request_filterx
+        // We are checking to see if the request has already been handled
+        // by the plugins i.e. (ok(true))
+        if let Ok(true) = execute_request_plugins(session, ctx, &route_container.plugins).await {
+            return Ok(true);
+        }
+
+        if route_container.cache.is_some() {
+            let cache = route_container.cache.as_ref().unwrap();
+            if cache.enabled.unwrap_or(false) {
+                let storage = get_cache_storage(&cache.cache_type);
+
+                stores::insert_cache_routing(
+                    &ctx.host,
+                    cache.path.to_string_lossy().to_string(),
+                    false,
+                );
+                session
+                    .cache
+                    .enable(storage, None, None, Some(&*CACHE_LOCK));
+            }
+        }
+
+        if let Some(retry) = route_container.retry.as_ref() {
+            ctx.retry = prepare_retry(session, retry).await?;
+        }
+
+        ctx.route_container = route_container.clone();
+
+        Ok(false)
+    }
+}
 
 // type Container = mapref::one::Ref<'static, String, RouteStoreContainer>;
 
@@ -54,8 +254,31 @@ fn get_cache_storage(cache_type: &RouteCacheType) -> &'static (dyn pingora_cache
 
 pub struct RouterContext {
     pub host: String,
+    /// The real client IP (see `real_ip` in the configuration)
+    pub client_ip: Option<IpAddr>,
     pub route_container: RouteStoreContainer,
     pub upstream: RouteUpstream,
+    /// Whether a failed request can be sent again to an upstream
+    pub retry: RequestRetry,
+    /// How the last upstream of the request was selected
+    pub upstream_selection: Option<UpstreamSelection>,
+    /// Number of upstreams the request was sent to (including retries)
+    pub upstream_attempts: usize,
+    /// The backends the request was sent to, its retries go to other ones
+    pub tried_backends: Vec<Backend>,
+    /// Why the last attempt failed, when the request is retried
+    pub upstream_failure: Option<UpstreamErrorCause>,
+    /// Number of redirects of the upstreams followed (see `follow_redirects`)
+    pub redirects: usize,
+    /// Where the last followed redirect points to, sent to the upstream in
+    /// place of the path of the request
+    pub redirect_to: Option<PathAndQuery>,
+    /// The in-flight slot of the request, released when the context is dropped
+    pub in_flight: Option<InFlightPermit>,
+    /// Carries the connection and request IDs to every log of the request
+    pub span: tracing::Span,
+    /// When the request times out (see `request_timeout_secs`)
+    pub deadline: RequestDeadline,
     pub extensions: HashMap<Cow<'static, str>, String>,
 
     pub timings: RouterTimings,
@@ -74,8 +297,19 @@ impl ProxyHttp for Router {
     fn new_ctx(&self) -> Self::CTX {
         RouterContext {
             host: String::new(),
+            client_ip: None,
             route_container: RouteStoreContainer::default(),
             upstream: RouteUpstream::default(),
+            retry: RequestRetry::default(),
+            upstream_selection: None,
+            upstream_attempts: 0,
+            tried_backends: Vec::new(),
+            upstream_failure: None,
+            redirects: 0,
+            redirect_to: None,
+            in_flight: None,
+            span: tracing::Span::none(),
+            deadline: RequestDeadline::default(),
             extensions: HashMap::with_capacity(2),
 
             timings: RouterTimings {
@@ -84,6 +318,13 @@ impl ProxyHttp for Router {
         }
     }
 
+    /// The pingora default (a disabled compression module), and the `Server`
+    /// header of the responses generated by proksi and its plugins
+    fn init_downstream_modules(&self, modules: &mut HttpModules) {
+        modules.add_module(ResponseCompressionBuilder::enable(0));
+        modules.add_module(self.server_header.module());
+    }
+
     // Define the filter that will be executed before the request is sent to the upstream.
     // If the filter returns `true`, the request has already been handled.
     // If the filter returns `false`, the request will be sent to the upstream.
@@ -92,55 +333,12 @@ impl ProxyHttp for Router {
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<bool> {
-        let req_host = get_host(session);
-        let host_without_port = req_host.split(':').collect::<Vec<_>>()[0];
-        host_without_port.clone_into(&mut ctx.host);
-
-        ctx.host = host_without_port.to_string();
-
-        // If there's no host matching, returns a 404
-        let Some(route_container) = stores::get_route_by_key(host_without_port) else {
-            session.respond_error(404).await?;
-            return Ok(true);
-        };
-
-        // Match request pattern based on the URI
-        let uri = get_uri(session);
-
-        match &route_container.path_matcher.pattern {
-            Some(pattern) if pattern.find(uri.path()).is_none() => {
-                session.respond_error(404).await?;
-                return Ok(true);
-            }
-            _ => {}
-        }
+        ctx.span = request_span(session.digest());
+        ctx.deadline = RequestDeadline::new(ctx.timings.request_filter_start, self.request_timeout);
+        ctx.deadline.apply_to_downstream(session);
+        let span = ctx.span.clone();
 
-        // Middleware phase: request_filterx
-        // We are checking to see if the request has already been handled
-        // by the plugins i.e. (ok(true))
-        if let Ok(true) = execute_request_plugins(session, ctx, &route_container.plugins).await {
-            return Ok(true);
-        }
-
-        if route_container.cache.is_some() {
-            let cache = route_container.cache.as_ref().unwrap();
-            if cache.enabled.unwrap_or(false) {
-                let storage = get_cache_storage(&cache.cache_type);
-
-                stores::insert_cache_routing(
-                    &ctx.host,
-                    cache.path.to_string_lossy().to_string(),
-                    false,
-                );
-                session
-                    .cache
-                    .enable(storage, None, None, Some(&*CACHE_LOCK));
-            }
-        }
-
-        ctx.route_container = route_container.clone();
-
-        Ok(false)
+        self.route_request(session, ctx).instrument(span).await
     }
 
     /// Define where the proxy should send the request to.
@@ -152,6 +350,8 @@ impl ProxyHttp for Router {
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<Box<HttpPeer>> {
+        ctx.deadline.check()?;
+
         // If there's no host matching, returns a 404
         let route_container = &ctx.route_container;
 
@@ -159,23 +359,72 @@ impl ProxyHttp for Router {
             session.cache.set_max_file_size_bytes(100 * 1024 * 1024);
         }
 
-        let Some(healthy_upstream) = route_container.load_balancer.select(b"", 32) else {
+        // The headers of the request can route it to a group of upstreams
+        let group = route_container
+            .upstream_groups
+            .as_ref()
+            .map(|groups| groups.group(&session.req_header().headers));
+
+        // The same key is sent to the same upstream (consistent hashing)
+        let hash_key = route_container
+            .hash_key
+            .as_ref()
+            .map(|template| template.key(session.req_header(), ctx.client_ip));
+
+        // A previous selection means the request is being retried
+        let Some((healthy_upstream, selection)) =
+            route_container.select_backend(&BackendCriteria {
+                is_retry: ctx.upstream_selection.is_some(),
+                zone: self.zone.as_deref(),
+                group,
+                hash_key: hash_key.as_deref(),
+                tried: &ctx.tried_backends,
+                ..BackendCriteria::new(&session.req_header().method)
+            })
+        else {
             return Err(pingora::Error::new(HTTPStatus(503)));
         };
 
+        ctx.upstream_attempts += 1;
+        if route_container.log_upstream_events {
+            let event = UpstreamEvent::new(
+                ctx.upstream_attempts,
+                ctx.upstream_selection.as_ref(),
+                &selection,
+                ctx.upstream_failure,
+            );
+            if let Some(event) = event {
+                ctx.span.in_scope(|| event.log(&ctx.host));
+            }
+        }
+        ctx.upstream_selection = Some(selection);
+        ctx.tried_backends.push(healthy_upstream.clone());
+
         let (healthy_ip, healthy_port) = if let Some(scr) = healthy_upstream.addr.as_inet() {
             (scr.ip().to_string(), scr.port())
         } else {
             return Err(pingora::Error::new(HTTPStatus(503)));
         };
 
-        let Some(upstream) = route_container.upstreams.iter().find(|u| {
-            format!("{}:{}", u.ip, u.port)
-                .to_socket_addrs()
-                .unwrap()
-                .any(|s| s.ip().to_string() == healthy_ip && s.port() == healthy_port)
-        }) else {
-            return Err(pingora::Error::new(HTTPStatus(503)));
+        let mut upstream = None;
+        let mut dns_failure = None;
+        for u in &route_container.upstreams {
+            match route_container.dns.resolve(&u.ip, u.port) {
+                Ok(addrs) => {
+                    if addrs
+                        .iter()
+                        .any(|s| s.ip().to_string() == healthy_ip && s.port() == healthy_port)
+                    {
+                        upstream = Some(u);
+                        break;
+                    }
+                }
+                Err(err) => dns_failure = Some(err),
+            }
+        }
+
+        let Some(upstream) = upstream else {
+            return Err(dns_failure.unwrap_or_else(|| pingora::Error::new(HTTPStatus(503))));
         };
 
         ctx.upstream = upstream.clone();
@@ -183,10 +432,16 @@ impl ProxyHttp for Router {
         // https://github.com/cloudflare/pingora/blob/main/docs/user_guide/peer.md?plain=1#L17
         let mut peer = HttpPeer::new(
             healthy_upstream,
-            healthy_port == 443,
+            upstream.is_tls(),
             upstream.sni.clone().unwrap_or(String::new()),
         );
         peer.options = default_peer_opts();
+        peer.options.verify_cert = self.verify_upstream_certs;
+        ctx.deadline.apply_to_peer(&mut peer.options);
+        if upstream.h2c && !peer.tls() {
+            // There is no negotiation without TLS, HTTP/2 has to be used right away
+            peer.options.alpn = ALPN::H2;
+        }
         Ok(Box::new(peer))
     }
 
@@ -200,8 +455,11 @@ impl ProxyHttp for Router {
         upstream_response: &mut ResponseHeader,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<()> {
+        downgrade_response(session, upstream_response);
+
         // If there's no host matching, returns a 404
         let route_container = &ctx.route_container;
+        strip_announced_trailers(upstream_response, &route_container.strip_trailers);
 
         for (name, value) in &route_container.host_header_add {
             upstream_response.insert_header(name, value)?;
@@ -212,6 +470,14 @@ impl ProxyHttp for Router {
             upstream_response.remove_header(name);
         }
 
+        // Responses served from the cache have no upstream selection
+        if let (Some(name), Some(selection)) = (
+            route_container.debug_upstream_header.as_ref(),
+            ctx.upstream_selection.as_ref(),
+        ) {
+            upstream_response.insert_header(name, &selection.backend)?;
+        }
+
         let cache_state = ctx.extensions.get("cache_state").cloned();
         if session.cache.enabled() && cache_state.is_some() {
             let cache_state = cache_state.unwrap();
@@ -229,7 +495,10 @@ impl ProxyHttp for Router {
         }
 
         // Middleware phase: response_filterx
-        execute_response_plugins(session, ctx).await?;
+        let span = ctx.span.clone();
+        execute_response_plugins(session, ctx)
+            .instrument(span)
+            .await?;
 
         Ok(())
     }
@@ -244,8 +513,22 @@ impl ProxyHttp for Router {
         upstream_request: &mut RequestHeader,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<()> {
-        // If there's no host matching, returns a 404
-        // let route_container = &ctx.route_container;
+        let hop_by_hop_headers = ctx
+            .route_container
+            .hop_by_hop_headers
+            .unwrap_or(self.hop_by_hop_headers);
+        filter_hop_by_hop_headers(hop_by_hop_headers, upstream_request);
+        filter_forwarded_headers(
+            ctx.route_container.forward_headers_allowlist.as_deref(),
+            upstream_request,
+        );
+        // Pingora doesn't read the trailers of the requests, the upstream won't
+        // receive the trailers announced by the client
+        upstream_request.remove_header(&http::header::TRAILER);
+
+        if let Some(target) = ctx.redirect_to.as_ref() {
+            upstream_request.set_uri(Uri::from(target.clone()));
+        }
 
         let upstream = &ctx.upstream;
 
@@ -260,7 +543,9 @@ impl ProxyHttp for Router {
             }
         }
 
+        let span = ctx.span.clone();
         execute_upstream_request_plugins(session, upstream_request, ctx)
+            .instrument(span)
             .await
             .ok();
 
@@ -280,14 +565,67 @@ impl ProxyHttp for Router {
         upstream_response: &mut ResponseHeader,
         ctx: &mut Self::CTX,
     ) -> Result<(), Box<pingora::Error>> {
+        mark_proxied(session);
+
         // If there's no host matching, returns a 404
         // let route_container = process_route(ctx);
 
-        execute_upstream_response_plugins(session, upstream_response, ctx);
+        // Nothing reached the client yet, a followed redirect sends the
+        // request again (to its target) instead
+        let can_follow = ctx
+            .route_container
+            .follow_redirects
+            .as_ref()
+            .is_some_and(|follow| ctx.redirects < follow.max_hops)
+            && is_followable_request(&session.req_header().method);
+        if let Some(target) = redirect_target(upstream_response, &ctx.host).filter(|_| can_follow) {
+            ctx.redirects += 1;
+            // The target is balanced as a new request, not as a retry
+            ctx.upstream_selection = None;
+            ctx.tried_backends.clear();
+            let error = follow_redirect_error(&target);
+            ctx.redirect_to = Some(target);
+            return Err(error);
+        }
+
+        ctx.retry.response_received();
+
+        // Answered with a 502 (and logged with the upstream) by `fail_to_proxy`
+        check_header_size(
+            upstream_response,
+            self.max_upstream_header_size,
+            ctx.extensions.get("peer").map(String::as_str),
+        )?;
+
+        let span = ctx.span.clone();
+        span.in_scope(|| execute_upstream_response_plugins(session, upstream_response, ctx));
 
         Ok(())
     }
 
+    /// Stops the upstreams streaming their response past the deadline of the request
+    fn upstream_response_body_filter(
+        &self,
+        _session: &mut Session,
+        _body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()> {
+        ctx.deadline.check()
+    }
+
+    /// Modify the response trailers from the upstream (only HTTP/2 upstreams
+    /// send trailers) before they are forwarded to the client
+    fn upstream_response_trailer_filter(
+        &self,
+        _session: &mut Session,
+        upstream_trailers: &mut http::HeaderMap,
+        ctx: &mut Self::CTX,
+    ) -> pingora::Result<()> {
+        strip_trailers(upstream_trailers, &ctx.route_container.strip_trailers);
+        Ok(())
+    }
+
     /// This filter is called when the entire response is sent to the downstream successfully or
     /// there is a fatal error that terminate the request.
     ///
@@ -299,7 +637,14 @@ impl ProxyHttp for Router {
         _: Option<&pingora::Error>,
         ctx: &mut Self::CTX,
     ) {
-        let duration_ms = ctx.timings.request_filter_start.elapsed().as_millis();
+        let duration = ctx.timings.request_filter_start.elapsed();
+        let duration_ms = duration.as_millis();
+
+        if let Some((algorithm, input_bytes, output_bytes, took)) =
+            session.upstream_compression.get_info()
+        {
+            metrics::record_compression(&ctx.host, algorithm, input_bytes, output_bytes, took);
+        }
 
         let http_version = if session.is_http2() {
             "http/2"
@@ -324,32 +669,59 @@ impl ProxyHttp for Router {
             .get("user-agent")
             .unwrap_or(&empty_header);
 
-        let client_ip = session
-            .client_addr()
-            .map(ToString::to_string)
-            .unwrap_or_default();
+        let client_ip = ctx.client_ip.map(|ip| ip.to_string()).unwrap_or_default();
 
         let status_code = session
             .response_written()
             .map(|v| v.status.as_u16())
             .unwrap_or_default();
 
-        tracing::info!(
-            method,
-            path,
-            query,
-            host,
-            duration_ms,
-            user_agent = user_agent.to_str().unwrap_or(""),
-            referer = referer.to_str().unwrap_or(""),
-            client_ip,
-            status_code,
-            http_version,
-            reused_connection = ctx.extensions.get("reused").unwrap_or(&String::new()),
-            peer_addr = ctx.extensions.get("peer").unwrap_or(&String::new()),
-            request_id = ctx.extensions.get("request_id_header"),
-            access_log = true
+        let bytes_sent = session.body_bytes_sent();
+
+        let upstream_selection = ctx
+            .upstream_selection
+            .as_ref()
+            .filter(|_| ctx.route_container.log_upstream_selection);
+
+        let slow_request_threshold_ms = ctx
+            .route_container
+            .slow_request_threshold_ms
+            .or(self.slow_request_threshold_ms);
+        let _entered = ctx.span.enter();
+        report_slow_request(
+            slow_request_threshold_ms,
+            &SlowRequest {
+                host: &ctx.host,
+                method: &method,
+                path,
+                duration,
+            },
         );
+
+        let access_log_destination = ctx.route_container.access_log_destination.as_ref();
+        with_access_log_destination(access_log_destination, || {
+            tracing::info!(
+                method,
+                path,
+                query,
+                host,
+                duration_ms,
+                user_agent = user_agent.to_str().unwrap_or(""),
+                referer = referer.to_str().unwrap_or(""),
+                client_ip,
+                status_code,
+                bytes_sent,
+                http_version,
+                reused_connection = ctx.extensions.get("reused").unwrap_or(&String::new()),
+                peer_addr = ctx.extensions.get("peer").unwrap_or(&String::new()),
+                request_id = ctx.extensions.get("request_id_header"),
+                upstream_backend = upstream_selection.map(|v| v.backend.as_str()),
+                upstream_weight = upstream_selection.map(|v| v.weight),
+                upstream_algorithm = upstream_selection.map(|v| v.algorithm),
+                upstream_selection = upstream_selection.map(|v| v.reason.as_str()),
+                access_log = true
+            );
+        });
     }
 
     // This callback generates the cache key
@@ -446,18 +818,147 @@ impl ProxyHttp for Router {
         reused: bool,
         peer: &HttpPeer,
         _fd: std::os::unix::io::RawFd,
-        _digest: Option<&Digest>,
+        digest: Option<&Digest>,
         ctx: &mut Self::CTX,
     ) -> pingora::Result<()>
     where
         Self::CTX: Send + Sync,
     {
+        // Check the certificate of new TLS connections (see `upstream_tls`)
+        let ssl_digest = digest.and_then(|digest| digest.ssl_digest.as_ref());
+        if let Some(ssl_digest) = ssl_digest.filter(|_| !reused && self.upstream_certs.is_enabled())
+        {
+            self.upstream_certs
+                .check_connection(peer, &ssl_digest.cert_digest)
+                .instrument(ctx.span.clone())
+                .await?;
+        }
+
         ctx.extensions
             .insert(Cow::Borrowed("reused"), reused.to_string());
         ctx.extensions
             .insert(Cow::Borrowed("peer"), peer.address().to_string());
         Ok(())
     }
+
+    /// Upstream failures are logged with their cause and answered with a 502,
+    /// other errors keep the default behaviour
+    async fn fail_to_proxy(
+        &self,
+        session: &mut Session,
+        e: &pingora::Error,
+        ctx: &mut Self::CTX,
+    ) -> FailToProxy
+    where
+        Self::CTX: Send + Sync,
+    {
+        // The upstream timeouts are bounded by the deadline, hitting them
+        // past it means the request timed out
+        if e.etype() == &REQUEST_TIMEOUT
+            || (ctx.deadline.is_exceeded() && RequestDeadline::is_timeout(e))
+        {
+            ctx.span.in_scope(|| {
+                tracing::warn!(
+                    host = ctx.host,
+                    error = e.to_string(),
+                    "request exceeded request_timeout_secs"
+                );
+            });
+            respond_error(session, 504).await.ok();
+            return FailToProxy {
+                error_code: 504,
+                can_reuse_downstream: false,
+            };
+        }
+
+        if respond_upstream_error(session, e, &ctx.host)
+            .instrument(ctx.span.clone())
+            .await
+            .is_some()
+        {
+            return FailToProxy {
+                error_code: 502,
+                can_reuse_downstream: false,
+            };
+        }
+
+        let code = match e.etype() {
+            HTTPStatus(code) => *code,
+            ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed
+                if e.esource() == &ErrorSource::Downstream =>
+            {
+                // the connection is already dead
+                0
+            }
+            _ if e.esource() == &ErrorSource::Downstream => 400,
+            _ => 500,
+        };
+
+        if code > 0 {
+            respond_error(session, code).await.unwrap_or_else(|err| {
+                ctx.span.in_scope(|| {
+                    tracing::error!("failed to send error response to downstream: {err}");
+                });
+            });
+        }
+
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
+    /// Upstream failures are already logged (with their cause) by `fail_to_proxy`
+    fn suppress_error_log(
+        &self,
+        _session: &Session,
+        _ctx: &Self::CTX,
+        error: &pingora::Error,
+    ) -> bool {
+        UpstreamErrorCause::from_error(error).is_some()
+    }
+
+    /// Retries the request on another upstream if the route allows it
+    /// (see `retry` in the route configuration)
+    fn fail_to_connect(
+        &self,
+        _session: &mut Session,
+        _peer: &HttpPeer,
+        ctx: &mut Self::CTX,
+        mut e: Box<pingora::Error>,
+    ) -> Box<pingora::Error> {
+        if ctx.retry.retry_connect() {
+            e.set_retry(true);
+            ctx.upstream_failure = UpstreamErrorCause::from_error(&e);
+        }
+        e
+    }
+
+    /// Retries the request if its body was buffered (or it has no body),
+    /// otherwise falls back to retrying only on stale reused connections
+    fn error_while_proxy(
+        &self,
+        peer: &HttpPeer,
+        session: &mut Session,
+        e: Box<pingora::Error>,
+        ctx: &mut Self::CTX,
+        client_reused: bool,
+    ) -> Box<pingora::Error> {
+        // Followed redirects don't use the retries of the route
+        if e.etype() == &FOLLOW_REDIRECT {
+            return e;
+        }
+
+        let mut e = e.more_context(format!("Peer: {peer}"));
+        if ctx.retry.retry_proxy() {
+            e.set_retry(true);
+            ctx.upstream_failure = UpstreamErrorCause::from_error(&e);
+        } else {
+            e.retry
+                .decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        }
+        e
+    }
 }
 
 fn get_uri(session: &mut Session) -> Uri {
@@ -477,3 +978,634 @@ fn get_host(session: &mut Session) -> &str {
 
     ""
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{borrow::Cow, time::Duration};
+
+    use crate::config::{Config, RoutePlugin, RouteRedirect, RouteSelectionAlgorithm};
+    use crate::test_support::{add_route, route, TestBackend, TestProxy};
+
+    #[tokio::test]
+    async fn test_debug_upstream_header() {
+        let backend = TestBackend::start(200, "hello").await;
+        let mut debug_route = route("debug.router.test", [backend.addr()]);
+        debug_route.debug_upstream_header = Some("X-Upstream".into());
+        add_route(debug_route).await;
+        add_route(route("default.router.test", [backend.addr()])).await;
+
+        let proxy = TestProxy::start().await;
+
+        let response = proxy
+            .request(reqwest::Method::GET, "debug.router.test", "/")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers().get("x-upstream").unwrap(),
+            &backend.addr().to_string()
+        );
+
+        let response = proxy
+            .request(reqwest::Method::GET, "default.router.test", "/")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get("x-upstream").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_routes_by_listener_port() {
+        let internal = TestBackend::start(200, "internal").await;
+        let external = TestBackend::start(200, "external").await;
+        let fallback = TestBackend::start(200, "fallback").await;
+        let internal_proxy = TestProxy::start().await;
+        let external_proxy = TestProxy::start().await;
+        let other_proxy = TestProxy::start().await;
+
+        let host = "listeners.router.test";
+        let mut internal_route = route(host, [internal.addr()]);
+        internal_route.listener_port = Some(internal_proxy.addr().port());
+        add_route(internal_route).await;
+        let mut external_route = route(host, [external.addr()]);
+        external_route.listener_port = Some(external_proxy.addr().port());
+        add_route(external_route).await;
+
+        // Without a route for every listener, other listeners don't know the host
+        assert_eq!(other_proxy.get(host, "/").await.0, 404);
+
+        add_route(route(host, [fallback.addr()])).await;
+
+        assert_eq!(
+            internal_proxy.get(host, "/").await,
+            (200, "internal".to_string())
+        );
+        assert_eq!(
+            external_proxy.get(host, "/").await,
+            (200, "external".to_string())
+        );
+        assert_eq!(
+            other_proxy.get(host, "/").await,
+            (200, "fallback".to_string())
+        );
+    }
+
+    /// Opens a keep-alive connection to `proxy` from the local address `ip`
+    async fn helper_connect_from(proxy: &TestProxy, ip: &str) -> tokio::net::TcpStream {
+        let socket = tokio::net::TcpSocket::new_v4().unwrap();
+        socket.bind(format!("{ip}:0").parse().unwrap()).unwrap();
+        socket.connect(proxy.addr()).await.unwrap()
+    }
+
+    /// Sends a `GET` request for `host` on `stream`, returns the status (or
+    /// `None` if the connection was closed without an answer)
+    async fn helper_get_on(stream: &mut tokio::net::TcpStream, host: &str) -> Option<u16> {
+        use tokio::io::AsyncWriteExt;
+
+        let request = format!("GET / HTTP/1.1\r\nHost: {host}\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.ok()?;
+
+        helper_read_response(stream).await.map(|(status, _)| status)
+    }
+
+    /// Reads a response (with a `Content-Length`) on `stream`, returns its
+    /// status and body
+    async fn helper_read_response(stream: &mut tokio::net::TcpStream) -> Option<(u16, String)> {
+        use tokio::io::AsyncReadExt;
+
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0; 1];
+            stream.read_exact(&mut byte).await.ok()?;
+            head.push(byte[0]);
+        }
+
+        let head = String::from_utf8_lossy(&head).to_lowercase();
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .map_or(0, |value| value.trim().parse().unwrap());
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.ok()?;
+
+        let status = head.split_whitespace().nth(1)?.parse().ok()?;
+        Some((status, String::from_utf8_lossy(&body).to_string()))
+    }
+
+    /// A backend reporting the size of the first chunk of request body it
+    /// receives, then answering with the size of the whole body and whether it
+    /// was forwarded chunked
+    async fn helper_upload_backend(
+        first_chunks: tokio::sync::mpsc::UnboundedSender<usize>,
+    ) -> std::net::SocketAddr {
+        use pingora::protocols::{http::ServerSession, l4};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let first_chunks = first_chunks.clone();
+                tokio::spawn(async move {
+                    let mut session =
+                        ServerSession::new_http1(Box::new(l4::stream::Stream::from(stream)));
+                    // Health checks only open a connection
+                    if !matches!(session.read_request().await, Ok(true)) {
+                        return;
+                    }
+                    let chunked = session
+                        .req_header()
+                        .headers
+                        .get("transfer-encoding")
+                        .is_some_and(|value| value == "chunked");
+
+                    let mut size = 0;
+                    while let Ok(Some(chunk)) = session.read_request_body().await {
+                        if size == 0 {
+                            first_chunks.send(chunk.len()).ok();
+                        }
+                        size += chunk.len();
+                    }
+
+                    let body = format!("{size} chunked={chunked}");
+                    let mut response = pingora::http::ResponseHeader::build(200, None).unwrap();
+                    response
+                        .insert_header("content-length", body.len())
+                        .unwrap();
+                    session
+                        .write_response_header(Box::new(response))
+                        .await
+                        .unwrap();
+                    session
+                        .write_response_body(body.into_bytes().into(), true)
+                        .await
+                        .unwrap();
+                    session.finish().await.ok();
+                });
+            }
+        });
+
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_request_bodies_are_streamed_to_the_upstream() {
+        use tokio::io::AsyncWriteExt;
+
+        const CHUNK_SIZE: usize = 1024 * 1024;
+        const CHUNKS: usize = 4;
+
+        let (first_chunks, mut received) = tokio::sync::mpsc::unbounded_channel();
+        let backend = helper_upload_backend(first_chunks).await;
+        add_route(route("streamed.upload.router.test", [backend])).await;
+        // Retried routes only buffer the bodies with a (small) length
+        let mut retried = route("retried.upload.router.test", [backend]);
+        retried.retry = Some(crate::config::RouteRetry {
+            attempts: 2,
+            buffer_request_body: true,
+            ..crate::config::RouteRetry::default()
+        });
+        add_route(retried).await;
+
+        let proxy = TestProxy::start().await;
+        let chunk = format!("{CHUNK_SIZE:x}\r\n{}\r\n", "a".repeat(CHUNK_SIZE));
+
+        for host in ["streamed.upload.router.test", "retried.upload.router.test"] {
+            let mut stream = tokio::net::TcpStream::connect(proxy.addr()).await.unwrap();
+            let head = format!(
+                "POST /upload HTTP/1.1\r\nHost: {host}\r\nTransfer-Encoding: chunked\r\n\r\n"
+            );
+            stream.write_all(head.as_bytes()).await.unwrap();
+            stream.write_all(chunk.as_bytes()).await.unwrap();
+
+            // The upstream receives the body while the client is still sending it
+            let first_chunk = tokio::time::timeout(Duration::from_secs(5), received.recv())
+                .await
+                .expect("the body was not forwarded before the end of the upload")
+                .unwrap();
+            assert!(first_chunk > 0 && first_chunk <= CHUNK_SIZE, "{host}");
+
+            for _ in 1..CHUNKS {
+                stream.write_all(chunk.as_bytes()).await.unwrap();
+            }
+            stream.write_all(b"0\r\n\r\n").await.unwrap();
+
+            assert_eq!(
+                helper_read_response(&mut stream).await,
+                Some((200, format!("{} chunked=true", CHUNK_SIZE * CHUNKS))),
+                "{host}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_limited_per_client_ip() {
+        use tokio::io::AsyncReadExt;
+
+        let backend = TestBackend::start(200, "ok").await;
+        let host = "connections.router.test";
+        add_route(route(host, [backend.addr()])).await;
+
+        let mut config = Config::default();
+        config.server.max_connections_per_ip = Some(2);
+        let proxy = TestProxy::start_with_config(&config).await;
+
+        // A connection counts from its accept, before it sent anything
+        let mut first = helper_connect_from(&proxy, "127.0.0.1").await;
+        assert_eq!(helper_get_on(&mut first, host).await, Some(200));
+        let second = helper_connect_from(&proxy, "127.0.0.1").await;
+
+        // The IP is at its cap: the new connection is closed without an answer,
+        // the open ones can still be used
+        let mut third = helper_connect_from(&proxy, "127.0.0.1").await;
+        let mut buf = [0; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), third.read(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0))), "{read:?}");
+        assert_eq!(helper_get_on(&mut first, host).await, Some(200));
+        assert_eq!(backend.requests(), 2);
+
+        // Other IPs are unaffected
+        let mut other = helper_connect_from(&proxy, "127.0.0.2").await;
+        assert_eq!(helper_get_on(&mut other, host).await, Some(200));
+
+        // Closing a connection frees its slot
+        drop(second);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let mut fourth = helper_connect_from(&proxy, "127.0.0.1").await;
+        assert_eq!(helper_get_on(&mut fourth, host).await, Some(200));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_upstream_headers_are_bad_gateway() {
+        let headers = (0..8)
+            .map(|index| (format!("x-large-{index}"), "a".repeat(1024)))
+            .collect();
+        let oversized = TestBackend::start_with_headers(200, "oversized", headers).await;
+        let normal = TestBackend::start_with_headers(
+            200,
+            "normal",
+            vec![("x-small".to_string(), "a".repeat(64))],
+        )
+        .await;
+        add_route(route("oversized.headers.router.test", [oversized.addr()])).await;
+        add_route(route("normal.headers.router.test", [normal.addr()])).await;
+
+        let config = Config {
+            max_upstream_header_size: Some(4 * 1024),
+            ..Config::default()
+        };
+        let proxy = TestProxy::start_with_config(&config).await;
+
+        let (status, body) = proxy.get("oversized.headers.router.test", "/").await;
+        assert_eq!(status, 502);
+        assert!(!body.contains("oversized"), "{body}");
+
+        assert_eq!(
+            proxy.get("normal.headers.router.test", "/").await,
+            (200, "normal".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_only_allowlisted_headers_reach_the_upstream() {
+        let backend = TestBackend::start_echo_headers().await;
+        let mut allowlisted = route("allowlisted.headers.router.test", [backend.addr()]);
+        allowlisted.forward_headers_allowlist = Some(vec!["x-tenant".into(), "Accept".into()]);
+        add_route(allowlisted).await;
+        add_route(route("all.headers.router.test", [backend.addr()])).await;
+
+        let proxy = TestProxy::start().await;
+        let echo = |host| {
+            proxy
+                .request(reqwest::Method::GET, host, "/")
+                .header("x-tenant", "acme")
+                .header("accept", "text/plain")
+                .header("cookie", "session=secret")
+                .header("x-internal", "1")
+                .send()
+        };
+
+        let headers = echo("allowlisted.headers.router.test")
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(
+            headers,
+            "accept: text/plain\nhost: allowlisted.headers.router.test\nx-tenant: acme\n"
+        );
+
+        let headers = echo("all.headers.router.test")
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert!(headers.contains("cookie: session=secret\n"), "{headers}");
+        assert!(headers.contains("x-internal: 1\n"), "{headers}");
+    }
+
+    /// Sends a `GET` for `host` (the test client doesn't follow redirects),
+    /// returns the status and the `Location` (or the body when there is none)
+    async fn helper_get_location(proxy: &TestProxy, host: &str) -> (u16, String) {
+        let response = proxy
+            .request(reqwest::Method::GET, host, "/")
+            .send()
+            .await
+            .unwrap();
+        let status = response.status().as_u16();
+        match response.headers().get("location") {
+            Some(location) => (status, location.to_str().unwrap().to_string()),
+            None => (status, response.text().await.unwrap()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_redirects_are_followed() {
+        let backend = TestBackend::start_redirect_chain(2).await;
+        let mut followed = route("followed.redirects.router.test", [backend.addr()]);
+        followed.follow_redirects = Some(crate::config::RouteFollowRedirects { max_hops: 3 });
+        add_route(followed).await;
+
+        let proxy = TestProxy::start().await;
+        assert_eq!(
+            helper_get_location(&proxy, "followed.redirects.router.test").await,
+            (200, "/2".to_string())
+        );
+        assert_eq!(backend.requests(), 3);
+
+        // Requests with a body are not followed
+        let response = proxy
+            .request(reqwest::Method::POST, "followed.redirects.router.test", "/")
+            .body("payload")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 302);
+        assert_eq!(backend.requests(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_upstream_redirects_are_passed_through() {
+        let backend = TestBackend::start_redirect_chain(2).await;
+        add_route(route("passthrough.redirects.router.test", [backend.addr()])).await;
+
+        let proxy = TestProxy::start().await;
+        assert_eq!(
+            helper_get_location(&proxy, "passthrough.redirects.router.test").await,
+            (302, "/1".to_string())
+        );
+        assert_eq!(backend.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_followed_redirects_are_capped() {
+        let backend = TestBackend::start_redirect_chain(5).await;
+        let mut capped = route("capped.redirects.router.test", [backend.addr()]);
+        capped.follow_redirects = Some(crate::config::RouteFollowRedirects { max_hops: 2 });
+        add_route(capped).await;
+
+        // After `max_hops` redirects, the last one is sent to the client
+        let proxy = TestProxy::start().await;
+        assert_eq!(
+            helper_get_location(&proxy, "capped.redirects.router.test").await,
+            (302, "/3".to_string())
+        );
+        assert_eq!(backend.requests(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_websocket_upgrades_reach_the_upstream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let backend = TestBackend::start_websocket().await;
+        add_route(route("ws.router.test", [backend.addr()])).await;
+        let proxy = TestProxy::start().await;
+
+        let mut stream = tokio::net::TcpStream::connect(proxy.addr()).await.unwrap();
+        stream
+            .write_all(
+                b"GET /chat HTTP/1.1\r\nHost: ws.router.test\r\nConnection: Upgrade\r\n\
+                  Upgrade: websocket\r\nSec-WebSocket-Version: 13\r\n\
+                  Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut response = Vec::new();
+        let mut buf = [0; 1024];
+        while !response.windows(4).any(|window| window == b"\r\n\r\n") {
+            let len = stream.read(&mut buf).await.unwrap();
+            assert_ne!(len, 0, "the proxy closed the connection");
+            response.extend_from_slice(&buf[..len]);
+        }
+        let response = String::from_utf8(response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"), "{response}");
+
+        // The connection is then tunneled to the upstream
+        stream.write_all(b"ping").await.unwrap();
+        let mut echo = [0; 4];
+        stream.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+        assert_eq!(backend.requests(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_redirect_routes_answer_with_their_redirect() {
+        let mut redirected = route("redirected.router.test", []);
+        redirected.redirect = Some(RouteRedirect {
+            to: "https://www.example.com".into(),
+            status: 308,
+        });
+        add_route(redirected).await;
+
+        let proxy = TestProxy::start().await;
+        assert_eq!(
+            helper_get_location(&proxy, "redirected.router.test").await,
+            (308, "https://www.example.com".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_requests_exceeding_the_global_timeout_get_a_504() {
+        let hanging = TestBackend::start_delayed(200, "late", Duration::from_secs(5)).await;
+        let fast = TestBackend::start(200, "fast").await;
+        // No timeout is configured on the routes
+        add_route(route("hanging.timeout.router.test", [hanging.addr()])).await;
+        add_route(route("fast.timeout.router.test", [fast.addr()])).await;
+
+        let config = Config {
+            request_timeout_secs: Some(1),
+            ..Config::default()
+        };
+        let proxy = TestProxy::start_with_config(&config).await;
+
+        let started = std::time::Instant::now();
+        let (status, body) = proxy.get("hanging.timeout.router.test", "/").await;
+        assert_eq!(status, 504);
+        assert!(!body.contains("late"), "{body}");
+        assert!(started.elapsed() < Duration::from_secs(3));
+
+        assert_eq!(
+            proxy.get("fast.timeout.router.test", "/").await,
+            (200, "fast".to_string())
+        );
+    }
+
+    async fn helper_server_header(server_header: Option<&'static str>) -> Vec<Option<String>> {
+        let mut config = Config::default();
+        config.server.server_header = server_header.map(Cow::Borrowed);
+        let proxy = TestProxy::start_with_config(&config).await;
+
+        let mut headers = vec![];
+        for host in [
+            "unknown.server-header.router.test",
+            "redirect.server-header.router.test",
+            "auth.server-header.router.test",
+            "failing.server-header.router.test",
+            "proxied.server-header.router.test",
+        ] {
+            let response = proxy
+                .request(reqwest::Method::GET, host, "/")
+                .send()
+                .await
+                .unwrap();
+            headers.push(
+                response
+                    .headers()
+                    .get(http::header::SERVER)
+                    .map(|value| value.to_str().unwrap().to_string()),
+            );
+        }
+        headers
+    }
+
+    #[tokio::test]
+    async fn test_server_header_of_proksi_responses() {
+        let backend = TestBackend::start(200, "ok").await;
+        let mut redirect = route("redirect.server-header.router.test", [backend.addr()]);
+        redirect.redirect = Some(RouteRedirect {
+            to: "https://example.com".into(),
+            status: 301,
+        });
+        add_route(redirect).await;
+
+        let mut auth = route("auth.server-header.router.test", [backend.addr()]);
+        auth.plugins = Some(vec![RoutePlugin {
+            name: "basic_auth".into(),
+            config: serde_json::from_value(serde_json::json!({ "user": "u", "pass": "p" }))
+                .unwrap(),
+        }]);
+        add_route(auth).await;
+
+        let failing = TestBackend::start_failing().await;
+        add_route(route("failing.server-header.router.test", [failing.addr()])).await;
+
+        let proxied =
+            TestBackend::start_with_headers(200, "ok", vec![("server".into(), "backend".into())])
+                .await;
+        add_route(route("proxied.server-header.router.test", [proxied.addr()])).await;
+
+        // Pingora's header for the errors, the redirects and the plugin
+        // responses have none. The upstream responses keep their own.
+        let some = |value: &str| Some(value.to_string());
+        assert_eq!(
+            helper_server_header(None).await,
+            vec![
+                some("Pingora"),
+                None,
+                None,
+                some("Pingora"),
+                some("backend")
+            ]
+        );
+        assert_eq!(
+            helper_server_header(Some("")).await,
+            vec![None, None, None, None, some("backend")]
+        );
+        assert_eq!(
+            helper_server_header(Some("edge")).await,
+            vec![
+                some("edge"),
+                some("edge"),
+                some("edge"),
+                some("edge"),
+                some("backend")
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retries_are_sent_to_other_backends() {
+        for algorithm in [
+            RouteSelectionAlgorithm::RoundRobin,
+            RouteSelectionAlgorithm::ConsistentHash,
+        ] {
+            let backends = [
+                TestBackend::start_failing().await,
+                TestBackend::start_failing().await,
+                TestBackend::start_failing().await,
+            ];
+            let host = format!(
+                "{}.retried.router.test",
+                algorithm.as_str().replace('_', "-")
+            );
+            let mut retried = route(&host, backends.iter().map(TestBackend::addr));
+            retried.selection_algorithm = algorithm;
+            // Every attempt has the same key, the retries still move on
+            retried.hash_key = Some("{path}".into());
+            retried.retry = Some(crate::config::RouteRetry {
+                attempts: 2,
+                ..crate::config::RouteRetry::default()
+            });
+            add_route(retried).await;
+
+            let proxy = TestProxy::start().await;
+            let (status, _) = proxy.get(&host, "/").await;
+            assert_eq!(status, 502, "{host}");
+
+            let requests = backends
+                .iter()
+                .map(TestBackend::requests)
+                .collect::<Vec<_>>();
+            assert_eq!(requests, [1, 1, 1], "{host}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_consistent_hash_by_composite_key() {
+        let backends = [
+            TestBackend::start(200, "first").await,
+            TestBackend::start(200, "second").await,
+            TestBackend::start(200, "third").await,
+            TestBackend::start(200, "fourth").await,
+        ];
+        let host = "hash-key.router.test";
+        let mut hashed_route = route(host, backends.iter().map(TestBackend::addr));
+        hashed_route.selection_algorithm = RouteSelectionAlgorithm::ConsistentHash;
+        hashed_route.hash_key = Some("{header.x-tenant}/{path.1}".into());
+        add_route(hashed_route).await;
+
+        let proxy = TestProxy::start().await;
+        let get = |tenant: String, path: String| {
+            let request = proxy
+                .request(reqwest::Method::GET, host, &path)
+                .header("x-tenant", tenant);
+            async move { request.send().await.unwrap().text().await.unwrap() }
+        };
+
+        // The rest of the path isn't part of the key
+        let served = get("eu".into(), "/shops/acme/orders".into()).await;
+        for path in ["/shops/acme/orders", "/shops/acme/cart", "/shops/acme"] {
+            assert_eq!(get("eu".into(), path.into()).await, served);
+        }
+
+        let mut spread = std::collections::HashSet::new();
+        for index in 0..20 {
+            spread.insert(get("eu".into(), format!("/shops/shop-{index}")).await);
+        }
+        assert!(spread.len() > 1, "{spread:?}");
+    }
+}
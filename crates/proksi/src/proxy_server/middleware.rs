@@ -66,6 +66,23 @@ pub async fn execute_request_plugins(
                     return Ok(true);
                 }
             }
+            "compression" => {
+                crate::plugins::PLUGINS
+                    .compression
+                    .request_filter(session, ctx, value)
+                    .await
+                    .ok();
+            }
+            "rate_limit" => {
+                if crate::plugins::PLUGINS
+                    .rate_limit
+                    .request_filter(session, ctx, value)
+                    .await
+                    .is_ok_and(|v| v)
+                {
+                    return Ok(true);
+                }
+            }
             _ => {}
         }
     }
@@ -5,10 +5,29 @@ use pingora::{
     upstreams::peer::PeerOptions,
 };
 
+pub mod accept;
 pub mod cert_store;
+pub mod client_ip;
+pub mod concurrency;
+pub mod connection_limit;
+pub mod dns;
+pub mod headers;
+pub mod http10;
 pub mod http_proxy;
 pub mod https_proxy;
 pub mod middleware;
+pub mod no_match;
+pub mod redirects;
+pub mod request_span;
+pub mod request_timeout;
+pub mod retry;
+pub mod server_header;
+pub mod slow_request;
+pub mod tls_passthrough;
+pub mod trailers;
+pub mod upstream_error;
+pub mod upstream_events;
+pub mod upstream_tls;
 
 /// Default peer options to be used on every upstream connection
 pub fn default_peer_opts() -> PeerOptions {
@@ -0,0 +1,117 @@
+use bytes::Bytes;
+use pingora::{http::ResponseHeader, proxy::Session};
+
+use crate::config::{RouteNoMatch, RouteNoMatchAction};
+
+use super::server_header::{respond_error, write_error_response};
+
+/// Handles a request that doesn't match the path patterns of its route.
+/// Returns `true` if a 404 was sent, `false` if the request should be proxied anyway.
+pub async fn respond_no_match(
+    session: &mut Session,
+    no_match: Option<&RouteNoMatch>,
+) -> pingora::Result<bool> {
+    let Some(no_match) = no_match else {
+        respond_error(session, 404).await?;
+        return Ok(true);
+    };
+
+    if no_match.action == RouteNoMatchAction::Proxy {
+        return Ok(false);
+    }
+
+    let Some(body) = no_match.body.as_ref() else {
+        respond_error(session, 404).await?;
+        return Ok(true);
+    };
+
+    let mut response = ResponseHeader::build(404, Some(2))?;
+    response.insert_header(http::header::CONTENT_TYPE, no_match.content_type.as_ref())?;
+    response.insert_header(http::header::CONTENT_LENGTH, body.len())?;
+
+    write_error_response(session, response, Bytes::from(body.to_string())).await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Sends a request through `respond_no_match`, returns whether it was
+    /// answered and the raw response
+    async fn helper_respond(no_match: Option<RouteNoMatch>) -> (bool, String) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(b"GET /unknown HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .await
+                .unwrap();
+
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+            String::from_utf8_lossy(&response).to_string()
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut session = Session::new_h1(Box::new(pingora::protocols::l4::stream::Stream::from(
+            stream,
+        )));
+        assert!(session.read_request().await.unwrap());
+
+        let responded = respond_no_match(&mut session, no_match.as_ref())
+            .await
+            .unwrap();
+        drop(session);
+
+        (responded, client.await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_path_returns_configured_404() {
+        let (responded, response) = helper_respond(Some(RouteNoMatch {
+            action: RouteNoMatchAction::Respond,
+            body: Some("{\"error\":\"unknown endpoint\"}".into()),
+            content_type: "application/json".into(),
+        }))
+        .await;
+
+        assert!(responded);
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{response}"
+        );
+        assert!(response
+            .to_lowercase()
+            .contains("content-type: application/json\r\n"));
+        assert!(response.ends_with("\r\n\r\n{\"error\":\"unknown endpoint\"}"));
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_path_without_config_returns_404() {
+        let (responded, response) = helper_respond(None).await;
+
+        assert!(responded);
+        assert!(
+            response.starts_with("HTTP/1.1 404 Not Found\r\n"),
+            "{response}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unmatched_path_can_be_proxied() {
+        let (responded, response) = helper_respond(Some(RouteNoMatch {
+            action: RouteNoMatchAction::Proxy,
+            body: Some("ignored".into()),
+            content_type: "text/plain".into(),
+        }))
+        .await;
+
+        assert!(!responded);
+        assert!(response.is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+use http::{header, uri::PathAndQuery, Method, Uri};
+use pingora::{http::ResponseHeader, Error, ErrorType};
+
+/// The most redirects followed for a request, pingora sends a request to the
+/// upstreams at most 16 times (retries included)
+pub const MAX_REDIRECT_HOPS: usize = 10;
+
+/// Error type used to send a request again to the target of a followed redirect
+pub const FOLLOW_REDIRECT: ErrorType = ErrorType::new("FollowRedirect");
+
+/// Only the requests without a body, which a redirect can't change, are followed
+pub fn is_followable_request(method: &Method) -> bool {
+    method == Method::GET || method == Method::HEAD
+}
+
+/// The path (and query) a redirect of the upstream points to, if it can be
+/// followed. Redirects to another host than the one of the request (or with
+/// an invalid `Location`) are sent to the client.
+pub fn redirect_target(response: &ResponseHeader, host: &str) -> Option<PathAndQuery> {
+    if !matches!(response.status.as_u16(), 301 | 302 | 303 | 307 | 308) {
+        return None;
+    }
+
+    let location = response.headers.get(header::LOCATION)?.to_str().ok()?;
+    if location.starts_with('/') && !location.starts_with("//") {
+        return location.parse().ok();
+    }
+
+    // Scheme relative URLs (`//host/path`) keep the scheme of the request
+    let uri = match location.strip_prefix("//") {
+        Some(location) => format!("http://{location}").parse::<Uri>().ok()?,
+        None => location.parse::<Uri>().ok()?,
+    };
+    if !matches!(uri.scheme_str(), Some("http" | "https"))
+        || !uri.host()?.eq_ignore_ascii_case(host)
+    {
+        return None;
+    }
+
+    Some(
+        uri.path_and_query()
+            .cloned()
+            .unwrap_or_else(|| PathAndQuery::from_static("/")),
+    )
+}
+
+/// The (retried) error sending the request again to `target`
+pub fn follow_redirect_error(target: &PathAndQuery) -> Box<Error> {
+    let mut error = Error::explain(
+        FOLLOW_REDIRECT,
+        format!("following the redirect to {target}"),
+    );
+    error.set_retry(true);
+    error
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn helper_redirect(status: u16, location: &str) -> ResponseHeader {
+        let mut response = ResponseHeader::build(status, None).unwrap();
+        response.insert_header(header::LOCATION, location).unwrap();
+        response
+    }
+
+    fn helper_target(status: u16, location: &str) -> Option<String> {
+        redirect_target(&helper_redirect(status, location), "example.com").map(|v| v.to_string())
+    }
+
+    #[test]
+    fn test_redirects_to_the_same_host_are_followed() {
+        assert_eq!(
+            helper_target(302, "/login?next=%2F"),
+            Some("/login?next=%2F".into())
+        );
+        assert_eq!(
+            helper_target(301, "https://example.com/new"),
+            Some("/new".into())
+        );
+        assert_eq!(
+            helper_target(308, "http://EXAMPLE.com:8080"),
+            Some("/".into())
+        );
+        assert_eq!(helper_target(307, "//example.com/v2"), Some("/v2".into()));
+        assert_eq!(helper_target(303, "/done"), Some("/done".into()));
+    }
+
+    #[test]
+    fn test_other_responses_are_not_followed() {
+        // Other hosts
+        assert_eq!(helper_target(302, "https://evil.example.org/"), None);
+        assert_eq!(helper_target(302, "//evil.example.org/"), None);
+        assert_eq!(helper_target(302, "https://example.com.evil.org/"), None);
+        assert_eq!(helper_target(302, "ftp://example.com/file"), None);
+
+        // Relative paths, other statuses and invalid locations
+        assert_eq!(helper_target(302, "next"), None);
+        assert_eq!(helper_target(304, "/cached"), None);
+        assert_eq!(helper_target(200, "/created"), None);
+        assert_eq!(helper_target(302, "http://exa mple.com/"), None);
+        let without_location = ResponseHeader::build(302, None).unwrap();
+        assert_eq!(redirect_target(&without_location, "example.com"), None);
+    }
+
+    #[test]
+    fn test_only_requests_without_body_are_followed() {
+        assert!(is_followable_request(&Method::GET));
+        assert!(is_followable_request(&Method::HEAD));
+        assert!(!is_followable_request(&Method::POST));
+        assert!(!is_followable_request(&Method::DELETE));
+    }
+}
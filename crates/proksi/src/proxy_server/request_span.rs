@@ -0,0 +1,145 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use pingora::protocols::Digest;
+
+/// An ID shared by every request of a (keep-alive or HTTP/2) connection,
+/// derived from the peer address and the time the connection was established
+pub fn connection_id(digest: Option<&Digest>) -> String {
+    let mut hasher = DefaultHasher::new();
+
+    if let Some(digest) = digest {
+        digest
+            .socket_digest
+            .as_ref()
+            .and_then(|socket| socket.peer_addr())
+            .map(ToString::to_string)
+            .hash(&mut hasher);
+
+        digest
+            .timing_digest
+            .first()
+            .and_then(Option::as_ref)
+            .map(|timing| timing.established_ts)
+            .hash(&mut hasher);
+    }
+
+    format!("{:016x}", hasher.finish())
+}
+
+/// Creates the span of a request, every log emitted while it is entered
+/// carries the `connection_id` and a new `trace_id` (the `request_id` of the
+/// access logs is the one of the `request_id` plugin)
+pub fn request_span(digest: Option<&Digest>) -> tracing::Span {
+    tracing::info_span!(
+        "request",
+        connection_id = %connection_id(digest),
+        trace_id = %uuid::Uuid::new_v4().simple(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use pingora::{
+        http::ResponseHeader,
+        protocols::{http::ServerSession, l4, Stream},
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::test_support::LogCapture;
+
+    /// Reads the next request of the connection, logs a line in its span and
+    /// answers it. Returns the stream so that the next request can be read.
+    async fn helper_handle_request(stream: Stream) -> Stream {
+        let mut session = ServerSession::new_http1(stream);
+        assert!(session.read_request().await.unwrap());
+
+        request_span(session.digest()).in_scope(|| tracing::info!("handling request"));
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header("content-length", 0).unwrap();
+        session
+            .write_response_header(Box::new(response))
+            .await
+            .unwrap();
+        session.finish().await.unwrap().unwrap()
+    }
+
+    /// The value of `field` in the JSON span of a log line
+    fn helper_span_field(line: &str, field: &str) -> String {
+        let log: serde_json::Value = serde_json::from_str(line).unwrap();
+        log["span"][field].as_str().unwrap().to_string()
+    }
+
+    /// Connects a client sending `requests` one after the other (each once the
+    /// previous one is answered), returns the server side stream
+    async fn helper_connection(requests: &'static [&'static [u8]]) -> Stream {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            for request in requests {
+                client.write_all(request).await.unwrap();
+
+                // The responses have no body, wait for the end of the headers
+                let mut response = Vec::new();
+                while !response.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0; 1];
+                    if client.read_exact(&mut byte).await.is_err() {
+                        return;
+                    }
+                    response.push(byte[0]);
+                }
+            }
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        Box::new(l4::stream::Stream::from(stream))
+    }
+
+    #[tokio::test]
+    async fn test_requests_of_a_connection_share_the_connection_id() {
+        let writer = LogCapture::default();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        // Two requests on the same (keep-alive) connection
+        let stream = helper_connection(&[
+            b"GET /first HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            b"GET /second HTTP/1.1\r\nHost: example.com\r\n\r\n",
+        ])
+        .await;
+        let stream = helper_handle_request(stream).await;
+        helper_handle_request(stream).await;
+
+        // And one on another connection
+        let stream = helper_connection(&[b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"]).await;
+        helper_handle_request(stream).await;
+
+        let output = writer.output();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3, "{output}");
+
+        let connection_ids = lines
+            .iter()
+            .map(|line| helper_span_field(line, "connection_id"))
+            .collect::<Vec<_>>();
+        let trace_ids = lines
+            .iter()
+            .map(|line| helper_span_field(line, "trace_id"))
+            .collect::<Vec<_>>();
+
+        assert_eq!(connection_ids[0], connection_ids[1]);
+        assert_ne!(connection_ids[0], connection_ids[2]);
+
+        assert_ne!(trace_ids[0], trace_ids[1]);
+        assert_ne!(trace_ids[1], trace_ids[2]);
+    }
+}
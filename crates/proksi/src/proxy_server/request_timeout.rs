@@ -0,0 +1,159 @@
+use std::time::{Duration, Instant};
+
+use pingora::{
+    protocols::http::ServerSession, upstreams::peer::PeerOptions, Error, ErrorSource, ErrorType,
+};
+
+/// Error type used when a request lasts longer than `request_timeout_secs`
+pub const REQUEST_TIMEOUT: ErrorType = ErrorType::new("RequestTimeout");
+
+/// The read timeout pingora sets on the downstream sessions
+const DOWNSTREAM_READ_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The time by which a request must be done (see `request_timeout_secs`),
+/// requests without a deadline can last forever
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RequestDeadline(Option<Instant>);
+
+impl RequestDeadline {
+    pub fn new(start: Instant, timeout: Option<Duration>) -> Self {
+        Self(timeout.map(|timeout| start + timeout))
+    }
+
+    /// The time left until the deadline
+    pub fn remaining(&self) -> Option<Duration> {
+        self.0
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn is_exceeded(&self) -> bool {
+        self.remaining()
+            .is_some_and(|remaining| remaining.is_zero())
+    }
+
+    /// Fails with a `REQUEST_TIMEOUT` error once the deadline is exceeded
+    pub fn check(&self) -> pingora::Result<()> {
+        if !self.is_exceeded() {
+            return Ok(());
+        }
+
+        Err(Error::create(
+            REQUEST_TIMEOUT,
+            ErrorSource::Internal,
+            Some("the request exceeded request_timeout_secs".into()),
+            None,
+        ))
+    }
+
+    /// Bounds the timeouts of the upstream connection by the time left, the
+    /// shorter timeouts of the peer are kept
+    pub fn apply_to_peer(&self, options: &mut PeerOptions) {
+        let Some(remaining) = self.remaining() else {
+            return;
+        };
+
+        for timeout in [
+            &mut options.connection_timeout,
+            &mut options.total_connection_timeout,
+            &mut options.read_timeout,
+            &mut options.write_timeout,
+        ] {
+            *timeout = Some(timeout.map_or(remaining, |timeout| timeout.min(remaining)));
+        }
+    }
+
+    /// Bounds the reads (request body) and writes (response) of the client
+    /// session by the time left, a client that stops sending or reading
+    /// doesn't hold the request past its deadline. Only HTTP/1 sessions
+    /// support these timeouts.
+    pub fn apply_to_downstream(&self, session: &mut ServerSession) {
+        let Some(remaining) = self.remaining() else {
+            return;
+        };
+
+        session.set_read_timeout(remaining.min(DOWNSTREAM_READ_TIMEOUT));
+        session.set_write_timeout(remaining);
+    }
+
+    /// Whether `error` is a timeout, the ones hit past the deadline are
+    /// caused by it as every timeout of the request is bounded by it
+    pub fn is_timeout(error: &Error) -> bool {
+        matches!(
+            error.etype(),
+            ErrorType::ConnectTimedout
+                | ErrorType::TLSHandshakeTimedout
+                | ErrorType::ReadTimedout
+                | ErrorType::WriteTimedout
+        ) || error.etype() == &REQUEST_TIMEOUT
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_without_deadline() {
+        let deadline = RequestDeadline::new(Instant::now(), None);
+        assert_eq!(deadline.remaining(), None);
+        assert!(deadline.check().is_ok());
+
+        let mut options = PeerOptions::new();
+        options.read_timeout = Some(Duration::from_secs(360));
+        deadline.apply_to_peer(&mut options);
+        assert_eq!(options.read_timeout, Some(Duration::from_secs(360)));
+        assert_eq!(options.write_timeout, None);
+    }
+
+    #[test]
+    fn test_peer_timeouts_are_bounded_by_the_deadline() {
+        let deadline = RequestDeadline::new(Instant::now(), Some(Duration::from_secs(30)));
+
+        let mut options = PeerOptions::new();
+        options.connection_timeout = Some(Duration::from_secs(10));
+        options.read_timeout = Some(Duration::from_secs(360));
+        deadline.apply_to_peer(&mut options);
+
+        // The shorter timeouts of the peer still win
+        assert_eq!(options.connection_timeout, Some(Duration::from_secs(10)));
+        for timeout in [options.read_timeout, options.write_timeout] {
+            let timeout = timeout.unwrap();
+            assert!(timeout <= Duration::from_secs(30) && timeout > Duration::from_secs(29));
+        }
+    }
+
+    #[test]
+    fn test_exceeded_deadline() {
+        let start = Instant::now() - Duration::from_secs(2);
+        let deadline = RequestDeadline::new(start, Some(Duration::from_secs(1)));
+
+        assert!(deadline.is_exceeded());
+        assert_eq!(deadline.check().unwrap_err().etype(), &REQUEST_TIMEOUT);
+        assert!(RequestDeadline::is_timeout(&deadline.check().unwrap_err()));
+        assert!(!RequestDeadline::is_timeout(&Error::new(
+            ErrorType::ConnectRefused
+        )));
+    }
+
+    #[tokio::test]
+    async fn test_client_reads_are_bounded_by_the_deadline() {
+        use tokio::io::AsyncWriteExt;
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut session = ServerSession::new_http1(Box::new(server));
+        client
+            .write_all(b"POST / HTTP/1.1\r\nHost: a.test\r\nContent-Length: 10\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(session.read_request().await.unwrap());
+
+        let deadline = RequestDeadline::new(Instant::now(), Some(Duration::from_millis(200)));
+        deadline.apply_to_downstream(&mut session);
+
+        // The client never sends its body
+        let started = Instant::now();
+        let err = session.read_request_body().await.unwrap_err();
+        assert_eq!(err.etype(), &ErrorType::ReadTimedout);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,209 @@
+use http::header::CONTENT_LENGTH;
+use pingora::proxy::Session;
+
+use crate::config::RouteRetry;
+
+/// The size of the pingora retry buffer, bigger bodies can't be sent again
+pub const MAX_BUFFERED_BODY_BYTES: usize = 64 * 1024;
+
+/// Tracks whether a failed request can be sent again to an upstream
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RequestRetry {
+    /// Number of retries left for the request
+    remaining: usize,
+    /// Whether the request (including its body) can be sent again once it
+    /// already reached an upstream
+    replayable: bool,
+}
+
+impl RequestRetry {
+    pub fn new(attempts: usize, replayable: bool) -> Self {
+        Self {
+            remaining: attempts,
+            replayable,
+        }
+    }
+
+    /// The connection to the upstream failed, nothing was sent yet so the
+    /// request can always be retried
+    pub fn retry_connect(&mut self) -> bool {
+        self.take()
+    }
+
+    /// The request failed after being (partially) sent to the upstream
+    pub fn retry_proxy(&mut self) -> bool {
+        self.replayable && self.take()
+    }
+
+    /// The upstream answered, the response may have been forwarded downstream
+    /// so the request must not be retried anymore
+    pub fn response_received(&mut self) {
+        self.remaining = 0;
+    }
+
+    fn take(&mut self) -> bool {
+        if self.remaining == 0 {
+            return false;
+        }
+
+        self.remaining -= 1;
+        true
+    }
+}
+
+/// Prepares the retries of a request based on the route configuration.
+///
+/// When `buffer_request_body` is enabled, the request body is read (and kept in
+/// the retry buffer) before proxying if its `Content-Length` is within
+/// `max_buffered_body_bytes`. Bodies without a length or bigger than the limit
+/// are not buffered and the request is only retried if the connection fails.
+pub async fn prepare_retry(
+    session: &mut Session,
+    config: &RouteRetry,
+) -> pingora::Result<RequestRetry> {
+    if config.attempts == 0 {
+        return Ok(RequestRetry::default());
+    }
+
+    if session.is_body_empty() {
+        return Ok(RequestRetry::new(config.attempts, true));
+    }
+
+    if !config.buffer_request_body {
+        return Ok(RequestRetry::new(config.attempts, false));
+    }
+
+    let max_bytes = config.max_buffered_body_bytes.min(MAX_BUFFERED_BODY_BYTES);
+    let content_length = session
+        .req_header()
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+
+    if content_length.is_none_or(|length| length > max_bytes) {
+        return Ok(RequestRetry::new(config.attempts, false));
+    }
+
+    // Every chunk read is kept in the retry buffer, which is then sent
+    // to the upstream in place of the downstream body (on every attempt)
+    session.enable_retry_buffering();
+    while session.read_request_body().await?.is_some() {}
+
+    let replayable = session.get_retry_buffer().is_some();
+    Ok(RequestRetry::new(config.attempts, replayable))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    fn helper_config(max_buffered_body_bytes: usize) -> RouteRetry {
+        RouteRetry {
+            attempts: 2,
+            buffer_request_body: true,
+            max_buffered_body_bytes,
+        }
+    }
+
+    /// Reads a request (sent by a TCP client) into a proxy session
+    async fn helper_session(request: Vec<u8>) -> Session {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client.write_all(&request).await.unwrap();
+            // keep the connection open while the session is used
+            tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut session = Session::new_h1(Box::new(pingora::protocols::l4::stream::Stream::from(
+            stream,
+        )));
+        assert!(session.read_request().await.unwrap());
+        session
+    }
+
+    fn helper_post(body: &[u8]) -> Vec<u8> {
+        let mut request = format!(
+            "POST /orders HTTP/1.1\r\nHost: example.com\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(body);
+        request
+    }
+
+    #[test]
+    fn test_request_retry_attempts() {
+        let mut retry = RequestRetry::new(2, true);
+        assert!(retry.retry_proxy());
+        assert!(retry.retry_connect());
+        assert!(!retry.retry_connect());
+
+        let mut retry = RequestRetry::new(2, false);
+        assert!(!retry.retry_proxy());
+        assert!(retry.retry_connect());
+
+        let mut retry = RequestRetry::new(2, true);
+        retry.response_received();
+        assert!(!retry.retry_connect());
+    }
+
+    #[tokio::test]
+    async fn test_small_post_is_buffered_and_retried() {
+        let body = b"{\"item\":42}";
+        let mut session = helper_session(helper_post(body)).await;
+
+        let mut retry = prepare_retry(&mut session, &helper_config(1024))
+            .await
+            .unwrap();
+
+        assert!(session.is_body_done());
+        assert_eq!(session.get_retry_buffer().unwrap().as_ref(), body);
+        assert!(retry.retry_proxy());
+        assert!(retry.retry_proxy());
+        assert!(!retry.retry_proxy());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_post_is_not_buffered_nor_retried() {
+        let body = vec![b'a'; 2048];
+        let mut session = helper_session(helper_post(&body)).await;
+
+        let mut retry = prepare_retry(&mut session, &helper_config(1024))
+            .await
+            .unwrap();
+
+        assert!(!session.is_body_done());
+        assert!(session.get_retry_buffer().is_none());
+        assert!(!retry.retry_proxy());
+        // nothing was sent yet when the connection fails
+        assert!(retry.retry_connect());
+
+        // The body is left untouched for the upstream
+        let mut read = 0;
+        while let Some(chunk) = session.read_request_body().await.unwrap() {
+            read += chunk.len();
+        }
+        assert_eq!(read, body.len());
+    }
+
+    #[tokio::test]
+    async fn test_post_is_not_retried_without_buffering() {
+        let mut session = helper_session(helper_post(b"hello")).await;
+        let config = RouteRetry {
+            buffer_request_body: false,
+            ..helper_config(1024)
+        };
+
+        let mut retry = prepare_retry(&mut session, &config).await.unwrap();
+
+        assert!(!session.is_body_done());
+        assert!(!retry.retry_proxy());
+    }
+}
@@ -0,0 +1,169 @@
+use std::any::Any;
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use http::{header, HeaderValue};
+use pingora::{
+    http::ResponseHeader,
+    modules::http::{HttpModule, HttpModuleBuilder, Module, ModuleBuilder},
+    protocols::http::ServerSession,
+    proxy::Session,
+};
+
+/// The `Server` header of the responses generated by proksi (errors, route
+/// redirects, plugin responses etc.), see `server.server_header`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ServerHeader {
+    /// The header set by pingora (`Server: Pingora`)
+    #[default]
+    Default,
+    /// The responses have no `Server` header
+    Omitted,
+    Custom(HeaderValue),
+}
+
+impl ServerHeader {
+    /// An empty value omits the header, invalid values are refused by the
+    /// configuration validation and keep the default
+    pub fn new(value: Option<&str>) -> Self {
+        match value {
+            None => Self::Default,
+            Some("") => Self::Omitted,
+            Some(value) => HeaderValue::from_str(value).map_or(Self::Default, Self::Custom),
+        }
+    }
+
+    /// Sets (or removes) the `Server` header of a response generated by proksi
+    pub fn apply(&self, response: &mut ResponseHeader) {
+        match self {
+            Self::Default => {}
+            Self::Omitted => {
+                response.remove_header(&header::SERVER);
+            }
+            Self::Custom(value) => {
+                response.insert_header(header::SERVER, value).ok();
+            }
+        }
+    }
+
+    /// The downstream module applying the header to every response written
+    /// through the session, except the ones of the upstreams
+    pub fn module(&self) -> ModuleBuilder {
+        Box::new(ServerHeaderBuilder(self.clone()))
+    }
+}
+
+struct ServerHeaderBuilder(ServerHeader);
+
+impl HttpModuleBuilder for ServerHeaderBuilder {
+    fn init(&self) -> Module {
+        Box::new(ServerHeaderModule {
+            server_header: self.0.clone(),
+            proxied: false,
+        })
+    }
+}
+
+/// The `Server` header of a request, set on its response unless the
+/// response comes from the upstream
+pub struct ServerHeaderModule {
+    server_header: ServerHeader,
+    proxied: bool,
+}
+
+#[async_trait]
+impl HttpModule for ServerHeaderModule {
+    async fn response_header_filter(
+        &mut self,
+        response: &mut ResponseHeader,
+        _end_of_stream: bool,
+    ) -> pingora::Result<()> {
+        if !self.proxied {
+            self.server_header.apply(response);
+        }
+        Ok(())
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// The response of the request comes from its upstream, which keeps its own
+/// `Server` header
+pub fn mark_proxied(session: &mut Session) {
+    if let Some(module) = session
+        .downstream_modules_ctx
+        .get_mut::<ServerHeaderModule>()
+    {
+        module.proxied = true;
+    }
+}
+
+/// Answers the client with the error `status`, like `Session::respond_error`
+pub async fn respond_error(session: &mut Session, status: u16) -> pingora::Result<()> {
+    respond_error_with_body(session, status, Bytes::new()).await
+}
+
+/// Answers the client with the error `status` and `body`, like
+/// `Session::respond_error_with_body`
+pub async fn respond_error_with_body(
+    session: &mut Session,
+    status: u16,
+    body: Bytes,
+) -> pingora::Result<()> {
+    let mut response = ServerSession::generate_error(status);
+    if !body.is_empty() {
+        response.set_content_length(body.len())?;
+    }
+
+    write_error_response(session, response, body).await
+}
+
+/// Writes an error response generated by proksi, like
+/// `Session::write_error_response` (which skips the downstream modules)
+pub async fn write_error_response(
+    session: &mut Session,
+    mut response: ResponseHeader,
+    body: Bytes,
+) -> pingora::Result<()> {
+    // Before a response is proxied, or after an upstream failed
+    if let Some(module) = session.downstream_modules_ctx.get::<ServerHeaderModule>() {
+        module.server_header.apply(&mut response);
+    }
+
+    session.write_error_response(response, body).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn helper_server(server_header: &ServerHeader) -> Option<String> {
+        let mut response = ServerSession::generate_error(404);
+        server_header.apply(&mut response);
+
+        response
+            .headers
+            .get(header::SERVER)
+            .map(|value| value.to_str().unwrap().to_string())
+    }
+
+    #[test]
+    fn test_server_header() {
+        assert_eq!(
+            helper_server(&ServerHeader::new(None)),
+            Some("Pingora".into())
+        );
+        assert_eq!(helper_server(&ServerHeader::new(Some(""))), None);
+        assert_eq!(
+            helper_server(&ServerHeader::new(Some("edge"))),
+            Some("edge".into())
+        );
+        assert_eq!(ServerHeader::new(Some("in\nvalid")), ServerHeader::Default);
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::Duration;
+
+use crate::metrics::SLOW_REQUESTS;
+
+/// The request details reported when a request is slower than the threshold
+pub struct SlowRequest<'a> {
+    pub host: &'a str,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub duration: Duration,
+}
+
+/// Emits a WARN log and increments the slow requests metric if the request took
+/// longer than `threshold_ms`. A missing (or 0) threshold disables the check.
+/// Returns whether the request was reported.
+pub fn report_slow_request(threshold_ms: Option<u64>, request: &SlowRequest<'_>) -> bool {
+    let Some(threshold_ms) = threshold_ms.filter(|v| *v > 0) else {
+        return false;
+    };
+
+    if request.duration <= Duration::from_millis(threshold_ms) {
+        return false;
+    }
+
+    SLOW_REQUESTS.with_label_values(&[request.host]).inc();
+
+    tracing::warn!(
+        host = request.host,
+        method = request.method,
+        path = request.path,
+        duration_ms = request.duration.as_millis(),
+        threshold_ms,
+        slow_request = true,
+        "slow request"
+    );
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::LogCapture;
+
+    fn helper_request(host: &'static str, duration_ms: u64) -> SlowRequest<'static> {
+        SlowRequest {
+            host,
+            method: "GET",
+            path: "/reports",
+            duration: Duration::from_millis(duration_ms),
+        }
+    }
+
+    fn helper_capture_logs(run: impl FnOnce()) -> String {
+        let writer = LogCapture::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, run);
+
+        writer.output()
+    }
+
+    #[test]
+    fn test_slow_request_is_reported() {
+        let host = "slow.example.com";
+        let logs = helper_capture_logs(|| {
+            assert!(report_slow_request(Some(100), &helper_request(host, 250)));
+        });
+
+        assert!(logs.contains("WARN"));
+        assert!(logs.contains("slow request"));
+        assert!(logs.contains("duration_ms=250"));
+        assert_eq!(SLOW_REQUESTS.with_label_values(&[host]).get(), 1);
+    }
+
+    #[test]
+    fn test_fast_request_is_not_reported() {
+        let host = "fast.example.com";
+        let logs = helper_capture_logs(|| {
+            assert!(!report_slow_request(Some(100), &helper_request(host, 40)));
+            assert!(!report_slow_request(Some(100), &helper_request(host, 100)));
+        });
+
+        assert!(logs.is_empty());
+        assert_eq!(SLOW_REQUESTS.with_label_values(&[host]).get(), 0);
+    }
+
+    #[test]
+    fn test_slow_request_disabled_without_threshold() {
+        let host = "disabled.example.com";
+        let logs = helper_capture_logs(|| {
+            assert!(!report_slow_request(None, &helper_request(host, 10_000)));
+            assert!(!report_slow_request(Some(0), &helper_request(host, 10_000)));
+        });
+
+        assert!(logs.is_empty());
+        assert_eq!(SLOW_REQUESTS.with_label_values(&[host]).get(), 0);
+    }
+}
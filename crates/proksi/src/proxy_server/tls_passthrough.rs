@@ -0,0 +1,665 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use pingora::{
+    apps::ServerApp,
+    lb::{health_check::TcpHealthCheck, selection::RoundRobin, LoadBalancer},
+    protocols::Stream,
+    server::{ListenFds, ShutdownWatch},
+    services::{listening::Service as ListeningService, Service},
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+
+use crate::{
+    config::{Config, TlsPassthrough},
+    services::{health_check::HEALTH_CHECK_INTERVAL, run_until_shutdown},
+};
+
+use super::accept::AcceptApp;
+use super::connection_limit::ConnectionLimiter;
+
+/// The maximum size of the records of a `ClientHello` that are buffered (the
+/// `ClientHello` can be fragmented over several records of up to 16KB)
+const MAX_CLIENT_HELLO_SIZE: usize = 64 * 1024;
+
+/// How long a client has to send its `ClientHello`
+const CLIENT_HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait for a connection to the upstream
+const UPSTREAM_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The result of parsing the beginning of a TLS connection
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientHello {
+    /// More bytes are needed to read the whole `ClientHello` record
+    Incomplete,
+    /// The bytes are not a TLS `ClientHello`
+    Invalid,
+    /// The `ClientHello` was parsed, with the server name if one was sent
+    Complete(Option<String>),
+}
+
+/// Parses the server name (SNI) from the first TLS records of a connection,
+/// the `ClientHello` handshake message is reassembled from the records it is
+/// fragmented over
+pub fn parse_client_hello(buf: &[u8]) -> ClientHello {
+    let mut records = buf;
+    let mut handshake = Vec::new();
+
+    loop {
+        // TLS record header: content type (handshake), version, length
+        if records.len() < 5 {
+            return ClientHello::Incomplete;
+        }
+
+        if records[0] != 0x16 {
+            return ClientHello::Invalid;
+        }
+
+        let record_len = usize::from(u16::from_be_bytes([records[3], records[4]]));
+        if records.len() < 5 + record_len {
+            return ClientHello::Incomplete;
+        }
+
+        handshake.extend_from_slice(&records[5..5 + record_len]);
+        records = &records[5 + record_len..];
+
+        // Handshake header: type and 3 bytes of length
+        if handshake.len() < 4 {
+            continue;
+        }
+
+        let handshake_len =
+            4 + u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+        if handshake.len() < handshake_len {
+            continue;
+        }
+
+        return match parse_server_name(&handshake[..handshake_len]) {
+            Some(server_name) => ClientHello::Complete(server_name),
+            None => ClientHello::Invalid,
+        };
+    }
+}
+
+/// A minimal cursor over the bytes of a handshake message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|v| v[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|v| u16::from_be_bytes([v[0], v[1]]))
+    }
+
+    /// Reads a vector prefixed by a 1 or 2 bytes length
+    fn vec(&mut self, len_bytes: usize) -> Option<&'a [u8]> {
+        let len = match len_bytes {
+            1 => usize::from(self.u8()?),
+            _ => usize::from(self.u16()?),
+        };
+
+        self.take(len)
+    }
+}
+
+/// Returns `None` if the handshake is not a valid `ClientHello`, `Some(None)` if
+/// it does not contain the `server_name` extension
+fn parse_server_name(handshake: &[u8]) -> Option<Option<String>> {
+    let mut reader = Reader(handshake);
+
+    // Handshake type (client_hello) and 3 bytes of length
+    if reader.u8()? != 0x01 {
+        return None;
+    }
+    reader.take(3)?;
+
+    // client_version, random, session_id, cipher_suites, compression_methods
+    reader.take(2 + 32)?;
+    reader.vec(1)?;
+    reader.vec(2)?;
+    reader.vec(1)?;
+
+    // Extensions are optional
+    let Some(extensions) = reader.vec(2) else {
+        return Some(None);
+    };
+
+    let mut extensions = Reader(extensions);
+    while let (Some(kind), Some(data)) = (extensions.u16(), extensions.vec(2)) {
+        // server_name extension (RFC 6066)
+        if kind != 0x0000 {
+            continue;
+        }
+
+        let mut names = Reader(data);
+        let mut names = Reader(names.vec(2)?);
+        while let (Some(name_type), Some(name)) = (names.u8(), names.vec(2)) {
+            // host_name
+            if name_type == 0x00 {
+                let name = std::str::from_utf8(name).ok()?;
+                return Some(Some(name.to_ascii_lowercase()));
+            }
+        }
+
+        return Some(None);
+    }
+
+    Some(None)
+}
+
+/// Reads from the stream until the whole `ClientHello` record is received.
+/// Returns the bytes read (to be forwarded to the upstream) and the server name.
+async fn read_client_hello<S>(stream: &mut S) -> anyhow::Result<(Vec<u8>, Option<String>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 4096];
+
+    loop {
+        match parse_client_hello(&buf) {
+            ClientHello::Complete(server_name) => return Ok((buf, server_name)),
+            ClientHello::Invalid => return Err(anyhow!("not a TLS ClientHello")),
+            ClientHello::Incomplete if buf.len() >= MAX_CLIENT_HELLO_SIZE => {
+                return Err(anyhow!("ClientHello is too large"));
+            }
+            ClientHello::Incomplete => {}
+        }
+
+        let read = stream.read(&mut chunk).await?;
+        if read == 0 {
+            return Err(anyhow!(
+                "connection closed before the ClientHello was received"
+            ));
+        }
+
+        buf.extend_from_slice(&chunk[..read]);
+    }
+}
+
+/// The load balancers of the passthrough routes, by SNI
+type PassthroughRoutes = HashMap<String, Arc<LoadBalancer<RoundRobin>>>;
+
+/// A listening app that routes raw TLS connections to upstreams based on their SNI
+pub struct TlsPassthroughApp {
+    routes: Arc<PassthroughRoutes>,
+}
+
+impl TlsPassthroughApp {
+    pub fn new(config: &TlsPassthrough) -> anyhow::Result<Self> {
+        let mut routes = HashMap::with_capacity(config.routes.len());
+
+        for route in &config.routes {
+            let upstreams = route
+                .upstreams
+                .iter()
+                .map(|u| format!("{}:{}", u.ip, u.port));
+            let mut load_balancer = LoadBalancer::<RoundRobin>::try_from_iter(upstreams)
+                .map_err(|err| anyhow!("invalid upstreams for SNI {}: {err}", route.sni))?;
+
+            // The traffic isn't decrypted, the upstreams are only checked to
+            // accept TCP connections
+            load_balancer.set_health_check(TcpHealthCheck::new());
+
+            routes.insert(route.sni.to_ascii_lowercase(), Arc::new(load_balancer));
+        }
+
+        Ok(Self {
+            routes: Arc::new(routes),
+        })
+    }
+
+    /// The service running the health checks of the upstreams of the routes
+    pub fn health_service(&self) -> TlsPassthroughHealthService {
+        TlsPassthroughHealthService {
+            routes: self.routes.clone(),
+        }
+    }
+
+    /// Selects the upstream address for the given server name
+    fn select_upstream(&self, server_name: &str) -> Option<SocketAddr> {
+        let backend = self.routes.get(server_name)?.select(b"", 32)?;
+        backend.addr.as_inet().copied()
+    }
+
+    /// Reads the `ClientHello` from the downstream and tunnels the connection
+    /// to the upstream selected for its SNI until one of the sides closes it
+    pub async fn proxy<S>(&self, downstream: &mut S) -> anyhow::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let (client_hello, server_name) =
+            tokio::time::timeout(CLIENT_HELLO_TIMEOUT, read_client_hello(downstream))
+                .await
+                .map_err(|_| anyhow!("timed out waiting for the ClientHello"))??;
+
+        let server_name = server_name.ok_or_else(|| anyhow!("ClientHello without SNI"))?;
+        let upstream_addr = self
+            .select_upstream(&server_name)
+            .ok_or_else(|| anyhow!("no upstream for SNI {server_name}"))?;
+
+        let mut upstream =
+            tokio::time::timeout(UPSTREAM_CONNECT_TIMEOUT, TcpStream::connect(upstream_addr))
+                .await
+                .map_err(|_| anyhow!("timed out connecting to {upstream_addr}"))??;
+
+        // The ClientHello was consumed from the downstream, send it as it is
+        upstream.write_all(&client_hello).await?;
+
+        let (to_upstream, to_downstream) =
+            tokio::io::copy_bidirectional(downstream, &mut upstream).await?;
+        tracing::debug!(
+            sni = server_name,
+            upstream = upstream_addr.to_string(),
+            bytes_to_upstream = client_hello.len() as u64 + to_upstream,
+            bytes_to_downstream = to_downstream,
+            "tls passthrough connection closed"
+        );
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ServerApp for TlsPassthroughApp {
+    async fn process_new(
+        self: &Arc<Self>,
+        mut session: Stream,
+        _shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        if let Err(err) = self.proxy(&mut session).await {
+            tracing::debug!("tls passthrough connection failed: {err}");
+        }
+
+        // Tunneled connections can't be reused
+        None
+    }
+}
+
+/// Runs the health checks of the passthrough upstreams, the unhealthy ones
+/// aren't selected until they accept connections again
+pub struct TlsPassthroughHealthService {
+    routes: Arc<PassthroughRoutes>,
+}
+
+impl TlsPassthroughHealthService {
+    async fn run_health_checks(&self) {
+        for load_balancer in self.routes.values() {
+            load_balancer.backends().run_health_check(false).await;
+        }
+    }
+}
+
+#[async_trait]
+impl Service for TlsPassthroughHealthService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+        run_until_shutdown(&mut shutdown, async {
+            loop {
+                interval.tick().await;
+                self.run_health_checks().await;
+            }
+        })
+        .await;
+    }
+
+    fn name(&self) -> &'static str {
+        "tls_passthrough_health_check_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+/// Creates the TLS passthrough listening service and the health checks of its
+/// upstreams if `tls_passthrough.address` is set
+pub fn tls_passthrough_services(
+    config: &Config,
+    connection_limiter: Option<Arc<ConnectionLimiter>>,
+) -> Vec<Box<dyn Service>> {
+    let Some(address) = config.tls_passthrough.address.as_ref() else {
+        return vec![];
+    };
+
+    let app = match TlsPassthroughApp::new(&config.tls_passthrough) {
+        Ok(app) => app,
+        Err(err) => {
+            tracing::error!("failed to create the TLS passthrough service: {err}");
+            return vec![];
+        }
+    };
+
+    let health_service = app.health_service();
+    let mut service = ListeningService::new(
+        "tls_passthrough_service".to_string(),
+        AcceptApp::new(app, connection_limiter, None),
+    );
+    service.add_tcp(address);
+    service.threads = config.worker_threads;
+
+    vec![Box::new(service), Box::new(health_service)]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        borrow::Cow,
+        io::{Read, Write},
+    };
+
+    use openssl::ssl::{SslAcceptor, SslConnector, SslMethod, SslVerifyMode};
+
+    use crate::config::{RouteUpstream, TlsPassthroughRoute};
+    use crate::test_support::self_signed_certificate;
+
+    use super::*;
+
+    /// Starts a TLS server that answers every connection with its name
+    fn helper_tls_backend(name: &'static str) -> SocketAddr {
+        let (cert, key) = self_signed_certificate(name, 2048, 1);
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(mut tls) = acceptor.accept(stream.unwrap()) else {
+                    continue;
+                };
+                tls.write_all(name.as_bytes()).ok();
+                tls.shutdown().ok();
+            }
+        });
+
+        addr
+    }
+
+    /// A `ClientHello` handshake message with the given server name
+    fn helper_client_hello(server_name: &str) -> Vec<u8> {
+        let name = server_name.as_bytes();
+        let len = |len: usize| u16::try_from(len).unwrap().to_be_bytes();
+
+        // server_name extension: the list of names, with a single host_name
+        let mut names = vec![0x00];
+        names.extend(len(name.len()));
+        names.extend(name);
+        let mut extensions = vec![0x00, 0x00];
+        extensions.extend(len(names.len() + 2));
+        extensions.extend(len(names.len()));
+        extensions.extend(names);
+
+        // client_version, random, session_id, cipher_suites, compression_methods
+        let mut body = vec![0x03, 0x03];
+        body.extend([0; 32]);
+        body.extend([0x00, 0x00, 0x02, 0x13, 0x01, 0x01, 0x00]);
+        body.extend(len(extensions.len()));
+        body.extend(extensions);
+
+        let mut handshake = vec![0x01];
+        handshake.extend(&u32::try_from(body.len()).unwrap().to_be_bytes()[1..]);
+        handshake.extend(body);
+        handshake
+    }
+
+    /// Fragments a handshake message over TLS records of up to `record_size` bytes
+    fn helper_records(handshake: &[u8], record_size: usize) -> Vec<u8> {
+        handshake
+            .chunks(record_size)
+            .flat_map(|fragment| {
+                let mut record = vec![0x16, 0x03, 0x01];
+                record.extend(u16::try_from(fragment.len()).unwrap().to_be_bytes());
+                record.extend(fragment);
+                record
+            })
+            .collect()
+    }
+
+    /// Starts a TCP server that reads `request_len` bytes of every connection
+    /// and answers with `response`
+    async fn helper_tcp_backend(request_len: usize, response: &'static [u8]) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut request = vec![0; request_len];
+                stream.read_exact(&mut request).await.ok();
+                stream.write_all(response).await.ok();
+            }
+        });
+
+        addr
+    }
+
+    /// Starts the passthrough app on a random port
+    async fn helper_passthrough(
+        routes: Vec<(&'static str, Vec<SocketAddr>)>,
+    ) -> (SocketAddr, Arc<TlsPassthroughApp>) {
+        let config = TlsPassthrough {
+            address: None,
+            routes: routes
+                .into_iter()
+                .map(|(sni, upstreams)| TlsPassthroughRoute {
+                    sni: Cow::Borrowed(sni),
+                    upstreams: upstreams
+                        .into_iter()
+                        .map(|addr| RouteUpstream {
+                            ip: Cow::Owned(addr.ip().to_string()),
+                            port: addr.port(),
+                            ..RouteUpstream::default()
+                        })
+                        .collect(),
+                })
+                .collect(),
+        };
+
+        let app = Arc::new(TlsPassthroughApp::new(&config).unwrap());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn({
+            let app = app.clone();
+            async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let app = app.clone();
+                    tokio::spawn(async move { app.proxy(&mut stream).await });
+                }
+            }
+        });
+
+        (addr, app)
+    }
+
+    /// Connects to the proxy with the given SNI and returns the backend response
+    /// and the common name of the certificate presented by the backend
+    fn helper_tls_client(proxy: SocketAddr, sni: &str) -> anyhow::Result<(String, String)> {
+        let mut connector = SslConnector::builder(SslMethod::tls_client())?;
+        connector.set_verify(SslVerifyMode::NONE);
+        let connector = connector.build();
+
+        let stream = std::net::TcpStream::connect(proxy)?;
+        let mut tls = connector.connect(sni, stream)?;
+
+        let common_name = tls
+            .ssl()
+            .peer_certificate()
+            .and_then(|cert| {
+                let entry = cert.subject_name().entries().next()?;
+                Some(entry.data().as_utf8().ok()?.to_string())
+            })
+            .unwrap_or_default();
+
+        let mut response = String::new();
+        tls.read_to_string(&mut response).ok();
+
+        Ok((response, common_name))
+    }
+
+    #[test]
+    fn test_parse_client_hello() {
+        assert_eq!(parse_client_hello(&[0x16, 0x03]), ClientHello::Incomplete);
+        assert_eq!(
+            parse_client_hello(b"GET / HTTP/1.1\r\n"),
+            ClientHello::Invalid
+        );
+
+        // record that announces more bytes than received
+        assert_eq!(
+            parse_client_hello(&[0x16, 0x03, 0x01, 0x00, 0x40, 0x01]),
+            ClientHello::Incomplete
+        );
+
+        let client_hello = helper_client_hello("A.example.com");
+        let records = helper_records(&client_hello, 16 * 1024);
+        assert_eq!(
+            parse_client_hello(&records),
+            ClientHello::Complete(Some("a.example.com".to_string()))
+        );
+
+        // The ClientHello fragmented over several records is reassembled
+        let records = helper_records(&client_hello, 10);
+        assert!(records.len() > 5 + client_hello.len());
+        assert_eq!(
+            parse_client_hello(&records[..records.len() - 1]),
+            ClientHello::Incomplete
+        );
+        assert_eq!(
+            parse_client_hello(&records),
+            ClientHello::Complete(Some("a.example.com".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_hello_split_across_reads() {
+        let records = helper_records(&helper_client_hello("a.example.com"), 10);
+        let (mut client, mut server) = tokio::io::duplex(64);
+
+        let sent = records.clone();
+        tokio::spawn(async move {
+            for chunk in sent.chunks(7) {
+                client.write_all(chunk).await.unwrap();
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            client
+        });
+
+        let (read, server_name) = read_client_hello(&mut server).await.unwrap();
+        assert_eq!(read, records);
+        assert_eq!(server_name.as_deref(), Some("a.example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_unhealthy_upstreams_are_not_selected() {
+        let healthy = helper_tcp_backend(0, b"healthy").await;
+        let down = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.local_addr().unwrap()
+        };
+        let (_, app) = helper_passthrough(vec![("a.example.com", vec![healthy, down])]).await;
+
+        let selected = (0..4)
+            .map(|_| app.select_upstream("a.example.com").unwrap())
+            .collect::<Vec<_>>();
+        assert!(selected.contains(&down));
+
+        app.health_service().run_health_checks().await;
+        assert!((0..4).all(|_| app.select_upstream("a.example.com") == Some(healthy)));
+    }
+
+    #[tokio::test]
+    async fn test_tunneled_bytes_are_logged() {
+        let logs = crate::test_support::LogCapture::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_max_level(tracing::Level::DEBUG)
+            .with_writer(logs.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let records = helper_records(&helper_client_hello("a.example.com"), 100);
+        let backend = helper_tcp_backend(records.len() + 4, b"response").await;
+        let (proxy, _) = helper_passthrough(vec![("a.example.com", vec![backend])]).await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client.write_all(&records).await.unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut response = [0; 8];
+        client.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response, b"response");
+        drop(client);
+
+        for _ in 0..50 {
+            if logs.output().contains("tls passthrough connection closed") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let output = logs.output();
+        assert!(
+            output.contains(&format!("bytes_to_upstream={}", records.len() + 4)),
+            "{output}"
+        );
+        assert!(output.contains("bytes_to_downstream=8"), "{output}");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_routes_by_sni_without_terminating_tls() {
+        let backend_a = helper_tls_backend("a.example.com");
+        let backend_b = helper_tls_backend("b.example.com");
+        let (proxy, _) = helper_passthrough(vec![
+            ("a.example.com", vec![backend_a]),
+            ("B.example.com", vec![backend_b]),
+        ])
+        .await;
+
+        let (response_a, response_b, unknown) = tokio::task::spawn_blocking(move || {
+            (
+                helper_tls_client(proxy, "a.example.com").unwrap(),
+                helper_tls_client(proxy, "b.example.com").unwrap(),
+                helper_tls_client(proxy, "unknown.example.com"),
+            )
+        })
+        .await
+        .unwrap();
+
+        // The certificates are the ones from the backends, TLS was terminated there
+        assert_eq!(
+            response_a,
+            ("a.example.com".to_string(), "a.example.com".to_string())
+        );
+        assert_eq!(
+            response_b,
+            ("b.example.com".to_string(), "b.example.com".to_string())
+        );
+
+        // Unknown SNIs are closed without reaching any backend
+        assert!(unknown.is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use http::{header, HeaderMap, HeaderName};
+use pingora::http::ResponseHeader;
+
+/// Removes the `strip` trailers from the trailers of an upstream response
+pub fn strip_trailers(trailers: &mut HeaderMap, strip: &[HeaderName]) {
+    for name in strip {
+        trailers.remove(name);
+    }
+}
+
+/// Removes the `strip` trailers from the `Trailer` header of a response,
+/// which announces the trailers sent after the body
+pub fn strip_announced_trailers(response: &mut ResponseHeader, strip: &[HeaderName]) {
+    if strip.is_empty() || !response.headers.contains_key(header::TRAILER) {
+        return;
+    }
+
+    let announced = response
+        .headers
+        .get_all(header::TRAILER)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .filter(|name| {
+            !strip
+                .iter()
+                .any(|strip| strip.as_str().eq_ignore_ascii_case(name))
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    response.remove_header(&header::TRAILER);
+    if !announced.is_empty() {
+        response.insert_header(header::TRAILER, announced).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{add_route, route, TestBackend, TestProxy};
+
+    const TRAILERS: &[(&str, &str)] = &[("grpc-status", "0"), ("grpc-message", "done")];
+
+    fn helper_h2c_route(host: &str, backend: &TestBackend) -> crate::config::Route {
+        let mut route = route(host, [backend.addr()]);
+        route.upstreams[0].h2c = true;
+        route
+    }
+
+    #[tokio::test]
+    async fn test_response_trailers_reach_the_client() {
+        let backend = TestBackend::start_h2c(200, "hello", TRAILERS).await;
+        add_route(helper_h2c_route("forwarded.trailers.test", &backend)).await;
+
+        let proxy = TestProxy::start().await;
+        let (status, body, trailers) = proxy.get_h2c("forwarded.trailers.test", "/").await;
+
+        assert_eq!(status, 200);
+        assert_eq!(body, "hello");
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+        assert_eq!(trailers.get("grpc-message").unwrap(), "done");
+    }
+
+    #[tokio::test]
+    async fn test_configured_trailers_are_stripped() {
+        let backend = TestBackend::start_h2c(200, "hello", TRAILERS).await;
+        let mut route = helper_h2c_route("stripped.trailers.test", &backend);
+        route.trailers = Some(crate::config::RouteTrailers {
+            strip: vec!["grpc-message".into()],
+        });
+        add_route(route).await;
+
+        let proxy = TestProxy::start().await;
+        let (status, _, trailers) = proxy.get_h2c("stripped.trailers.test", "/").await;
+
+        assert_eq!(status, 200);
+        assert_eq!(trailers.get("grpc-status").unwrap(), "0");
+        assert!(trailers.get("grpc-message").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_requests_announcing_trailers_are_forwarded_without_them() {
+        let backend = TestBackend::start_echo_headers().await;
+        // The `Trailer` header is removed even if the hop-by-hop headers are kept
+        let mut route = route("request.trailers.test", [backend.addr()]);
+        route.headers = Some(crate::config::RouteHeader {
+            add: None,
+            remove: None,
+            hop_by_hop: Some(crate::config::HopByHopHeaders::Preserve),
+        });
+        add_route(route).await;
+
+        let proxy = TestProxy::start().await;
+        let response = proxy
+            .request(reqwest::Method::POST, "request.trailers.test", "/")
+            .header("trailer", "x-checksum")
+            .body("hello")
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(response.status().as_u16(), 200);
+        let headers = response.text().await.unwrap();
+        assert!(headers.contains("content-length: 5\n"));
+        assert!(!headers.lines().any(|line| line.starts_with("trailer:")));
+    }
+
+    #[test]
+    fn test_stripped_trailers_are_no_longer_announced() {
+        let strip = [HeaderName::from_static("grpc-message")];
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response
+            .insert_header("trailer", "grpc-status, Grpc-Message")
+            .unwrap();
+        strip_announced_trailers(&mut response, &strip);
+        assert_eq!(response.headers.get("trailer").unwrap(), "grpc-status");
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header("trailer", "grpc-message").unwrap();
+        strip_announced_trailers(&mut response, &strip);
+        assert!(response.headers.get("trailer").is_none());
+    }
+}
@@ -0,0 +1,287 @@
+use std::net::{SocketAddr, ToSocketAddrs};
+
+use bytes::Bytes;
+use pingora::{http::ResponseHeader, proxy::Session, Error, ErrorSource, ErrorType};
+
+use super::server_header::respond_error_with_body;
+
+/// Error type used when the address of an upstream can't be resolved
+pub const DNS_FAILURE: ErrorType = ErrorType::new("DNSFailure");
+
+/// Error type used when the response headers of an upstream are too large
+/// (see `max_upstream_header_size`)
+pub const OVERSIZED_HEADERS: ErrorType = ErrorType::new("OversizedHeaders");
+
+/// The body sent to the client when an upstream fails
+const BAD_GATEWAY_BODY: &str = "502 Bad Gateway\n";
+
+/// Why a request couldn't be proxied to an upstream, logged as the `cause` field
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamErrorCause {
+    ConnectRefused,
+    ConnectTimeout,
+    NoRoute,
+    DnsFailure,
+    TlsHandshake,
+    InvalidCertificate,
+    ReadTimeout,
+    WriteTimeout,
+    ConnectionClosed,
+    InvalidResponse,
+    OversizedHeaders,
+    Other,
+}
+
+impl UpstreamErrorCause {
+    /// The cause of an upstream error, `None` if the error doesn't come from an upstream
+    pub fn from_error(error: &Error) -> Option<Self> {
+        if error.esource() != &ErrorSource::Upstream {
+            return None;
+        }
+
+        let cause = match error.etype() {
+            ErrorType::ConnectRefused => Self::ConnectRefused,
+            ErrorType::ConnectTimedout => Self::ConnectTimeout,
+            ErrorType::ConnectNoRoute => Self::NoRoute,
+            ErrorType::TLSHandshakeFailure
+            | ErrorType::TLSHandshakeTimedout
+            | ErrorType::TLSWantX509Lookup
+            | ErrorType::HandshakeError => Self::TlsHandshake,
+            ErrorType::InvalidCert => Self::InvalidCertificate,
+            ErrorType::ReadTimedout => Self::ReadTimeout,
+            ErrorType::WriteTimedout => Self::WriteTimeout,
+            ErrorType::ConnectionClosed | ErrorType::ReadError | ErrorType::WriteError => {
+                Self::ConnectionClosed
+            }
+            ErrorType::InvalidHTTPHeader
+            | ErrorType::H1Error
+            | ErrorType::H2Error
+            | ErrorType::InvalidH2 => Self::InvalidResponse,
+            etype if *etype == DNS_FAILURE => Self::DnsFailure,
+            etype if *etype == OVERSIZED_HEADERS => Self::OversizedHeaders,
+            _ => Self::Other,
+        };
+
+        Some(cause)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::ConnectRefused => "connect_refused",
+            Self::ConnectTimeout => "connect_timeout",
+            Self::NoRoute => "no_route",
+            Self::DnsFailure => "dns_failure",
+            Self::TlsHandshake => "tls_handshake",
+            Self::InvalidCertificate => "invalid_certificate",
+            Self::ReadTimeout => "read_timeout",
+            Self::WriteTimeout => "write_timeout",
+            Self::ConnectionClosed => "connection_closed",
+            Self::InvalidResponse => "invalid_response",
+            Self::OversizedHeaders => "oversized_headers",
+            Self::Other => "other",
+        }
+    }
+}
+
+/// Resolves the address of an upstream, failing with a `DNS_FAILURE` upstream error
+pub fn resolve_upstream(ip: &str, port: u16) -> pingora::Result<Vec<SocketAddr>> {
+    format!("{ip}:{port}")
+        .to_socket_addrs()
+        .map(Iterator::collect)
+        .map_err(|err| {
+            Error::create(
+                DNS_FAILURE,
+                ErrorSource::Upstream,
+                Some(format!("failed to resolve upstream {ip}:{port}").into()),
+                Some(Box::new(err)),
+            )
+        })
+}
+
+/// The size of the response headers as sent on the wire (HTTP/1)
+fn header_size(response: &ResponseHeader) -> usize {
+    response
+        .headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + ": \r\n".len())
+        .sum()
+}
+
+/// Fails with an `OVERSIZED_HEADERS` upstream error if the headers of the
+/// response of `peer` are larger than `max_size`
+pub fn check_header_size(
+    response: &ResponseHeader,
+    max_size: Option<usize>,
+    peer: Option<&str>,
+) -> pingora::Result<()> {
+    let Some(max_size) = max_size else {
+        return Ok(());
+    };
+
+    let size = header_size(response);
+    if size <= max_size {
+        return Ok(());
+    }
+
+    Err(Error::create(
+        OVERSIZED_HEADERS,
+        ErrorSource::Upstream,
+        Some(
+            format!(
+                "the response headers of upstream {} are {size} bytes, over max_upstream_header_size ({max_size})",
+                peer.unwrap_or("unknown")
+            )
+            .into(),
+        ),
+        None,
+    ))
+}
+
+/// Logs an upstream failure with its cause and answers the client with a 502.
+/// Returns `None` (and does nothing) if the error doesn't come from an upstream.
+pub async fn respond_upstream_error(
+    session: &mut Session,
+    error: &Error,
+    host: &str,
+) -> Option<UpstreamErrorCause> {
+    let cause = UpstreamErrorCause::from_error(error)?;
+
+    tracing::error!(
+        host,
+        cause = cause.as_str(),
+        error = error.to_string(),
+        "upstream request failed"
+    );
+
+    if let Err(err) = respond_error_with_body(
+        session,
+        502,
+        Bytes::from_static(BAD_GATEWAY_BODY.as_bytes()),
+    )
+    .await
+    {
+        tracing::error!("failed to send error response to downstream: {err}");
+    }
+
+    Some(cause)
+}
+
+#[cfg(test)]
+mod tests {
+    use pingora::{connectors::TransportConnector, upstreams::peer::HttpPeer};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::test_support::LogCapture;
+
+    /// Answers a request with the given error, returns the raw response and the logs
+    async fn helper_respond(error: &Error) -> (String, String) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut client = tokio::net::TcpStream::connect(addr).await.unwrap();
+            client
+                .write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .await
+                .unwrap();
+
+            let mut response = vec![0; 1024];
+            let read = client.read(&mut response).await.unwrap();
+            String::from_utf8_lossy(&response[..read]).to_string()
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut session = Session::new_h1(Box::new(pingora::protocols::l4::stream::Stream::from(
+            stream,
+        )));
+        assert!(session.read_request().await.unwrap());
+
+        let writer = LogCapture::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        respond_upstream_error(&mut session, error, "example.com")
+            .await
+            .unwrap();
+
+        let logs = writer.output();
+        (client.await.unwrap(), logs)
+    }
+
+    #[tokio::test]
+    async fn test_connect_refused_responds_502() {
+        // Nothing listens on the port once the listener is dropped
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let peer = HttpPeer::new(addr, false, String::new());
+        let error = TransportConnector::new(None)
+            .new_stream(&peer)
+            .await
+            .unwrap_err()
+            .into_up();
+
+        assert_eq!(
+            UpstreamErrorCause::from_error(&error),
+            Some(UpstreamErrorCause::ConnectRefused)
+        );
+
+        let (response, logs) = helper_respond(&error).await;
+        assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+        assert!(response.ends_with(BAD_GATEWAY_BODY), "{response}");
+        assert!(logs.contains("cause=\"connect_refused\""), "{logs}");
+        assert!(logs.contains("upstream request failed"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn test_dns_failure_responds_502() {
+        let error = resolve_upstream("upstream.invalid", 80).unwrap_err();
+
+        assert_eq!(
+            UpstreamErrorCause::from_error(&error),
+            Some(UpstreamErrorCause::DnsFailure)
+        );
+
+        let (response, logs) = helper_respond(&error).await;
+        assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+        assert!(logs.contains("cause=\"dns_failure\""), "{logs}");
+        assert!(logs.contains("upstream.invalid:80"), "{logs}");
+    }
+
+    #[tokio::test]
+    async fn test_oversized_headers_respond_502() {
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response.insert_header("x-large", "a".repeat(100)).unwrap();
+
+        assert!(check_header_size(&response, None, None).is_ok());
+        assert!(check_header_size(&response, Some(1024), None).is_ok());
+
+        let error = check_header_size(&response, Some(64), Some("10.0.0.1:80")).unwrap_err();
+        assert_eq!(
+            UpstreamErrorCause::from_error(&error),
+            Some(UpstreamErrorCause::OversizedHeaders)
+        );
+
+        let (response, logs) = helper_respond(&error).await;
+        assert!(response.starts_with("HTTP/1.1 502"), "{response}");
+        assert!(logs.contains("cause=\"oversized_headers\""), "{logs}");
+        assert!(logs.contains("10.0.0.1:80"), "{logs}");
+    }
+
+    #[test]
+    fn test_non_upstream_errors_have_no_cause() {
+        let error = Error::new(ErrorType::HTTPStatus(503));
+        assert_eq!(UpstreamErrorCause::from_error(&error), None);
+
+        let error = Error::new_up(ErrorType::TLSHandshakeFailure);
+        assert_eq!(
+            UpstreamErrorCause::from_error(&error),
+            Some(UpstreamErrorCause::TlsHandshake)
+        );
+    }
+}
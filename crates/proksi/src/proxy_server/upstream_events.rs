@@ -0,0 +1,179 @@
+use crate::stores::routes::{UpstreamSelection, UpstreamSelectionReason};
+
+use super::upstream_error::UpstreamErrorCause;
+
+/// How the upstream of a request changed from the one the algorithm picked
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpstreamEventKind {
+    /// The previous attempt failed, the request is sent again
+    Retry,
+    /// The backend picked by the algorithm was unavailable, another one was used
+    Fallback,
+    /// No backend of the higher priority tiers was available
+    Failover,
+}
+
+impl UpstreamEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Retry => "upstream_retry",
+            Self::Fallback => "upstream_fallback",
+            Self::Failover => "upstream_failover",
+        }
+    }
+}
+
+/// A change of upstream during a request, logged as a distinct event for the
+/// routes with `log_upstream_events` enabled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamEvent<'a> {
+    pub kind: UpstreamEventKind,
+    /// The attempt the backend was selected for, starting at 1
+    pub attempt: usize,
+    /// The backend of the previous attempt (retries only)
+    pub from_backend: Option<&'a str>,
+    pub to_backend: &'a str,
+    pub reason: &'static str,
+    /// The priority tier of the selected backend
+    pub tier: usize,
+}
+
+impl<'a> UpstreamEvent<'a> {
+    /// The event of the selection of `next` for the `attempt` of a request,
+    /// `previous` is the selection of the attempt that failed with `failure`.
+    /// `None` when the backend picked by the algorithm is used right away.
+    pub fn new(
+        attempt: usize,
+        previous: Option<&'a UpstreamSelection>,
+        next: &'a UpstreamSelection,
+        failure: Option<UpstreamErrorCause>,
+    ) -> Option<Self> {
+        let (kind, reason) = match (previous, next.reason) {
+            (Some(_), _) | (None, UpstreamSelectionReason::Retry) => (
+                UpstreamEventKind::Retry,
+                failure.unwrap_or(UpstreamErrorCause::Other).as_str(),
+            ),
+            (None, _) if next.tier > 0 => (UpstreamEventKind::Failover, "tier_unavailable"),
+            (None, UpstreamSelectionReason::Fallback) => {
+                (UpstreamEventKind::Fallback, "backend_unavailable")
+            }
+            (None, UpstreamSelectionReason::Balanced) => return None,
+        };
+
+        Some(Self {
+            kind,
+            attempt,
+            from_backend: previous.map(|selection| selection.backend.as_str()),
+            to_backend: &next.backend,
+            reason,
+            tier: next.tier,
+        })
+    }
+
+    pub fn log(&self, host: &str) {
+        tracing::warn!(
+            upstream_event = self.kind.as_str(),
+            host,
+            attempt = self.attempt,
+            from_backend = self.from_backend,
+            to_backend = self.to_backend,
+            reason = self.reason,
+            tier = self.tier,
+            "upstream changed during the request"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::RouteRetry,
+        test_support::{add_route, route, LogCapture, TestBackend, TestProxy},
+    };
+
+    fn helper_selection(backend: &str, reason: UpstreamSelectionReason) -> UpstreamSelection {
+        UpstreamSelection {
+            backend: backend.to_string(),
+            weight: 1,
+            algorithm: "round_robin",
+            reason,
+            tier: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retried_request_emits_a_retry_event() {
+        let backends = [
+            TestBackend::start_failing().await,
+            TestBackend::start_failing().await,
+        ];
+        let host = "retried.upstream-events.test";
+        let mut retried = route(host, backends.iter().map(TestBackend::addr));
+        retried.log_upstream_events = true;
+        retried.retry = Some(RouteRetry {
+            attempts: 1,
+            ..RouteRetry::default()
+        });
+        add_route(retried).await;
+
+        let writer = LogCapture::default();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(writer.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let proxy = TestProxy::start().await;
+        let (status, _) = proxy.get(host, "/").await;
+        assert_eq!(status, 502);
+
+        let events = writer
+            .output()
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap())
+            .filter(|log| log["fields"]["upstream_event"].is_string())
+            .collect::<Vec<_>>();
+        assert_eq!(events.len(), 1, "{events:?}");
+
+        // The retry moves from the backend that failed to the other one
+        let fields = &events[0]["fields"];
+        assert_eq!(fields["upstream_event"], "upstream_retry");
+        assert_eq!(fields["host"], host);
+        assert_eq!(fields["attempt"], 2);
+        assert_eq!(fields["reason"], "connection_closed");
+        let mut moved = [&fields["from_backend"], &fields["to_backend"]]
+            .map(|backend| backend.as_str().unwrap().to_string());
+        moved.sort();
+        let mut addrs = backends
+            .iter()
+            .map(|backend| backend.addr().to_string())
+            .collect::<Vec<_>>();
+        addrs.sort();
+        assert_eq!(moved.to_vec(), addrs);
+    }
+
+    #[test]
+    fn test_fallback_and_failover_events() {
+        let fallback = helper_selection("10.0.0.2:80", UpstreamSelectionReason::Fallback);
+        let event = UpstreamEvent::new(1, None, &fallback, None).unwrap();
+        assert_eq!(event.kind, UpstreamEventKind::Fallback);
+        assert_eq!(event.from_backend, None);
+        assert_eq!(event.reason, "backend_unavailable");
+
+        let failover = UpstreamSelection {
+            tier: 1,
+            ..fallback.clone()
+        };
+        let event = UpstreamEvent::new(1, None, &failover, None).unwrap();
+        assert_eq!(event.kind, UpstreamEventKind::Failover);
+        assert_eq!(event.tier, 1);
+        assert_eq!(event.reason, "tier_unavailable");
+
+        // A retry is reported as such, even when it fails over
+        let previous = helper_selection("10.0.0.1:80", UpstreamSelectionReason::Balanced);
+        let event = UpstreamEvent::new(2, Some(&previous), &failover, None).unwrap();
+        assert_eq!(event.kind, UpstreamEventKind::Retry);
+        assert_eq!(event.reason, "other");
+    }
+}
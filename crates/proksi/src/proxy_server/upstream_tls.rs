@@ -0,0 +1,282 @@
+use openssl::{
+    asn1::Asn1Time,
+    hash::MessageDigest,
+    pkey::Id,
+    x509::{X509Ref, X509},
+};
+use pingora::{
+    connectors::TransportConnector,
+    upstreams::peer::{HttpPeer, Peer},
+    ErrorType,
+};
+
+use crate::config::UpstreamTls;
+
+/// The outcome of checking an upstream certificate against the `upstream_tls` settings
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CertCheck {
+    Valid,
+    /// The certificate is valid but expires in `days_left` days
+    Expiring {
+        days_left: i32,
+    },
+    /// The certificate must not be used
+    Rejected(String),
+}
+
+/// Checks the certificates of HTTPS upstreams (key size, expiration).
+///
+/// Pingora doesn't expose the upstream certificate of a connection, only its digest.
+/// The first time a certificate (digest) is seen, the upstream is probed with a new
+/// connection to inspect the certificate, and the outcome is kept for that digest.
+/// Warnings are therefore only logged once per certificate.
+pub struct UpstreamCertChecker {
+    min_key_bits: Option<u32>,
+    warn_expiry_days: Option<u32>,
+    connector: TransportConnector,
+    /// Certificate digest -> rejection reason (if rejected)
+    checked: papaya::HashMap<Vec<u8>, Option<String>>,
+}
+
+impl UpstreamCertChecker {
+    pub fn new(config: &UpstreamTls) -> Self {
+        Self {
+            min_key_bits: config.min_key_bits,
+            warn_expiry_days: config.warn_expiry_days,
+            connector: TransportConnector::new(None),
+            checked: papaya::HashMap::new(),
+        }
+    }
+
+    /// Whether there's anything to check at all
+    pub fn is_enabled(&self) -> bool {
+        self.min_key_bits.is_some() || self.warn_expiry_days.is_some()
+    }
+
+    /// Checks a certificate against the settings
+    pub fn check(&self, cert: &X509Ref) -> CertCheck {
+        if let Some(min_key_bits) = self.min_key_bits {
+            let Ok(key) = cert.public_key() else {
+                return CertCheck::Rejected("the certificate public key is invalid".to_string());
+            };
+
+            // EC keys are much smaller for the same strength, only RSA/DSA are compared
+            if matches!(key.id(), Id::RSA | Id::DSA) && key.bits() < min_key_bits {
+                return CertCheck::Rejected(format!(
+                    "the certificate key has {} bits, the minimum is {min_key_bits}",
+                    key.bits()
+                ));
+            }
+        }
+
+        if let Some(warn_expiry_days) = self.warn_expiry_days {
+            let days_left = Asn1Time::days_from_now(0)
+                .and_then(|now| now.diff(cert.not_after()))
+                .map(|diff| diff.days);
+
+            if let Ok(days_left) = days_left {
+                if i64::from(days_left) < i64::from(warn_expiry_days) {
+                    return CertCheck::Expiring { days_left };
+                }
+            }
+        }
+
+        CertCheck::Valid
+    }
+
+    /// Checks the certificate (identified by `cert_digest`) of a new connection to `peer`.
+    /// Fails if the certificate is rejected, expiring certificates only log a warning.
+    pub async fn check_connection(
+        &self,
+        peer: &HttpPeer,
+        cert_digest: &[u8],
+    ) -> pingora::Result<()> {
+        // The map guard can't be held across the probe
+        let checked = self.checked.pin().get(cert_digest).cloned();
+        let reason = match checked {
+            Some(reason) => reason,
+            None => {
+                let (digest, reason) = self.probe(peer).await?;
+                let checked = self.checked.pin();
+                checked.insert(cert_digest.to_vec(), reason.clone());
+                checked.insert(digest, reason.clone());
+                reason
+            }
+        };
+
+        match reason {
+            Some(reason) => Err(pingora::Error::explain(
+                ErrorType::InvalidCert,
+                format!("upstream {} certificate rejected: {reason}", peer.address()),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Connects to the upstream to inspect its certificate.
+    /// Returns the certificate digest and the rejection reason (if rejected).
+    async fn probe(&self, peer: &HttpPeer) -> pingora::Result<(Vec<u8>, Option<String>)> {
+        let stream = self.connector.new_stream(peer).await?;
+        let cert = stream
+            .get_ssl()
+            .and_then(|ssl| ssl.peer_certificate())
+            .ok_or_else(|| {
+                pingora::Error::explain(ErrorType::InvalidCert, "upstream sent no certificate")
+            })?;
+
+        Ok((cert_digest(&cert), self.report(peer, &cert)))
+    }
+
+    fn report(&self, peer: &HttpPeer, cert: &X509Ref) -> Option<String> {
+        match self.check(cert) {
+            CertCheck::Valid => None,
+            CertCheck::Expiring { days_left } => {
+                tracing::warn!(
+                    upstream = peer.address().to_string(),
+                    sni = peer.sni(),
+                    days_left,
+                    "upstream certificate expires soon"
+                );
+                None
+            }
+            CertCheck::Rejected(reason) => {
+                tracing::error!(
+                    upstream = peer.address().to_string(),
+                    sni = peer.sni(),
+                    reason,
+                    "upstream certificate rejected"
+                );
+                Some(reason)
+            }
+        }
+    }
+}
+
+/// The digest pingora uses to identify the certificate of a connection
+fn cert_digest(cert: &X509) -> Vec<u8> {
+    cert.digest(MessageDigest::sha256())
+        .map(|digest| digest.as_ref().to_vec())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, net::SocketAddr};
+
+    use openssl::{
+        pkey::{PKey, Private},
+        ssl::{SslAcceptor, SslMethod},
+    };
+
+    use super::*;
+    use crate::test_support::{self_signed_certificate, LogCapture};
+
+    fn helper_checker(
+        min_key_bits: Option<u32>,
+        warn_expiry_days: Option<u32>,
+    ) -> UpstreamCertChecker {
+        UpstreamCertChecker::new(&UpstreamTls {
+            verify: false,
+            min_key_bits,
+            warn_expiry_days,
+        })
+    }
+
+    /// Starts a TLS server using the given certificate
+    fn helper_tls_upstream(cert: &X509, key: &PKey<Private>) -> SocketAddr {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).unwrap();
+        // allows serving weak keys
+        acceptor.set_security_level(1);
+        acceptor.set_certificate(cert).unwrap();
+        acceptor.set_private_key(key).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(mut tls) = acceptor.accept(stream.unwrap()) {
+                    tls.write_all(b"ok").ok();
+                    tls.shutdown().ok();
+                }
+            }
+        });
+
+        addr
+    }
+
+    fn helper_peer(addr: SocketAddr) -> HttpPeer {
+        let mut peer = HttpPeer::new(addr, true, "upstream.test".to_string());
+        // the test certificates are self-signed
+        peer.options.verify_cert = false;
+        peer
+    }
+
+    #[test]
+    fn test_check_certificate() {
+        let checker = helper_checker(Some(2048), Some(14));
+
+        let (weak, _) = self_signed_certificate("upstream.test", 1024, 90);
+        assert!(matches!(checker.check(&weak), CertCheck::Rejected(_)));
+
+        let (expiring, _) = self_signed_certificate("upstream.test", 2048, 3);
+        assert!(matches!(
+            checker.check(&expiring),
+            CertCheck::Expiring { days_left: 2..=3 }
+        ));
+
+        let (valid, _) = self_signed_certificate("upstream.test", 2048, 90);
+        assert_eq!(checker.check(&valid), CertCheck::Valid);
+        assert!(!helper_checker(None, None).is_enabled());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_upstream_with_weak_key() {
+        let (cert, key) = self_signed_certificate("upstream.test", 1024, 90);
+        let addr = helper_tls_upstream(&cert, &key);
+        let checker = helper_checker(Some(2048), None);
+
+        let err = checker
+            .check_connection(&helper_peer(addr), &cert_digest(&cert))
+            .await
+            .unwrap_err();
+        assert_eq!(err.etype(), &ErrorType::InvalidCert);
+
+        // The outcome is kept for the certificate
+        assert!(checker
+            .checked
+            .pin()
+            .get(&cert_digest(&cert))
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_warns_about_expiring_upstream_certificate() {
+        let (cert, key) = self_signed_certificate("upstream.test", 2048, 3);
+        let addr = helper_tls_upstream(&cert, &key);
+        let checker = helper_checker(Some(2048), Some(14));
+
+        let writer = LogCapture::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(writer.clone())
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        // The connection is still allowed
+        checker
+            .check_connection(&helper_peer(addr), &cert_digest(&cert))
+            .await
+            .unwrap();
+
+        let output = writer.output();
+        assert!(output.contains("WARN"), "{output}");
+        assert!(
+            output.contains("upstream certificate expires soon"),
+            "{output}"
+        );
+        assert!(output.contains(&addr.to_string()), "{output}");
+    }
+}
@@ -1 +1,40 @@
+use async_trait::async_trait;
+use pingora::server::{ShutdownSignal, ShutdownSignalWatch};
+use tokio::signal::unix;
 
+use crate::services::logger::LogFlusher;
+
+/// Watches the process signals and coordinates the shutdown of proksi:
+///
+/// - `SIGTERM`: graceful terminate, every service is notified and stops accepting new work.
+///   The logger writes and flushes the remaining logs before returning.
+/// - `SIGINT`: fast shutdown, queued logs are flushed before the process exits.
+/// - `SIGQUIT`: graceful upgrade
+pub struct ProksiShutdownSignal {
+    log_flusher: LogFlusher,
+}
+
+impl ProksiShutdownSignal {
+    pub fn new(log_flusher: LogFlusher) -> Self {
+        Self { log_flusher }
+    }
+}
+
+#[async_trait]
+impl ShutdownSignalWatch for ProksiShutdownSignal {
+    async fn recv(&self) -> ShutdownSignal {
+        let mut graceful_upgrade_signal = unix::signal(unix::SignalKind::quit()).unwrap();
+        let mut graceful_terminate_signal = unix::signal(unix::SignalKind::terminate()).unwrap();
+        let mut fast_shutdown_signal = unix::signal(unix::SignalKind::interrupt()).unwrap();
+
+        tokio::select! {
+            _ = graceful_upgrade_signal.recv() => ShutdownSignal::GracefulUpgrade,
+            _ = graceful_terminate_signal.recv() => ShutdownSignal::GracefulTerminate,
+            _ = fast_shutdown_signal.recv() => {
+                tracing::info!("SIGINT received, flushing logs before exiting");
+                self.log_flusher.flush().await;
+                ShutdownSignal::FastShutdown
+            }
+        }
+    }
+}
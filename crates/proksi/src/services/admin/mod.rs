@@ -0,0 +1,444 @@
+use async_trait::async_trait;
+use http::{header, Method, Response, StatusCode};
+use pingora::{
+    apps::http_app::ServeHttp, protocols::http::ServerSession, services::listening::Service,
+};
+use serde_json::json;
+use tokio::sync::Mutex;
+
+use crate::config::{self, Config, Route};
+use crate::metrics::CONFIG_RELOAD_FAILURES;
+use crate::stores;
+
+use super::discovery::{apply_route_changes, RouteChanges};
+
+/// The admin API, every request must be authenticated with the configured token.
+///
+/// - `POST /reload`: re-reads the configuration file and applies the route changes,
+///   answering with the added/updated/removed hosts.
+/// - `GET /routes`: lists the routes currently served, with their labels.
+pub struct AdminApp {
+    token: String,
+    config_path: String,
+    /// The routes of the configuration currently applied
+    routes: Mutex<Vec<Route>>,
+}
+
+impl AdminApp {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            token: config
+                .admin
+                .token
+                .as_deref()
+                .unwrap_or_default()
+                .to_string(),
+            config_path: config.config_path.to_string(),
+            routes: Mutex::new(config.routes.clone()),
+        }
+    }
+
+    /// Answers an admin request, `authorization` is the value of the `Authorization` header
+    pub async fn handle(
+        &self,
+        method: &Method,
+        path: &str,
+        authorization: Option<&str>,
+    ) -> Response<Vec<u8>> {
+        if !self.is_authorized(authorization) {
+            return json_response(
+                StatusCode::UNAUTHORIZED,
+                &json!({ "error": "unauthorized" }),
+            );
+        }
+
+        match (method, path) {
+            (&Method::POST, "/reload") => match self.reload().await {
+                Ok(changes) => json_response(StatusCode::OK, &json!(changes)),
+                Err(err) => {
+                    CONFIG_RELOAD_FAILURES
+                        .with_label_values(&["admin_api"])
+                        .inc();
+                    tracing::error!(
+                        config_reload_failed = true,
+                        "failed to reload configuration, keeping the current one: {err}"
+                    );
+                    json_response(
+                        StatusCode::UNPROCESSABLE_ENTITY,
+                        &json!({ "error": err.to_string() }),
+                    )
+                }
+            },
+            (&Method::GET, "/routes") => json_response(StatusCode::OK, &list_routes()),
+            (_, "/reload" | "/routes") => json_response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                &json!({ "error": "method not allowed" }),
+            ),
+            _ => json_response(StatusCode::NOT_FOUND, &json!({ "error": "not found" })),
+        }
+    }
+
+    /// Loads the configuration file again and applies the route changes.
+    /// Nothing is applied if the configuration is invalid.
+    async fn reload(&self) -> Result<RouteChanges, anyhow::Error> {
+        // Holding the lock for the whole reload prevents concurrent reloads
+        let mut routes = self.routes.lock().await;
+
+        let config = config::load(&self.config_path)?;
+        let changes = apply_route_changes(&routes, &config.routes).await;
+
+        // Refused updates are still pending, the next reload tries them again
+        let mut next_routes = config.routes;
+        for route in &mut next_routes {
+            let key = stores::route_key(&route.host, route.listener_port);
+            if changes.refused.contains(&key) {
+                if let Some(previous) = routes
+                    .iter()
+                    .find(|v| v.host == route.host && v.listener_port == route.listener_port)
+                {
+                    route.clone_from(previous);
+                }
+            }
+        }
+        // Refused removals are kept as well
+        let refused_removals = routes
+            .iter()
+            .filter(|route| {
+                changes
+                    .refused
+                    .contains(&stores::route_key(&route.host, route.listener_port))
+                    && next_routes
+                        .iter()
+                        .all(|v| v.host != route.host || v.listener_port != route.listener_port)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        next_routes.extend(refused_removals);
+        *routes = next_routes;
+
+        tracing::info!(
+            added = ?changes.added,
+            updated = ?changes.updated,
+            removed = ?changes.removed,
+            refused = ?changes.refused,
+            "configuration reloaded"
+        );
+
+        Ok(changes)
+    }
+
+    fn is_authorized(&self, authorization: Option<&str>) -> bool {
+        let Some(token) = authorization.and_then(|v| v.strip_prefix("Bearer ")) else {
+            return false;
+        };
+
+        !self.token.is_empty()
+            && token.len() == self.token.len()
+            && openssl::memcmp::eq(token.as_bytes(), self.token.as_bytes())
+    }
+}
+
+#[async_trait]
+impl ServeHttp for AdminApp {
+    async fn response(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        let request = http_session.req_header();
+        let method = request.method.clone();
+        let path = request.uri.path().to_string();
+        let authorization = request
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .map(ToString::to_string);
+
+        self.handle(&method, &path, authorization.as_deref()).await
+    }
+}
+
+/// The routes of the route store (configuration and discovered), sorted by host
+fn list_routes() -> serde_json::Value {
+    let mut routes = stores::get_routes()
+        .iter()
+        .map(|(host, container)| (host.clone(), container.labels.clone()))
+        .collect::<Vec<_>>();
+    routes.sort_by(|a, b| a.0.cmp(&b.0));
+
+    routes
+        .into_iter()
+        .map(|(host, labels)| json!({ "host": host, "labels": labels }))
+        .collect()
+}
+
+fn json_response(status: StatusCode, body: &serde_json::Value) -> Response<Vec<u8>> {
+    let body = body.to_string().into_bytes();
+
+    Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap_or_default()
+}
+
+/// Creates the admin API service if an address is configured
+pub fn admin_service(config: &Config) -> Option<Service<AdminApp>> {
+    let address = config.admin.address.as_ref()?;
+
+    let mut service = Service::new("admin_api".to_string(), AdminApp::new(config));
+    service.add_tcp(address);
+    Some(service)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::*;
+
+    fn helper_config(dir: &str) -> Config {
+        let mut config = config::load(dir).unwrap();
+        config.config_path = Cow::Owned(dir.to_string());
+        config.admin.token = Some(Cow::Borrowed("secret"));
+        config
+    }
+
+    fn helper_body(response: &Response<Vec<u8>>) -> serde_json::Value {
+        serde_json::from_slice(response.body()).unwrap()
+    }
+
+    #[test]
+    fn test_requires_token() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy().to_string();
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                "#,
+            )?;
+
+            let app = AdminApp::new(&helper_config(&tmp_dir));
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+
+            runtime.block_on(async {
+                for authorization in [None, Some("Bearer wrong"), Some("secret")] {
+                    let response = app.handle(&Method::POST, "/reload", authorization).await;
+                    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+                }
+
+                let response = app
+                    .handle(&Method::GET, "/reload", Some("Bearer secret"))
+                    .await;
+                assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+            });
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_reload_applies_config_changes() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy().to_string();
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "kept.admin.test"
+                        upstreams = [{ ip = "127.0.0.1", port = 3000 }]
+                    },
+                    {
+                        host = "updated.admin.test"
+                        upstreams = [{ ip = "127.0.0.1", port = 3001 }]
+                    },
+                    {
+                        host = "removed.admin.test"
+                        upstreams = [{ ip = "127.0.0.1", port = 3002 }]
+                    }
+                ]
+                "#,
+            )?;
+
+            let config = helper_config(&tmp_dir);
+            let app = AdminApp::new(&config);
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+
+            runtime.block_on(async {
+                // Routes currently applied
+                apply_route_changes(&[], &config.routes).await;
+            });
+            assert!(stores::get_route_by_key("removed.admin.test").is_some());
+
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "kept.admin.test"
+                        upstreams = [{ ip = "127.0.0.1", port = 3000 }]
+                    },
+                    {
+                        host = "updated.admin.test"
+                        upstreams = [{ ip = "127.0.0.1", port = 4001 }]
+                    },
+                    {
+                        host = "added.admin.test"
+                        upstreams = [{ ip = "127.0.0.1", port = 3003 }]
+                    }
+                ]
+                "#,
+            )?;
+
+            let response =
+                runtime.block_on(app.handle(&Method::POST, "/reload", Some("Bearer secret")));
+
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(
+                helper_body(&response),
+                json!({
+                    "added": ["added.admin.test"],
+                    "updated": ["updated.admin.test"],
+                    "removed": ["removed.admin.test"],
+                })
+            );
+
+            assert!(stores::get_route_by_key("added.admin.test").is_some());
+            assert!(stores::get_route_by_key("removed.admin.test").is_none());
+            let updated = stores::get_route_by_key("updated.admin.test").unwrap();
+            assert_eq!(updated.upstreams[0].port, 4001);
+
+            // Nothing changed since the last reload
+            let response =
+                runtime.block_on(app.handle(&Method::POST, "/reload", Some("Bearer secret")));
+            assert_eq!(
+                helper_body(&response),
+                json!({ "added": [], "updated": [], "removed": [] })
+            );
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_the_current_routes() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy().to_string();
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "intact.admin.test"
+                        upstreams = [{ ip = "127.0.0.1", port = 3000 }]
+                    }
+                ]
+                "#,
+            )?;
+
+            let config = helper_config(&tmp_dir);
+            let app = AdminApp::new(&config);
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            runtime.block_on(apply_route_changes(&[], &config.routes));
+
+            let failures = CONFIG_RELOAD_FAILURES.with_label_values(&["admin_api"]);
+            let failures_before = failures.get();
+
+            // The new routes are valid but not the configuration (default email)
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@example.com"
+                }
+                routes = [
+                    {
+                        host = "intact.admin.test"
+                        upstreams = [{ ip = "127.0.0.1", port = 4000 }]
+                    },
+                    {
+                        host = "never-added.admin.test"
+                        upstreams = [{ ip = "127.0.0.1", port = 3001 }]
+                    }
+                ]
+                "#,
+            )?;
+
+            let response =
+                runtime.block_on(app.handle(&Method::POST, "/reload", Some("Bearer secret")));
+            assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+            assert_eq!(failures.get(), failures_before + 1);
+
+            // Nothing of the new configuration was applied
+            assert!(stores::get_route_by_key("never-added.admin.test").is_none());
+            let intact = stores::get_route_by_key("intact.admin.test").unwrap();
+            assert_eq!(intact.upstreams[0].port, 3000);
+            let routes = runtime.block_on(app.routes.lock());
+            assert_eq!(routes.len(), 1);
+            assert_eq!(routes[0].upstreams[0].port, 3000);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_list_routes_with_labels() {
+        figment::Jail::expect_with(|jail| {
+            let tmp_dir = jail.directory().to_string_lossy().to_string();
+            jail.create_file(
+                format!("{}/proksi.hcl", tmp_dir),
+                r#"
+                lets_encrypt {
+                    email = "domain@valid.com"
+                }
+                routes = [
+                    {
+                        host = "labeled.admin.test"
+                        upstreams = [{ ip = "127.0.0.1", port = 3000 }]
+                        labels = {
+                            team = "payments"
+                            tier = "critical"
+                        }
+                    }
+                ]
+                "#,
+            )?;
+
+            let config = helper_config(&tmp_dir);
+            let app = AdminApp::new(&config);
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+
+            let response = runtime.block_on(async {
+                apply_route_changes(&[], &config.routes).await;
+                app.handle(&Method::GET, "/routes", Some("Bearer secret"))
+                    .await
+            });
+
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = helper_body(&response);
+            let route = body
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|route| route["host"] == "labeled.admin.test")
+                .unwrap();
+            assert_eq!(
+                route["labels"],
+                json!({ "team": "payments", "tier": "critical" })
+            );
+
+            Ok(())
+        });
+    }
+}
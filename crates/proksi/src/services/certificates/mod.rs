@@ -0,0 +1,352 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::anyhow;
+use async_trait::async_trait;
+use bytes::Bytes;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+use tokio::sync::broadcast::Sender;
+
+use crate::{
+    config::{Config, Route, RouteSslPath},
+    services::run_until_shutdown,
+    stores::{self, certificates::Certificate},
+    MsgCert, MsgProxy,
+};
+
+/// How often the certificate files of the routes are checked for changes
+const CERTIFICATE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Watches the certificate files of the routes (`ssl.path`) and sends the
+/// certificates that changed to the routing service, which swaps them in the
+/// certificate store. New TLS handshakes are served the new certificate, the
+/// established connections keep the previous one. The routes are the ones of
+/// the router store, the routes added or updated by a reload are watched too.
+pub struct CertificateWatcherService {
+    config: Arc<Config>,
+    broadcast: Sender<MsgProxy>,
+}
+
+impl CertificateWatcherService {
+    pub fn new(config: Arc<Config>, broadcast: Sender<MsgProxy>) -> Self {
+        Self { config, broadcast }
+    }
+}
+
+/// The modification time of the certificate files of every host
+struct CertificateFiles {
+    modified: HashMap<String, (SystemTime, SystemTime)>,
+}
+
+impl CertificateFiles {
+    /// The files as they are now, the certificates loaded on startup are up to date
+    fn new(routes: &[Route]) -> Self {
+        let modified = routes
+            .iter()
+            .filter_map(|route| Some((route.host.to_string(), modified_at(ssl_path(route)?)?)))
+            .collect();
+
+        Self { modified }
+    }
+
+    /// The certificates whose files changed since the last check. A pair that
+    /// can't be loaded (ex: only the certificate was written so far) is
+    /// skipped, it is loaded once the other file changes too. The files of a
+    /// route seen for the first time were just loaded with the route.
+    fn changed<'a>(&mut self, routes: impl IntoIterator<Item = &'a Route>) -> Vec<MsgCert> {
+        routes
+            .into_iter()
+            .filter_map(|route| {
+                let path = ssl_path(route)?;
+                let modified = modified_at(path)?;
+                match self.modified.insert(route.host.to_string(), modified) {
+                    Some(previous) if previous != modified => {}
+                    _ => return None,
+                }
+
+                match read_certificate(path) {
+                    Ok((cert, key)) => Some(MsgCert {
+                        host: route.host.to_string(),
+                        cert,
+                        key,
+                    }),
+                    Err(err) => {
+                        tracing::warn!(host = %route.host, "not reloading the certificate: {err}");
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// The configuration of the routes in the router store
+fn store_routes() -> Vec<Arc<Route>> {
+    stores::get_routes()
+        .values()
+        .filter_map(|route_container| route_container.config.clone())
+        .collect()
+}
+
+fn ssl_path(route: &Route) -> Option<&RouteSslPath> {
+    route.ssl.as_ref()?.path.as_ref()
+}
+
+fn modified_at(path: &RouteSslPath) -> Option<(SystemTime, SystemTime)> {
+    let modified = |path: &Path| {
+        std::fs::metadata(path)
+            .and_then(|meta| meta.modified())
+            .ok()
+    };
+
+    Some((modified(&path.pem)?, modified(&path.key)?))
+}
+
+/// Reads the certificate and the private key, refusing an invalid pair
+fn read_certificate(path: &RouteSslPath) -> Result<(Bytes, Bytes), anyhow::Error> {
+    let cert = std::fs::read(&path.pem)
+        .map_err(|err| anyhow!("failed to read certificate {:?}: {err}", path.pem))?;
+    let key = std::fs::read(&path.key)
+        .map_err(|err| anyhow!("failed to read private key {:?}: {err}", path.key))?;
+
+    Certificate::from_pem(&key, &cert).map_err(|err| anyhow!("{err}"))?;
+
+    Ok((cert.into(), key.into()))
+}
+
+/// Loads the certificate files of a route (`ssl.path`) into the certificate
+/// store, the same way as when they change
+pub async fn add_route_ssl_to_store(route: &Route) -> Result<(), anyhow::Error> {
+    let Some(path) = ssl_path(route) else {
+        return Ok(());
+    };
+
+    let (cert, key) = read_certificate(path)?;
+    update_certificate(&MsgCert {
+        host: route.host.to_string(),
+        cert,
+        key,
+    })
+    .await
+}
+
+/// Swaps the certificate of `cert.host` in the certificate store
+pub async fn update_certificate(cert: &MsgCert) -> Result<(), anyhow::Error> {
+    let certificate = Certificate::from_pem(&cert.key, &cert.cert)
+        .map_err(|err| anyhow!("invalid certificate for {}: {err}", cert.host))?;
+
+    stores::global::get_store()
+        .set_certificate(&cert.host, certificate)
+        .await
+        .map_err(|err| anyhow!("failed to store the certificate of {}: {err}", cert.host))
+}
+
+#[async_trait]
+impl Service for CertificateWatcherService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        let mut files = CertificateFiles::new(&self.config.routes);
+        tracing::info!("starting certificate watcher service");
+
+        let mut interval = tokio::time::interval(CERTIFICATE_CHECK_INTERVAL);
+        interval.tick().await;
+
+        run_until_shutdown(&mut shutdown, async {
+            loop {
+                interval.tick().await;
+
+                let routes = store_routes();
+                for cert in files.changed(routes.iter().map(AsRef::as_ref)) {
+                    tracing::info!(host = cert.host, "certificate files changed, reloading");
+                    self.broadcast.send(MsgProxy::NewCertificate(cert)).ok();
+                }
+            }
+        })
+        .await;
+    }
+
+    fn name(&self) -> &'static str {
+        "certificate_watcher_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::{
+        config::{ProtoVersion, RouteSsl},
+        test_support::{init_memory_store, self_signed_certificate},
+    };
+
+    /// A self-signed certificate and its private key, PEM encoded
+    fn helper_pem(domain: &str) -> (Vec<u8>, Vec<u8>) {
+        let (cert, key) = self_signed_certificate(domain, 2048, 1);
+        (
+            cert.to_pem().unwrap(),
+            key.private_key_to_pem_pkcs8().unwrap(),
+        )
+    }
+
+    /// Writes `contents` to `path` and moves its modification time forward, so
+    /// that the change is seen whatever the resolution of the file system
+    fn helper_write(path: &Path, contents: &[u8], modified_secs: u64) {
+        std::fs::write(path, contents).unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(modified_secs))
+            .unwrap();
+    }
+
+    fn helper_route(name: &str) -> (Route, PathBuf) {
+        let dir =
+            std::env::temp_dir().join(format!("proksi-certificates-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let route = Route {
+            host: format!("{name}.certificates.test").into(),
+            ssl: Some(RouteSsl {
+                path: Some(RouteSslPath {
+                    key: dir.join("cert.key"),
+                    pem: dir.join("cert.pem"),
+                }),
+                min_proto: ProtoVersion::V1_2,
+                max_proto: ProtoVersion::V1_3,
+                self_signed_fallback: false,
+            }),
+            ..Route::default()
+        };
+
+        (route, dir)
+    }
+
+    #[test]
+    fn test_changed_certificates_are_reloaded() {
+        let (route, dir) = helper_route("changed");
+        let (pem, key) = helper_pem("first");
+        helper_write(&dir.join("cert.pem"), &pem, 1);
+        helper_write(&dir.join("cert.key"), &key, 1);
+
+        let routes = [route];
+        let mut files = CertificateFiles::new(&routes);
+        assert!(files.changed(&routes).is_empty());
+
+        let (pem, key) = helper_pem("second");
+        helper_write(&dir.join("cert.pem"), &pem, 2);
+        helper_write(&dir.join("cert.key"), &key, 2);
+
+        let changed = files.changed(&routes);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].host, "changed.certificates.test");
+        assert_eq!(changed[0].cert, pem);
+        assert_eq!(changed[0].key, key);
+
+        // Until the files change again
+        assert!(files.changed(&routes).is_empty());
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_pair_is_reloaded_once_both_files_changed() {
+        let (route, dir) = helper_route("pair");
+        let (pem, key) = helper_pem("first");
+        helper_write(&dir.join("cert.pem"), &pem, 1);
+        helper_write(&dir.join("cert.key"), &key, 1);
+
+        let routes = [route];
+        let mut files = CertificateFiles::new(&routes);
+
+        // The new certificate doesn't match the current key yet
+        let (pem, key) = helper_pem("second");
+        helper_write(&dir.join("cert.pem"), &pem, 2);
+        assert!(files.changed(&routes).is_empty());
+
+        helper_write(&dir.join("cert.key"), &key, 2);
+        let changed = files.changed(&routes);
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].cert, pem);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[test]
+    fn test_certificates_of_routes_added_later_are_watched() {
+        let (route, dir) = helper_route("added");
+        let (pem, key) = helper_pem("first");
+        helper_write(&dir.join("cert.pem"), &pem, 1);
+        helper_write(&dir.join("cert.key"), &key, 1);
+
+        let routes = [route];
+        let mut files = CertificateFiles::new(&[]);
+        assert!(files.changed(&routes).is_empty());
+
+        let (pem, key) = helper_pem("second");
+        helper_write(&dir.join("cert.pem"), &pem, 2);
+        helper_write(&dir.join("cert.key"), &key, 2);
+        assert_eq!(files.changed(&routes).len(), 1);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_route_certificates_keep_their_chain() {
+        init_memory_store();
+        let (route, dir) = helper_route("chain");
+        let (leaf, key) = self_signed_certificate("leaf", 2048, 1);
+        let (intermediate, _) = self_signed_certificate("intermediate", 2048, 1);
+        let (root, _) = self_signed_certificate("root", 2048, 1);
+        let pem = [leaf, intermediate, root]
+            .iter()
+            .flat_map(|cert| cert.to_pem().unwrap())
+            .collect::<Vec<_>>();
+        helper_write(&dir.join("cert.pem"), &pem, 1);
+        helper_write(
+            &dir.join("cert.key"),
+            &key.private_key_to_pem_pkcs8().unwrap(),
+            1,
+        );
+
+        add_route_ssl_to_store(&route).await.unwrap();
+        let certificate = stores::global::get_store()
+            .get_certificate(&route.host)
+            .await
+            .unwrap();
+        assert_eq!(certificate.chain.len(), 2);
+        std::fs::remove_dir_all(dir).ok();
+    }
+
+    #[tokio::test]
+    async fn test_update_certificate_refuses_invalid_pairs() {
+        init_memory_store();
+        let host = "invalid.certificates.test";
+        let (pem, _) = helper_pem("first");
+        let (_, other_key) = helper_pem("other");
+
+        let cert = MsgCert {
+            host: host.to_string(),
+            cert: pem.into(),
+            key: other_key.into(),
+        };
+        assert!(update_certificate(&cert).await.is_err());
+        assert!(stores::global::get_store()
+            .get_certificate(host)
+            .await
+            .is_none());
+    }
+}
@@ -11,7 +11,11 @@ use pingora::{
     services::Service,
 };
 
-use crate::config::Config;
+use crate::{
+    config::{self, Config},
+    metrics::CONFIG_RELOAD_FAILURES,
+    services::run_until_shutdown,
+};
 
 pub struct FileWatcherService {
     config: Arc<Config>,
@@ -43,9 +47,13 @@ impl FileWatcherService {
     }
 }
 
-pub struct FileWatcherServiceHandler {}
+pub struct FileWatcherServiceHandler {
+    config_path: String,
+}
+
 impl EventHandler for FileWatcherServiceHandler {
-    /// Handles configuration file changes and restarts the server
+    /// Handles configuration file changes and restarts the server, unless the
+    /// new configuration is invalid (the server keeps the current one)
     fn handle_event(&mut self, notif: notify::Result<notify::Event>) {
         let Ok(n) = notif else {
             tracing::error!("error handling auto_reload event: {:?}", notif);
@@ -62,6 +70,18 @@ impl EventHandler for FileWatcherServiceHandler {
             return;
         }
 
+        // Restarting with an invalid configuration would stop the server
+        if let Err(err) = config::load(&self.config_path) {
+            CONFIG_RELOAD_FAILURES
+                .with_label_values(&["file_watcher"])
+                .inc();
+            tracing::error!(
+                config_reload_failed = true,
+                "failed to reload configuration, keeping the current one: {err}"
+            );
+            return;
+        }
+
         let Ok(cmd) = std::env::current_exe() else {
             return;
         };
@@ -90,7 +110,7 @@ impl Service for FileWatcherService {
     async fn start_service(
         &mut self,
         _fds: Option<ListenFds>,
-        _shutdown: ShutdownWatch,
+        mut shutdown: ShutdownWatch,
         _listeners_per_fd: usize,
     ) {
         if self.config.auto_reload.enabled.is_some_and(|v| !v) {
@@ -106,7 +126,9 @@ impl Service for FileWatcherService {
         tracing::info!("starting config watcher service");
 
         let mut watcher = notify::poll::PollWatcher::new(
-            FileWatcherServiceHandler {},
+            FileWatcherServiceHandler {
+                config_path: self.config.config_path.to_string(),
+            },
             notify::Config::default().with_manual_polling(),
         )
         .unwrap();
@@ -124,12 +146,15 @@ impl Service for FileWatcherService {
         ));
         interval.tick().await;
 
-        loop {
-            interval.tick().await;
-            if watcher.poll().is_ok() {
-                tracing::debug!("config watcher service tick");
+        run_until_shutdown(&mut shutdown, async {
+            loop {
+                interval.tick().await;
+                if watcher.poll().is_ok() {
+                    tracing::debug!("config watcher service tick");
+                }
             }
-        }
+        })
+        .await;
     }
 
     fn name(&self) -> &'static str {
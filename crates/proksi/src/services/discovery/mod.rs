@@ -1,27 +1,59 @@
-use std::net::ToSocketAddrs;
-use std::{borrow::Cow, str::FromStr, sync::Arc, time::Duration};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::{
+    borrow::Cow,
+    collections::{BTreeSet, HashMap},
+    str::FromStr,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
 
-use anyhow::anyhow;
 use async_trait::async_trait;
 
+use futures::FutureExt;
+use http::Extensions;
 use http::{HeaderName, HeaderValue};
-use openssl::pkey::PKey;
-use openssl::x509::X509;
-use pingora::lb::{health_check::TcpHealthCheck, selection::RoundRobin, LoadBalancer};
+use pingora::lb::{
+    discovery::Static, health_check::TcpHealthCheck, selection::RoundRobin, Backend, Backends,
+    LoadBalancer,
+};
 use pingora::{
     server::{ListenFds, ShutdownWatch},
     services::Service,
 };
-use tokio::sync::broadcast::Sender;
+use serde::Serialize;
+use tokio::sync::broadcast::{error::RecvError, Sender};
+use tokio::task::AbortHandle;
 
-use crate::config::{Route, RouteCache, RouteUpstream};
+use crate::config::{
+    Route, RouteLastHealthyBackend, RouteSelectionAlgorithm, RouteUpstream, RouteUpstreamAccess,
+};
+use crate::proxy_server::{concurrency::ConcurrencyLimiter, dns::DnsNegativeCache};
+use crate::services::{
+    certificates::{add_route_ssl_to_store, update_certificate},
+    health_check::http_check::HttpHealthCheck,
+    run_until_shutdown,
+};
 use crate::MsgRoute;
 use crate::{
-    config::{Config, RouteHeader, RouteMatcher, RoutePathMatcher, RoutePlugin},
-    stores::{self, routes::RouteStoreContainer},
+    config::{Config, RouteHeader, RouteMatcher, RoutePathMatcher},
+    stores::{
+        self,
+        hash_key::{HashKeyTemplate, DEFAULT_HASH_KEY},
+        routes::{
+            seed_selection, selection_seed, RouteBackendTags, RouteRequiredHeader,
+            RouteStoreContainer, RouteUpstreamGroupRouting, DEFAULT_UPSTREAM_PRIORITY,
+        },
+        smooth_weighted::SmoothWeightedRoundRobin,
+    },
     MsgProxy,
 };
 
+/// The delay before the initial check of a newly added route is run again,
+/// until one of its backends is healthy
+const INITIAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// The delay is doubled after every failed initial check, up to this one
+const MAX_INITIAL_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
 // Service discovery for load balancers
 pub struct RoutingService {
     config: Arc<Config>,
@@ -48,15 +80,7 @@ impl RoutingService {
                 );
             }
 
-            add_route_to_router(
-                &route.host,
-                route.upstreams.clone(),
-                route.match_with.clone(),
-                route.headers.as_ref(),
-                route.plugins.as_ref(),
-                route.cache.as_ref(),
-                self_signed_cert_on_failure.unwrap_or(false),
-            );
+            add_route_to_router(route, self_signed_cert_on_failure.unwrap_or(false));
 
             tracing::debug!("Added route: {}, {:?}", route.host, route.upstreams);
         }
@@ -78,6 +102,7 @@ impl RoutingService {
         let route_header = RouteHeader {
             add: Some(route.host_headers_add),
             remove: Some(route.host_headers_remove),
+            hop_by_hop: None,
         };
 
         // create route upstreams from ip + port
@@ -94,6 +119,12 @@ impl RoutingService {
                         weight: Some(1),
                         headers: None,
                         sni: None,
+                        access: RouteUpstreamAccess::default(),
+                        zone: None,
+                        priority: None,
+                        group: None,
+                        h2c: false,
+                        tls: None,
                     })
                     .collect::<Vec<_>>()
                 } else {
@@ -102,15 +133,18 @@ impl RoutingService {
             })
             .collect::<Vec<_>>();
 
-        add_route_to_router(
-            &route.host,
+        let route_config = Route {
+            host: route.host.clone(),
             upstreams,
-            matcher,
-            Some(&route_header),
-            Some(&route.plugins),
-            None,
-            route.self_signed_certs,
-        );
+            match_with: matcher,
+            headers: Some(route_header),
+            plugins: Some(route.plugins),
+            initial_check: route.initial_check,
+            warmup_secs: route.warmup_secs,
+            ..Route::default()
+        };
+
+        add_route_to_router(&route_config, route.self_signed_certs);
 
         tracing::debug!(
             "Added route: {}, {:?} self-signed: {}",
@@ -126,7 +160,7 @@ impl Service for RoutingService {
     async fn start_service(
         &mut self,
         _fds: Option<ListenFds>,
-        _shutdown: ShutdownWatch,
+        mut shutdown: ShutdownWatch,
         _listeners_per_fd: usize,
     ) {
         // Setup initial routes from config file
@@ -134,9 +168,30 @@ impl Service for RoutingService {
 
         // Watch for new hosts being added and configure them accordingly
         let mut receiver = self.broadcast.subscribe();
-        while let Ok(MsgProxy::NewRoute(route)) = receiver.recv().await {
-            Self::watch_for_route_changes(route);
-        }
+        run_until_shutdown(&mut shutdown, async {
+            loop {
+                match receiver.recv().await {
+                    Ok(MsgProxy::NewRoute(route)) => Self::watch_for_route_changes(route),
+                    Ok(MsgProxy::NewCertificate(cert)) => match update_certificate(&cert).await {
+                        Ok(()) => tracing::info!(host = cert.host, "certificate updated"),
+                        Err(err) => tracing::error!(
+                            host = cert.host,
+                            "failed to update the certificate, keeping the current one: {err}"
+                        ),
+                    },
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(skipped)) => {
+                        // The routes are sent again on the next discovery run
+                        tracing::warn!(
+                            skipped,
+                            "route updates were dropped, consider increasing broadcast_capacity"
+                        );
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+        .await;
     }
 
     fn name(&self) -> &'static str {
@@ -164,49 +219,427 @@ fn has_new_backend(host: &str, upstream_input: &LoadBalancer<RoundRobin>) -> boo
     }
 }
 
-/// Adds new routes to the store if there are changes to an existing route or
-/// if the host does not exist in the store.
-fn add_route_to_router(
-    host: &str,
-    upstream_input: Vec<RouteUpstream>,
-    match_with: Option<RouteMatcher>,
-    headers: Option<&RouteHeader>,
-    plugins: Option<&Vec<RoutePlugin>>,
-    cache: Option<&RouteCache>,
-    should_self_sign_cert_on_failure: bool,
-) {
-    // Check if current route already exists
-    let upstream_str = upstream_input
+/// The priority tiers of the upstreams (see `RouteStoreContainer::priority_tiers`)
+fn priority_tiers(upstreams: &[RouteUpstream]) -> Vec<u16> {
+    let mut tiers = upstreams
+        .iter()
+        .map(|upstream| upstream.priority.unwrap_or(DEFAULT_UPSTREAM_PRIORITY))
+        .collect::<Vec<_>>();
+    tiers.sort_unstable();
+    tiers.dedup();
+
+    if tiers.len() > 1 {
+        tiers
+    } else {
+        Vec::new()
+    }
+}
+
+/// Resolves every upstream into the addresses used by the load balancer
+/// and tags them with the upstream settings
+fn backend_tags_from_upstreams(
+    upstreams: &[RouteUpstream],
+) -> HashMap<SocketAddr, RouteBackendTags> {
+    upstreams
+        .iter()
+        .flat_map(|upstream| {
+            let tags = RouteBackendTags {
+                read_only: upstream.access == RouteUpstreamAccess::ReadOnly,
+                zone: upstream.zone.as_deref().map(Arc::from),
+                priority: upstream.priority.unwrap_or(DEFAULT_UPSTREAM_PRIORITY),
+                group: upstream.group.as_deref().map(Arc::from),
+                tls: upstream.is_tls(),
+            };
+
+            format!("{}:{}", upstream.ip, upstream.port)
+                .to_socket_addrs()
+                .map(|addrs| addrs.map(|addr| (addr, tags.clone())).collect::<Vec<_>>())
+                .unwrap_or_default()
+        })
+        .collect()
+}
+
+/// Whether replacing the backends of `host` with `upstream_input` only removes
+/// backends and leaves none of its currently healthy ones
+fn removes_last_healthy_backend(host: &str, upstream_input: &LoadBalancer<RoundRobin>) -> bool {
+    let Some(route_container) = stores::get_route_by_key(host) else {
+        return false;
+    };
+    let current = route_container.load_balancer.backends();
+    let backends = current.get_backend();
+    let new_backends = upstream_input.backends().get_backend();
+
+    let is_current = |backend: &Backend| backends.iter().any(|be| be.addr == backend.addr);
+    if !new_backends.iter().all(is_current) {
+        // New backends are added, the route is updated rather than drained
+        return false;
+    }
+
+    let healthy = backends
         .iter()
-        .map(|u| format!("{}:{}", u.ip, u.port))
-        .collect::<Vec<String>>();
+        .filter(|be| current.ready(be))
+        .collect::<Vec<_>>();
+    !healthy.is_empty()
+        && healthy
+            .iter()
+            .all(|be| new_backends.iter().all(|new| new.addr != be.addr))
+}
+
+/// Whether the update of `route` is refused by its `last_healthy_backend` policy
+fn is_update_refused(route: &Route, upstreams: &LoadBalancer<RoundRobin>) -> bool {
+    if route.last_healthy_backend != RouteLastHealthyBackend::Refuse
+        || !removes_last_healthy_backend(
+            &stores::route_key(&route.host, route.listener_port),
+            upstreams,
+        )
+    {
+        return false;
+    }
+
+    tracing::warn!(
+        host = route.host.as_ref(),
+        "route update refused, it removes the last healthy backends of the route"
+    );
+    true
+}
+
+/// Whether the removal of `route` is refused by its `last_healthy_backend`
+/// policy, removing a route with healthy backends removes all of them
+fn is_removal_refused(route: &Route) -> bool {
+    if route.last_healthy_backend != RouteLastHealthyBackend::Refuse {
+        return false;
+    }
+    let key = stores::route_key(&route.host, route.listener_port);
+    let Some(route_container) = stores::get_route_by_key(&key) else {
+        return false;
+    };
+    let backends = route_container.load_balancer.backends();
+    if !backends.get_backend().iter().any(|be| backends.ready(be)) {
+        return false;
+    }
+
+    tracing::warn!(
+        host = route.host.as_ref(),
+        "route removal refused, it removes the last healthy backends of the route"
+    );
+    true
+}
+
+/// Hosts changed when a reloaded configuration is applied
+#[derive(Debug, Default, Serialize, PartialEq, Eq)]
+pub struct RouteChanges {
+    pub added: Vec<String>,
+    pub updated: Vec<String>,
+    pub removed: Vec<String>,
+    /// Updates and removals refused by the `last_healthy_backend` policy of
+    /// the route
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub refused: Vec<String>,
+}
+
+/// Applies the routes of a reloaded configuration to the router store.
+/// `previous` are the routes of the configuration currently applied, routes that
+/// didn't change are left untouched and routes that are gone are removed.
+pub async fn apply_route_changes(previous: &[Route], next: &[Route]) -> RouteChanges {
+    let mut changes = RouteChanges::default();
+    let key = |route: &Route| stores::route_key(&route.host, route.listener_port);
+
+    for route in next {
+        let previous_route = previous.iter().find(|v| key(v) == key(route));
+        match previous_route {
+            None => changes.added.push(key(route)),
+            Some(previous_route) if !is_same_route(previous_route, route) => {
+                changes.updated.push(key(route));
+            }
+            Some(_) => continue,
+        }
+
+        if let Err(err) = add_route_ssl_to_store(route).await {
+            tracing::error!(
+                "failed to add SSL certificate to store for host {:?}: {err}",
+                route.host
+            );
+        }
+
+        let self_signed_cert_on_failure = route
+            .ssl_certificate
+            .as_ref()
+            .and_then(|v| v.self_signed_on_failure);
+
+        if let Some((upstreams, backend_tags)) = route_backends(route) {
+            if is_update_refused(route, &upstreams) {
+                changes.updated.retain(|host| *host != key(route));
+                changes.refused.push(key(route));
+                continue;
+            }
+
+            insert_route_into_router(
+                route,
+                self_signed_cert_on_failure.unwrap_or(false),
+                upstreams,
+                backend_tags,
+            );
+        }
+    }
+
+    for route in previous {
+        if next.iter().all(|v| key(v) != key(route)) {
+            let removed_key = key(route);
+            if is_removal_refused(route) {
+                changes.refused.push(removed_key);
+                continue;
+            }
 
-    let Ok(mut upstreams) = LoadBalancer::<RoundRobin>::try_from_iter(upstream_str) else {
+            let removed = stores::get_route_by_key(&removed_key);
+            if let Some(removed) = removed.as_ref() {
+                removed.stop_warm_up();
+            }
+            match (removed, route.drain_on_remove_secs.filter(|secs| *secs > 0)) {
+                (Some(removed), Some(secs)) => {
+                    drain_route(removed_key.clone(), removed, Duration::from_secs(secs));
+                }
+                _ => stores::remove_route(&removed_key),
+            }
+            changes.removed.push(removed_key);
+        }
+    }
+
+    changes
+}
+
+/// Keeps a removed route in the store for the `grace` period, so that the
+/// requests already in flight complete while the new ones are refused. The
+/// route is only removed if it wasn't added back in the meantime.
+fn drain_route(key: String, route_container: RouteStoreContainer, grace: Duration) {
+    let load_balancer = route_container.load_balancer.clone();
+    stores::insert_route(
+        key.clone(),
+        RouteStoreContainer {
+            draining: true,
+            ..route_container
+        },
+    );
+    tracing::info!(
+        host = key,
+        grace_secs = grace.as_secs(),
+        "draining removed route"
+    );
+
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+
+        let is_drained_route = stores::get_route_by_key(&key).is_some_and(|current| {
+            current.draining && Arc::ptr_eq(&current.load_balancer, &load_balancer)
+        });
+        if is_drained_route {
+            stores::remove_route(&key);
+            tracing::info!(host = key, "removed drained route");
+        }
+    });
+}
+
+/// Routes are compared through their serialized form, as some of the
+/// configuration types can't be compared directly
+fn is_same_route(a: &Route, b: &Route) -> bool {
+    match (serde_json::to_value(a), serde_json::to_value(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Creates the load balancer (and its backend tags) for the upstreams of a route
+fn route_backends(
+    route: &Route,
+) -> Option<(
+    LoadBalancer<RoundRobin>,
+    HashMap<SocketAddr, RouteBackendTags>,
+)> {
+    let Ok(backends) = weighted_backends(&route.upstreams) else {
         tracing::info!(
             "Could not create upstreams for host: {}, upstreams {:?}",
-            host,
-            upstream_input
+            route.host,
+            route.upstreams
         );
+        return None;
+    };
+
+    let upstreams = LoadBalancer::<RoundRobin>::from_backends(Backends::new(Static::new(backends)));
+    upstreams
+        .update()
+        .now_or_never()
+        .expect("static should not block")
+        .expect("static should not error");
+
+    Some((upstreams, backend_tags_from_upstreams(&route.upstreams)))
+}
+
+/// Resolves every upstream into the backends of the load balancer,
+/// using the upstream `weight` (defaults to 1)
+fn weighted_backends(upstreams: &[RouteUpstream]) -> std::io::Result<BTreeSet<Backend>> {
+    let mut backends = BTreeSet::new();
+
+    for upstream in upstreams {
+        let weight = upstream
+            .weight
+            .and_then(|weight| usize::try_from(weight).ok())
+            .filter(|weight| *weight > 0)
+            .unwrap_or(1);
+
+        for addr in format!("{}:{}", upstream.ip, upstream.port).to_socket_addrs()? {
+            backends.insert(Backend {
+                addr: pingora::protocols::l4::socket::SocketAddr::Inet(addr),
+                weight,
+                ext: Extensions::new(),
+            });
+        }
+    }
+
+    Ok(backends)
+}
+
+/// Adds new routes to the store if there are changes to an existing route or
+/// if the host does not exist in the store.
+fn add_route_to_router(route: &Route, should_self_sign_cert_on_failure: bool) {
+    let host = &stores::route_key(&route.host, route.listener_port);
+
+    let Some((upstreams, backend_tags)) = route_backends(route) else {
         return;
     };
 
-    if stores::get_route_by_key(host).is_some() && !has_new_backend(host, &upstreams) {
+    // Check if current route already exists, the backends of its upstreams
+    // can change over time (e.g. DNS) while its configuration doesn't
+    let is_unchanged = stores::get_route_by_key(host).is_some_and(|current| {
+        current.self_signed_certificate == should_self_sign_cert_on_failure
+            && current
+                .config
+                .as_ref()
+                .is_some_and(|config| is_same_route(config, route))
+    });
+    if is_unchanged && !has_new_backend(host, &upstreams) {
         tracing::debug!("skipping update, no routing changes for host: {}", host);
         return;
     }
 
-    // TODO: support defining health checks in the configuration file
-    let tcp_health_check = TcpHealthCheck::new();
-    upstreams.set_health_check(tcp_health_check);
+    if is_update_refused(route, &upstreams) {
+        return;
+    }
+
+    insert_route_into_router(
+        route,
+        should_self_sign_cert_on_failure,
+        upstreams,
+        backend_tags,
+    );
+}
+
+/// Creates the routing container of a route and inserts (or replaces) it in the store
+fn insert_route_into_router(
+    route: &Route,
+    should_self_sign_cert_on_failure: bool,
+    mut upstreams: LoadBalancer<RoundRobin>,
+    backend_tags: HashMap<SocketAddr, RouteBackendTags>,
+) {
+    let host = route.host.as_ref();
+    let upstream_input = route.upstreams.clone();
+
+    match route.health_check.as_ref() {
+        Some(health_check) => {
+            upstreams.set_health_check(HttpHealthCheck::new(host, health_check, &backend_tags));
+        }
+        None => upstreams.set_health_check(TcpHealthCheck::new()),
+    }
     upstreams.health_check_frequency = Some(Duration::from_secs(15));
 
     // Create new routing container
     let mut route_store_container = RouteStoreContainer::new(upstreams);
+    seed_selection(&route_store_container, selection_seed(route.selection_seed));
     route_store_container.self_signed_certificate = should_self_sign_cert_on_failure;
     route_store_container.upstreams = upstream_input;
-    route_store_container.cache = cache.cloned();
+    route_store_container.backend_tags = backend_tags;
+    route_store_container.priority_tiers = priority_tiers(&route.upstreams);
+    route_store_container.upstream_groups =
+        route
+            .upstream_groups
+            .as_ref()
+            .map(|groups| RouteUpstreamGroupRouting {
+                default: Arc::from(groups.default.as_ref()),
+                rules: groups
+                    .rules
+                    .iter()
+                    .filter_map(|rule| {
+                        let header = RouteRequiredHeader {
+                            name: HeaderName::from_str(&rule.name).ok()?,
+                            value: match rule.value.as_ref() {
+                                Some(value) => Some(HeaderValue::from_str(value).ok()?),
+                                None => None,
+                            },
+                        };
+                        Some((header, Arc::from(rule.group.as_ref())))
+                    })
+                    .collect(),
+            });
+    // Weights derived from the load of the backends change over time, which
+    // the round robin of the load balancer doesn't support
+    if route.selection_algorithm == RouteSelectionAlgorithm::SmoothWeightedRoundRobin
+        || route.load_weights.is_some()
+    {
+        let backends = route_store_container.load_balancer.backends().get_backend();
+        route_store_container.smooth_weighted = Some(Arc::new(SmoothWeightedRoundRobin::new(
+            backends.iter().cloned(),
+        )));
+    }
+    route_store_container.load_weights = route.load_weights.clone();
+    if route.selection_algorithm == RouteSelectionAlgorithm::ConsistentHash {
+        let hash_key = route.hash_key.as_deref().unwrap_or(DEFAULT_HASH_KEY);
+        match HashKeyTemplate::parse(hash_key) {
+            Ok(template) => route_store_container.hash_key = Some(template),
+            Err(err) => tracing::error!(
+                host = %route.host,
+                "invalid hash_key, the upstreams are balanced with round robin: {err}"
+            ),
+        }
+    }
+    route_store_container.cache = route.cache.clone();
+    route_store_container.slow_request_threshold_ms = route.slow_request_threshold_ms;
+    route_store_container.access_log_destination = route
+        .access_log
+        .as_ref()
+        .map(|access_log| Arc::from(access_log.destination.to_string_lossy().as_ref()));
+    route_store_container.retry = route.retry.clone();
+    route_store_container.follow_redirects = route.follow_redirects.clone();
+    route_store_container.dns = Arc::new(DnsNegativeCache::new(Duration::from_secs(
+        route.dns.negative_ttl_secs,
+    )));
+    route_store_container.concurrency = route
+        .concurrency
+        .as_ref()
+        .map(|concurrency| Arc::new(ConcurrencyLimiter::new(concurrency)));
+    route_store_container.redirect = route.redirect.clone();
+    route_store_container.strip_trailers = route
+        .trailers
+        .iter()
+        .flat_map(|trailers| &trailers.strip)
+        .filter_map(|name| HeaderName::from_str(name).ok())
+        .collect();
+    route_store_container.log_upstream_selection = route.log_upstream_selection;
+    route_store_container.log_upstream_events = route.log_upstream_events;
+    route_store_container.debug_upstream_header = route
+        .debug_upstream_header
+        .as_ref()
+        .and_then(|name| HeaderName::from_str(name).ok());
+
+    let key = stores::route_key(host, route.listener_port);
+    let previous = stores::get_route_by_key(&key);
+    if let Some(previous) = previous.as_ref() {
+        previous.stop_warm_up();
+    }
+    let is_new_route = previous
+        .as_ref()
+        .is_none_or(RouteStoreContainer::is_warming_up);
+    route_store_container.labels = route.labels.clone();
+    route_store_container.config = Some(Arc::new(route.clone()));
 
-    if let Some(headers) = headers {
+    if let Some(headers) = route.headers.as_ref() {
         if let Some(headers) = headers.add.as_ref() {
             route_store_container.host_header_add = headers
                 .iter()
@@ -223,12 +656,37 @@ fn add_route_to_router(
             route_store_container.host_header_remove =
                 to_remove.iter().map(|v| v.name.to_string()).collect();
         }
+
+        route_store_container.hop_by_hop_headers = headers.hop_by_hop;
+    }
+
+    route_store_container.forward_headers_allowlist =
+        route.forward_headers_allowlist.as_ref().map(|allowlist| {
+            allowlist
+                .iter()
+                .filter_map(|name| HeaderName::from_str(name).ok())
+                .collect()
+        });
+
+    if let Some(require_headers) = route.require_headers.as_ref() {
+        route_store_container.require_headers = require_headers
+            .iter()
+            .filter_map(|header| {
+                Some(RouteRequiredHeader {
+                    name: HeaderName::from_str(&header.name).ok()?,
+                    value: match header.value.as_ref() {
+                        Some(value) => Some(HeaderValue::from_str(value).ok()?),
+                        None => None,
+                    },
+                })
+            })
+            .collect();
     }
 
-    if let Some(plugins) = plugins {
+    if let Some(plugins) = route.plugins.as_ref() {
         for plugin in plugins {
             match plugin.name.as_ref() {
-                "oauth2" | "request_id" | "basic_auth" => {
+                "oauth2" | "request_id" | "basic_auth" | "rate_limit" | "compression" => {
                     route_store_container
                         .plugins
                         .insert(plugin.name.to_string(), plugin.clone());
@@ -241,7 +699,7 @@ fn add_route_to_router(
 
     // Prepare route matchers
     // TODO: enable matchers for upstreams for true load balancing based on path
-    if let Some(match_with) = match_with {
+    if let Some(match_with) = route.match_with.clone() {
         // Path matchers
         match match_with.path {
             Some(path_matcher) if !path_matcher.patterns.is_empty() => {
@@ -251,65 +709,171 @@ fn add_route_to_router(
             _ => {}
         }
     }
+    route_store_container.path_matcher.no_match = route.no_match.clone();
 
-    stores::insert_route(host.to_string(), route_store_container);
+    // Only the routes that don't serve yet are held, updates apply right away
+    let warmup = route.warmup_secs.filter(|secs| *secs > 0);
+    if is_new_route && (route.initial_check || warmup.is_some()) {
+        route_store_container
+            .warming_up
+            .store(true, Ordering::Relaxed);
+        route_store_container.warm_up = Some(warm_up_route(
+            key.clone(),
+            &route_store_container,
+            route.initial_check,
+            Duration::from_secs(warmup.unwrap_or(0)),
+        ));
+        stores::insert_route(key, route_store_container);
+        return;
+    }
+
+    stores::insert_route(key, route_store_container);
 }
 
-// TODO: refactor this into its own module
-async fn add_route_ssl_to_store(route: &Route) -> Result<(), anyhow::Error> {
-    let Some(ssl_path) = route.ssl.as_ref().and_then(|v| v.path.as_ref()) else {
-        return Ok(());
-    };
+/// Holds a newly added route until one of its backends passed a health check
+/// (with `initial_check`) and its `warmup` is over. The failed initial checks
+/// are backed off, the task is aborted if the route is replaced or removed in
+/// the meantime.
+fn warm_up_route(
+    key: String,
+    route_container: &RouteStoreContainer,
+    initial_check: bool,
+    warmup: Duration,
+) -> AbortHandle {
+    let load_balancer = route_container.load_balancer.clone();
+    let warming_up = route_container.warming_up.clone();
+    tracing::info!(
+        host = key,
+        initial_check,
+        warmup_secs = warmup.as_secs(),
+        "warming up added route"
+    );
 
-    let key_from_file = std::fs::read_to_string(ssl_path.key.as_os_str()).map_err(|err| {
-        anyhow::anyhow!(
-            "Failed to load private key from file {:?}: {err}",
-            ssl_path.key
-        )
-    })?;
-    let pem_from_file = std::fs::read_to_string(ssl_path.pem.as_os_str()).map_err(|err| {
-        anyhow::anyhow!(
-            "Failed to load certificate from file {:?}: {err}",
-            ssl_path.pem
-        )
-    })?;
+    let task = tokio::spawn(async move {
+        if initial_check {
+            let backends = load_balancer.backends();
+            let mut interval = INITIAL_CHECK_INTERVAL;
+            loop {
+                backends.run_health_check(false).await;
+                if backends
+                    .get_backend()
+                    .iter()
+                    .any(|backend| backends.ready(backend))
+                {
+                    break;
+                }
 
-    let key = PKey::private_key_from_pem(key_from_file.as_bytes()).map_err(|err| {
-        anyhow::anyhow!(
-            "Failed to load private key from file {:?}: {err}",
-            ssl_path.key
-        )
-    })?;
-    let pem = X509::from_pem(pem_from_file.as_bytes()).map_err(|err| {
-        anyhow::anyhow!(
-            "Failed to load certificate from file {:?}: {err}",
-            ssl_path.pem
-        )
-    })?;
-
-    stores::global::get_store()
-        .set_certificate(
-            &route.host,
-            stores::certificates::Certificate {
-                key,
-                leaf: pem,
-                chain: None,
-            },
-        )
-        .await
-        .map_err(|_| {
-            anyhow!(
-                "Failed to insert certificate from config for route:  {}",
-                &route.host
-            )
-        })?;
-
-    Ok(())
+                tokio::time::sleep(interval).await;
+                interval = (interval * 2).min(MAX_INITIAL_CHECK_INTERVAL);
+            }
+        }
+
+        tokio::time::sleep(warmup).await;
+        warming_up.store(false, Ordering::Relaxed);
+        tracing::info!(host = key, "added route is warmed up");
+    });
+
+    task.abort_handle()
 }
 
 #[cfg(test)]
 mod test {
-    use std::net::ToSocketAddrs;
+    use std::{
+        net::{SocketAddr, ToSocketAddrs},
+        sync::Arc,
+        time::Duration,
+    };
+
+    use pingora::services::Service;
+    use tokio::sync::broadcast;
+
+    use super::{
+        add_route_to_router, apply_route_changes, drain_route, has_new_backend, route_backends,
+        RoutingService,
+    };
+    use crate::{
+        config::{Config, Route, RouteLastHealthyBackend},
+        stores, test_support, MsgProxy, MsgRoute,
+    };
+
+    fn helper_route_message(host: String, upstream: SocketAddr) -> MsgProxy {
+        MsgProxy::NewRoute(MsgRoute {
+            host: host.into(),
+            upstreams: vec![upstream.to_string()],
+            path_matchers: vec![],
+            host_headers_add: vec![],
+            host_headers_remove: vec![],
+            plugins: vec![],
+            self_signed_certs: false,
+            initial_check: false,
+            warmup_secs: None,
+        })
+    }
+
+    /// Starts a routing service listening to a channel of `capacity` and sends
+    /// it a burst of `burst` routes to `upstream`, returns the hosts of the
+    /// routes and the logs of the service
+    async fn helper_send_route_burst(
+        capacity: usize,
+        burst: usize,
+        upstream: SocketAddr,
+    ) -> (Vec<String>, test_support::LogCapture) {
+        let logs = test_support::LogCapture::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(logs.clone())
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let (sender, _) = broadcast::channel::<MsgProxy>(capacity);
+        let (_shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+        let mut service = RoutingService::new(Arc::new(Config::default()), sender.clone());
+        tokio::spawn(async move { service.start_service(None, shutdown, 1).await });
+        helper_wait_until(|| sender.receiver_count() == 1).await;
+
+        let hosts = (0..burst)
+            .map(|index| format!("{index}.{capacity}.burst.discovery.test"))
+            .collect::<Vec<_>>();
+        for host in &hosts {
+            sender
+                .send(helper_route_message(host.clone(), upstream))
+                .ok();
+        }
+
+        let last = hosts.last().unwrap().clone();
+        helper_wait_until(|| stores::get_route_by_key(&last).is_some()).await;
+
+        (hosts, logs)
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_capacity_handles_route_bursts() {
+        let backend = test_support::TestBackend::start(200, "burst").await;
+        let proxy = test_support::TestProxy::start().await;
+
+        // The default capacity keeps every route of the burst
+        let capacity = Config::default().broadcast_capacity;
+        let (hosts, logs) = helper_send_route_burst(capacity, 100, backend.addr()).await;
+        assert!(hosts
+            .iter()
+            .all(|host| stores::get_route_by_key(host).is_some()));
+        for host in [&hosts[0], hosts.last().unwrap()] {
+            assert_eq!(proxy.get(host, "/").await, (200, "burst".to_string()));
+        }
+        assert!(!logs.output().contains("route updates were dropped"));
+
+        // A small channel makes the service lag behind, only the latest routes
+        // are added
+        let (hosts, logs) = helper_send_route_burst(16, 100, backend.addr()).await;
+        assert_eq!(proxy.get(&hosts[0], "/").await.0, 404);
+        assert_eq!(
+            proxy.get(hosts.last().unwrap(), "/").await,
+            (200, "burst".to_string())
+        );
+        let output = logs.output();
+        assert!(output.contains("route updates were dropped"), "{output}");
+        assert!(output.contains("skipped=84"), "{output}");
+    }
 
     #[test]
     fn test_socket_addr() {
@@ -326,132 +890,330 @@ mod test {
         assert!(addr.ip().is_ipv4());
         assert_eq!(addr.port(), 80);
     }
-}
 
-// #[cfg(test)]
-// mod tests {
-//     use std::collections::HashMap;
-
-//     use super::*;
-//     use crate::stores::routes::RouteStore;
-
-//     fn setup_mock_route_store() -> RouteStore {
-//         Arc::new(HashMap::new())
-//     }
-
-//     fn setup_route_store_with_entry() -> RouteStore {
-//         let store = setup_mock_route_store();
-//         let upstreams = vec!["127.0.0.1:8080".to_string(), "127.0.0.2:8080".to_string()];
-
-//         let load_balancer =
-//             LoadBalancer::<RoundRobin>::try_from_iter(upstreams.into_iter()).unwrap();
-//         store.insert(
-//             "example.com".to_string(),
-//             RouteStoreContainer::new(load_balancer),
-//         );
-
-//         store
-//     }
-
-//     #[test]
-//     fn test_add_route_to_router_new_route() {
-//         let store = setup_mock_route_store();
-//         let host = "example.com";
-//         let upstreams = vec!["127.0.0.1:8080".to_string()];
-//         let matcher = None;
-//         let headers = None;
-//         let plugins = None;
-//         let should_self_sign_cert_on_failure = false;
-
-//         add_route_to_router(
-//             &store,
-//             host,
-//             &upstreams,
-//             matcher,
-//             headers,
-//             plugins,
-//             should_self_sign_cert_on_failure,
-//         );
-
-//         assert!(store.contains_key(host));
-//     }
-
-//     #[test]
-//     fn test_add_route_to_router_existing_route_no_changes() {
-//         let store = setup_route_store_with_entry();
-//         let host = "example.com";
-//         let upstreams = vec!["127.0.0.1:8080".to_string()];
-//         let matcher = None;
-//         let headers = None;
-//         let plugins = None;
-//         let should_self_sign_cert_on_failure = false;
-
-//         add_route_to_router(
-//             &store,
-//             host,
-//             &upstreams,
-//             matcher,
-//             headers,
-//             plugins,
-//             should_self_sign_cert_on_failure,
-//         );
-
-//         // Verify the route still exists and no new upstreams were added
-//         assert!(store.contains_key(host));
-//     }
-
-//     #[test]
-//     fn test_has_new_backend_no_change() {
-//         let store = setup_route_store_with_entry();
-//         let host = "example.com";
-//         let upstreams = LoadBalancer::try_from_iter(vec![
-//             "127.0.0.1:8080".to_string(),
-//             "127.0.0.2:8080".to_string(),
-//         ])
-//         .unwrap();
-
-//         assert!(!has_new_backend(&store, host, &upstreams));
-//     }
-
-//     #[test]
-//     fn test_has_new_backend_with_change() {
-//         let store = setup_route_store_with_entry();
-//         let host = "example.com";
-//         let upstreams = LoadBalancer::try_from_iter(vec!["127.0.0.3:8080".to_string()]).unwrap();
-
-//         assert!(has_new_backend(&store, host, &upstreams));
-//     }
-
-//     #[test]
-//     fn test_add_route_to_router_existing_route_with_changes() {
-//         let store = setup_route_store_with_entry();
-//         let host = "example.com";
-//         let upstreams = vec!["127.0.0.3:8080".to_string()];
-//         let matcher = None;
-//         let headers = None;
-//         let plugins = None;
-//         let should_self_sign_cert_on_failure = false;
-
-//         add_route_to_router(
-//             &store,
-//             host,
-//             &upstreams,
-//             matcher,
-//             headers,
-//             plugins,
-//             should_self_sign_cert_on_failure,
-//         );
-
-//         // Verify that the route exists and the upstreams have been updated
-//         assert!(store.contains_key(host));
-//         let route_container = store.get(host).unwrap();
-//         let backends: Vec<String> = route_container
-//             .load_balancer
-//             .backends()
-//             .get_backend()
-//             .iter()
-//             .map(|backend| backend.addr.to_string())
-//             .collect();
-//         assert!(backends.contains(&"127.0.0.3:8080".to_string()));
-//     }
-// }
+    fn helper_route(host: &str, upstreams: &[&str]) -> Route {
+        test_support::route(host, upstreams.iter().map(|addr| addr.parse().unwrap()))
+    }
+
+    fn helper_backends(host: &str) -> Vec<String> {
+        stores::get_route_by_key(host)
+            .unwrap()
+            .load_balancer
+            .backends()
+            .get_backend()
+            .iter()
+            .map(|backend| backend.addr.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_add_route_to_router_new_route() {
+        let host = "new.discovery.test";
+        add_route_to_router(&helper_route(host, &["127.0.0.1:8080"]), false);
+
+        assert_eq!(helper_backends(host), vec!["127.0.0.1:8080"]);
+    }
+
+    #[test]
+    fn test_add_route_to_router_existing_route_no_changes() {
+        let host = "unchanged.discovery.test";
+        let route = helper_route(host, &["127.0.0.1:8080", "127.0.0.2:8080"]);
+        add_route_to_router(&route, false);
+        let before = stores::get_route_by_key(host).unwrap();
+
+        add_route_to_router(&route, false);
+
+        // The route wasn't replaced
+        let after = stores::get_route_by_key(host).unwrap();
+        assert!(Arc::ptr_eq(&before.load_balancer, &after.load_balancer));
+    }
+
+    #[test]
+    fn test_add_route_to_router_existing_route_config_changes() {
+        let host = "config-changes.discovery.test";
+        let mut route = helper_route(host, &["127.0.0.1:8080"]);
+        add_route_to_router(&route, false);
+
+        route.slow_request_threshold_ms = Some(250);
+        add_route_to_router(&route, false);
+        let container = stores::get_route_by_key(host).unwrap();
+        assert_eq!(container.slow_request_threshold_ms, Some(250));
+
+        add_route_to_router(&route, true);
+        assert!(
+            stores::get_route_by_key(host)
+                .unwrap()
+                .self_signed_certificate
+        );
+    }
+
+    #[test]
+    fn test_has_new_backend_no_change() {
+        let host = "same-backends.discovery.test";
+        let route = helper_route(host, &["127.0.0.1:8080", "127.0.0.2:8080"]);
+        add_route_to_router(&route, false);
+
+        let (upstreams, _) = route_backends(&route).unwrap();
+        assert!(!has_new_backend(host, &upstreams));
+    }
+
+    #[test]
+    fn test_has_new_backend_with_change() {
+        let host = "new-backends.discovery.test";
+        add_route_to_router(
+            &helper_route(host, &["127.0.0.1:8080", "127.0.0.2:8080"]),
+            false,
+        );
+
+        let (upstreams, _) = route_backends(&helper_route(host, &["127.0.0.3:8080"])).unwrap();
+        assert!(has_new_backend(host, &upstreams));
+    }
+
+    #[test]
+    fn test_add_route_to_router_existing_route_with_changes() {
+        let host = "changed.discovery.test";
+        add_route_to_router(
+            &helper_route(host, &["127.0.0.1:8080", "127.0.0.2:8080"]),
+            false,
+        );
+
+        add_route_to_router(&helper_route(host, &["127.0.0.3:8080"]), false);
+
+        assert_eq!(helper_backends(host), vec!["127.0.0.3:8080"]);
+    }
+
+    /// A route of `host` with the backends `127.0.0.1:8080` (healthy) and
+    /// `127.0.0.2:8080` (unhealthy)
+    fn helper_partially_healthy_route(host: &str, policy: RouteLastHealthyBackend) -> Route {
+        let mut route = helper_route(host, &["127.0.0.1:8080", "127.0.0.2:8080"]);
+        route.last_healthy_backend = policy;
+        add_route_to_router(&route, false);
+
+        let backends = stores::get_route_by_key(host).unwrap().load_balancer;
+        let unhealthy = backends.backends().get_backend();
+        let unhealthy = unhealthy
+            .iter()
+            .find(|backend| backend.addr.to_string() == "127.0.0.2:8080")
+            .unwrap();
+        backends.backends().set_enable(unhealthy, false);
+
+        route
+    }
+
+    #[test]
+    fn test_draining_the_last_healthy_backend_is_refused() {
+        let host = "refused-drain.discovery.test";
+        let mut route = helper_partially_healthy_route(host, RouteLastHealthyBackend::Refuse);
+
+        route.upstreams.remove(0);
+        add_route_to_router(&route, false);
+        assert_eq!(
+            helper_backends(host),
+            vec!["127.0.0.1:8080", "127.0.0.2:8080"]
+        );
+
+        route.upstreams.clear();
+        add_route_to_router(&route, false);
+        assert_eq!(helper_backends(host).len(), 2);
+    }
+
+    #[test]
+    fn test_removing_unhealthy_or_adding_backends_is_not_refused() {
+        let host = "healthy-drain.discovery.test";
+        let mut route = helper_partially_healthy_route(host, RouteLastHealthyBackend::Refuse);
+        route.upstreams.remove(1);
+        add_route_to_router(&route, false);
+        assert_eq!(helper_backends(host), vec!["127.0.0.1:8080"]);
+
+        let host = "replaced.discovery.test";
+        let mut route = helper_partially_healthy_route(host, RouteLastHealthyBackend::Refuse);
+        route.upstreams[0].ip = "127.0.0.3".into();
+        add_route_to_router(&route, false);
+        assert_eq!(
+            helper_backends(host),
+            vec!["127.0.0.2:8080", "127.0.0.3:8080"]
+        );
+    }
+
+    #[test]
+    fn test_draining_the_last_healthy_backend_is_allowed_by_default() {
+        let host = "allowed-drain.discovery.test";
+        let mut route = helper_partially_healthy_route(host, RouteLastHealthyBackend::default());
+
+        route.upstreams.remove(0);
+        add_route_to_router(&route, false);
+        assert_eq!(helper_backends(host), vec!["127.0.0.2:8080"]);
+    }
+
+    #[tokio::test]
+    async fn test_refused_updates_are_reported() {
+        let host = "refused-reload.discovery.test";
+        let route = helper_partially_healthy_route(host, RouteLastHealthyBackend::Refuse);
+        let mut drained = route.clone();
+        drained.upstreams.remove(0);
+
+        let changes = apply_route_changes(&[route], &[drained]).await;
+        assert!(changes.updated.is_empty());
+        assert_eq!(changes.refused, vec![host]);
+        assert_eq!(helper_backends(host).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_removing_a_route_with_healthy_backends_is_refused() {
+        let host = "refused-removal.discovery.test";
+        let route = helper_partially_healthy_route(host, RouteLastHealthyBackend::Refuse);
+
+        let changes = apply_route_changes(&[route], &[]).await;
+        assert!(changes.removed.is_empty());
+        assert_eq!(changes.refused, vec![host]);
+        assert_eq!(helper_backends(host).len(), 2);
+
+        let host = "allowed-removal.discovery.test";
+        let route = helper_partially_healthy_route(host, RouteLastHealthyBackend::default());
+
+        let changes = apply_route_changes(&[route], &[]).await;
+        assert_eq!(changes.removed, vec![host]);
+        assert!(stores::get_route_by_key(host).is_none());
+    }
+
+    /// Waits for `done`, for up to 5 seconds
+    async fn helper_wait_until(done: impl Fn() -> bool) {
+        for _ in 0..100 {
+            if done() {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        panic!("timed out");
+    }
+
+    #[tokio::test]
+    async fn test_removed_route_is_drained() {
+        let backend =
+            test_support::TestBackend::start_delayed(200, "slow", Duration::from_millis(300)).await;
+        let proxy = test_support::TestProxy::start().await;
+        let host = "drained.discovery.test";
+        let mut route = test_support::route(host, [backend.addr()]);
+        route.drain_on_remove_secs = Some(1);
+        apply_route_changes(&[], std::slice::from_ref(&route)).await;
+
+        let in_flight = tokio::spawn({
+            let request = proxy.request(reqwest::Method::GET, host, "/");
+            async move { request.send().await.unwrap() }
+        });
+        helper_wait_until(|| backend.requests() == 1).await;
+
+        let changes = apply_route_changes(std::slice::from_ref(&route), &[]).await;
+        assert_eq!(changes.removed, vec![host]);
+
+        // Within the grace window, the new requests are refused and their
+        // connection closed, while the one in flight completes
+        let response = proxy
+            .request(reqwest::Method::GET, host, "/")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(response.headers()["connection"], "close");
+        assert!(!in_flight.is_finished());
+
+        let response = in_flight.await.unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.text().await.unwrap(), "slow");
+        assert_eq!(backend.requests(), 1);
+
+        helper_wait_until(|| stores::get_route_by_key(host).is_none()).await;
+        assert_eq!(proxy.get(host, "/").await.0, 404);
+    }
+
+    #[tokio::test]
+    async fn test_new_route_serves_once_its_initial_check_passed() {
+        let mut backend = test_support::TestBackend::start(200, "cold").await;
+        let addr = backend.addr();
+        backend.stop().await;
+
+        let proxy = test_support::TestProxy::start().await;
+        let host = "initial-check.discovery.test";
+        let mut route = test_support::route(host, [addr]);
+        route.initial_check = true;
+        add_route_to_router(&route, false);
+        assert!(stores::get_route_by_key(host).unwrap().is_warming_up());
+
+        // The backend is down, the check is run again until it passes
+        assert_eq!(proxy.get(host, "/").await.0, 503);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(proxy.get(host, "/").await.0, 503);
+
+        let backend = test_support::TestBackend::start_on(addr, 200, "ready").await;
+        let mut response = proxy.get(host, "/").await;
+        for _ in 0..50 {
+            if response.0 != 503 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            response = proxy.get(host, "/").await;
+        }
+        assert_eq!(response, (200, "ready".to_string()));
+        assert!(!stores::get_route_by_key(host).unwrap().is_warming_up());
+        assert_eq!(backend.requests(), 1);
+
+        // Updates of a route already serving aren't held
+        let mut upstream = route.upstreams[0].clone();
+        upstream.port = 1;
+        route.upstreams.push(upstream);
+        add_route_to_router(&route, false);
+        assert!(!stores::get_route_by_key(host).unwrap().is_warming_up());
+    }
+
+    #[tokio::test]
+    async fn test_replaced_warming_route_restarts_its_warm_up() {
+        let host = "replaced-warm-up.discovery.test";
+        let mut route = test_support::route(host, ["127.0.0.1:1".parse().unwrap()]);
+        route.initial_check = true;
+        add_route_to_router(&route, false);
+        let first = stores::get_route_by_key(host).unwrap().warm_up.unwrap();
+
+        // Only the warm-up of the current route keeps running
+        route.upstreams[0].port = 2;
+        add_route_to_router(&route, false);
+        let second = stores::get_route_by_key(host).unwrap();
+        assert!(second.is_warming_up());
+        helper_wait_until(|| first.is_finished()).await;
+        assert!(!second.warm_up.as_ref().unwrap().is_finished());
+
+        let removed = second.warm_up.clone().unwrap();
+        apply_route_changes(std::slice::from_ref(&route), &[]).await;
+        helper_wait_until(|| removed.is_finished()).await;
+        assert!(stores::get_route_by_key(host).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_new_route_is_held_during_its_warmup() {
+        let backend = test_support::TestBackend::start(200, "warm").await;
+        let proxy = test_support::TestProxy::start().await;
+        let host = "warmup.discovery.test";
+        let mut route = test_support::route(host, [backend.addr()]);
+        route.warmup_secs = Some(1);
+        add_route_to_router(&route, false);
+
+        assert_eq!(proxy.get(host, "/").await.0, 503);
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert_eq!(proxy.get(host, "/").await, (200, "warm".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_route_added_back_is_not_drained() {
+        let host = "added-back.discovery.test";
+        let route = test_support::route(host, ["127.0.0.1:3000".parse().unwrap()]);
+        apply_route_changes(&[], std::slice::from_ref(&route)).await;
+
+        let grace = Duration::from_millis(100);
+        drain_route(host.into(), stores::get_route_by_key(host).unwrap(), grace);
+        assert!(stores::get_route_by_key(host).unwrap().draining);
+        apply_route_changes(&[], std::slice::from_ref(&route)).await;
+
+        tokio::time::sleep(grace * 3).await;
+        let route_container = stores::get_route_by_key(host).unwrap();
+        assert!(!route_container.draining);
+    }
+}
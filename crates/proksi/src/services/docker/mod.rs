@@ -18,6 +18,7 @@ use tracing::{debug, info};
 
 use crate::{
     config::{Config, DockerServiceMode, RouteHeaderAdd, RouteHeaderRemove, RoutePlugin},
+    services::run_until_shutdown,
     MsgProxy, MsgRoute,
 };
 
@@ -42,6 +43,8 @@ pub struct ProksiDockerRoute {
     host_header_remove: Option<Vec<RouteHeaderRemove>>,
     ssl_certificate_self_signed_on_failure: bool,
     plugins: Option<Vec<RoutePlugin>>,
+    initial_check: bool,
+    warmup_secs: Option<u64>,
 }
 
 impl ProksiDockerRoute {
@@ -53,6 +56,8 @@ impl ProksiDockerRoute {
             host_header_remove: None,
             ssl_certificate_self_signed_on_failure: false,
             plugins: None,
+            initial_check: false,
+            warmup_secs: None,
         }
     }
 }
@@ -135,6 +140,8 @@ impl LabelService {
             let mut oauth2_jwt_secret: Option<String> = None;
             let mut oauth2_validations: Option<serde_json::Value> = None;
             let mut ssl_certificate_self_signed_on_failure = false;
+            let mut initial_check = false;
+            let mut warmup_secs = None;
             let mut docker_request_id = false;
             let mut basic_auth_user = None;
             let mut basic_auth_password = None;
@@ -166,6 +173,8 @@ impl LabelService {
                         "proksi.ssl_certificate.self_signed_on_failure" => {
                             ssl_certificate_self_signed_on_failure = v == "true";
                         }
+                        "proksi.initial_check" => initial_check = v == "true",
+                        "proksi.warmup_secs" => warmup_secs = v.parse().ok(),
                         "proksi.plugins.oauth2.provider" => oauth2_provider = Some(v.clone()),
                         "proksi.plugins.oauth2.client_id" => oauth2_client_id = Some(v.clone()),
                         "proksi.plugins.oauth2.client_secret" => {
@@ -217,6 +226,8 @@ impl LabelService {
                 routed.host_header_remove = route_header_remove;
                 routed.ssl_certificate_self_signed_on_failure =
                     ssl_certificate_self_signed_on_failure;
+                routed.initial_check = initial_check;
+                routed.warmup_secs = warmup_secs;
 
                 // This part is optional
                 let mut plugins: Vec<RoutePlugin> = vec![];
@@ -297,6 +308,8 @@ impl LabelService {
             let mut route_header_add: Option<Vec<RouteHeaderAdd>> = None;
             let mut route_header_remove: Option<Vec<RouteHeaderRemove>> = None;
             let mut ssl_certificate_self_signed_on_failure = false;
+            let mut initial_check = false;
+            let mut warmup_secs = None;
 
             // Map through extra labels
             for (k, v) in container_labels {
@@ -321,6 +334,8 @@ impl LabelService {
                         "proksi.ssl_certificate.self_signed_on_failure" => {
                             ssl_certificate_self_signed_on_failure = v == "true";
                         }
+                        "proksi.initial_check" => initial_check = v == "true",
+                        "proksi.warmup_secs" => warmup_secs = v.parse().ok(),
                         k if k.starts_with("proksi.match_with.path.pattern.") => {
                             match_with_path_patterns.push(v.clone());
                         }
@@ -352,6 +367,8 @@ impl LabelService {
                 routed.host_header_remove = route_header_remove;
                 routed.ssl_certificate_self_signed_on_failure =
                     ssl_certificate_self_signed_on_failure;
+                routed.initial_check = initial_check;
+                routed.warmup_secs = warmup_secs;
                 host_map.insert(proxy_host.to_string(), routed);
             }
 
@@ -438,6 +455,8 @@ impl LabelService {
                     plugins: value.plugins.unwrap_or_else(Vec::new),
 
                     self_signed_certs: value.ssl_certificate_self_signed_on_failure,
+                    initial_check: value.initial_check,
+                    warmup_secs: value.warmup_secs,
                 }))
                 .ok();
         }
@@ -464,7 +483,7 @@ impl Service for LabelService {
     async fn start_service(
         &mut self,
         _fds: Option<ListenFds>,
-        mut _shutdown: ShutdownWatch,
+        mut shutdown: ShutdownWatch,
         _listeners_per_fd: usize,
     ) {
         if self.config.docker.enabled.is_some_and(|v| !v) {
@@ -479,10 +498,13 @@ impl Service for LabelService {
         ));
 
         interval.tick().await;
-        loop {
-            interval.tick().await;
-            self.send_route_message(self.get_routes_from_docker().await);
-        }
+        run_until_shutdown(&mut shutdown, async {
+            loop {
+                interval.tick().await;
+                self.send_route_message(self.get_routes_from_docker().await);
+            }
+        })
+        .await;
     }
 
     fn name(&self) -> &'static str {
@@ -0,0 +1,292 @@
+use std::{collections::HashMap, net::SocketAddr, str::FromStr, time::Duration};
+
+use async_trait::async_trait;
+use http::Method;
+use pingora::{
+    connectors::http::Connector,
+    http::RequestHeader,
+    lb::{health_check::HealthCheck, Backend},
+    upstreams::peer::HttpPeer,
+    Error,
+    ErrorType::{Custom, CustomCode},
+};
+
+use crate::{config::RouteHealthCheck, stores::routes::RouteBackendTags};
+
+/// The largest body read when looking for `expected_body`
+const MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Health checks upstreams with an HTTP request, an upstream is healthy when it
+/// answers with a `200` (and a body containing `expected_body`, if set)
+pub struct HttpHealthCheck {
+    request: RequestHeader,
+    expected_body: Option<String>,
+    sni: String,
+    /// The backends checked with TLS, like the requests proxied to them
+    tls_backends: Vec<SocketAddr>,
+    connector: Connector,
+}
+
+impl HttpHealthCheck {
+    /// Creates the health check of the route `host`, the configuration is
+    /// validated when it is loaded so invalid headers are skipped
+    pub fn new(
+        host: &str,
+        config: &RouteHealthCheck,
+        backend_tags: &HashMap<SocketAddr, RouteBackendTags>,
+    ) -> Box<Self> {
+        let method = Method::from_str(&config.method).unwrap_or(Method::GET);
+        let mut request = RequestHeader::build(method.clone(), config.path.as_bytes(), None)
+            .unwrap_or_else(|_| RequestHeader::build(method.clone(), b"/", None).unwrap());
+        request.insert_header(http::header::HOST, host).ok();
+
+        // The health check never sends a body
+        if method != Method::GET && method != Method::HEAD {
+            request.insert_header(http::header::CONTENT_LENGTH, 0).ok();
+        }
+
+        for header in &config.headers {
+            request
+                .insert_header(header.name.to_string(), header.value.as_ref())
+                .ok();
+        }
+
+        Box::new(Self {
+            request,
+            expected_body: config.expected_body.as_ref().map(ToString::to_string),
+            sni: host.to_string(),
+            tls_backends: backend_tags
+                .iter()
+                .filter(|(_, tags)| tags.tls)
+                .map(|(addr, _)| *addr)
+                .collect(),
+            connector: Connector::new(None),
+        })
+    }
+}
+
+#[async_trait]
+impl HealthCheck for HttpHealthCheck {
+    async fn check(&self, target: &Backend) -> pingora::Result<()> {
+        let tls = target
+            .addr
+            .as_inet()
+            .is_some_and(|addr| self.tls_backends.contains(addr));
+        let mut peer = HttpPeer::new(target.addr.clone(), tls, self.sni.clone());
+        // Like the proxied requests by default, upstreams often use self-signed certificates
+        peer.options.verify_cert = false;
+        peer.options.connection_timeout = Some(Duration::from_secs(1));
+        peer.options.read_timeout = Some(Duration::from_secs(1));
+
+        let (mut session, _) = self.connector.get_http_session(&peer).await?;
+        session
+            .write_request_header(Box::new(self.request.clone()))
+            .await?;
+        session.finish_request_body().await?;
+        session.read_response_header().await?;
+
+        let status = session.response_header().map(|h| h.status.as_u16());
+        if status != Some(200) {
+            return Error::e_explain(
+                CustomCode("non 200 code", status.unwrap_or_default()),
+                "during http health check",
+            );
+        }
+
+        let Some(expected_body) = self.expected_body.as_ref() else {
+            return Ok(());
+        };
+
+        let mut body = Vec::new();
+        while let Some(chunk) = session.read_response_body().await? {
+            body.extend_from_slice(&chunk);
+            if body.len() >= MAX_BODY_BYTES {
+                break;
+            }
+        }
+
+        if !String::from_utf8_lossy(&body).contains(expected_body.as_str()) {
+            return Error::e_explain(
+                Custom("unexpected body"),
+                "during http health check, the body doesn't contain `expected_body`",
+            );
+        }
+
+        Ok(())
+    }
+
+    fn health_threshold(&self, _success: bool) -> usize {
+        1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use std::io::{Read, Write};
+
+    use openssl::ssl::{SslAcceptor, SslMethod};
+
+    use crate::{config::RouteHeaderAdd, test_support};
+
+    use super::*;
+
+    /// Starts an upstream answering every request with `respond(request)`
+    async fn helper_upstream(respond: fn(&str) -> &'static str) -> Backend {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = vec![0; 4096];
+                let read = stream.read(&mut request).await.unwrap();
+                let request = String::from_utf8_lossy(&request[..read]).to_lowercase();
+
+                stream
+                    .write_all(respond(&request).as_bytes())
+                    .await
+                    .unwrap();
+            }
+        });
+
+        Backend::new(&addr.to_string()).unwrap()
+    }
+
+    /// Answers `200 service is healthy` to requests with the right
+    /// credentials (and `example.com` as host), `401` to the others
+    fn helper_authenticated(request: &str) -> &'static str {
+        let authorized = request.contains("authorization: bearer secret")
+            && request.contains("host: example.com");
+
+        if authorized {
+            "HTTP/1.1 200 OK\r\nContent-Length: 18\r\n\r\nservice is healthy"
+        } else {
+            "HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n"
+        }
+    }
+
+    fn helper_config(authorization: &str, expected_body: Option<&str>) -> RouteHealthCheck {
+        RouteHealthCheck {
+            method: "GET".into(),
+            path: "/health".into(),
+            headers: vec![RouteHeaderAdd {
+                name: "Authorization".into(),
+                value: authorization.to_string().into(),
+            }],
+            expected_body: expected_body.map(|v| v.to_string().into()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wrong_auth_header_is_unhealthy() {
+        let backend = helper_upstream(helper_authenticated).await;
+        let check = HttpHealthCheck::new(
+            "example.com",
+            &helper_config("Bearer wrong", None),
+            &HashMap::new(),
+        );
+
+        assert!(check.check(&backend).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_right_auth_header_and_body_is_healthy() {
+        let backend = helper_upstream(helper_authenticated).await;
+        let check = HttpHealthCheck::new(
+            "example.com",
+            &helper_config("Bearer secret", Some("is healthy")),
+            &HashMap::new(),
+        );
+
+        assert!(check.check(&backend).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_body_is_unhealthy() {
+        let backend = helper_upstream(helper_authenticated).await;
+        let check = HttpHealthCheck::new(
+            "example.com",
+            &helper_config("Bearer secret", Some("degraded")),
+            &HashMap::new(),
+        );
+
+        assert!(check.check(&backend).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_host_header_can_be_overridden() {
+        let backend = helper_upstream(helper_authenticated).await;
+        let mut config = helper_config("Bearer secret", None);
+        config.headers.push(RouteHeaderAdd {
+            name: "Host".into(),
+            value: "other.example.org".into(),
+        });
+
+        let check = HttpHealthCheck::new("example.com", &config, &HashMap::new());
+        assert!(check.check(&backend).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_head_health_check() {
+        // Only `HEAD /health` is answered with a 200 (without a body)
+        let backend = helper_upstream(|request| {
+            if request.starts_with("head /health ") {
+                "HTTP/1.1 200 OK\r\nContent-Length: 18\r\n\r\n"
+            } else {
+                "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\n\r\n"
+            }
+        })
+        .await;
+
+        let mut config = RouteHealthCheck {
+            path: "/health".into(),
+            ..RouteHealthCheck::default()
+        };
+        let check = HttpHealthCheck::new("example.com", &config, &HashMap::new());
+        assert!(check.check(&backend).await.is_err());
+
+        config.method = "HEAD".into();
+        let check = HttpHealthCheck::new("example.com", &config, &HashMap::new());
+        assert!(check.check(&backend).await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_tls_upstreams_are_checked_with_tls() {
+        let (cert, key) = test_support::self_signed_certificate("example.com", 2048, 1);
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server()).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        acceptor.set_private_key(&key).unwrap();
+        let acceptor = acceptor.build();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                if let Ok(mut tls) = acceptor.accept(stream.unwrap()) {
+                    let mut request = [0; 4096];
+                    let _ = tls.read(&mut request);
+                    tls.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                        .ok();
+                }
+            }
+        });
+
+        // The upstream isn't on port 443, only its TLS flag enables TLS
+        let backend = Backend::new(&addr.to_string()).unwrap();
+        let config = RouteHealthCheck::default();
+        let check = HttpHealthCheck::new("example.com", &config, &HashMap::new());
+        assert!(check.check(&backend).await.is_err());
+
+        let tags = RouteBackendTags {
+            tls: true,
+            ..RouteBackendTags::default()
+        };
+        let check = HttpHealthCheck::new("example.com", &config, &HashMap::from([(addr, tags)]));
+        assert!(check.check(&backend).await.is_ok());
+    }
+}
@@ -1,4 +1,4 @@
-use std::time::Duration;
+use std::{collections::HashMap, time::Duration};
 
 use async_trait::async_trait;
 use pingora::{
@@ -6,7 +6,14 @@ use pingora::{
     services::Service,
 };
 
-use crate::stores::{self};
+use tokio::time::Instant;
+
+use crate::{
+    services::run_until_shutdown,
+    stores::{self, routes::RouteStoreContainer},
+};
+
+pub mod http_check;
 
 /// Health check service that will run health checks on all upstreams
 /// And update the route store with the new healthy upstreams.
@@ -19,28 +26,97 @@ impl HealthService {
     }
 }
 
+/// The interval between two health checks of a route
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The longest interval between two health checks of a route which is down
+const MAX_HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(8 * 60);
+
+/// When the health checks of a route are due. While all of its upstreams are
+/// down, the interval between two checks doubles (up to
+/// `MAX_HEALTH_CHECK_INTERVAL`) so that a recovering service isn't flooded
+/// with probes. It is reset as soon as one of them is healthy again.
+#[derive(Debug, Default)]
+struct HealthCheckSchedule {
+    down_checks: u32,
+    next_check: Option<Instant>,
+}
+
+impl HealthCheckSchedule {
+    fn is_due(&self, now: Instant) -> bool {
+        self.next_check.is_none_or(|at| now >= at)
+    }
+
+    fn interval(&self) -> Duration {
+        HEALTH_CHECK_INTERVAL
+            .saturating_mul(2u32.saturating_pow(self.down_checks))
+            .min(MAX_HEALTH_CHECK_INTERVAL)
+    }
+
+    /// Schedules the next check after a check made at `now`
+    fn record_check(&mut self, all_down: bool, now: Instant) {
+        self.down_checks = if all_down {
+            self.down_checks.saturating_add(1)
+        } else {
+            0
+        };
+        self.next_check = Some(now + self.interval());
+    }
+}
+
+/// Whether none of the upstreams of the route is healthy
+fn all_upstreams_down(route_container: &RouteStoreContainer) -> bool {
+    let backends = route_container.load_balancer.backends();
+    let backends_list = backends.get_backend();
+
+    !backends_list.is_empty() && !backends_list.iter().any(|backend| backends.ready(backend))
+}
+
+/// Runs the health checks of the `routes` that are due. The load balancers
+/// are shared with the route store and updated in place: the routes must not
+/// be inserted back, as they may have been replaced or removed meanwhile.
+async fn run_due_health_checks<'a>(
+    routes: impl IntoIterator<Item = (&'a String, &'a RouteStoreContainer)>,
+    schedules: &mut HashMap<String, HealthCheckSchedule>,
+    now: Instant,
+) {
+    for (host, route_container) in routes {
+        let schedule = schedules.entry(host.clone()).or_default();
+        if !schedule.is_due(now) {
+            continue;
+        }
+
+        tracing::trace!("Running health check for host {}", host);
+
+        route_container.load_balancer.update().await.ok();
+        route_container
+            .load_balancer
+            .backends()
+            .run_health_check(false)
+            .await;
+
+        let all_down = all_upstreams_down(route_container);
+        schedule.record_check(all_down, now);
+        if all_down {
+            tracing::debug!(
+                host,
+                next_check_secs = schedule.interval().as_secs(),
+                "all upstreams are down, backing off the health checks"
+            );
+        }
+    }
+}
+
 async fn run_health_check_loop() {
-    let mut interval = tokio::time::interval(Duration::from_secs(30));
+    let mut schedules: HashMap<String, HealthCheckSchedule> = HashMap::new();
+    let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
     interval.tick().await;
 
     loop {
-        interval.tick().await;
-
-        for (host, route_container) in &stores::get_routes() {
-            tracing::trace!("Running health check for host {}", host);
-
-            // clone the route_container
-            let route_container = route_container.clone();
-            route_container.load_balancer.update().await.ok();
-            route_container
-                .load_balancer
-                .backends()
-                .run_health_check(false)
-                .await;
-
-            // insert it back into the store
-            stores::insert_route(host.clone(), route_container);
-        }
+        let now = interval.tick().await;
+        let routes = stores::get_routes();
+        schedules.retain(|host, _| routes.contains_key(host));
+        run_due_health_checks(routes.iter(), &mut schedules, now).await;
     }
 }
 
@@ -49,12 +125,12 @@ impl Service for HealthService {
     async fn start_service(
         &mut self,
         _fds: Option<ListenFds>,
-        _shutdown: ShutdownWatch,
+        mut shutdown: ShutdownWatch,
         _listeners_per_fd: usize,
     ) {
         tracing::info!("Starting health check service");
 
-        run_health_check_loop().await;
+        run_until_shutdown(&mut shutdown, run_health_check_loop()).await;
     }
 
     fn name(&self) -> &'static str {
@@ -65,3 +141,76 @@ impl Service for HealthService {
         Some(1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RouteHealthCheck;
+    use crate::test_support::{add_route, check_health, route, TestBackend};
+
+    #[test]
+    fn test_interval_backs_off_while_down_and_resets_on_recovery() {
+        let mut schedule = HealthCheckSchedule::default();
+        let start = Instant::now();
+        assert!(schedule.is_due(start));
+
+        let mut now = start;
+        let mut intervals = Vec::new();
+        for _ in 0..6 {
+            schedule.record_check(true, now);
+            intervals.push(schedule.interval().as_secs());
+
+            assert!(!schedule.is_due(now + schedule.interval() - Duration::from_secs(1)));
+            now += schedule.interval();
+            assert!(schedule.is_due(now));
+        }
+        assert_eq!(intervals, [60, 120, 240, 480, 480, 480]);
+
+        schedule.record_check(false, now);
+        assert_eq!(schedule.interval(), HEALTH_CHECK_INTERVAL);
+        assert!(schedule.is_due(now + HEALTH_CHECK_INTERVAL));
+    }
+
+    #[tokio::test]
+    async fn test_all_upstreams_down() {
+        let mut first = TestBackend::start(200, "").await;
+        let mut second = TestBackend::start(200, "").await;
+        let host = "down.health-check.test";
+        add_route(route(host, [first.addr(), second.addr()])).await;
+
+        check_health(host).await;
+        assert!(!all_upstreams_down(
+            &stores::get_route_by_key(host).unwrap()
+        ));
+
+        first.stop().await;
+        check_health(host).await;
+        assert!(!all_upstreams_down(
+            &stores::get_route_by_key(host).unwrap()
+        ));
+
+        second.stop().await;
+        check_health(host).await;
+        assert!(all_upstreams_down(&stores::get_route_by_key(host).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_routes_removed_during_a_health_check_stay_removed() {
+        let backend = TestBackend::start_delayed(200, "", Duration::from_millis(300)).await;
+        let host = "removed.health-check.test";
+        let mut removed = route(host, [backend.addr()]);
+        removed.health_check = Some(RouteHealthCheck::default());
+        add_route(removed).await;
+
+        let route_container = stores::get_route_by_key(host).unwrap();
+        let checks = tokio::spawn(async move {
+            let routes = HashMap::from([(host.to_string(), route_container)]);
+            run_due_health_checks(&routes, &mut HashMap::new(), Instant::now()).await;
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        stores::remove_route(host);
+        checks.await.unwrap();
+
+        assert!(stores::get_route_by_key(host).is_none());
+    }
+}
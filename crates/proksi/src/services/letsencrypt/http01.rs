@@ -14,6 +14,7 @@ use tracing::info;
 
 use crate::{
     config::Config,
+    services::run_until_shutdown,
     stores::{self, certificates::Certificate},
 };
 
@@ -58,18 +59,15 @@ impl LetsencryptService {
             .map(str::trim)
             .collect::<Vec<&str>>();
 
-        let leaf_pem = split.first();
-        let chain_pem = split.get(1);
-
-        let Some(leaf) = leaf_pem else {
+        let Some((leaf, chain_pem)) = split.split_first() else {
             return Err(anyhow::anyhow!("Certificate is empty"));
         };
         let leaf = Self::parse_x509_cert(leaf)?;
-        let mut chain: Option<X509> = None;
+        let mut chain = Vec::with_capacity(chain_pem.len());
 
-        if let Some(chain_pem) = chain_pem {
+        for chain_pem in chain_pem.iter().filter(|pem| !pem.is_empty()) {
             tracing::trace!("chain PEM: {:?}", chain_pem);
-            chain = Some(Self::parse_x509_cert(chain_pem)?);
+            chain.push(Self::parse_x509_cert(chain_pem)?);
         }
 
         let key = Self::parse_private_key(key_pem)?;
@@ -159,7 +157,7 @@ impl LetsencryptService {
                 Certificate {
                     key,
                     leaf: openssl_cert,
-                    chain: None,
+                    chain: Vec::new(),
                 },
             )
             .await
@@ -312,7 +310,7 @@ impl Service for LetsencryptService {
     async fn start_service(
         &mut self,
         _fds: Option<ListenFds>,
-        mut _shutdown: ShutdownWatch,
+        mut shutdown: ShutdownWatch,
         _listeners_per_fd: usize,
     ) {
         if self.config.lets_encrypt.enabled.is_some_and(|v| !v) {
@@ -331,10 +329,13 @@ impl Service for LetsencryptService {
             .account(&self.config.lets_encrypt.email)
             .expect("failed to create or retrieve existing account");
 
-        let _ = tokio::join!(
-            self.watch_for_route_changes(&account),
-            self.check_for_certificates_expiration(&account)
-        );
+        run_until_shutdown(&mut shutdown, async {
+            tokio::join!(
+                self.watch_for_route_changes(&account),
+                self.check_for_certificates_expiration(&account)
+            )
+        })
+        .await;
     }
 
     fn name(&self) -> &'static str {
@@ -0,0 +1,354 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use futures::future::join_all;
+use pingora::{
+    server::{ListenFds, ShutdownWatch},
+    services::Service,
+};
+
+use crate::{config::RouteLoadWeights, services::run_until_shutdown, stores};
+
+/// Scrapes the load metric of the upstreams of the routes with `load_weights`
+/// and derives their weights from it, so that busy upstreams get less traffic.
+pub struct LoadWeightsService {}
+
+impl LoadWeightsService {
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+async fn run_load_weights_loop() {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(2))
+        .no_proxy()
+        .build()
+        .expect("failed to create the load metrics client");
+
+    let mut scraped_at: HashMap<String, Instant> = HashMap::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+
+        let routes = stores::get_routes()
+            .iter()
+            .filter_map(|(host, route_container)| {
+                let load_weights = route_container.load_weights.as_ref()?;
+                Some((
+                    host.clone(),
+                    Duration::from_secs(load_weights.interval_secs),
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        // The routes are scraped concurrently, the slowest one bounds the tick
+        let due = due_routes(&mut scraped_at, routes, now);
+        join_all(due.iter().map(|host| refresh_route_weights(&client, host))).await;
+    }
+}
+
+/// The hosts whose weights have to be refreshed at `now`, marked as scraped.
+/// The hosts that no longer have `load_weights` are forgotten.
+fn due_routes(
+    scraped_at: &mut HashMap<String, Instant>,
+    routes: Vec<(String, Duration)>,
+    now: Instant,
+) -> Vec<String> {
+    scraped_at.retain(|host, _| routes.iter().any(|(route_host, _)| route_host == host));
+
+    let mut due = Vec::new();
+    for (host, every) in routes {
+        let is_due = scraped_at
+            .get(&host)
+            .is_none_or(|at| now.duration_since(*at) >= every);
+
+        if is_due {
+            scraped_at.insert(host.clone(), now);
+            due.push(host);
+        }
+    }
+
+    due
+}
+
+/// Scrapes the load of every backend of `host` and updates their weights.
+/// Backends whose load can't be scraped keep their current weight.
+pub async fn refresh_route_weights(client: &reqwest::Client, host: &str) {
+    let Some(route_container) = stores::get_route_by_key(host) else {
+        return;
+    };
+    let (Some(load_weights), Some(smooth_weighted)) = (
+        route_container.load_weights.as_ref(),
+        route_container.smooth_weighted.as_ref(),
+    ) else {
+        return;
+    };
+
+    let addrs = route_container
+        .load_balancer
+        .backends()
+        .get_backend()
+        .iter()
+        .filter_map(|backend| backend.addr.as_inet().copied())
+        .collect::<Vec<_>>();
+
+    // The backends are scraped concurrently, a slow one only delays its route
+    let loads = join_all(
+        addrs
+            .iter()
+            .map(|addr| scrape_load(client, addr, load_weights)),
+    )
+    .await;
+
+    let mut weights = HashMap::new();
+    for (addr, load) in addrs.iter().zip(loads) {
+        match load {
+            Some(load) => {
+                weights.insert(*addr, weight_from_load(load, load_weights));
+            }
+            None => tracing::debug!(
+                host,
+                backend = %addr,
+                "could not scrape the load of the backend, keeping its weight"
+            ),
+        }
+    }
+
+    tracing::trace!(host, ?weights, "backend weights derived from their load");
+    smooth_weighted.set_weights(&weights);
+}
+
+async fn scrape_load(
+    client: &reqwest::Client,
+    addr: &SocketAddr,
+    load_weights: &RouteLoadWeights,
+) -> Option<f64> {
+    let response = client
+        .get(format!("http://{addr}{}", load_weights.path))
+        .send()
+        .await
+        .ok()?;
+
+    if !response.status().is_success() {
+        return None;
+    }
+
+    parse_metric(&response.text().await.ok()?, &load_weights.metric)
+}
+
+/// The value of the first sample of `metric` in a Prometheus text exposition
+fn parse_metric(body: &str, metric: &str) -> Option<f64> {
+    body.lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#'))
+        .find_map(|line| {
+            let sample = line.strip_prefix(metric)?;
+            let sample = match sample.strip_prefix('{') {
+                Some(labels) => labels.split_once('}')?.1,
+                None => sample,
+            };
+
+            // Another metric starting with the same name
+            if !sample.starts_with(char::is_whitespace) {
+                return None;
+            }
+
+            sample.split_whitespace().next()?.parse().ok()
+        })
+}
+
+/// An idle backend gets `max_weight`, a fully loaded one (`max_load`) gets 1
+fn weight_from_load(load: f64, load_weights: &RouteLoadWeights) -> usize {
+    let load = (load / load_weights.max_load).clamp(0.0, 1.0);
+    let weight = ((1.0 - load) * load_weights.max_weight as f64).round() as usize;
+
+    weight.max(1)
+}
+
+#[async_trait]
+impl Service for LoadWeightsService {
+    async fn start_service(
+        &mut self,
+        _fds: Option<ListenFds>,
+        mut shutdown: ShutdownWatch,
+        _listeners_per_fd: usize,
+    ) {
+        tracing::info!("Starting load weights service");
+
+        run_until_shutdown(&mut shutdown, run_load_weights_loop()).await;
+    }
+
+    fn name(&self) -> &'static str {
+        "load_weights_service"
+    }
+
+    fn threads(&self) -> Option<usize> {
+        Some(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        stores::routes::BackendCriteria,
+        test_support::{add_route, route, TestBackend},
+    };
+
+    fn helper_load_weights() -> RouteLoadWeights {
+        RouteLoadWeights {
+            path: "/metrics".into(),
+            metric: "cpu_usage".into(),
+            max_load: 1.0,
+            max_weight: 10,
+            interval_secs: 10,
+        }
+    }
+
+    fn helper_weight(host: &str, addr: SocketAddr) -> Option<usize> {
+        stores::get_route_by_key(host)?
+            .smooth_weighted?
+            .weight(&addr)
+    }
+
+    #[test]
+    fn test_parse_metric() {
+        let body = "# HELP cpu_usage The CPU usage\n\
+                    # TYPE cpu_usage gauge\n\
+                    cpu_usage_seconds_total 1200\n\
+                    cpu_usage{core=\"all\"} 0.75\n\
+                    cpu_usage 0.5\n";
+
+        assert_eq!(parse_metric(body, "cpu_usage"), Some(0.75));
+        assert_eq!(parse_metric(body, "cpu_usage_seconds_total"), Some(1200.0));
+        assert_eq!(
+            parse_metric("cpu_usage 0.2 1700000000000", "cpu_usage"),
+            Some(0.2)
+        );
+        assert_eq!(parse_metric(body, "memory_usage"), None);
+        assert_eq!(parse_metric("cpu_usage high", "cpu_usage"), None);
+    }
+
+    #[test]
+    fn test_weight_from_load() {
+        let load_weights = helper_load_weights();
+
+        assert_eq!(weight_from_load(0.0, &load_weights), 10);
+        assert_eq!(weight_from_load(0.25, &load_weights), 8);
+        assert_eq!(weight_from_load(0.9, &load_weights), 1);
+        assert_eq!(weight_from_load(3.0, &load_weights), 1);
+
+        // The load can be in another unit (ex: a percentage)
+        let load_weights = RouteLoadWeights {
+            max_load: 100.0,
+            ..helper_load_weights()
+        };
+        assert_eq!(weight_from_load(50.0, &load_weights), 5);
+    }
+
+    #[tokio::test]
+    async fn test_weights_shift_away_from_busy_backends() {
+        let idle = TestBackend::start(200, "cpu_usage 0.1\n").await;
+        let busy = TestBackend::start(200, "cpu_usage 0.9\n").await;
+        let host = "shifted.load-weights.test";
+
+        let mut route = route(host, [idle.addr(), busy.addr()]);
+        route.load_weights = Some(helper_load_weights());
+        add_route(route).await;
+
+        // Until the first scrape, the configured weights are used
+        assert_eq!(helper_weight(host, idle.addr()), Some(1));
+        assert_eq!(helper_weight(host, busy.addr()), Some(1));
+
+        refresh_route_weights(&reqwest::Client::new(), host).await;
+        assert_eq!(helper_weight(host, idle.addr()), Some(9));
+        assert_eq!(helper_weight(host, busy.addr()), Some(1));
+
+        let route_container = stores::get_route_by_key(host).unwrap();
+        let selected = (0..10)
+            .filter_map(|_| {
+                route_container.select_backend(&BackendCriteria::new(&http::Method::GET))
+            })
+            .filter(|(backend, _)| backend.addr.as_inet() == Some(&idle.addr()))
+            .count();
+        assert_eq!(selected, 9);
+    }
+
+    #[test]
+    fn test_due_routes() {
+        let start = Instant::now();
+        let every = Duration::from_secs(10);
+        let mut scraped_at = HashMap::new();
+
+        let routes = vec![("a.test".to_string(), every), ("b.test".to_string(), every)];
+        assert_eq!(
+            due_routes(&mut scraped_at, routes.clone(), start),
+            ["a.test", "b.test"]
+        );
+        assert!(due_routes(
+            &mut scraped_at,
+            routes.clone(),
+            start + Duration::from_secs(9)
+        )
+        .is_empty());
+
+        // A removed route is forgotten, it is scraped right away if it comes back
+        due_routes(
+            &mut scraped_at,
+            routes[..1].to_vec(),
+            start + Duration::from_secs(9),
+        );
+        assert_eq!(scraped_at.keys().collect::<Vec<_>>(), ["a.test"]);
+        assert_eq!(
+            due_routes(&mut scraped_at, routes, start + every),
+            ["a.test", "b.test"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_backends_are_scraped_concurrently() {
+        let delay = Duration::from_millis(500);
+        let mut backends = Vec::new();
+        for _ in 0..3 {
+            backends.push(TestBackend::start_delayed(200, "cpu_usage 0.5\n", delay).await);
+        }
+        let host = "concurrent.load-weights.test";
+
+        let mut route = route(host, backends.iter().map(TestBackend::addr));
+        route.load_weights = Some(helper_load_weights());
+        add_route(route).await;
+
+        let started = Instant::now();
+        refresh_route_weights(&reqwest::Client::new(), host).await;
+        assert!(started.elapsed() < delay * 2, "{:?}", started.elapsed());
+        for backend in &backends {
+            assert_eq!(helper_weight(host, backend.addr()), Some(5));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unreachable_backend_keeps_its_weight() {
+        let idle = TestBackend::start(200, "cpu_usage 0\n").await;
+        let mut stopped = TestBackend::start(200, "cpu_usage 0\n").await;
+        let invalid = TestBackend::start(200, "cpu_usage unknown\n").await;
+        stopped.stop().await;
+        let host = "unreachable.load-weights.test";
+
+        let mut route = route(host, [idle.addr(), stopped.addr(), invalid.addr()]);
+        route.upstreams[1].weight = Some(3);
+        route.load_weights = Some(helper_load_weights());
+        add_route(route).await;
+
+        refresh_route_weights(&reqwest::Client::new(), host).await;
+        assert_eq!(helper_weight(host, idle.addr()), Some(10));
+        assert_eq!(helper_weight(host, stopped.addr()), Some(3));
+        assert_eq!(helper_weight(host, invalid.addr()), Some(1));
+    }
+}
@@ -0,0 +1,288 @@
+use std::fmt;
+
+use once_cell::sync::Lazy;
+
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_subscriber::{
+    fmt::{
+        format::{Format, Writer},
+        FmtContext, FormatEvent, FormatFields,
+    },
+    registry::LookupSpan,
+};
+
+static CLF_DATE_FORMAT: Lazy<Vec<time::format_description::FormatItem<'static>>> =
+    Lazy::new(|| {
+        time::format_description::parse(
+            "[day]/[month repr:short]/[year]:[hour]:[minute]:[second] \
+             [offset_hour sign:mandatory][offset_minute]",
+        )
+        .expect("Unable to create the CLF date formatter; this is a bug")
+    });
+
+/// The classic (Apache) access log layouts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClfStyle {
+    /// `host ident authuser [date] "request" status bytes`
+    Common,
+    /// Common Log Format followed by `"referer" "user-agent"`
+    Combined,
+}
+
+/// The structured request record emitted by the router for every access log.
+/// It is built from the fields of the `access_log` tracing event.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct AccessLogRecord {
+    pub client_ip: String,
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub http_version: String,
+    pub status_code: u64,
+    pub bytes_sent: u64,
+    pub referer: String,
+    pub user_agent: String,
+}
+
+impl AccessLogRecord {
+    /// Formats the record using the Common Log Format
+    pub fn to_common(&self, date: time::OffsetDateTime) -> String {
+        let bytes = if self.bytes_sent == 0 {
+            "-".to_string()
+        } else {
+            self.bytes_sent.to_string()
+        };
+
+        format!(
+            "{} - - [{}] \"{}\" {} {}",
+            or_dash(&self.remote_host()),
+            format_clf_date(date),
+            escape(&self.request_line()),
+            self.status_code,
+            bytes
+        )
+    }
+
+    /// Formats the record using the Combined Log Format
+    pub fn to_combined(&self, date: time::OffsetDateTime) -> String {
+        format!(
+            "{} \"{}\" \"{}\"",
+            self.to_common(date),
+            escape(or_dash(&self.referer)),
+            escape(or_dash(&self.user_agent)),
+        )
+    }
+
+    /// Formats the record using the given style
+    pub fn format(&self, style: ClfStyle, date: time::OffsetDateTime) -> String {
+        match style {
+            ClfStyle::Common => self.to_common(date),
+            ClfStyle::Combined => self.to_combined(date),
+        }
+    }
+
+    /// The client address without the port (if any)
+    fn remote_host(&self) -> String {
+        self.client_ip
+            .parse::<std::net::SocketAddr>()
+            .map_or_else(|_| self.client_ip.clone(), |addr| addr.ip().to_string())
+    }
+
+    /// `METHOD /path?query PROTOCOL`
+    fn request_line(&self) -> String {
+        let mut line = format!("{} {}", self.method, self.path);
+        if !self.query.is_empty() {
+            line.push('?');
+            line.push_str(&self.query);
+        }
+
+        line.push(' ');
+        line.push_str(&self.http_version.to_uppercase());
+        line
+    }
+}
+
+impl Visit for AccessLogRecord {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let target = match field.name() {
+            "client_ip" => &mut self.client_ip,
+            "method" => &mut self.method,
+            "path" => &mut self.path,
+            "query" => &mut self.query,
+            "http_version" => &mut self.http_version,
+            "referer" => &mut self.referer,
+            "user_agent" => &mut self.user_agent,
+            _ => return,
+        };
+
+        value.clone_into(target);
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match field.name() {
+            "status_code" => self.status_code = value,
+            "bytes_sent" => self.bytes_sent = value,
+            _ => {}
+        }
+    }
+
+    // None of the fields used by the log formats are recorded as `Debug`
+    fn record_debug(&mut self, _: &Field, _: &dyn fmt::Debug) {}
+}
+
+/// A `FormatEvent` that writes access logs in the Common or Combined Log Format.
+/// Every other event (info, errors etc) is written using the default text format.
+pub struct ClfEventFormat {
+    style: ClfStyle,
+    fallback: Format,
+}
+
+impl ClfEventFormat {
+    pub fn new(style: ClfStyle) -> Self {
+        Self {
+            style,
+            fallback: Format::default().with_ansi(false),
+        }
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for ClfEventFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        if event.metadata().fields().field("access_log").is_none() {
+            return self.fallback.format_event(ctx, writer, event);
+        }
+
+        let mut record = AccessLogRecord::default();
+        event.record(&mut record);
+
+        writeln!(
+            writer,
+            "{}",
+            record.format(self.style, time::OffsetDateTime::now_utc())
+        )
+    }
+}
+
+/// Formats a date as `10/Oct/2000:13:55:36 +0000`
+fn format_clf_date(date: time::OffsetDateTime) -> String {
+    date.format(&*CLF_DATE_FORMAT).unwrap_or_default()
+}
+
+fn or_dash(value: &str) -> &str {
+    if value.is_empty() {
+        "-"
+    } else {
+        value
+    }
+}
+
+/// Escapes double quotes and backslashes so that quoted fields can't be broken
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::LogCapture;
+
+    fn helper_record() -> AccessLogRecord {
+        AccessLogRecord {
+            client_ip: "10.0.0.1:54321".to_string(),
+            method: "GET".to_string(),
+            path: "/index.html".to_string(),
+            query: "page=1".to_string(),
+            http_version: "http/1.1".to_string(),
+            status_code: 200,
+            bytes_sent: 2326,
+            referer: "https://example.com/start".to_string(),
+            user_agent: "Mozilla/5.0 (\"test\")".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_common_log_format() {
+        let date = time::OffsetDateTime::from_unix_timestamp(0).unwrap();
+        let line = helper_record().to_common(date);
+
+        assert_eq!(
+            line,
+            r#"10.0.0.1 - - [01/Jan/1970:00:00:00 +0000] "GET /index.html?page=1 HTTP/1.1" 200 2326"#
+        );
+    }
+
+    #[test]
+    fn test_combined_log_format() {
+        let date = time::OffsetDateTime::from_unix_timestamp(0).unwrap();
+        let line = helper_record().to_combined(date);
+
+        assert_eq!(
+            line,
+            r#"10.0.0.1 - - [01/Jan/1970:00:00:00 +0000] "GET /index.html?page=1 HTTP/1.1" 200 2326 "https://example.com/start" "Mozilla/5.0 (\"test\")""#
+        );
+    }
+
+    #[test]
+    fn test_common_log_format_empty_values() {
+        let date = time::OffsetDateTime::from_unix_timestamp(0).unwrap();
+        let record = AccessLogRecord {
+            method: "HEAD".to_string(),
+            path: "/".to_string(),
+            http_version: "http/2".to_string(),
+            status_code: 304,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            record.to_combined(date),
+            r#"- - - [01/Jan/1970:00:00:00 +0000] "HEAD / HTTP/2" 304 - "-" "-""#
+        );
+    }
+
+    #[test]
+    fn test_event_format_writes_access_log_lines() {
+        let writer = LogCapture::default();
+        let subscriber = tracing_subscriber::fmt()
+            .event_format(ClfEventFormat::new(ClfStyle::Combined))
+            .with_writer(writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info!(
+                method = "POST",
+                path = "/api",
+                query = "",
+                host = None::<&str>,
+                duration_ms = 10u128,
+                user_agent = "curl/8.0",
+                referer = "",
+                client_ip = "192.168.1.20:4000",
+                status_code = 201u16,
+                bytes_sent = 17usize,
+                http_version = "http/1.1",
+                access_log = true
+            );
+            tracing::info!("not an access log");
+        });
+
+        let output = writer.output();
+        let lines = output.lines().collect::<Vec<_>>();
+
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("192.168.1.20 - - ["));
+        assert!(lines[0].ends_with(r#"] "POST /api HTTP/1.1" 201 17 "-" "curl/8.0""#));
+        assert!(lines[1].contains("not an access log"));
+    }
+}
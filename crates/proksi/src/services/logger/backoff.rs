@@ -0,0 +1,81 @@
+use std::time::{Duration, Instant};
+
+/// The delay before the first retry of a failing log target
+const MIN_RETRY_DELAY: Duration = Duration::from_secs(1);
+/// The delay is doubled on every failure, up to this one
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Backs off a log target that keeps failing (a syslog server that is down, a
+/// file that can't be opened): the lines are dropped until the next retry
+/// instead of waiting on the target for each of them, and a failure is only
+/// reported once per retry.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    delay: Duration,
+    retry_at: Option<Instant>,
+    dropped: u64,
+}
+
+impl Backoff {
+    /// Whether the target is skipped until its next retry, the skipped line
+    /// is counted as dropped
+    pub fn skip(&mut self, now: Instant) -> bool {
+        if self.retry_at.is_some_and(|retry_at| now < retry_at) {
+            self.dropped += 1;
+            return true;
+        }
+
+        false
+    }
+
+    /// Schedules the next retry after a failure, returns the number of lines
+    /// dropped since the previous one
+    pub fn fail(&mut self, now: Instant) -> u64 {
+        self.delay = match self.retry_at {
+            Some(_) => (self.delay * 2).min(MAX_RETRY_DELAY),
+            None => MIN_RETRY_DELAY,
+        };
+        self.retry_at = Some(now + self.delay);
+
+        std::mem::take(&mut self.dropped)
+    }
+
+    /// The target works again, the next failure is retried quickly
+    pub fn succeed(&mut self) {
+        self.retry_at = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_retries_are_backed_off() {
+        let start = Instant::now();
+        let mut backoff = Backoff::default();
+        assert!(!backoff.skip(start));
+
+        assert_eq!(backoff.fail(start), 0);
+        assert!(backoff.skip(start));
+        assert!(backoff.skip(start + Duration::from_millis(999)));
+        assert!(!backoff.skip(start + MIN_RETRY_DELAY));
+
+        // The delay doubles on every failure, the dropped lines are reported once
+        let retry = start + MIN_RETRY_DELAY;
+        assert_eq!(backoff.fail(retry), 2);
+        assert!(backoff.skip(retry + Duration::from_millis(1999)));
+        assert!(!backoff.skip(retry + Duration::from_secs(2)));
+        assert_eq!(backoff.fail(retry), 1);
+
+        for _ in 0..10 {
+            backoff.fail(retry);
+        }
+        assert!(!backoff.skip(retry + MAX_RETRY_DELAY));
+
+        backoff.succeed();
+        assert!(!backoff.skip(retry));
+        backoff.fail(retry);
+        assert!(!backoff.skip(retry + MIN_RETRY_DELAY));
+    }
+}
@@ -1,11 +1,15 @@
 use std::{
+    cell::RefCell,
+    collections::HashMap,
     io,
+    path::Path,
     pin::Pin,
     sync::{
         atomic::{AtomicI64, Ordering},
         Arc,
     },
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 
 use async_trait::async_trait;
@@ -18,25 +22,79 @@ use pingora::{
 use rotation::Rotation;
 use tokio::{
     io::AsyncWriteExt,
-    sync::mpsc::{UnboundedReceiver, UnboundedSender},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        oneshot,
+    },
 };
 use tracing_subscriber::fmt::MakeWriter;
 
-use crate::config::Config;
+use crate::{
+    config::Config,
+    services::wait_for_shutdown,
+};
 
+mod access_log;
+mod backoff;
 mod rotation;
+mod syslog;
+
+pub use access_log::{ClfEventFormat, ClfStyle};
+use backoff::Backoff;
+use syslog::SyslogSink;
+
+/// A log sent to the background service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLine {
+    /// The file the log is written to instead of the global logger target
+    pub destination: Option<Arc<str>>,
+    /// Whether the log is an access log (sent to `logging.syslog` if set)
+    pub access_log: bool,
+    pub buf: Vec<u8>,
+}
+
+impl From<Vec<u8>> for LogLine {
+    fn from(buf: Vec<u8>) -> Self {
+        LogLine {
+            destination: None,
+            access_log: false,
+            buf,
+        }
+    }
+}
+
+thread_local! {
+    static ACCESS_LOG_DESTINATION: RefCell<Option<Arc<str>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` with the access logs it emits written to `destination` (if set)
+/// instead of the global logger target, other logs are not affected
+pub fn with_access_log_destination<R>(destination: Option<&Arc<str>>, f: impl FnOnce() -> R) -> R {
+    let previous = ACCESS_LOG_DESTINATION.replace(destination.cloned());
+    let result = f();
+    ACCESS_LOG_DESTINATION.set(previous);
+    result
+}
 
 /// A `io::Write` implementation that sends logs to a background service
 #[derive(Debug, Clone)]
 pub struct StdoutWriter<'a> {
-    chan: &'a UnboundedSender<Vec<u8>>,
+    chan: &'a UnboundedSender<LogLine>,
     skip_log: bool,
+    destination: Option<Arc<str>>,
+    access_log: bool,
 }
 
 impl io::Write for StdoutWriter<'_> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         if !self.skip_log {
-            self.chan.send(buf.to_vec()).ok();
+            self.chan
+                .send(LogLine {
+                    destination: self.destination.clone(),
+                    access_log: self.access_log,
+                    buf: buf.to_vec(),
+                })
+                .ok();
         }
         Ok(buf.len())
     }
@@ -54,7 +112,7 @@ impl io::Write for StdoutWriter<'_> {
 #[derive(Debug)]
 pub struct ProxyLog {
     enabled: bool,
-    chan: UnboundedSender<Vec<u8>>,
+    chan: UnboundedSender<LogLine>,
     access_logs: bool,
     error_logs: bool,
 }
@@ -62,7 +120,7 @@ pub struct ProxyLog {
 impl ProxyLog {
     #[allow(clippy::fn_params_excessive_bools)]
     pub fn new(
-        sender: UnboundedSender<Vec<u8>>,
+        sender: UnboundedSender<LogLine>,
         log_enabled: bool,
         access_logs: bool,
         error_logs: bool,
@@ -85,6 +143,8 @@ impl<'a> MakeWriter<'a> for ProxyLog {
         StdoutWriter {
             skip_log: false,
             chan: &self.chan,
+            destination: None,
+            access_log: false,
         }
     }
 
@@ -96,13 +156,22 @@ impl<'a> MakeWriter<'a> for ProxyLog {
             skip_log = meta.level() == &tracing::Level::ERROR;
         }
 
+        let is_access_log = meta.fields().field("access_log").is_some();
         if !self.access_logs {
-            skip_log = meta.fields().field("access_log").is_some();
+            skip_log = is_access_log;
         }
 
+        let destination = if is_access_log {
+            ACCESS_LOG_DESTINATION.with_borrow(Clone::clone)
+        } else {
+            None
+        };
+
         StdoutWriter {
             skip_log: skip_log || !self.enabled,
             chan: &self.chan,
+            destination,
+            access_log: is_access_log,
         }
     }
 }
@@ -140,11 +209,41 @@ impl tokio::io::AsyncWrite for LogWriter {
     }
 }
 
+/// After a shutdown signal, the logger keeps writing logs (ex: access logs of in-flight
+/// requests) until no new log is received for this long
+const SHUTDOWN_IDLE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// A handle to ask the `ProxyLoggerReceiver` to write every queued log and flush them,
+/// used when the process exits without a graceful shutdown (ex: `SIGINT`)
+#[derive(Debug, Clone)]
+pub struct LogFlusher {
+    chan: UnboundedSender<oneshot::Sender<()>>,
+}
+
+impl LogFlusher {
+    /// Waits until every log queued before this call is written and flushed.
+    /// Returns immediately if the logger service is not running.
+    pub async fn flush(&self) {
+        let (ack, done) = oneshot::channel();
+        if self.chan.send(ack).is_ok() {
+            done.await.ok();
+        }
+    }
+}
+
 /// A background service that receives logs from the main thread and writes them to stdout
 pub struct ProxyLoggerReceiver {
-    receiver: UnboundedReceiver<Vec<u8>>,
+    receiver: UnboundedReceiver<LogLine>,
+    flush_sender: UnboundedSender<oneshot::Sender<()>>,
+    flush_receiver: UnboundedReceiver<oneshot::Sender<()>>,
     config: Arc<Config>,
     bufwriter: tokio::io::BufWriter<LogWriter>,
+    /// The writers of the routes overriding the access log destination
+    destinations: HashMap<Arc<str>, tokio::io::BufWriter<LogWriter>>,
+    /// The destinations that failed to open, retried once their backoff is over
+    failed_destinations: HashMap<Arc<str>, Backoff>,
+    /// Receives the access logs when `logging.syslog` is set
+    syslog: Option<SyslogSink>,
     suffix: String,
     state: Inner,
     rotation: Rotation,
@@ -156,15 +255,26 @@ pub struct Inner {
 }
 
 impl ProxyLoggerReceiver {
-    pub fn new(receiver: UnboundedReceiver<Vec<u8>>, config: &Arc<Config>) -> Self {
+    pub fn new(receiver: UnboundedReceiver<LogLine>, config: &Arc<Config>) -> Self {
+        let (flush_sender, flush_receiver) = mpsc::unbounded_channel();
+
         ProxyLoggerReceiver {
             receiver,
+            flush_sender,
+            flush_receiver,
             config: config.clone(),
             // capacity is 10 for non-file logging
             bufwriter: tokio::io::BufWriter::with_capacity(
                 10,
                 LogWriter::Stdout(tokio::io::stdout()),
             ),
+            destinations: HashMap::new(),
+            failed_destinations: HashMap::new(),
+            syslog: config
+                .logging
+                .syslog
+                .as_ref()
+                .map(|syslog| SyslogSink::new(syslog, &config.service_name)),
             suffix: String::new(),
             state: Inner {
                 next_date: AtomicI64::new(0),
@@ -173,6 +283,13 @@ impl ProxyLoggerReceiver {
         }
     }
 
+    /// Returns a handle that can be used to flush the logs from other services
+    pub fn flusher(&self) -> LogFlusher {
+        LogFlusher {
+            chan: self.flush_sender.clone(),
+        }
+    }
+
     /// Based on the defined rotation strategy, create a new file for the logs
     /// and set the suffix for the file name
     /// If the rotation strategy is `NEVER`, the suffix is empty
@@ -257,6 +374,132 @@ impl ProxyLoggerReceiver {
             self.file_buf_writer(date).await;
         }
     }
+
+    /// The writer of a route access log destination, the file is opened (or
+    /// created) on its first log. Destination files are never rotated. The
+    /// logs of a destination that can't be opened are dropped until it is
+    /// tried again.
+    async fn destination_writer(
+        &mut self,
+        destination: &Arc<str>,
+    ) -> Option<&mut tokio::io::BufWriter<LogWriter>> {
+        if !self.destinations.contains_key(destination) {
+            let backoff = self.failed_destinations.get_mut(destination);
+            if backoff.is_some_and(|backoff| backoff.skip(Instant::now())) {
+                return None;
+            }
+
+            let file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(Path::new(destination.as_ref()))
+                .await;
+
+            let file = match file {
+                Ok(file) => file,
+                Err(err) => {
+                    let dropped = self
+                        .failed_destinations
+                        .entry(destination.clone())
+                        .or_default()
+                        .fail(Instant::now());
+                    tracing::error!(
+                        dropped,
+                        "Failed to open access log destination {destination}: {err}"
+                    );
+                    return None;
+                }
+            };
+
+            self.failed_destinations.remove(destination);
+            self.destinations.insert(
+                destination.clone(),
+                Self::new_buf_writer(LogWriter::File(file)),
+            );
+        }
+
+        self.destinations.get_mut(destination)
+    }
+
+    /// Writes a log to its destination, or to the global target by default.
+    /// The access logs without a destination are sent to syslog instead
+    /// (or as well, with `keep_local`) when it is configured.
+    async fn write_line(&mut self, line: &LogLine) {
+        if let Some(syslog) = self.syslog.as_mut() {
+            if line.access_log && line.destination.is_none() {
+                syslog.send(&line.buf).await;
+                if !syslog.keep_local() {
+                    return;
+                }
+            }
+        }
+
+        let writer = match line.destination.as_ref() {
+            Some(destination) => self.destination_writer(destination).await,
+            None => Some(&mut self.bufwriter),
+        };
+
+        if let Some(writer) = writer {
+            let _ = writer.write(&line.buf).await.ok();
+        }
+    }
+
+    /// Flushes the global target and every destination
+    async fn flush_all(&mut self) {
+        self.bufwriter.flush().await.ok();
+        for writer in self.destinations.values_mut() {
+            writer.flush().await.ok();
+        }
+    }
+
+    /// Writes a single log, flushing the buffers once there are no more queued logs
+    /// so nothing is left behind in memory while the logger is idle
+    async fn write_log(&mut self, line: &LogLine) {
+        self.write_line(line).await;
+        self.handle_log_rotation().await;
+
+        if self.receiver.is_empty() {
+            self.flush_all().await;
+        }
+    }
+
+    /// Writes every queued log and flushes the buffers
+    async fn drain(&mut self) {
+        while let Ok(line) = self.receiver.try_recv() {
+            self.write_line(&line).await;
+        }
+
+        self.flush_all().await;
+    }
+
+    /// Receives and writes logs until the server starts shutting down.
+    /// Once it does, logs are written until the channel is idle and flushed before returning.
+    async fn run(&mut self, mut shutdown: ShutdownWatch) {
+        loop {
+            tokio::select! {
+                line = self.receiver.recv() => {
+                    let Some(line) = line else {
+                        break;
+                    };
+
+                    self.write_log(&line).await;
+                }
+                Some(ack) = self.flush_receiver.recv() => {
+                    self.drain().await;
+                    ack.send(()).ok();
+                }
+                () = wait_for_shutdown(&mut shutdown) => break,
+            }
+        }
+
+        while let Ok(Some(line)) =
+            tokio::time::timeout(SHUTDOWN_IDLE_TIMEOUT, self.receiver.recv()).await
+        {
+            self.write_log(&line).await;
+        }
+
+        self.drain().await;
+    }
 }
 
 #[async_trait]
@@ -264,17 +507,13 @@ impl Service for ProxyLoggerReceiver {
     async fn start_service(
         &mut self,
         _fds: Option<ListenFds>,
-        _shutdown: ShutdownWatch,
+        shutdown: ShutdownWatch,
         _listeners_per_fd: usize,
     ) {
         tracing::info!("starting logger service");
         self.prepare_buf_writer().await;
 
-        while let Some(buf) = self.receiver.recv().await {
-            let _ = self.bufwriter.write(&buf).await.ok();
-
-            self.handle_log_rotation().await;
-        }
+        self.run(shutdown).await;
     }
 
     fn name(&self) -> &'static str {
@@ -285,3 +524,225 @@ impl Service for ProxyLoggerReceiver {
         Some(1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn helper_log_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("proksi-logger-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::remove_file(dir.join("proksi.log")).ok();
+        dir
+    }
+
+    fn helper_logger(dir: &std::path::Path) -> (UnboundedSender<LogLine>, ProxyLoggerReceiver) {
+        let mut config = Config::default();
+        config.logging.path = Some(dir.to_path_buf());
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (
+            sender,
+            ProxyLoggerReceiver::new(receiver, &Arc::new(config)),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_logs_are_flushed_on_shutdown() {
+        let dir = helper_log_dir("shutdown");
+        let (sender, mut logger) = helper_logger(&dir);
+        let (shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+
+        let service = tokio::spawn(async move {
+            logger.start_service(None, shutdown, 1).await;
+        });
+
+        sender.send(b"first log\n".to_vec().into()).unwrap();
+        shutdown_sender.send(true).unwrap();
+        sender
+            .send(b"log written just before exit\n".to_vec().into())
+            .unwrap();
+
+        // the service must return on its own after the shutdown signal
+        tokio::time::timeout(Duration::from_secs(5), service)
+            .await
+            .expect("logger service did not stop after shutdown")
+            .unwrap();
+
+        let output = std::fs::read_to_string(dir.join("proksi.log")).unwrap();
+        assert_eq!(output, "first log\nlog written just before exit\n");
+    }
+
+    #[tokio::test]
+    async fn test_flusher_writes_queued_logs() {
+        let dir = helper_log_dir("flusher");
+        let (sender, mut logger) = helper_logger(&dir);
+        let flusher = logger.flusher();
+        let (_shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+
+        tokio::spawn(async move {
+            logger.start_service(None, shutdown, 1).await;
+        });
+
+        for index in 0..100 {
+            sender
+                .send(format!("log {index}\n").into_bytes().into())
+                .unwrap();
+        }
+
+        tokio::time::timeout(Duration::from_secs(5), flusher.flush())
+            .await
+            .expect("logger did not flush");
+
+        let output = std::fs::read_to_string(dir.join("proksi.log")).unwrap();
+        assert_eq!(output.lines().count(), 100);
+        assert!(output.ends_with("log 99\n"));
+    }
+
+    #[test]
+    fn test_access_logs_of_a_route_are_sent_to_its_destination() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(ProxyLog::new(sender, true, true, true))
+            .finish();
+        let destination: Arc<str> = Arc::from("/var/log/proksi/restricted.log");
+
+        tracing::subscriber::with_default(subscriber, || {
+            with_access_log_destination(Some(&destination), || {
+                tracing::info!(access_log = true, "restricted route");
+                tracing::info!("not an access log");
+            });
+            with_access_log_destination(None, || {
+                tracing::info!(access_log = true, "other route");
+            });
+        });
+
+        let destinations = std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|line| line.destination)
+            .collect::<Vec<_>>();
+        assert_eq!(destinations, vec![Some(destination), None, None]);
+    }
+
+    #[tokio::test]
+    async fn test_destination_logs_are_written_to_their_own_file() {
+        let dir = helper_log_dir("destination");
+        let destination = dir.join("restricted.log");
+        std::fs::remove_file(&destination).ok();
+
+        let (sender, mut logger) = helper_logger(&dir);
+        let (shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+        let service = tokio::spawn(async move {
+            logger.start_service(None, shutdown, 1).await;
+        });
+
+        sender.send(b"default route\n".to_vec().into()).unwrap();
+        sender
+            .send(LogLine {
+                destination: Some(Arc::from(destination.to_string_lossy().as_ref())),
+                access_log: true,
+                buf: b"restricted route\n".to_vec(),
+            })
+            .unwrap();
+        shutdown_sender.send(true).unwrap();
+        service.await.unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(dir.join("proksi.log")).unwrap(),
+            "default route\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&destination).unwrap(),
+            "restricted route\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failed_destinations_are_retried_after_a_backoff() {
+        let dir = helper_log_dir("failed-destination");
+        let missing = dir.join("missing");
+        std::fs::remove_dir_all(&missing).ok();
+        let destination: Arc<str> =
+            Arc::from(missing.join("access.log").to_string_lossy().as_ref());
+        let line = |buf: &[u8]| LogLine {
+            destination: Some(destination.clone()),
+            access_log: true,
+            buf: buf.to_vec(),
+        };
+
+        let (_sender, mut logger) = helper_logger(&dir);
+        logger.write_line(&line(b"lost\n")).await;
+        std::fs::create_dir_all(&missing).unwrap();
+
+        // The file isn't opened again until the retry
+        logger.write_line(&line(b"dropped\n")).await;
+        assert!(!missing.join("access.log").exists());
+        let backoff = logger.failed_destinations.get_mut(&destination).unwrap();
+        assert_eq!(backoff.fail(Instant::now()), 1);
+
+        backoff.succeed();
+        logger.write_line(&line(b"written\n")).await;
+        logger.flush_all().await;
+        assert!(logger.failed_destinations.is_empty());
+        assert_eq!(
+            std::fs::read_to_string(missing.join("access.log")).unwrap(),
+            "written\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_access_logs_are_sent_to_syslog() {
+        for keep_local in [false, true] {
+            let dir = helper_log_dir(&format!("syslog-{keep_local}"));
+            let server = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+            let mut config = Config::default();
+            config.logging.path = Some(dir.clone());
+            config.logging.syslog = Some(crate::config::LogSyslog {
+                address: server.local_addr().unwrap().to_string().into(),
+                protocol: crate::config::SyslogProtocol::Udp,
+                facility: crate::config::SyslogFacility::Local3,
+                severity: crate::config::SyslogSeverity::Notice,
+                keep_local,
+            });
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let mut logger = ProxyLoggerReceiver::new(receiver, &Arc::new(config));
+            let (shutdown_sender, shutdown) = tokio::sync::watch::channel(false);
+            let service = tokio::spawn(async move {
+                logger.start_service(None, shutdown, 1).await;
+            });
+
+            sender.send(b"starting\n".to_vec().into()).unwrap();
+            sender
+                .send(LogLine {
+                    destination: None,
+                    access_log: true,
+                    buf: b"GET / 200\n".to_vec(),
+                })
+                .unwrap();
+            shutdown_sender.send(true).unwrap();
+            service.await.unwrap();
+
+            let mut buf = vec![0; 1024];
+            let len = tokio::time::timeout(Duration::from_secs(5), server.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            let message = String::from_utf8_lossy(&buf[..len]).to_string();
+            // local3 (19) * 8 + notice (5)
+            assert!(message.starts_with("<157>1 "), "{message}");
+            assert!(message.ends_with(" access - GET / 200"), "{message}");
+
+            let expected = if keep_local {
+                "starting\nGET / 200\n"
+            } else {
+                "starting\n"
+            };
+            assert_eq!(
+                std::fs::read_to_string(dir.join("proksi.log")).unwrap(),
+                expected
+            );
+        }
+    }
+}
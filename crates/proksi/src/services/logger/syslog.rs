@@ -0,0 +1,315 @@
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::config::{LogSyslog, SyslogProtocol};
+
+use super::backoff::Backoff;
+
+/// The `MSGID` of the access logs
+const ACCESS_LOG_MSGID: &str = "access";
+
+/// How long connecting to the syslog server (or sending it a message) can
+/// take, the logger doesn't wait longer on a server that is down
+const SYSLOG_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// RFC 5424 timestamps are in UTC, with at most 6 digits for the fraction
+static SYSLOG_DATE_FORMAT: Lazy<Vec<time::format_description::FormatItem<'static>>> =
+    Lazy::new(|| {
+        time::format_description::parse(
+            "[year]-[month]-[day]T[hour]:[minute]:[second].[subsecond digits:6]Z",
+        )
+        .expect("Unable to create the syslog date formatter; this is a bug")
+    });
+
+enum SyslogConnection {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+/// Sends the access logs to a syslog server (see `logging.syslog`), the
+/// connection is opened on the first log and again after a failure. The logs
+/// are dropped while the server can't be reached, until the next reconnection.
+pub struct SyslogSink {
+    config: LogSyslog,
+    hostname: String,
+    app_name: String,
+    connection: Option<SyslogConnection>,
+    reconnect: Backoff,
+}
+
+impl SyslogSink {
+    pub fn new(config: &LogSyslog, app_name: &str) -> Self {
+        Self {
+            config: config.clone(),
+            hostname: header_field(&hostname(), 255),
+            app_name: header_field(app_name, 48),
+            connection: None,
+            reconnect: Backoff::default(),
+        }
+    }
+
+    /// Whether the logs sent to syslog are also written to the logger target
+    pub fn keep_local(&self) -> bool {
+        self.config.keep_local
+    }
+
+    /// Formats a log line as an RFC 5424 message
+    pub fn message(&self, date: time::OffsetDateTime, line: &[u8]) -> Vec<u8> {
+        let priority =
+            u16::from(self.config.facility.code()) * 8 + u16::from(self.config.severity.code());
+        let timestamp = date
+            .to_offset(time::UtcOffset::UTC)
+            .format(&*SYSLOG_DATE_FORMAT)
+            .unwrap_or_else(|_| "-".to_string());
+
+        let mut message = format!(
+            "<{priority}>1 {timestamp} {} {} {} {ACCESS_LOG_MSGID} - ",
+            self.hostname,
+            self.app_name,
+            std::process::id()
+        )
+        .into_bytes();
+        message.extend_from_slice(line.trim_ascii_end());
+        message
+    }
+
+    /// Sends a log line to the syslog server, the line is dropped if the
+    /// server can't be reached
+    pub async fn send(&mut self, line: &[u8]) {
+        if self.connection.is_none() && self.reconnect.skip(Instant::now()) {
+            return;
+        }
+
+        let message = self.message(time::OffsetDateTime::now_utc(), line);
+        let sent = tokio::time::timeout(SYSLOG_TIMEOUT, self.send_message(&message))
+            .await
+            .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")));
+
+        match sent {
+            Ok(()) => self.reconnect.succeed(),
+            Err(err) => {
+                self.connection = None;
+                let dropped = self.reconnect.fail(Instant::now());
+                tracing::error!(
+                    address = self.config.address.as_ref(),
+                    dropped,
+                    "failed to send the access log to the syslog server: {err}"
+                );
+            }
+        }
+    }
+
+    async fn send_message(&mut self, message: &[u8]) -> io::Result<()> {
+        if self.connection.is_none() {
+            self.connection = Some(self.connect().await?);
+        }
+
+        match self.connection.as_mut() {
+            Some(SyslogConnection::Udp(socket)) => socket.send(message).await.map(drop),
+            Some(SyslogConnection::Tcp(stream)) => {
+                // Octet counting (RFC 6587), messages can contain new lines
+                let mut framed = format!("{} ", message.len()).into_bytes();
+                framed.extend_from_slice(message);
+                stream.write_all(&framed).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    async fn connect(&self) -> io::Result<SyslogConnection> {
+        let address = self.config.address.as_ref();
+
+        match self.config.protocol {
+            SyslogProtocol::Udp => {
+                let server = tokio::net::lookup_host(address)
+                    .await?
+                    .next()
+                    .ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no address found for the server")
+                    })?;
+                let local = if server.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(server).await?;
+                Ok(SyslogConnection::Udp(socket))
+            }
+            SyslogProtocol::Tcp => Ok(SyslogConnection::Tcp(TcpStream::connect(address).await?)),
+        }
+    }
+}
+
+/// The name of the host proksi runs on, `-` when unknown
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .or_else(|| std::env::var("HOSTNAME").ok())
+        .map(|hostname| hostname.trim().to_string())
+        .unwrap_or_default()
+}
+
+/// Header fields are printable ASCII without spaces, `-` when empty
+fn header_field(value: &str, max_len: usize) -> String {
+    let field = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max_len)
+        .collect::<String>();
+
+    if field.is_empty() {
+        "-".to_string()
+    } else {
+        field
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::config::{SyslogFacility, SyslogSeverity};
+
+    fn helper_syslog(address: String, protocol: SyslogProtocol) -> LogSyslog {
+        LogSyslog {
+            address: address.into(),
+            protocol,
+            facility: SyslogFacility::Local0,
+            severity: SyslogSeverity::Info,
+            keep_local: false,
+        }
+    }
+
+    /// Checks that `message` is a well-formed RFC 5424 message, returns its MSG
+    fn helper_parse(message: &[u8]) -> String {
+        let message = String::from_utf8(message.to_vec()).unwrap();
+        let parts = message.splitn(8, ' ').collect::<Vec<_>>();
+        let [header, timestamp, hostname, app_name, proc_id, msg_id, data, msg] = parts[..] else {
+            panic!("not a syslog message: {message}");
+        };
+
+        assert_eq!(header, "<134>1", "{message}");
+        time::OffsetDateTime::parse(timestamp, &time::format_description::well_known::Rfc3339)
+            .unwrap();
+        assert_ne!(hostname, "");
+        assert_eq!(app_name, "proksi");
+        assert_eq!(proc_id, std::process::id().to_string());
+        assert_eq!(msg_id, "access");
+        assert_eq!(data, "-");
+        msg.to_string()
+    }
+
+    #[test]
+    fn test_message_format() {
+        let mut syslog = helper_syslog("127.0.0.1:514".into(), SyslogProtocol::Udp);
+        syslog.facility = SyslogFacility::Daemon;
+        syslog.severity = SyslogSeverity::Notice;
+        let mut sink = SyslogSink::new(&syslog, "edge proxy");
+        sink.hostname = "web-1".into();
+
+        let date = time::OffsetDateTime::from_unix_timestamp_nanos(1_500_000_000).unwrap();
+        let message = sink.message(date, b"GET / 200\n");
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            format!(
+                "<29>1 1970-01-01T00:00:01.500000Z web-1 edgeproxy {} access - GET / 200",
+                std::process::id()
+            )
+        );
+        assert_eq!(header_field("", 48), "-");
+    }
+
+    #[tokio::test]
+    async fn test_access_logs_are_sent_over_udp() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let syslog = helper_syslog(
+            server.local_addr().unwrap().to_string(),
+            SyslogProtocol::Udp,
+        );
+        let mut sink = SyslogSink::new(&syslog, "proksi");
+
+        sink.send(b"{\"method\":\"GET\",\"access_log\":true}\n")
+            .await;
+        sink.send(b"second\n").await;
+
+        let mut buf = vec![0; 2048];
+        for expected in ["{\"method\":\"GET\",\"access_log\":true}", "second"] {
+            let len = tokio::time::timeout(Duration::from_secs(5), server.recv(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(helper_parse(&buf[..len]), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_logs_are_dropped_until_the_next_reconnection() {
+        let addr = crate::test_support::free_addr();
+        let syslog = helper_syslog(addr.to_string(), SyslogProtocol::Tcp);
+        let mut sink = SyslogSink::new(&syslog, "proksi");
+
+        // Nothing listens yet, the line is dropped and the server isn't
+        // reached again until the retry
+        sink.send(b"lost\n").await;
+        let server = tokio::net::TcpListener::bind(addr).await.unwrap();
+        sink.send(b"dropped\n").await;
+        assert!(
+            tokio::time::timeout(Duration::from_millis(200), server.accept())
+                .await
+                .is_err()
+        );
+        assert_eq!(sink.reconnect.fail(Instant::now()), 1);
+
+        sink.reconnect.succeed();
+        sink.send(b"sent\n").await;
+        drop(sink);
+        let (mut stream, _) = server.accept().await.unwrap();
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+        assert!(String::from_utf8(received).unwrap().ends_with(" sent"));
+    }
+
+    #[tokio::test]
+    async fn test_access_logs_are_framed_over_tcp() {
+        let server = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let syslog = helper_syslog(
+            server.local_addr().unwrap().to_string(),
+            SyslogProtocol::Tcp,
+        );
+        let mut sink = SyslogSink::new(&syslog, "proksi");
+
+        sink.send(b"first\n").await;
+        sink.send(b"second\n").await;
+        drop(sink);
+
+        let (mut stream, _) = server.accept().await.unwrap();
+        let mut received = vec![];
+        stream.read_to_end(&mut received).await.unwrap();
+
+        let mut messages = vec![];
+        let mut rest = received.as_slice();
+        while let Some(space) = rest.iter().position(|b| *b == b' ') {
+            let len: usize = std::str::from_utf8(&rest[..space])
+                .unwrap()
+                .parse()
+                .unwrap();
+            let message = &rest[space + 1..space + 1 + len];
+            messages.push(helper_parse(message));
+            rest = &rest[space + 1 + len..];
+        }
+        assert_eq!(messages, ["first", "second"]);
+    }
+}
@@ -1,6 +1,7 @@
-use std::sync::Arc;
+use std::{future::Future, sync::Arc};
 
 use async_trait::async_trait;
+use certificates::CertificateWatcherService;
 use config::FileWatcherService;
 use discovery::RoutingService;
 use docker::LabelService;
@@ -10,13 +11,35 @@ use tokio::sync::broadcast::Sender;
 
 use crate::{config::Config, MsgProxy};
 
+pub mod admin;
+pub mod certificates;
 pub mod config;
 pub mod discovery;
 pub mod docker;
 pub mod health_check;
 pub mod letsencrypt;
+pub mod load_weights;
 pub mod logger;
 
+/// Resolves once the server starts shutting down (or the shutdown watch is gone)
+pub async fn wait_for_shutdown(shutdown: &mut ShutdownWatch) {
+    let _ = shutdown
+        .wait_for(|is_shutting_down| *is_shutting_down)
+        .await;
+}
+
+/// Runs `future` until it completes or the server starts shutting down.
+/// Returns `None` if the future was interrupted by the shutdown.
+pub async fn run_until_shutdown<F: Future>(
+    shutdown: &mut ShutdownWatch,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        output = future => Some(output),
+        () = wait_for_shutdown(shutdown) => None,
+    }
+}
+
 /// Exploring: what if we grouped all the services into a single service using a single thread?
 pub struct BackgroundFunctionService {
     config: Arc<Config>,
@@ -40,17 +63,24 @@ impl pingora::services::Service for BackgroundFunctionService {
         let mut routing_service = RoutingService::new(self.config.clone(), self.broadcast.clone());
 
         let mut health_service = health_check::HealthService::new();
+        let mut load_weights_service = load_weights::LoadWeightsService::new();
         let mut docker_service = LabelService::new(self.config.clone(), self.broadcast.clone());
         let mut letsencrypt_service = LetsencryptService::new(self.config.clone());
         let mut config_server = FileWatcherService::new(self.config.clone());
+        let mut certificate_watcher =
+            CertificateWatcherService::new(self.config.clone(), self.broadcast.clone());
 
         let _ = tokio::join!(
             routing_service.start_service(None, shutdown.clone(), _listeners_per_fd),
             health_service.start_service(None, shutdown.clone(), _listeners_per_fd),
+            load_weights_service.start_service(None, shutdown.clone(), _listeners_per_fd),
             config_server.start_service(None, shutdown.clone(), _listeners_per_fd),
+            certificate_watcher.start_service(None, shutdown.clone(), _listeners_per_fd),
             docker_service.start_service(None, shutdown.clone(), _listeners_per_fd),
             letsencrypt_service.start_service(None, shutdown, _listeners_per_fd),
         );
+
+        tracing::info!("background services stopped");
     }
 
     fn name(&self) -> &'static str {
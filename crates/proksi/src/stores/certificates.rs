@@ -10,7 +10,8 @@ use std::error::Error;
 pub struct Certificate {
     pub key: PKey<Private>,
     pub leaf: X509,
-    pub chain: Option<X509>,
+    /// The intermediate certificates served after the leaf
+    pub chain: Vec<X509>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -25,7 +26,15 @@ impl Certificate {
         Ok(SerializableCertificate {
             key: base64::encode_block(&self.key.private_key_to_pem_pkcs8()?),
             leaf: base64::encode_block(&self.leaf.to_pem()?),
-            chain: self.chain.as_ref().map(|c| base64::encode_block(&c.to_pem().unwrap_or_default())),
+            chain: if self.chain.is_empty() {
+                None
+            } else {
+                let mut pem = Vec::new();
+                for cert in &self.chain {
+                    pem.extend(cert.to_pem()?);
+                }
+                Some(base64::encode_block(&pem))
+            },
         })
     }
 
@@ -37,13 +46,29 @@ impl Certificate {
         let leaf = X509::from_pem(&leaf_data)?;
         let chain = if let Some(chain_b64) = cert.chain {
             let chain_data = base64::decode_block(&chain_b64)?;
-            Some(X509::from_pem(&chain_data)?)
+            X509::stack_from_pem(&chain_data)?
         } else {
-            None
+            Vec::new()
         };
 
         Ok(Certificate { key, leaf, chain })
     }
 
+    /// Parses a PEM private key and PEM certificates, the first certificate
+    /// being the leaf and the next ones (if any) its chain. The key must belong
+    /// to the leaf, a pair caught in the middle of an update is refused.
+    pub fn from_pem(key: &[u8], pem: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let key = PKey::private_key_from_pem(key)?;
+        let mut chain = X509::stack_from_pem(pem)?;
+        if chain.is_empty() {
+            return Err("no certificate found in the PEM".into());
+        }
+        let leaf = chain.remove(0);
 
+        if !leaf.public_key()?.public_eq(&key) {
+            return Err("the private key doesn't match the certificate".into());
+        }
+
+        Ok(Certificate { key, leaf, chain })
+    }
 }
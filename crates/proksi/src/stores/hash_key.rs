@@ -0,0 +1,283 @@
+use std::{hash::Hasher, net::IpAddr};
+
+use fnv::FnvHasher;
+use pingora::{http::RequestHeader, lb::Backend};
+
+/// The hash key of the `consistent_hash` routes without `hash_key`
+pub const DEFAULT_HASH_KEY: &str = "{client_ip}";
+
+/// A part of a hash key template
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HashKeyPart {
+    Literal(String),
+    /// `{header.<name>}`, the first value of the request header
+    Header(http::HeaderName),
+    /// `{query.<name>}`, the first value of the (raw) query parameter
+    Query(String),
+    /// `{path.<index>}`, a segment of the path (`0` is the first one)
+    PathSegment(usize),
+    /// `{path}`
+    Path,
+    /// `{method}`
+    Method,
+    /// `{client_ip}`, the real client IP (see `real_ip`)
+    ClientIp,
+}
+
+/// The template of the key the upstream of a request is selected with
+/// (ex: `{header.x-tenant}/{path.1}`), values missing from the request are
+/// replaced by an empty string
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HashKeyTemplate {
+    parts: Vec<HashKeyPart>,
+}
+
+impl HashKeyTemplate {
+    pub fn parse(template: &str) -> Result<Self, anyhow::Error> {
+        let mut parts = Vec::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(HashKeyPart::Literal(rest[..start].to_string()));
+            }
+
+            let Some(end) = rest[start..].find('}') else {
+                return Err(anyhow::anyhow!("unclosed `{{` in hash key {template:?}"));
+            };
+            parts.push(Self::parse_variable(&rest[start + 1..start + end])?);
+            rest = &rest[start + end + 1..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(HashKeyPart::Literal(rest.to_string()));
+        }
+
+        Ok(Self { parts })
+    }
+
+    fn parse_variable(variable: &str) -> Result<HashKeyPart, anyhow::Error> {
+        let part = match variable.split_once('.') {
+            None if variable == "path" => HashKeyPart::Path,
+            None if variable == "method" => HashKeyPart::Method,
+            None if variable == "client_ip" => HashKeyPart::ClientIp,
+            Some(("header", name)) => HashKeyPart::Header(
+                http::HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|_| anyhow::anyhow!("{name:?} is not a valid header name"))?,
+            ),
+            Some(("query", name)) if !name.is_empty() => HashKeyPart::Query(name.to_string()),
+            Some(("path", index)) => HashKeyPart::PathSegment(
+                index
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("{index:?} is not a path segment index"))?,
+            ),
+            _ => return Err(anyhow::anyhow!("unknown hash key variable {{{variable}}}")),
+        };
+
+        Ok(part)
+    }
+
+    /// Evaluates the template for a request
+    pub fn key(&self, request: &RequestHeader, client_ip: Option<IpAddr>) -> String {
+        let mut key = String::new();
+
+        for part in &self.parts {
+            match part {
+                HashKeyPart::Literal(literal) => key.push_str(literal),
+                HashKeyPart::Header(name) => key.push_str(
+                    request
+                        .headers
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or_default(),
+                ),
+                HashKeyPart::Query(name) => {
+                    let value = request.uri.query().and_then(|query| {
+                        query.split('&').find_map(|param| {
+                            let (param, value) = param.split_once('=').unwrap_or((param, ""));
+                            (param == name).then_some(value)
+                        })
+                    });
+                    key.push_str(value.unwrap_or_default());
+                }
+                HashKeyPart::PathSegment(index) => key.push_str(
+                    request
+                        .uri
+                        .path()
+                        .split('/')
+                        .filter(|segment| !segment.is_empty())
+                        .nth(*index)
+                        .unwrap_or_default(),
+                ),
+                HashKeyPart::Path => key.push_str(request.uri.path()),
+                HashKeyPart::Method => key.push_str(request.method.as_str()),
+                HashKeyPart::ClientIp => {
+                    if let Some(ip) = client_ip {
+                        key.push_str(&ip.to_string());
+                    }
+                }
+            }
+        }
+
+        key
+    }
+}
+
+/// Selects the backend of `key` among the ones accepted by `accept` (weighted
+/// rendezvous hashing): the key keeps its backend while it is accepted, only
+/// the keys of a removed backend move to other backends, and every backend
+/// gets a share of the keys proportional to its weight.
+pub fn select_by_key<'a>(
+    backends: impl IntoIterator<Item = &'a Backend>,
+    key: &str,
+    accept: impl Fn(&Backend) -> bool,
+) -> Option<&'a Backend> {
+    backends
+        .into_iter()
+        .filter(|backend| accept(backend))
+        .map(|backend| (backend, key_score(key, backend)))
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(backend, _)| backend)
+}
+
+/// The score of `backend` for `key`, the backend with the highest score is
+/// selected. The hash is fixed (FNV-1a with a final mix, no random state), so
+/// every proksi instance of any version selects the same backend.
+fn key_score(key: &str, backend: &Backend) -> f64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(key.as_bytes());
+    hasher.write(&[0xff]);
+    if let Some(addr) = backend.addr.as_inet() {
+        match addr.ip() {
+            IpAddr::V4(ip) => hasher.write(&ip.octets()),
+            IpAddr::V6(ip) => hasher.write(&ip.octets()),
+        }
+        hasher.write(&addr.port().to_be_bytes());
+    }
+
+    // splitmix64 finalizer, FNV alone doesn't spread close inputs enough
+    let mut hash = hasher.finish();
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^= hash >> 31;
+
+    // A uniform value in (0, 1), the weight scales the score
+    let unit = ((hash >> 11) as f64 + 0.5) / (1u64 << 53) as f64;
+    backend.weight as f64 / -unit.ln()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn helper_request(uri: &str, headers: &[(&str, &str)]) -> RequestHeader {
+        let mut request = RequestHeader::build("GET", uri.as_bytes(), None).unwrap();
+        for (name, value) in headers {
+            request.insert_header(name.to_string(), *value).unwrap();
+        }
+        request
+    }
+
+    fn helper_backends() -> Vec<Backend> {
+        (1..=5)
+            .map(|index| Backend::new(&format!("10.0.0.{index}:80")).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_composite_key() {
+        let template = HashKeyTemplate::parse("{header.x-tenant}/{path.1}?{query.v}").unwrap();
+        let request = helper_request("/tenants/acme/orders?page=2&v=3", &[("x-tenant", "eu")]);
+
+        assert_eq!(template.key(&request, None), "eu/acme?3");
+        // Missing values are empty
+        assert_eq!(template.key(&helper_request("/", &[]), None), "/?");
+
+        let template = HashKeyTemplate::parse("{method} {path} {client_ip}").unwrap();
+        assert_eq!(
+            template.key(&request, Some("10.1.2.3".parse().unwrap())),
+            "GET /tenants/acme/orders 10.1.2.3"
+        );
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        for template in [
+            "{header.x-tenant",
+            "{cookie.session}",
+            "{path.first}",
+            "{header.in valid}",
+            "{query.}",
+        ] {
+            assert!(HashKeyTemplate::parse(template).is_err(), "{template}");
+        }
+        assert!(HashKeyTemplate::parse(DEFAULT_HASH_KEY).is_ok());
+    }
+
+    #[test]
+    fn test_same_key_selects_the_same_backend() {
+        let backends = helper_backends();
+        let selected = |key: &str| select_by_key(&backends, key, |_| true).unwrap().clone();
+
+        assert_eq!(selected("eu/acme"), selected("eu/acme"));
+
+        // Different keys are spread over the backends
+        let spread = (0..50)
+            .map(|index| selected(&format!("tenant-{index}")).addr)
+            .collect::<std::collections::HashSet<_>>();
+        assert!(spread.len() > 1);
+    }
+
+    #[test]
+    fn test_keys_are_spread_by_weight() {
+        let backends = [
+            Backend::new_with_weight("10.0.0.1:80", 3).unwrap(),
+            Backend::new_with_weight("10.0.0.2:80", 1).unwrap(),
+        ];
+
+        let heavier = (0..2000)
+            .filter(|index| {
+                let key = format!("tenant-{index}");
+                select_by_key(&backends, &key, |_| true).unwrap().addr == backends[0].addr
+            })
+            .count();
+        assert!((1350..1650).contains(&heavier), "{heavier}");
+    }
+
+    #[test]
+    fn test_selection_is_stable() {
+        // The hash doesn't depend on the build, these keys never move
+        let backends = helper_backends();
+        let selected = ["eu/acme", "us/globex", "10.1.2.3"].map(|key| {
+            select_by_key(&backends, key, |_| true)
+                .unwrap()
+                .addr
+                .to_string()
+        });
+        assert_eq!(selected, ["10.0.0.3:80", "10.0.0.4:80", "10.0.0.1:80"]);
+    }
+
+    #[test]
+    fn test_only_the_keys_of_a_rejected_backend_move() {
+        let backends = helper_backends();
+        let removed = select_by_key(&backends, "eu/acme", |_| true)
+            .unwrap()
+            .clone();
+        let kept = |backend: &Backend| backend.addr != removed.addr;
+
+        let moved = select_by_key(&backends, "eu/acme", kept).unwrap();
+        assert_ne!(moved.addr, removed.addr);
+
+        for index in 0..50 {
+            let key = format!("tenant-{index}");
+            let before = select_by_key(&backends, &key, |_| true).unwrap();
+            if before.addr != removed.addr {
+                assert_eq!(
+                    select_by_key(&backends, &key, kept).unwrap().addr,
+                    before.addr
+                );
+            }
+        }
+        assert!(select_by_key(&backends, "eu/acme", |_| false).is_none());
+    }
+}
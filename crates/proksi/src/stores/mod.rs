@@ -1,15 +1,20 @@
+use std::collections::HashSet;
 use std::hash::RandomState;
 
 use once_cell::sync::Lazy;
 use papaya::HashMapRef;
 use routes::{RouteStore, RouteStoreContainer};
 
+use crate::config::RouteLabels;
+
 pub mod cache;
 pub mod certificates;
 pub mod global;
+pub mod hash_key;
 pub mod memory_store;
 pub mod redis_store;
 pub mod routes;
+pub mod smooth_weighted;
 pub mod store_trait;
 
 // Re-export stores
@@ -35,6 +40,47 @@ pub fn get_route_by_key(key: &str) -> Option<RouteStoreContainer> {
     ROUTE_STORE.pin().get(key).cloned()
 }
 
+/// The labels of the route of `key`
+pub fn get_route_labels(key: &str) -> Option<RouteLabels> {
+    ROUTE_STORE.pin().get(key).map(|route| route.labels.clone())
+}
+
+/// The key of a route in the store: its host, followed by the port of the
+/// listener when the route is restricted to one (ex: `example.com:8443`)
+pub fn route_key(host: &str, listener_port: Option<u16>) -> String {
+    match listener_port {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    }
+}
+
+/// The hosts of the routes in the store, the routes restricted to a listener
+/// count for their host
+pub fn get_route_hosts() -> HashSet<String> {
+    ROUTE_STORE
+        .pin()
+        .keys()
+        .map(|key| match key.rsplit_once(':') {
+            Some((host, port)) if port.parse::<u16>().is_ok() => host.to_string(),
+            _ => key.clone(),
+        })
+        .collect()
+}
+
+/// The route of `host` for a request received on `listener_port`: the route
+/// restricted to this listener if there is one, otherwise the route of `host`
+pub fn get_route_for_listener(
+    host: &str,
+    listener_port: Option<u16>,
+) -> Option<RouteStoreContainer> {
+    let routes = ROUTE_STORE.pin();
+
+    listener_port
+        .and_then(|port| routes.get(&route_key(host, Some(port))))
+        .or_else(|| routes.get(host))
+        .cloned()
+}
+
 pub fn get_routes(
 ) -> HashMapRef<'static, String, RouteStoreContainer, RandomState, seize::OwnedGuard<'static>> {
     ROUTE_STORE.pin_owned()
@@ -44,6 +90,10 @@ pub fn insert_route(key: String, value: RouteStoreContainer) {
     ROUTE_STORE.pin().insert(key, value);
 }
 
+pub fn remove_route(key: &str) {
+    ROUTE_STORE.pin().remove(key);
+}
+
 // CERTIFICATE store
 // static CERTIFICATE_STORE: Lazy<CertificateStore> = Lazy::new(papaya::HashMap::new);
 
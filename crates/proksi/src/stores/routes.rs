@@ -1,14 +1,36 @@
-use std::{borrow::Cow, collections::HashMap, sync::Arc};
+use std::{
+    borrow::Cow,
+    cell::Cell,
+    collections::HashMap,
+    hash::{BuildHasher, Hasher, RandomState},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use http::{HeaderName, HeaderValue};
+use http::{HeaderMap, HeaderName, HeaderValue, Method};
 use path_tree::PathTree;
-use pingora::lb::{selection::RoundRobin, LoadBalancer};
+use pingora::lb::{selection::RoundRobin, Backend, LoadBalancer};
+use tokio::task::AbortHandle;
 
-use crate::config::{RouteCache, RoutePlugin, RouteUpstream};
+use crate::config::{
+    HopByHopHeaders, Route, RouteCache, RouteDns, RouteFollowRedirects, RouteLabels,
+    RouteLoadWeights, RouteNoMatch, RoutePlugin, RouteRedirect, RouteRetry,
+    RouteSelectionAlgorithm, RouteUpstream, DEFAULT_UPSTREAM_GROUP,
+};
+use crate::proxy_server::{concurrency::ConcurrencyLimiter, dns::DnsNegativeCache};
+
+use super::hash_key::{select_by_key, HashKeyTemplate};
+use super::smooth_weighted::SmoothWeightedRoundRobin;
 
 #[derive(Debug, Default, Clone)]
 pub struct RouteStorePathMatcher {
     pub pattern: Option<PathTree<usize>>,
+    /// How requests that don't match `pattern` are handled
+    pub no_match: Option<RouteNoMatch>,
 }
 
 impl RouteStorePathMatcher {
@@ -33,19 +55,239 @@ impl RouteStorePathMatcher {
     }
 }
 
+/// The priority of the upstreams without one
+pub const DEFAULT_UPSTREAM_PRIORITY: u16 = 1;
+
+/// Settings attached to a single backend (resolved address) of a route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteBackendTags {
+    /// The backend only serves read methods (GET, HEAD)
+    pub read_only: bool,
+    /// The zone of the backend, backends in the zone of proksi are preferred
+    pub zone: Option<Arc<str>>,
+    /// The priority tier of the backend (see `RouteStoreContainer::priority_tiers`)
+    pub priority: u16,
+    /// The upstream group of the backend, `None` for the default group
+    pub group: Option<Arc<str>>,
+    /// The backend is connected to with TLS
+    pub tls: bool,
+}
+
+impl Default for RouteBackendTags {
+    fn default() -> Self {
+        RouteBackendTags {
+            read_only: false,
+            zone: None,
+            priority: DEFAULT_UPSTREAM_PRIORITY,
+            group: None,
+            tls: false,
+        }
+    }
+}
+
+impl RouteBackendTags {
+    /// Whether a request with the given method can be sent to this backend
+    pub fn accepts(&self, method: &Method) -> bool {
+        !self.read_only || method == Method::GET || method == Method::HEAD
+    }
+}
+
+/// A header that every request to the route must have
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteRequiredHeader {
+    pub name: HeaderName,
+    /// If set, one of the header values must match it exactly
+    pub value: Option<HeaderValue>,
+}
+
+impl RouteRequiredHeader {
+    /// Whether the given request headers satisfy this requirement
+    pub fn is_satisfied_by(&self, headers: &HeaderMap) -> bool {
+        let mut values = headers.get_all(&self.name).iter();
+
+        match &self.value {
+            Some(expected) => values.any(|value| value == expected),
+            None => values.next().is_some(),
+        }
+    }
+}
+
+/// Which upstream group handles the requests of a route
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteUpstreamGroupRouting {
+    /// The group of the requests matching none of the rules
+    pub default: Arc<str>,
+    /// The group of a request is the one of the first header it has
+    pub rules: Vec<(RouteRequiredHeader, Arc<str>)>,
+}
+
+impl RouteUpstreamGroupRouting {
+    /// The group of a request with the given headers
+    pub fn group(&self, headers: &HeaderMap) -> &str {
+        self.rules
+            .iter()
+            .find(|(header, _)| header.is_satisfied_by(headers))
+            .map_or(&self.default, |(_, group)| group)
+    }
+}
+
+/// Why a backend was selected for a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UpstreamSelectionReason {
+    /// The backend picked by the load balancing algorithm
+    Balanced,
+    /// The backend picked by the algorithm was unhealthy (or couldn't serve
+    /// the request method), the next one was used instead. With the smooth
+    /// weighted round robin, such backends were left out of the selection.
+    Fallback,
+    /// The request is being retried after a failure
+    Retry,
+}
+
+impl UpstreamSelectionReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Balanced => "balanced",
+            Self::Fallback => "fallback",
+            Self::Retry => "retry",
+        }
+    }
+}
+
+/// What the backend of a request is selected by (see
+/// [`RouteStoreContainer::select_backend`])
+#[derive(Debug, Clone, Copy)]
+pub struct BackendCriteria<'a> {
+    /// Only the backends accepting the method are selected (see `read_only`)
+    pub method: &'a Method,
+    /// The request was already sent to another backend
+    pub is_retry: bool,
+    /// The zone of proksi, the backends in the zone are preferred and the
+    /// other zones are only used when none of them is available
+    pub zone: Option<&'a str>,
+    /// Only the backends of this upstream group are selected
+    pub group: Option<&'a str>,
+    /// The key of the request (see `hash_key`), for the routes using
+    /// consistent hashing
+    pub hash_key: Option<&'a str>,
+    /// The backends already tried by the request, only selected again once
+    /// every other backend was tried
+    pub tried: &'a [Backend],
+}
+
+impl<'a> BackendCriteria<'a> {
+    pub fn new(method: &'a Method) -> Self {
+        Self {
+            method,
+            is_retry: false,
+            zone: None,
+            group: None,
+            hash_key: None,
+            tried: &[],
+        }
+    }
+}
+
+/// How the backend of a request was selected, added to the access logs of
+/// routes with `log_upstream_selection` enabled
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpstreamSelection {
+    pub backend: String,
+    /// The weight of the backend in the load balancer
+    pub weight: usize,
+    pub algorithm: &'static str,
+    pub reason: UpstreamSelectionReason,
+    /// The index of the priority tier of the backend, 0 unless the higher
+    /// priority tiers had no backend available (failover)
+    pub tier: usize,
+}
+
 #[derive(Clone)]
 pub struct RouteStoreContainer {
     pub load_balancer: Arc<LoadBalancer<RoundRobin>>,
+    /// The round robin position of the route, shared by the clones of the container
+    pub round_robin: Arc<AtomicUsize>,
     pub path_matcher: RouteStorePathMatcher,
     pub host_header_remove: Vec<String>,
     pub host_header_add: Vec<(HeaderName, HeaderValue)>,
+    /// Headers required on every request to the route
+    pub require_headers: Vec<RouteRequiredHeader>,
+    /// The request headers forwarded to the upstreams (all of them when `None`)
+    pub forward_headers_allowlist: Option<Vec<HeaderName>>,
+    /// Route override for the global hop-by-hop headers policy
+    pub hop_by_hop_headers: Option<HopByHopHeaders>,
 
     pub upstreams: Vec<RouteUpstream>,
+    /// The state of the smooth weighted round robin, when it is the selection
+    /// algorithm of the route (instead of the round robin of `load_balancer`)
+    pub smooth_weighted: Option<Arc<SmoothWeightedRoundRobin>>,
+    /// Derives the weights of `smooth_weighted` from the load of the backends
+    pub load_weights: Option<RouteLoadWeights>,
+    /// The key of the requests, when the route selects their backend by
+    /// consistent hashing
+    pub hash_key: Option<HashKeyTemplate>,
+    /// Tags for each backend, backends without tags accept every request
+    pub backend_tags: HashMap<SocketAddr, RouteBackendTags>,
+    /// The priorities of the backends, in the order they receive the traffic:
+    /// the backends of a tier are only used when none of the previous tiers is
+    /// available. Empty when every backend has the same priority.
+    pub priority_tiers: Vec<u16>,
+    /// Routes the requests to a group of the backends depending on their headers
+    pub upstream_groups: Option<RouteUpstreamGroupRouting>,
     pub self_signed_certificate: bool,
 
     pub plugins: HashMap<String, RoutePlugin>,
 
     pub cache: Option<RouteCache>,
+
+    /// Route override for the global slow request threshold
+    pub slow_request_threshold_ms: Option<u64>,
+
+    /// Route override for the destination of the access logs
+    pub access_log_destination: Option<Arc<str>>,
+
+    pub retry: Option<RouteRetry>,
+
+    /// Redirects of the upstreams followed instead of sent to the client
+    pub follow_redirects: Option<RouteFollowRedirects>,
+
+    /// Failed upstream resolutions, shared by every request to the route
+    pub dns: Arc<DnsNegativeCache>,
+
+    /// In-flight request limits, shared by every request to the route
+    pub concurrency: Option<Arc<ConcurrencyLimiter>>,
+
+    /// Requests to the route are redirected instead of proxied
+    pub redirect: Option<RouteRedirect>,
+
+    /// Response trailers removed before reaching the client
+    pub strip_trailers: Vec<HeaderName>,
+
+    /// Whether the upstream selection is added to the access logs
+    pub log_upstream_selection: bool,
+
+    /// Whether the retries, fallbacks and failovers are logged as events
+    pub log_upstream_events: bool,
+
+    /// Response header set to the backend that served the request (debug)
+    pub debug_upstream_header: Option<HeaderName>,
+
+    /// The labels of the route (used in metrics and the admin API)
+    pub labels: RouteLabels,
+
+    /// The route was removed by a reload and is only kept until it is drained
+    pub draining: bool,
+
+    /// The route was just added and doesn't serve until its initial check (and
+    /// warm-up) is over, shared by the clones of the container
+    pub warming_up: Arc<AtomicBool>,
+
+    /// The task warming up the route, aborted when the route is replaced or
+    /// removed before it is over
+    pub warm_up: Option<AbortHandle>,
+
+    /// The configuration of the route the container was created from
+    pub config: Option<Arc<Route>>,
 }
 
 impl Default for RouteStoreContainer {
@@ -54,13 +296,41 @@ impl Default for RouteStoreContainer {
             load_balancer: Arc::new(
                 LoadBalancer::<RoundRobin>::try_from_iter(vec!["127.0.0.1:80"]).unwrap(),
             ),
+            round_robin: Arc::new(AtomicUsize::new(0)),
             path_matcher: RouteStorePathMatcher::default(),
             host_header_remove: Vec::with_capacity(0),
             host_header_add: Vec::with_capacity(0),
+            require_headers: Vec::with_capacity(0),
+            forward_headers_allowlist: None,
+            hop_by_hop_headers: None,
             self_signed_certificate: false,
             plugins: HashMap::new(),
             upstreams: Vec::with_capacity(0),
+            smooth_weighted: None,
+            load_weights: None,
+            hash_key: None,
+            backend_tags: HashMap::new(),
+            priority_tiers: Vec::with_capacity(0),
+            upstream_groups: None,
             cache: None,
+            slow_request_threshold_ms: None,
+            access_log_destination: None,
+            retry: None,
+            follow_redirects: None,
+            dns: Arc::new(DnsNegativeCache::new(Duration::from_secs(
+                RouteDns::default().negative_ttl_secs,
+            ))),
+            concurrency: None,
+            redirect: None,
+            strip_trailers: Vec::with_capacity(0),
+            log_upstream_selection: false,
+            log_upstream_events: false,
+            debug_upstream_header: None,
+            labels: RouteLabels::new(),
+            draining: false,
+            warming_up: Arc::new(AtomicBool::new(false)),
+            warm_up: None,
+            config: None,
         }
     }
 }
@@ -69,15 +339,293 @@ impl RouteStoreContainer {
     pub fn new(load_balancer: LoadBalancer<RoundRobin>) -> Self {
         RouteStoreContainer {
             load_balancer: Arc::new(load_balancer),
+            round_robin: Arc::new(AtomicUsize::new(0)),
             path_matcher: RouteStorePathMatcher::new(),
             host_header_remove: Vec::with_capacity(5),
             host_header_add: Vec::with_capacity(5),
+            require_headers: Vec::with_capacity(0),
+            forward_headers_allowlist: None,
+            hop_by_hop_headers: None,
             self_signed_certificate: false,
             plugins: HashMap::new(),
             upstreams: Vec::with_capacity(5),
+            smooth_weighted: None,
+            load_weights: None,
+            hash_key: None,
+            backend_tags: HashMap::new(),
+            priority_tiers: Vec::with_capacity(0),
+            upstream_groups: None,
             cache: None,
+            slow_request_threshold_ms: None,
+            access_log_destination: None,
+            retry: None,
+            follow_redirects: None,
+            dns: Arc::new(DnsNegativeCache::new(Duration::from_secs(
+                RouteDns::default().negative_ttl_secs,
+            ))),
+            concurrency: None,
+            redirect: None,
+            strip_trailers: Vec::with_capacity(0),
+            log_upstream_selection: false,
+            log_upstream_events: false,
+            debug_upstream_header: None,
+            labels: RouteLabels::new(),
+            draining: false,
+            warming_up: Arc::new(AtomicBool::new(false)),
+            warm_up: None,
+            config: None,
         }
     }
+
+    /// Whether the route waits for its initial check (or warm-up) before serving
+    pub fn is_warming_up(&self) -> bool {
+        self.warming_up.load(Ordering::Relaxed)
+    }
+
+    /// Stops the warm-up of the route (if any), the route is going away
+    pub fn stop_warm_up(&self) {
+        if let Some(warm_up) = self.warm_up.as_ref() {
+            warm_up.abort();
+        }
+    }
+
+    /// Returns the first required header that is missing (or doesn't match)
+    /// from the given request headers
+    pub fn missing_required_header(&self, headers: &HeaderMap) -> Option<&RouteRequiredHeader> {
+        self.require_headers
+            .iter()
+            .find(|required| !required.is_satisfied_by(headers))
+    }
+
+    /// Selects a healthy backend that is able to serve the request and
+    /// describes how it was selected. The backends of the first priority tier
+    /// are used while one of them is available, then the next tier etc.
+    /// See [`BackendCriteria`] for how the request narrows the selection.
+    pub fn select_backend(
+        &self,
+        criteria: &BackendCriteria,
+    ) -> Option<(Backend, UpstreamSelection)> {
+        let select = |tried: &[Backend]| {
+            (0..self.priority_tiers.len().max(1)).find_map(|index| {
+                let tier = self.priority_tiers.get(index).copied();
+                let (backend, algorithm, fallback) = self.select_in_tier(criteria, tier, tried)?;
+                // Backends of the next tiers only receive traffic on failover
+                Some((backend, algorithm, fallback || index > 0, index))
+            })
+        };
+        let (backend, algorithm, fallback, tier) = match select(criteria.tried) {
+            Some(selected) => selected,
+            None if !criteria.tried.is_empty() => select(&[])?,
+            None => return None,
+        };
+
+        let reason = if criteria.is_retry {
+            UpstreamSelectionReason::Retry
+        } else if fallback {
+            UpstreamSelectionReason::Fallback
+        } else {
+            UpstreamSelectionReason::Balanced
+        };
+
+        // The weights of the smooth weighted round robin can be derived from the load
+        let weight = self
+            .smooth_weighted
+            .as_ref()
+            .zip(backend.addr.as_inet())
+            .and_then(|(smooth_weighted, addr)| smooth_weighted.weight(addr))
+            .unwrap_or(backend.weight);
+
+        let selection = UpstreamSelection {
+            backend: backend.addr.to_string(),
+            weight,
+            algorithm: algorithm.as_str(),
+            reason,
+            tier,
+        };
+
+        Some((backend, selection))
+    }
+
+    /// Selects a backend of the priority `tier` (every backend when `None`),
+    /// preferring the backends of the zone of proksi. Also returns whether the
+    /// selection fell back to another backend than the one picked by the algorithm.
+    fn select_in_tier(
+        &self,
+        criteria: &BackendCriteria,
+        tier: Option<u16>,
+        tried: &[Backend],
+    ) -> Option<(Backend, RouteSelectionAlgorithm, bool)> {
+        // Backends of other groups, tiers and zones (or already tried) are
+        // skipped, not rejected
+        let in_scope = |backend: &Backend, zone: Option<&str>| {
+            !tried.contains(backend)
+                && criteria
+                    .group
+                    .is_none_or(|group| self.backend_group(backend) == group)
+                && tier.is_none_or(|tier| self.backend_priority(backend) == Some(tier))
+                && zone.is_none_or(|zone| self.backend_in_zone(backend, zone))
+        };
+        let serves = |backend: &Backend, healthy: bool| {
+            healthy && self.backend_accepts(backend, criteria.method)
+        };
+
+        // Routes without backends in the zone are balanced across every zone
+        let local_zone = criteria.zone.filter(|zone| {
+            self.backend_tags
+                .values()
+                .any(|tags| tags.zone.as_deref() == Some(*zone))
+        });
+
+        match self.select_with(criteria.hash_key, |b| in_scope(b, local_zone), serves) {
+            Some(selected) => Some(selected),
+            None if local_zone.is_some() => {
+                let (backend, algorithm, _) =
+                    self.select_with(criteria.hash_key, |b| in_scope(b, None), serves)?;
+                Some((backend, algorithm, true))
+            }
+            None => None,
+        }
+    }
+
+    /// Selects a backend `in_scope` that `serves` the request (depending on
+    /// its health) with the algorithm of the route. Also returns whether a
+    /// backend picked by the algorithm couldn't serve it.
+    fn select_with(
+        &self,
+        hash_key: Option<&str>,
+        in_scope: impl Fn(&Backend) -> bool,
+        serves: impl Fn(&Backend, bool) -> bool,
+    ) -> Option<(Backend, RouteSelectionAlgorithm, bool)> {
+        let backends = self.load_balancer.backends();
+        let candidates = backends.get_backend();
+        let rejected = Cell::new(false);
+        let accept = |backend: &Backend| {
+            if !in_scope(backend) {
+                return false;
+            }
+
+            let accepted = serves(backend, backends.ready(backend));
+            rejected.set(rejected.get() || !accepted);
+            accepted
+        };
+
+        if let (Some(_), Some(key)) = (self.hash_key.as_ref(), hash_key) {
+            let backend = select_by_key(candidates.iter(), key, accept)?;
+            return Some((
+                backend.clone(),
+                RouteSelectionAlgorithm::ConsistentHash,
+                rejected.get(),
+            ));
+        }
+
+        if let Some(smooth_weighted) = self.smooth_weighted.as_ref() {
+            let backend = smooth_weighted.select(accept)?;
+            return Some((
+                backend,
+                RouteSelectionAlgorithm::SmoothWeightedRoundRobin,
+                rejected.get(),
+            ));
+        }
+
+        // Weighted round robin over the backends in scope, the requests the
+        // picked backend can't serve are balanced over the ones that can
+        let index = self.round_robin.fetch_add(1, Ordering::Relaxed);
+        let picked = weighted_pick(candidates.iter().filter(|b| in_scope(b)), index)?;
+        if serves(picked, backends.ready(picked)) {
+            return Some((picked.clone(), RouteSelectionAlgorithm::RoundRobin, false));
+        }
+
+        let backend = weighted_pick(
+            candidates
+                .iter()
+                .filter(|b| in_scope(b) && serves(b, backends.ready(b))),
+            index,
+        )?;
+        Some((backend.clone(), RouteSelectionAlgorithm::RoundRobin, true))
+    }
+
+    fn backend_in_zone(&self, backend: &Backend, zone: &str) -> bool {
+        backend
+            .addr
+            .as_inet()
+            .and_then(|addr| self.backend_tags.get(addr))
+            .is_some_and(|tags| tags.zone.as_deref() == Some(zone))
+    }
+
+    fn backend_group(&self, backend: &Backend) -> &str {
+        backend
+            .addr
+            .as_inet()
+            .and_then(|addr| self.backend_tags.get(addr))
+            .and_then(|tags| tags.group.as_deref())
+            .unwrap_or(DEFAULT_UPSTREAM_GROUP)
+    }
+
+    fn backend_priority(&self, backend: &Backend) -> Option<u16> {
+        backend
+            .addr
+            .as_inet()
+            .and_then(|addr| self.backend_tags.get(addr))
+            .map(|tags| tags.priority)
+    }
+
+    fn backend_accepts(&self, backend: &Backend, method: &Method) -> bool {
+        backend
+            .addr
+            .as_inet()
+            .and_then(|addr| self.backend_tags.get(addr))
+            .is_none_or(|tags| tags.accepts(method))
+    }
+}
+
+/// The backend at `index` of the round robin over `backends`, every backend
+/// taking as many turns as its weight
+fn weighted_pick<'a>(
+    backends: impl Iterator<Item = &'a Backend> + Clone,
+    index: usize,
+) -> Option<&'a Backend> {
+    let total_weight = backends
+        .clone()
+        .map(|backend| backend.weight)
+        .sum::<usize>();
+    if total_weight == 0 {
+        return None;
+    }
+
+    let mut position = index % total_weight;
+    backends.into_iter().find(|backend| {
+        if position < backend.weight {
+            return true;
+        }
+        position -= backend.weight;
+        false
+    })
+}
+
+/// The seed of the upstream selection of a route, random unless configured
+pub fn selection_seed(configured: Option<u64>) -> u64 {
+    configured.unwrap_or_else(|| RandomState::new().build_hasher().finish())
+}
+
+/// Moves the round robin of the route forward by `seed` selections (modulo
+/// the total weight of the backends), so that the sequence of selected
+/// backends only depends on the seed
+pub fn seed_selection(route_container: &RouteStoreContainer, seed: u64) {
+    let total_weight = route_container
+        .load_balancer
+        .backends()
+        .get_backend()
+        .iter()
+        .map(|backend| backend.weight as u64)
+        .sum::<u64>();
+
+    if total_weight == 0 {
+        return;
+    }
+
+    route_container
+        .round_robin
+        .store((seed % total_weight) as usize, Ordering::Relaxed);
 }
 
 // LoadBalancer<RoundRobin>
@@ -85,11 +633,680 @@ impl RouteStoreContainer {
 pub type RouteStore = papaya::HashMap<String, RouteStoreContainer>;
 
 #[cfg(test)]
-
 mod tests {
+    use futures::FutureExt;
+    use pingora::lb::{discovery::Static, Backends};
 
     use super::*;
 
+    fn helper_read_write_container() -> RouteStoreContainer {
+        let load_balancer = LoadBalancer::<RoundRobin>::try_from_iter(vec![
+            "10.0.0.1:80",
+            "10.0.0.2:80",
+            "10.0.0.3:80",
+        ])
+        .unwrap();
+        let mut route_store = RouteStoreContainer::new(load_balancer);
+        route_store.backend_tags = HashMap::from([
+            ("10.0.0.1:80".parse().unwrap(), RouteBackendTags::default()),
+            (
+                "10.0.0.2:80".parse().unwrap(),
+                RouteBackendTags {
+                    read_only: true,
+                    ..Default::default()
+                },
+            ),
+            (
+                "10.0.0.3:80".parse().unwrap(),
+                RouteBackendTags {
+                    read_only: true,
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        route_store
+    }
+
+    fn helper_weighted_container(backends: &[(&str, usize)]) -> RouteStoreContainer {
+        let backends = backends
+            .iter()
+            .map(|(addr, weight)| Backend::new_with_weight(addr, *weight).unwrap())
+            .collect();
+        let load_balancer =
+            LoadBalancer::<RoundRobin>::from_backends(Backends::new(Static::new(backends)));
+        load_balancer.update().now_or_never().unwrap().unwrap();
+
+        RouteStoreContainer::new(load_balancer)
+    }
+
+    fn selected_addr(backend: &Backend) -> SocketAddr {
+        *backend.addr.as_inet().unwrap()
+    }
+
+    #[test]
+    fn test_router_container_writes_only_select_read_write_backends() {
+        let route_store = helper_read_write_container();
+
+        for method in [Method::POST, Method::PUT, Method::PATCH, Method::DELETE] {
+            for _ in 0..10 {
+                let backend = route_store
+                    .select_backend(&BackendCriteria::new(&method))
+                    .unwrap()
+                    .0;
+                assert_eq!(selected_addr(&backend), "10.0.0.1:80".parse().unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_backends_are_found_among_many_skipped_ones() {
+        let backends = (1..=64)
+            .map(|index| format!("10.0.0.{index}:80"))
+            .collect::<Vec<_>>();
+        let load_balancer = LoadBalancer::<RoundRobin>::try_from_iter(&backends).unwrap();
+        let mut route_store = RouteStoreContainer::new(load_balancer);
+        route_store.backend_tags = backends
+            .iter()
+            .map(|addr| {
+                let tags = RouteBackendTags {
+                    read_only: addr != "10.0.0.64:80",
+                    ..RouteBackendTags::default()
+                };
+                (addr.parse().unwrap(), tags)
+            })
+            .collect();
+
+        // The only backend serving writes is always found
+        let fallbacks = (0..64)
+            .filter(|_| {
+                let (backend, selection) = route_store
+                    .select_backend(&BackendCriteria::new(&Method::POST))
+                    .unwrap();
+                assert_eq!(selected_addr(&backend), "10.0.0.64:80".parse().unwrap());
+                selection.reason == UpstreamSelectionReason::Fallback
+            })
+            .count();
+        assert_eq!(fallbacks, 63);
+    }
+
+    #[test]
+    fn test_router_container_reads_select_any_backend() {
+        let route_store = helper_read_write_container();
+
+        for method in [Method::GET, Method::HEAD] {
+            let selected = (0..10)
+                .map(|_| {
+                    selected_addr(
+                        &route_store
+                            .select_backend(&BackendCriteria::new(&method))
+                            .unwrap()
+                            .0,
+                    )
+                })
+                .collect::<std::collections::HashSet<_>>();
+
+            assert_eq!(selected.len(), 3);
+        }
+    }
+
+    #[test]
+    fn test_router_container_weighted_selection() {
+        let route_store = helper_weighted_container(&[("10.0.0.1:80", 3), ("10.0.0.2:80", 1)]);
+
+        let selections = (0..40)
+            .map(|_| {
+                route_store
+                    .select_backend(&BackendCriteria::new(&Method::GET))
+                    .unwrap()
+                    .1
+            })
+            .collect::<Vec<_>>();
+
+        // The chosen backend is reported with its effective weight
+        for selection in &selections {
+            let expected_weight = if selection.backend == "10.0.0.1:80" {
+                3
+            } else {
+                1
+            };
+            assert_eq!(selection.weight, expected_weight);
+            assert_eq!(selection.algorithm, "round_robin");
+            assert_eq!(selection.reason, UpstreamSelectionReason::Balanced);
+        }
+
+        let heavier = selections
+            .iter()
+            .filter(|s| s.backend == "10.0.0.1:80")
+            .count();
+        assert_eq!(heavier, 30);
+    }
+
+    fn helper_seeded_sequence(seed: u64, count: usize) -> Vec<String> {
+        let route_store = helper_weighted_container(&[
+            ("10.0.0.1:80", 1),
+            ("10.0.0.2:80", 2),
+            ("10.0.0.3:80", 1),
+        ]);
+        seed_selection(&route_store, seed);
+
+        (0..count)
+            .map(|_| {
+                route_store
+                    .select_backend(&BackendCriteria::new(&Method::GET))
+                    .unwrap()
+                    .1
+                    .backend
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fixed_seed_selection_sequence() {
+        assert_eq!(
+            helper_seeded_sequence(1, 5),
+            vec![
+                "10.0.0.2:80",
+                "10.0.0.2:80",
+                "10.0.0.3:80",
+                "10.0.0.1:80",
+                "10.0.0.2:80",
+            ]
+        );
+
+        // The same seed always gives the same sequence, only the seed modulo
+        // the total weight matters
+        assert_eq!(
+            helper_seeded_sequence(42, 20),
+            helper_seeded_sequence(42, 20)
+        );
+        assert_eq!(helper_seeded_sequence(5, 20), helper_seeded_sequence(1, 20));
+        assert_ne!(helper_seeded_sequence(0, 20), helper_seeded_sequence(1, 20));
+    }
+
+    #[test]
+    fn test_selection_seed_is_random_unless_configured() {
+        assert_eq!(selection_seed(Some(7)), 7);
+        assert_ne!(selection_seed(None), selection_seed(None));
+    }
+
+    #[test]
+    fn test_router_container_smooth_weighted_selection() {
+        let mut route_store = helper_weighted_container(&[
+            ("10.0.0.1:80", 5),
+            ("10.0.0.2:80", 1),
+            ("10.0.0.3:80", 1),
+        ]);
+        let backends = route_store.load_balancer.backends().get_backend();
+        route_store.smooth_weighted = Some(Arc::new(SmoothWeightedRoundRobin::new(
+            backends.iter().cloned(),
+        )));
+
+        let selections = (0..7)
+            .map(|_| {
+                route_store
+                    .select_backend(&BackendCriteria::new(&Method::GET))
+                    .unwrap()
+                    .1
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            selections
+                .iter()
+                .map(|s| s.backend.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "10.0.0.1:80",
+                "10.0.0.1:80",
+                "10.0.0.2:80",
+                "10.0.0.1:80",
+                "10.0.0.3:80",
+                "10.0.0.1:80",
+                "10.0.0.1:80",
+            ]
+        );
+        assert!(selections
+            .iter()
+            .all(|s| s.algorithm == "smooth_weighted_round_robin"
+                && s.reason == UpstreamSelectionReason::Balanced));
+    }
+
+    /// Two backends in `zone-a` and one in `zone-b`
+    fn helper_zoned_container() -> RouteStoreContainer {
+        let mut route_store = helper_weighted_container(&[
+            ("10.0.0.1:80", 1),
+            ("10.0.0.2:80", 1),
+            ("10.0.0.3:80", 1),
+        ]);
+        route_store.backend_tags = [
+            ("10.0.0.1:80", "zone-a"),
+            ("10.0.0.2:80", "zone-a"),
+            ("10.0.0.3:80", "zone-b"),
+        ]
+        .into_iter()
+        .map(|(addr, zone)| {
+            let tags = RouteBackendTags {
+                zone: Some(Arc::from(zone)),
+                ..Default::default()
+            };
+            (addr.parse().unwrap(), tags)
+        })
+        .collect();
+
+        route_store
+    }
+
+    fn helper_zone_selections(
+        route_store: &RouteStoreContainer,
+        zone: &str,
+    ) -> Vec<UpstreamSelection> {
+        (0..12)
+            .map(|_| {
+                route_store
+                    .select_backend(&BackendCriteria {
+                        zone: Some(zone),
+                        ..BackendCriteria::new(&Method::GET)
+                    })
+                    .unwrap()
+                    .1
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_same_zone_backends_are_preferred() {
+        let route_store = helper_zoned_container();
+
+        let selections = helper_zone_selections(&route_store, "zone-a");
+        let selected = selections
+            .iter()
+            .map(|s| s.backend.as_str())
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(selected, ["10.0.0.1:80", "10.0.0.2:80"].into());
+        assert!(selections
+            .iter()
+            .all(|s| s.reason == UpstreamSelectionReason::Balanced));
+
+        let selections = helper_zone_selections(&route_store, "zone-b");
+        assert!(selections.iter().all(|s| s.backend == "10.0.0.3:80"));
+
+        // Without backends in the zone, every backend is used
+        let selections = helper_zone_selections(&route_store, "zone-c");
+        let selected = selections
+            .iter()
+            .map(|s| s.backend.as_str())
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(selected.len(), 3);
+    }
+
+    #[test]
+    fn test_cross_zone_only_when_the_zone_is_unhealthy() {
+        let route_store = helper_zoned_container();
+        let backends = route_store.load_balancer.backends();
+
+        let unhealthy = backends
+            .get_backend()
+            .iter()
+            .find(|backend| backend.addr.to_string() == "10.0.0.1:80")
+            .cloned()
+            .unwrap();
+        backends.set_enable(&unhealthy, false);
+
+        // One backend of the zone is still healthy
+        let selections = helper_zone_selections(&route_store, "zone-a");
+        assert!(selections.iter().all(|s| s.backend == "10.0.0.2:80"));
+
+        for backend in backends.get_backend().iter() {
+            if backend.addr.to_string() == "10.0.0.2:80" {
+                backends.set_enable(backend, false);
+            }
+        }
+
+        let selections = helper_zone_selections(&route_store, "zone-a");
+        assert!(selections
+            .iter()
+            .all(|s| s.backend == "10.0.0.3:80" && s.reason == UpstreamSelectionReason::Fallback));
+    }
+
+    /// Two backends of priority 1, one of priority 2 and one of priority 3
+    fn helper_tiered_container() -> RouteStoreContainer {
+        let mut route_store = helper_weighted_container(&[
+            ("10.0.0.1:80", 1),
+            ("10.0.0.2:80", 1),
+            ("10.0.0.3:80", 1),
+            ("10.0.0.4:80", 1),
+        ]);
+        route_store.backend_tags = [
+            ("10.0.0.1:80", 1),
+            ("10.0.0.2:80", 1),
+            ("10.0.0.3:80", 2),
+            ("10.0.0.4:80", 3),
+        ]
+        .into_iter()
+        .map(|(addr, priority)| {
+            let tags = RouteBackendTags {
+                priority,
+                ..Default::default()
+            };
+            (addr.parse().unwrap(), tags)
+        })
+        .collect();
+        route_store.priority_tiers = vec![1, 2, 3];
+
+        route_store
+    }
+
+    fn helper_disable(route_store: &RouteStoreContainer, addr: &str) {
+        let backends = route_store.load_balancer.backends();
+        for backend in backends.get_backend().iter() {
+            if backend.addr.to_string() == addr {
+                backends.set_enable(backend, false);
+            }
+        }
+    }
+
+    fn helper_tier_selections(route_store: &RouteStoreContainer) -> Vec<UpstreamSelection> {
+        (0..8)
+            .map(|_| {
+                route_store
+                    .select_backend(&BackendCriteria::new(&Method::GET))
+                    .unwrap()
+                    .1
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_traffic_stays_on_the_first_tier_until_it_is_unhealthy() {
+        let route_store = helper_tiered_container();
+
+        let selections = helper_tier_selections(&route_store);
+        let selected = selections
+            .iter()
+            .map(|s| s.backend.as_str())
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(selected, ["10.0.0.1:80", "10.0.0.2:80"].into());
+        assert!(selections
+            .iter()
+            .all(|s| s.reason == UpstreamSelectionReason::Balanced));
+
+        // One backend of the tier is still healthy
+        helper_disable(&route_store, "10.0.0.1:80");
+        let selections = helper_tier_selections(&route_store);
+        assert!(selections.iter().all(|s| s.backend == "10.0.0.2:80"));
+
+        // The whole tier is down, the traffic spills to the next one
+        helper_disable(&route_store, "10.0.0.2:80");
+        let selections = helper_tier_selections(&route_store);
+        assert!(selections
+            .iter()
+            .all(|s| s.backend == "10.0.0.3:80" && s.reason == UpstreamSelectionReason::Fallback));
+
+        helper_disable(&route_store, "10.0.0.3:80");
+        let selections = helper_tier_selections(&route_store);
+        assert!(selections.iter().all(|s| s.backend == "10.0.0.4:80"));
+
+        helper_disable(&route_store, "10.0.0.4:80");
+        assert!(route_store
+            .select_backend(&BackendCriteria::new(&Method::GET))
+            .is_none());
+    }
+
+    #[test]
+    fn test_traffic_goes_back_to_the_first_tier_when_it_recovers() {
+        let route_store = helper_tiered_container();
+        helper_disable(&route_store, "10.0.0.1:80");
+        helper_disable(&route_store, "10.0.0.2:80");
+        assert!(helper_tier_selections(&route_store)
+            .iter()
+            .all(|s| s.backend == "10.0.0.3:80"));
+
+        let backends = route_store.load_balancer.backends();
+        for backend in backends.get_backend().iter() {
+            if backend.addr.to_string() == "10.0.0.1:80" {
+                backends.set_enable(backend, true);
+            }
+        }
+        assert!(helper_tier_selections(&route_store)
+            .iter()
+            .all(|s| s.backend == "10.0.0.1:80"));
+    }
+
+    #[test]
+    fn test_retries_skip_the_backends_already_tried() {
+        let route_store = helper_tiered_container();
+
+        let mut tried = vec![];
+        let mut selected = vec![];
+        for attempt in 0..5 {
+            let (backend, selection) = route_store
+                .select_backend(&BackendCriteria {
+                    is_retry: attempt > 0,
+                    tried: &tried,
+                    ..BackendCriteria::new(&Method::GET)
+                })
+                .unwrap();
+            tried.push(backend);
+            selected.push(selection.backend);
+        }
+
+        // The untried backends of the tier come first, then the next tiers
+        let mut first_tier = selected[..2].to_vec();
+        first_tier.sort();
+        assert_eq!(first_tier, ["10.0.0.1:80", "10.0.0.2:80"]);
+        assert_eq!(selected[2..4], ["10.0.0.3:80", "10.0.0.4:80"]);
+        // Every backend was tried, they can be selected again
+        assert!(selected[4] == "10.0.0.1:80" || selected[4] == "10.0.0.2:80");
+    }
+
+    /// Two backends of the `default` group, one `canary` and one `beta`
+    fn helper_grouped_container() -> RouteStoreContainer {
+        let mut route_store = helper_weighted_container(&[
+            ("10.0.0.1:80", 1),
+            ("10.0.0.2:80", 1),
+            ("10.0.0.3:80", 1),
+            ("10.0.0.4:80", 1),
+        ]);
+        route_store.backend_tags = [("10.0.0.3:80", "canary"), ("10.0.0.4:80", "beta")]
+            .into_iter()
+            .map(|(addr, group)| {
+                let tags = RouteBackendTags {
+                    group: Some(Arc::from(group)),
+                    ..Default::default()
+                };
+                (addr.parse().unwrap(), tags)
+            })
+            .collect();
+        route_store.upstream_groups = Some(RouteUpstreamGroupRouting {
+            default: Arc::from(DEFAULT_UPSTREAM_GROUP),
+            rules: vec![
+                (
+                    RouteRequiredHeader {
+                        name: HeaderName::from_static("x-canary"),
+                        value: Some(HeaderValue::from_static("true")),
+                    },
+                    Arc::from("canary"),
+                ),
+                (
+                    RouteRequiredHeader {
+                        name: HeaderName::from_static("x-beta"),
+                        value: None,
+                    },
+                    Arc::from("beta"),
+                ),
+            ],
+        });
+
+        route_store
+    }
+
+    /// The backends selected for requests with `headers`
+    fn helper_group_selections(
+        route_store: &RouteStoreContainer,
+        headers: &[(&'static str, &'static str)],
+    ) -> std::collections::HashSet<String> {
+        let headers = headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_static(value),
+                )
+            })
+            .collect::<HeaderMap>();
+        let group = route_store
+            .upstream_groups
+            .as_ref()
+            .map(|groups| groups.group(&headers));
+
+        (0..8)
+            .map(|_| {
+                route_store
+                    .select_backend(&BackendCriteria {
+                        group,
+                        ..BackendCriteria::new(&Method::GET)
+                    })
+                    .unwrap()
+                    .1
+                    .backend
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_requests_are_routed_to_the_group_of_their_headers() {
+        let route_store = helper_grouped_container();
+
+        assert_eq!(
+            helper_group_selections(&route_store, &[("x-canary", "true")]),
+            ["10.0.0.3:80".to_string()].into()
+        );
+        assert_eq!(
+            helper_group_selections(&route_store, &[("x-beta", "1")]),
+            ["10.0.0.4:80".to_string()].into()
+        );
+
+        // The first matching rule wins
+        assert_eq!(
+            helper_group_selections(&route_store, &[("x-beta", "1"), ("x-canary", "true")]),
+            ["10.0.0.3:80".to_string()].into()
+        );
+    }
+
+    #[test]
+    fn test_requests_matching_no_rule_go_to_the_default_group() {
+        let route_store = helper_grouped_container();
+        let default_group = ["10.0.0.1:80".to_string(), "10.0.0.2:80".to_string()].into();
+
+        assert_eq!(helper_group_selections(&route_store, &[]), default_group);
+        assert_eq!(
+            helper_group_selections(&route_store, &[("x-canary", "false")]),
+            default_group
+        );
+
+        // Without groups, every backend is used
+        let mut route_store = helper_grouped_container();
+        route_store.upstream_groups = None;
+        assert_eq!(helper_group_selections(&route_store, &[]).len(), 4);
+    }
+
+    #[test]
+    fn test_router_container_selection_reason() {
+        let route_store = helper_read_write_container();
+
+        // Only the first backend accepts writes, the others fall back to it
+        let reasons = (0..3)
+            .map(|_| {
+                route_store
+                    .select_backend(&BackendCriteria::new(&Method::POST))
+                    .unwrap()
+                    .1
+                    .reason
+            })
+            .collect::<std::collections::HashSet<_>>();
+        assert!(reasons.contains(&UpstreamSelectionReason::Fallback));
+
+        let (_, selection) = route_store
+            .select_backend(&BackendCriteria {
+                is_retry: true,
+                ..BackendCriteria::new(&Method::GET)
+            })
+            .unwrap();
+        assert_eq!(selection.reason, UpstreamSelectionReason::Retry);
+    }
+
+    #[test]
+    fn test_router_container_without_read_write_backends() {
+        let mut route_store = helper_read_write_container();
+        route_store
+            .backend_tags
+            .values_mut()
+            .for_each(|tags| tags.read_only = true);
+
+        assert!(route_store
+            .select_backend(&BackendCriteria::new(&Method::POST))
+            .is_none());
+        assert!(route_store
+            .select_backend(&BackendCriteria::new(&Method::GET))
+            .is_some());
+    }
+
+    fn helper_required_headers_container() -> RouteStoreContainer {
+        let load_balancer = LoadBalancer::<RoundRobin>::try_from_iter(vec!["1.1.1.1:80"]).unwrap();
+        let mut route_store = RouteStoreContainer::new(load_balancer);
+        route_store.require_headers = vec![
+            RouteRequiredHeader {
+                name: HeaderName::from_static("x-api-version"),
+                value: None,
+            },
+            RouteRequiredHeader {
+                name: HeaderName::from_static("x-tenant"),
+                value: Some(HeaderValue::from_static("acme")),
+            },
+        ];
+
+        route_store
+    }
+
+    #[test]
+    fn test_router_container_missing_required_header() {
+        let route_store = helper_required_headers_container();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-tenant", HeaderValue::from_static("acme"));
+
+        let missing = route_store.missing_required_header(&headers).unwrap();
+        assert_eq!(missing.name, "x-api-version");
+    }
+
+    #[test]
+    fn test_router_container_required_header_value_mismatch() {
+        let route_store = helper_required_headers_container();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-version", HeaderValue::from_static("2"));
+        headers.insert("x-tenant", HeaderValue::from_static("other"));
+
+        let missing = route_store.missing_required_header(&headers).unwrap();
+        assert_eq!(missing.name, "x-tenant");
+    }
+
+    #[test]
+    fn test_router_container_required_headers_present() {
+        let route_store = helper_required_headers_container();
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-version", HeaderValue::from_static(""));
+        headers.append("x-tenant", HeaderValue::from_static("other"));
+        headers.append("x-tenant", HeaderValue::from_static("acme"));
+
+        assert!(route_store.missing_required_header(&headers).is_none());
+        assert!(RouteStoreContainer::default()
+            .missing_required_header(&HeaderMap::new())
+            .is_none());
+    }
+
     #[test]
     fn test_router_container_defaults_empty_pattern() {
         let load_balancer = LoadBalancer::<RoundRobin>::try_from_iter(vec!["1.1.1.1:80"]).unwrap();
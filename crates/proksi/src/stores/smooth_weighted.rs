@@ -0,0 +1,153 @@
+use std::{collections::HashMap, net::SocketAddr, sync::Mutex};
+
+use pingora::lb::Backend;
+
+/// Smooth weighted round robin (the nginx algorithm): on every selection the
+/// weight of each candidate is added to its current weight, the candidate with
+/// the highest current weight is selected and the total weight is removed from
+/// it. Heavier backends are selected more often while still being interleaved
+/// with the others (weights `5, 1, 1` give `a a b a c a a`).
+#[derive(Debug)]
+pub struct SmoothWeightedRoundRobin {
+    backends: Mutex<Vec<WeightedBackend>>,
+}
+
+#[derive(Debug)]
+struct WeightedBackend {
+    /// Left untouched, its weight is part of its identity in the load balancer
+    /// (ex: to look up its health)
+    backend: Backend,
+    weight: usize,
+    current: i64,
+}
+
+impl SmoothWeightedRoundRobin {
+    pub fn new(backends: impl IntoIterator<Item = Backend>) -> Self {
+        let backends = backends
+            .into_iter()
+            .map(|backend| WeightedBackend {
+                weight: backend.weight,
+                backend,
+                current: 0,
+            })
+            .collect();
+
+        Self {
+            backends: Mutex::new(backends),
+        }
+    }
+
+    /// Selects the next backend, backends rejected by `accept` (ex: unhealthy)
+    /// don't take part in the selection
+    pub fn select(&self, accept: impl Fn(&Backend) -> bool) -> Option<Backend> {
+        let mut backends = self.backends.lock().unwrap();
+        let mut total = 0;
+        let mut best: Option<(usize, i64)> = None;
+
+        for (index, weighted) in backends.iter_mut().enumerate() {
+            if !accept(&weighted.backend) {
+                continue;
+            }
+
+            let weight = i64::try_from(weighted.weight).unwrap_or(i64::MAX);
+            weighted.current += weight;
+            total += weight;
+
+            if best.is_none_or(|(_, best_current)| weighted.current > best_current) {
+                best = Some((index, weighted.current));
+            }
+        }
+
+        let (index, _) = best?;
+        backends[index].current -= total;
+        Some(backends[index].backend.clone())
+    }
+
+    /// Changes the weight of the backends in `weights`, the others keep theirs
+    pub fn set_weights(&self, weights: &HashMap<SocketAddr, usize>) {
+        let mut backends = self.backends.lock().unwrap();
+
+        for weighted in backends.iter_mut() {
+            let addr = weighted.backend.addr.as_inet();
+            if let Some(weight) = addr.and_then(|addr| weights.get(addr)) {
+                weighted.weight = *weight;
+            }
+        }
+    }
+
+    /// The current weight of the backend `addr`
+    pub fn weight(&self, addr: &SocketAddr) -> Option<usize> {
+        let backends = self.backends.lock().unwrap();
+
+        backends
+            .iter()
+            .find(|weighted| weighted.backend.addr.as_inet() == Some(addr))
+            .map(|weighted| weighted.weight)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn helper_swrr(weights: &[usize]) -> SmoothWeightedRoundRobin {
+        SmoothWeightedRoundRobin::new(weights.iter().enumerate().map(|(index, weight)| {
+            Backend::new_with_weight(&format!("10.0.0.{}:80", index + 1), *weight).unwrap()
+        }))
+    }
+
+    /// The last digit of the address of the next `count` selected backends
+    fn helper_sequence(
+        swrr: &SmoothWeightedRoundRobin,
+        count: usize,
+        accept: impl Fn(&Backend) -> bool,
+    ) -> String {
+        (0..count)
+            .map(|_| {
+                let backend = swrr.select(&accept).unwrap();
+                let addr = backend.addr.as_inet().unwrap().ip().to_string();
+                addr.chars().last().unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_nginx_sequence() {
+        let swrr = helper_swrr(&[5, 1, 1]);
+
+        // a a b a c a a, then the sequence repeats
+        assert_eq!(helper_sequence(&swrr, 14, |_| true), "11213111121311");
+    }
+
+    #[test]
+    fn test_weights_are_interleaved() {
+        let swrr = helper_swrr(&[3, 1]);
+        assert_eq!(helper_sequence(&swrr, 8, |_| true), "11211121");
+
+        let swrr = helper_swrr(&[2, 2, 1]);
+        assert_eq!(helper_sequence(&swrr, 5, |_| true), "12312");
+    }
+
+    #[test]
+    fn test_rejected_backends_are_skipped() {
+        let swrr = helper_swrr(&[5, 1, 1]);
+        let unhealthy = Backend::new_with_weight("10.0.0.1:80", 5).unwrap();
+
+        assert_eq!(
+            helper_sequence(&swrr, 4, |backend| backend.addr != unhealthy.addr),
+            "2323"
+        );
+        assert!(swrr.select(|_| false).is_none());
+    }
+
+    #[test]
+    fn test_weights_can_be_changed() {
+        let swrr = helper_swrr(&[1, 1]);
+        assert_eq!(helper_sequence(&swrr, 4, |_| true), "1212");
+
+        swrr.set_weights(&HashMap::from([("10.0.0.1:80".parse().unwrap(), 3)]));
+        assert_eq!(swrr.weight(&"10.0.0.1:80".parse().unwrap()), Some(3));
+        assert_eq!(swrr.weight(&"10.0.0.2:80".parse().unwrap()), Some(1));
+        assert_eq!(helper_sequence(&swrr, 8, |_| true), "11211121");
+    }
+}
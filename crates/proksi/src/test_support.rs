@@ -0,0 +1,722 @@
+//! End-to-end test harness: in-process backends, a proksi router serving the
+//! routes created by the test and helpers to send it requests.
+//!
+//! Routes live in the global route store, every test should use its own hosts.
+//! The module is only compiled for the tests of the crate.
+
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Once,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http::HeaderMap;
+use openssl::{
+    asn1::Asn1Time,
+    hash::MessageDigest,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    x509::{X509NameBuilder, X509},
+};
+use pingora::{
+    apps::HttpServerOptions,
+    connectors::http::v2::Connector,
+    http::{RequestHeader, ResponseHeader},
+    protocols::{
+        http::{client::HttpSession, v2::server, ServerSession},
+        l4, Digest, Stream, ALPN,
+    },
+    proxy::{http_proxy_service, ProxyHttp},
+    server::configuration::ServerConf,
+    services::Service,
+    upstreams::peer::HttpPeer,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::watch,
+    task::JoinSet,
+};
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{
+    config::{Config, Route, RouteUpstream},
+    proxy_server::{accept::AcceptApp, connection_limit::ConnectionLimiter, https_proxy::Router},
+    services::discovery::apply_route_changes,
+    stores::{self, global::init_store, MemoryStore},
+};
+
+/// Reserves a free local address (the listener is closed right away)
+pub fn free_addr() -> SocketAddr {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap()
+}
+
+/// Waits until something accepts connections on `addr`
+async fn wait_for_listener(addr: SocketAddr) {
+    for _ in 0..100 {
+        if tokio::net::TcpStream::connect(addr).await.is_ok() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("nothing is listening on {addr}");
+}
+
+/// An HTTP/1.1 backend answering every request with the same response
+pub struct TestBackend {
+    addr: SocketAddr,
+    requests: Arc<AtomicUsize>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl TestBackend {
+    /// Starts a backend answering with `status` and `body`
+    pub async fn start(status: u16, body: &'static str) -> Self {
+        Self::start_with_headers(status, body, Vec::new()).await
+    }
+
+    /// Starts a backend answering with `status`, `body` and the response `headers`
+    pub async fn start_with_headers(
+        status: u16,
+        body: &'static str,
+        headers: Vec<(String, String)>,
+    ) -> Self {
+        Self::start_with(status, body, headers, Duration::ZERO).await
+    }
+
+    /// Starts a backend answering with `status` and `body` after `delay`
+    pub async fn start_delayed(status: u16, body: &'static str, delay: Duration) -> Self {
+        Self::start_with(status, body, Vec::new(), delay).await
+    }
+
+    async fn start_with(
+        status: u16,
+        body: &'static str,
+        headers: Vec<(String, String)>,
+        delay: Duration,
+    ) -> Self {
+        let headers = Arc::new(headers);
+        Self::listen(move |stream, requests| {
+            serve_connection(stream, status, body, headers.clone(), delay, requests)
+        })
+        .await
+    }
+
+    /// Starts a backend answering with `status` and `body` on `addr` (ex: the
+    /// address of a stopped backend)
+    pub async fn start_on(addr: SocketAddr, status: u16, body: &'static str) -> Self {
+        let headers = Arc::new(Vec::new());
+        Self::listen_on(addr, move |stream, requests| {
+            serve_connection(
+                stream,
+                status,
+                body,
+                headers.clone(),
+                Duration::ZERO,
+                requests,
+            )
+        })
+        .await
+    }
+
+    /// Starts an HTTP/2 backend without TLS (h2c) answering with `status`,
+    /// `body` and the response `trailers`
+    pub async fn start_h2c(
+        status: u16,
+        body: &'static str,
+        trailers: &'static [(&'static str, &'static str)],
+    ) -> Self {
+        Self::listen(move |stream, requests| {
+            serve_h2c_connection(stream, status, body, trailers, requests)
+        })
+        .await
+    }
+
+    /// Starts a backend answering with the headers of the request it received,
+    /// one `name: value` line per header (sorted by name)
+    pub async fn start_echo_headers() -> Self {
+        Self::listen(serve_echo_headers_connection).await
+    }
+
+    /// Starts a backend accepting the WebSocket upgrades (answered with a 101)
+    /// and echoing what it receives afterwards, other requests get a 400
+    pub async fn start_websocket() -> Self {
+        Self::listen(serve_websocket_connection).await
+    }
+
+    /// Starts a backend closing the connections without answering the requests
+    pub async fn start_failing() -> Self {
+        Self::listen(serve_failing_connection).await
+    }
+
+    /// Starts a backend redirecting `/` to `/1`, `/1` to `/2` etc. up to
+    /// `/{hops}`, which is answered with a 200 and its path
+    pub async fn start_redirect_chain(hops: usize) -> Self {
+        Self::listen(move |stream, requests| {
+            serve_redirect_chain_connection(stream, hops, requests)
+        })
+        .await
+    }
+
+    async fn listen<F, Fut>(serve: F) -> Self
+    where
+        F: Fn(Stream, Arc<AtomicUsize>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self::listen_on(SocketAddr::from(([127, 0, 0, 1], 0)), serve).await
+    }
+
+    async fn listen_on<F, Fut>(addr: SocketAddr, serve: F) -> Self
+    where
+        F: Fn(Stream, Arc<AtomicUsize>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+
+        let counter = requests.clone();
+        let task = tokio::spawn(async move {
+            // Dropped with the task, so that `stop` also closes the connections
+            let mut connections = JoinSet::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                let stream: Stream = Box::new(l4::stream::Stream::from(stream));
+                connections.spawn(serve(stream, counter.clone()));
+            }
+        });
+
+        Self {
+            addr,
+            requests,
+            task,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The number of requests answered so far
+    pub fn requests(&self) -> usize {
+        self.requests.load(Ordering::SeqCst)
+    }
+
+    /// Stops the backend, its connections are closed and new ones refused
+    pub async fn stop(&mut self) {
+        self.task.abort();
+        (&mut self.task).await.ok();
+    }
+}
+
+impl Drop for TestBackend {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve_connection(
+    stream: Stream,
+    status: u16,
+    body: &'static str,
+    headers: Arc<Vec<(String, String)>>,
+    delay: Duration,
+    requests: Arc<AtomicUsize>,
+) {
+    let mut stream = Some(stream);
+
+    while let Some(reused) = stream.take() {
+        let mut session = ServerSession::new_http1(reused);
+        if !matches!(session.read_request().await, Ok(true)) {
+            return;
+        }
+        while let Ok(Some(_)) = session.read_request_body().await {}
+        requests.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(delay).await;
+
+        let mut response = ResponseHeader::build(status, None).unwrap();
+        response
+            .insert_header(http::header::CONTENT_LENGTH, body.len())
+            .unwrap();
+        for (name, value) in headers.iter() {
+            response
+                .append_header(name.clone(), value.as_str())
+                .unwrap();
+        }
+        if session
+            .write_response_header(Box::new(response))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        if !body.is_empty() {
+            session
+                .write_response_body(Bytes::from_static(body.as_bytes()), true)
+                .await
+                .ok();
+        }
+
+        stream = session.finish().await.ok().flatten();
+    }
+}
+
+async fn serve_echo_headers_connection(stream: Stream, requests: Arc<AtomicUsize>) {
+    let mut stream = Some(stream);
+
+    while let Some(reused) = stream.take() {
+        let mut session = ServerSession::new_http1(reused);
+        if !matches!(session.read_request().await, Ok(true)) {
+            return;
+        }
+        while let Ok(Some(_)) = session.read_request_body().await {}
+        requests.fetch_add(1, Ordering::SeqCst);
+
+        let mut headers = session
+            .req_header()
+            .headers
+            .iter()
+            .map(|(name, value)| format!("{name}: {}\n", value.to_str().unwrap_or_default()))
+            .collect::<Vec<_>>();
+        headers.sort();
+        let body = headers.concat();
+
+        let mut response = ResponseHeader::build(200, None).unwrap();
+        response
+            .insert_header(http::header::CONTENT_LENGTH, body.len())
+            .unwrap();
+        if session
+            .write_response_header(Box::new(response))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        session
+            .write_response_body(Bytes::from(body), true)
+            .await
+            .ok();
+
+        stream = session.finish().await.ok().flatten();
+    }
+}
+
+async fn serve_websocket_connection(mut stream: Stream, requests: Arc<AtomicUsize>) {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(len) => request.extend_from_slice(&buf[..len]),
+        }
+    }
+    requests.fetch_add(1, Ordering::SeqCst);
+
+    let request = String::from_utf8_lossy(&request).to_lowercase();
+    let has_header = |name: &str, value: &str| {
+        request
+            .lines()
+            .filter_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+            .any(|values| values.split(',').any(|v| v.trim() == value))
+    };
+    if !has_header("connection", "upgrade") || !has_header("upgrade", "websocket") {
+        stream
+            .write_all(
+                b"HTTP/1.1 400 Bad Request\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+            )
+            .await
+            .ok();
+        return;
+    }
+
+    let response =
+        b"HTTP/1.1 101 Switching Protocols\r\nupgrade: websocket\r\nconnection: upgrade\r\n\r\n";
+    if stream.write_all(response).await.is_err() || stream.flush().await.is_err() {
+        return;
+    }
+    loop {
+        match stream.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(len) => {
+                if stream.write_all(&buf[..len]).await.is_err() || stream.flush().await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+async fn serve_failing_connection(stream: Stream, requests: Arc<AtomicUsize>) {
+    let mut session = ServerSession::new_http1(stream);
+    if matches!(session.read_request().await, Ok(true)) {
+        requests.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+async fn serve_redirect_chain_connection(stream: Stream, hops: usize, requests: Arc<AtomicUsize>) {
+    let mut stream = Some(stream);
+
+    while let Some(reused) = stream.take() {
+        let mut session = ServerSession::new_http1(reused);
+        if !matches!(session.read_request().await, Ok(true)) {
+            return;
+        }
+        requests.fetch_add(1, Ordering::SeqCst);
+
+        let path = session.req_header().uri.path().to_string();
+        let hop = path.trim_start_matches('/').parse::<usize>().unwrap_or(0);
+        let (mut response, body) = if hop < hops {
+            let mut response = ResponseHeader::build(302, None).unwrap();
+            response
+                .insert_header(http::header::LOCATION, format!("/{}", hop + 1))
+                .unwrap();
+            (response, String::new())
+        } else {
+            (ResponseHeader::build(200, None).unwrap(), path)
+        };
+        response
+            .insert_header(http::header::CONTENT_LENGTH, body.len())
+            .unwrap();
+        if session
+            .write_response_header(Box::new(response))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        if !body.is_empty() {
+            session
+                .write_response_body(Bytes::from(body), true)
+                .await
+                .ok();
+        }
+
+        stream = session.finish().await.ok().flatten();
+    }
+}
+
+async fn serve_h2c_connection(
+    stream: Stream,
+    status: u16,
+    body: &'static str,
+    trailers: &'static [(&'static str, &'static str)],
+    requests: Arc<AtomicUsize>,
+) {
+    let Ok(mut connection) = server::handshake(stream, None).await else {
+        return;
+    };
+    let digest = Arc::new(Digest::default());
+
+    let mut streams = JoinSet::new();
+    while let Ok(Some(mut session)) =
+        server::HttpSession::from_h2_conn(&mut connection, digest.clone()).await
+    {
+        let requests = requests.clone();
+        streams.spawn(async move {
+            while let Ok(Some(_)) = session.read_body_bytes().await {}
+            requests.fetch_add(1, Ordering::SeqCst);
+
+            let response = ResponseHeader::build(status, None).unwrap();
+            if session
+                .write_response_header(Box::new(response), false)
+                .is_err()
+            {
+                return;
+            }
+            session
+                .write_body(Bytes::from_static(body.as_bytes()), trailers.is_empty())
+                .await
+                .ok();
+
+            if !trailers.is_empty() {
+                let mut response_trailers = HeaderMap::new();
+                for (name, value) in trailers {
+                    response_trailers.insert(*name, value.parse().unwrap());
+                }
+                session.write_trailers(response_trailers).ok();
+            }
+        });
+    }
+}
+
+/// A route of `host` load balancing between `upstreams`
+pub fn route(host: &str, upstreams: impl IntoIterator<Item = SocketAddr>) -> Route {
+    Route {
+        host: host.to_string().into(),
+        upstreams: upstreams
+            .into_iter()
+            .map(|addr| RouteUpstream {
+                ip: addr.ip().to_string().into(),
+                port: addr.port(),
+                ..RouteUpstream::default()
+            })
+            .collect(),
+        ..Route::default()
+    }
+}
+
+/// Adds `route` to the route store (or replaces the route of the same host)
+pub async fn add_route(route: Route) {
+    stores::remove_route(&stores::route_key(&route.host, route.listener_port));
+    apply_route_changes(&[], &[route]).await;
+}
+
+/// Runs the health check of the upstreams of `host` once
+pub async fn check_health(host: &str) {
+    let route = stores::get_route_by_key(host).expect("the route doesn't exist");
+    route.load_balancer.backends().run_health_check(false).await;
+}
+
+/// Whether the upstream `addr` of `host` is healthy
+pub fn is_healthy(host: &str, addr: SocketAddr) -> bool {
+    let route = stores::get_route_by_key(host).expect("the route doesn't exist");
+    let backends = route.load_balancer.backends();
+
+    backends
+        .get_backend()
+        .iter()
+        .filter(|backend| backend.addr.as_inet() == Some(&addr))
+        .any(|backend| backends.ready(backend))
+}
+
+/// Initializes the certificate store with a memory store (once for all the tests)
+pub fn init_memory_store() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| init_store(MemoryStore::new()));
+}
+
+/// A self-signed certificate of `domain` (as its common name) valid for
+/// `valid_days`, and its RSA key of `key_bits`
+pub fn self_signed_certificate(
+    domain: &str,
+    key_bits: u32,
+    valid_days: u32,
+) -> (X509, PKey<Private>) {
+    let key = PKey::from_rsa(Rsa::generate(key_bits).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", domain).unwrap();
+    let name = name.build();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(valid_days).unwrap())
+        .unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+    (cert.build(), key)
+}
+
+/// The proksi router listening on a local address, without TLS
+pub struct TestProxy {
+    addr: SocketAddr,
+    client: reqwest::Client,
+    shutdown: watch::Sender<bool>,
+}
+
+impl TestProxy {
+    /// Starts the router with the default configuration
+    pub async fn start() -> Self {
+        Self::start_with_config(&Config::default()).await
+    }
+
+    pub async fn start_with_config(config: &Config) -> Self {
+        Self::listen(Router::new(config), ConnectionLimiter::from_config(config)).await
+    }
+
+    /// Starts another proxy application (ex: the HTTP one) instead of the router
+    pub async fn start_with_app<SV>(app: SV) -> Self
+    where
+        SV: ProxyHttp + Send + Sync + 'static,
+        SV::CTX: Send + Sync,
+    {
+        Self::listen(app, None).await
+    }
+
+    /// Serves `app` like the HTTPS listeners do, without TLS
+    async fn listen<SV>(app: SV, connection_limiter: Option<Arc<ConnectionLimiter>>) -> Self
+    where
+        SV: ProxyHttp + Send + Sync + 'static,
+        SV::CTX: Send + Sync,
+    {
+        let addr = free_addr();
+        let mut service = http_proxy_service(&Arc::new(ServerConf::default()), app);
+        // HTTP/1 clients are still served, HTTP/2 ones don't need TLS
+        let mut server_options = HttpServerOptions::default();
+        server_options.h2c = true;
+        service.app_logic_mut().unwrap().server_options = Some(server_options);
+        let mut service = AcceptApp::service(service, connection_limiter, None);
+        service.add_tcp(&addr.to_string());
+
+        let (shutdown, watch) = watch::channel(false);
+        tokio::spawn(async move { service.start_service(None, watch, 1).await });
+        wait_for_listener(addr).await;
+
+        Self {
+            addr,
+            // The responses of the proxy are checked as they are sent
+            client: reqwest::Client::builder()
+                .no_proxy()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .unwrap(),
+            shutdown,
+        }
+    }
+
+    /// The address the proxy listens on
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// A request to the proxy for `host`, to send (or customize) with reqwest
+    pub fn request(
+        &self,
+        method: reqwest::Method,
+        host: &str,
+        path: &str,
+    ) -> reqwest::RequestBuilder {
+        self.client
+            .request(method, format!("http://{}{path}", self.addr))
+            .header(http::header::HOST, host)
+    }
+
+    /// Sends a `GET` request for `host` and returns the status and the body
+    pub async fn get(&self, host: &str, path: &str) -> (u16, String) {
+        let response = self
+            .request(reqwest::Method::GET, host, path)
+            .send()
+            .await
+            .unwrap();
+
+        let status = response.status().as_u16();
+        (status, response.text().await.unwrap())
+    }
+
+    /// Sends a `GET` request for `host` over HTTP/2 (h2c), returns the status,
+    /// the body and the trailers
+    pub async fn get_h2c(&self, host: &str, path: &str) -> (u16, String, HeaderMap) {
+        let mut peer = HttpPeer::new(self.addr, false, String::new());
+        peer.options.alpn = ALPN::H2;
+        let session = Connector::new(None).new_http_session(&peer).await.unwrap();
+        let HttpSession::H2(mut session) = session else {
+            panic!("the proxy didn't answer with HTTP/2");
+        };
+
+        let mut request = RequestHeader::build("GET", path.as_bytes(), None).unwrap();
+        request.insert_header(http::header::HOST, host).unwrap();
+        session
+            .write_request_header(Box::new(request), true)
+            .unwrap();
+
+        session.read_response_header().await.unwrap();
+        let status = session.response_header().unwrap().status.as_u16();
+        let mut body = Vec::new();
+        while let Some(chunk) = session.read_response_body().await.unwrap() {
+            body.extend_from_slice(&chunk);
+        }
+        let trailers = session.read_trailers().await.unwrap().unwrap_or_default();
+
+        (status, String::from_utf8_lossy(&body).to_string(), trailers)
+    }
+}
+
+impl Drop for TestProxy {
+    fn drop(&mut self) {
+        self.shutdown.send(true).ok();
+    }
+}
+
+/// Captures the logs of a tracing subscriber, to pass to its `with_writer`
+#[derive(Clone, Default)]
+pub struct LogCapture(Arc<Mutex<Vec<u8>>>);
+
+impl LogCapture {
+    /// The logs written so far
+    pub fn output(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl std::io::Write for LogCapture {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogCapture {
+    type Writer = LogCapture;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_requests_are_balanced_between_the_backends() {
+        let first = TestBackend::start(200, "first").await;
+        let second = TestBackend::start(200, "second").await;
+        add_route(route(
+            "balanced.harness.test",
+            [first.addr(), second.addr()],
+        ))
+        .await;
+
+        let proxy = TestProxy::start().await;
+        for _ in 0..4 {
+            let (status, body) = proxy.get("balanced.harness.test", "/").await;
+            assert_eq!(status, 200);
+            assert!(body == "first" || body == "second", "{body}");
+        }
+
+        assert_eq!(first.requests(), 2);
+        assert_eq!(second.requests(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_host_is_not_found() {
+        let proxy = TestProxy::start().await;
+
+        let (status, _) = proxy.get("unknown.harness.test", "/").await;
+        assert_eq!(status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_stopped_backend_is_no_longer_selected() {
+        let mut stopped = TestBackend::start(200, "stopped").await;
+        let running = TestBackend::start(200, "running").await;
+        let host = "health.harness.test";
+        add_route(route(host, [stopped.addr(), running.addr()])).await;
+
+        check_health(host).await;
+        assert!(is_healthy(host, stopped.addr()));
+
+        stopped.stop().await;
+        check_health(host).await;
+        assert!(!is_healthy(host, stopped.addr()));
+        assert!(is_healthy(host, running.addr()));
+
+        let proxy = TestProxy::start().await;
+        for _ in 0..3 {
+            assert_eq!(proxy.get(host, "/").await, (200, "running".to_string()));
+        }
+        assert_eq!(running.requests(), 3);
+    }
+}